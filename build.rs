@@ -1,5 +1,54 @@
 extern crate configure_me_codegen;
 
+use std::path::Path;
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Short hash of the built commit ("unknown" when not building from a git checkout)
+fn git_commit() -> String {
+    for path in &[".git/HEAD", ".git/index"] {
+        if Path::new(path).exists() {
+            println!("cargo:rerun-if-changed={}", path);
+        }
+    }
+    Command::new("git")
+        .args(&["rev-parse", "--short=12", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|hash| hash.trim().to_owned())
+        .filter(|hash| !hash.is_empty())
+        .unwrap_or_else(|| "unknown".to_owned())
+}
+
+// UTC build date (YYYY-MM-DD), using `SOURCE_DATE_EPOCH` for reproducible builds
+fn build_date() -> String {
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+    let secs = std::env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|epoch| epoch.parse::<u64>().ok())
+        .unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .expect("invalid system time")
+                .as_secs()
+        });
+    // see http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let days = (secs / 86400) as i64 + 719_468;
+    let era = days.div_euclid(146_097);
+    let doe = days.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
 fn main() -> Result<(), configure_me_codegen::Error> {
+    println!("cargo:rustc-env=ELECTRS_GIT_COMMIT={}", git_commit());
+    println!("cargo:rustc-env=ELECTRS_BUILD_DATE={}", build_date());
     configure_me_codegen::build_script_auto()
 }
@@ -1,28 +1,55 @@
-use bitcoin::{Transaction, Txid};
-use parking_lot::RwLock;
+use anyhow::Result;
+use bitcoin::{BlockHash, Transaction, Txid};
+use parking_lot::{Mutex, RwLock};
+use serde_json::Value;
 
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, Write};
 use std::sync::Arc;
 
-use crate::metrics::{self, Histogram, Metrics};
+use crate::{
+    merkle::{MerkleTree, Proof},
+    metrics::{self, Counter, Histogram, Metrics},
+    types::{ScriptHash, StatusHash},
+};
+
+const MAX_CACHED_HISTORIES: usize = 10_000;
+const MAX_CACHED_HISTORY_BYTES: usize = 100 << 20; // serialized JSON size
+const MAX_CACHED_MERKLE_TREES: usize = 10;
+const MAX_CACHED_PROOFS: usize = 1_000;
 
 pub(crate) struct Cache {
     txs: Arc<RwLock<HashMap<Txid, Transaction>>>,
+    proofs: Arc<Mutex<VecDeque<((BlockHash, Txid), Proof)>>>, // most recently used first
+    merkle_trees: Arc<Mutex<VecDeque<(BlockHash, Arc<MerkleTree>)>>>, // most recently used first
+    histories: Arc<RwLock<HistoryCache>>,
 
     // stats
     txs_size: Histogram,
+    history_lookups: Counter,
 }
 
 impl Cache {
     pub fn new(metrics: &Metrics) -> Self {
         Cache {
             txs: Default::default(),
+            proofs: Default::default(),
+            merkle_trees: Default::default(),
+            histories: Arc::new(RwLock::new(HistoryCache::new(
+                MAX_CACHED_HISTORIES,
+                MAX_CACHED_HISTORY_BYTES,
+            ))),
             txs_size: metrics.histogram_vec(
                 "cache_txs_size",
                 "Cached transactions' size (in bytes)",
                 "type",
                 metrics::default_size_buckets(),
             ),
+            history_lookups: metrics.counter(
+                "cache_history_lookups",
+                "# of cached history lookups",
+                "result",
+            ),
         }
     }
 
@@ -40,4 +67,273 @@ impl Cache {
     {
         self.txs.read().get(txid).map(f)
     }
+
+    pub(crate) fn add_proof<F>(&self, blockhash: BlockHash, txid: Txid, f: F)
+    where
+        F: FnOnce() -> Proof,
+    {
+        let key = (blockhash, txid);
+        let mut proofs = self.proofs.lock();
+        if proofs.iter().all(|(cached, _)| *cached != key) {
+            proofs.push_front((key, f()));
+            proofs.truncate(MAX_CACHED_PROOFS);
+        }
+    }
+
+    pub(crate) fn get_proof<F, T>(&self, blockhash: BlockHash, txid: Txid, f: F) -> Option<T>
+    where
+        F: FnOnce(&Proof) -> T,
+    {
+        let key = (blockhash, txid);
+        let mut proofs = self.proofs.lock();
+        let index = proofs.iter().position(|(cached, _)| *cached == key)?;
+        let entry = proofs.remove(index).unwrap();
+        let result = f(&entry.1);
+        proofs.push_front(entry);
+        Some(result)
+    }
+
+    /// Return the cached merkle tree of a block, or build it.
+    /// Only a few trees are kept, since large blocks' trees take a few MBs.
+    pub(crate) fn get_merkle_tree<F>(&self, blockhash: BlockHash, f: F) -> Result<Arc<MerkleTree>>
+    where
+        F: FnOnce() -> Result<MerkleTree>,
+    {
+        {
+            let mut trees = self.merkle_trees.lock();
+            if let Some(index) = trees.iter().position(|(hash, _)| *hash == blockhash) {
+                let entry = trees.remove(index).unwrap();
+                let tree = Arc::clone(&entry.1);
+                trees.push_front(entry);
+                return Ok(tree);
+            }
+        }
+        // the tree is built without holding the lock
+        let tree = Arc::new(f()?);
+        let mut trees = self.merkle_trees.lock();
+        trees.retain(|(hash, _)| *hash != blockhash);
+        trees.push_front((blockhash, Arc::clone(&tree)));
+        trees.truncate(MAX_CACHED_MERKLE_TREES);
+        Ok(tree)
+    }
+
+    /// Return the cached history response (if the status hash is unchanged), or compute it.
+    pub(crate) fn get_history<F>(
+        &self,
+        scripthash: ScriptHash,
+        statushash: StatusHash,
+        f: F,
+    ) -> Arc<Value>
+    where
+        F: FnOnce() -> Value,
+    {
+        if let Some(value) = self.histories.read().get(scripthash, statushash) {
+            self.history_lookups.inc("hit");
+            return value;
+        }
+        self.history_lookups.inc("miss");
+        let value = Arc::new(f());
+        self.histories
+            .write()
+            .insert(scripthash, statushash, Arc::clone(&value));
+        value
+    }
+}
+
+/// Counts the bytes written to it (used to estimate the memory used by cached responses).
+struct SizeCounter(usize);
+
+impl Write for SizeCounter {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.0 += data.len();
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn serialized_size(value: &Value) -> usize {
+    let mut counter = SizeCounter(0);
+    serde_json::to_writer(&mut counter, value).expect("failed to serialize JSON");
+    counter.0
+}
+
+/// History responses, valid until the scripthash status hash changes
+struct HistoryCache {
+    entries: HashMap<ScriptHash, (StatusHash, Arc<Value>, usize)>, // including serialized size
+    order: VecDeque<ScriptHash>, // insertion order, for evicting the oldest entries
+    capacity: usize,
+    total_bytes: usize,
+    max_bytes: usize,
+}
+
+impl HistoryCache {
+    fn new(capacity: usize, max_bytes: usize) -> Self {
+        Self {
+            entries: HashMap::new(),
+            order: VecDeque::new(),
+            capacity,
+            total_bytes: 0,
+            max_bytes,
+        }
+    }
+
+    fn get(&self, scripthash: ScriptHash, statushash: StatusHash) -> Option<Arc<Value>> {
+        match self.entries.get(&scripthash) {
+            Some((cached, value, _)) if *cached == statushash => Some(Arc::clone(value)),
+            _ => None,
+        }
+    }
+
+    fn insert(&mut self, scripthash: ScriptHash, statushash: StatusHash, value: Arc<Value>) {
+        let size = serialized_size(&value);
+        if size > self.max_bytes {
+            return; // don't evict the whole cache for a single huge history
+        }
+        self.total_bytes += size;
+        // an entry with a different status hash is replaced
+        match self.entries.insert(scripthash, (statushash, value, size)) {
+            Some((_, _, old_size)) => self.total_bytes -= old_size,
+            None => self.order.push_back(scripthash),
+        }
+        while self.order.len() > self.capacity || self.total_bytes > self.max_bytes {
+            let oldest = self.order.pop_front().expect("empty order");
+            let (_, _, old_size) = self.entries.remove(&oldest).expect("missing entry");
+            self.total_bytes -= old_size;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Cache, HistoryCache, MAX_CACHED_MERKLE_TREES, MAX_CACHED_PROOFS};
+    use crate::merkle::{MerkleTree, Proof};
+    use crate::tests::metrics;
+    use crate::types::{ScriptHash, StatusHash};
+    use bitcoin::{hashes::Hash, BlockHash, Txid};
+    use serde_json::json;
+
+    use std::cell::Cell;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_history_cache() {
+        let cache = Cache::new(&metrics());
+        let scripthash = ScriptHash::hash(&[1]);
+        let (status1, status2) = (StatusHash::hash(&[1]), StatusHash::hash(&[2]));
+        let calls = Cell::new(0);
+        let get = |statushash, history| {
+            cache.get_history(scripthash, statushash, || {
+                calls.set(calls.get() + 1);
+                json!(history)
+            })
+        };
+
+        assert_eq!(*get(status1, vec![1]), json!([1]));
+        assert_eq!(*get(status1, vec![1]), json!([1])); // cache hit
+        assert_eq!(calls.get(), 1);
+
+        // status has changed
+        assert_eq!(*get(status2, vec![1, 2]), json!([1, 2]));
+        assert_eq!(*get(status2, vec![1, 2]), json!([1, 2]));
+        assert_eq!(calls.get(), 2);
+    }
+
+    #[test]
+    fn test_merkle_tree_cache() {
+        let cache = Cache::new(&metrics());
+        let calls = Cell::new(0);
+        let get = |i: u8| {
+            cache
+                .get_merkle_tree(BlockHash::hash(&[i]), || {
+                    calls.set(calls.get() + 1);
+                    Ok(MerkleTree::build(&[Txid::hash(&[i])]))
+                })
+                .unwrap()
+        };
+
+        let tree = get(0);
+        assert!(Arc::ptr_eq(&tree, &get(0))); // cache hit
+        assert_eq!(calls.get(), 1);
+
+        for i in 1..=MAX_CACHED_MERKLE_TREES as u8 {
+            get(i);
+        }
+        assert_eq!(calls.get(), MAX_CACHED_MERKLE_TREES + 1);
+        assert_eq!(cache.merkle_trees.lock().len(), MAX_CACHED_MERKLE_TREES);
+        get(0); // evicted
+        assert_eq!(calls.get(), MAX_CACHED_MERKLE_TREES + 2);
+
+        // failures are not cached
+        assert!(cache
+            .get_merkle_tree(BlockHash::hash(&[100]), || anyhow::bail!("failed"))
+            .is_err());
+        assert_eq!(cache.merkle_trees.lock().len(), MAX_CACHED_MERKLE_TREES);
+    }
+
+    #[test]
+    fn test_proof_cache() {
+        let cache = Cache::new(&metrics());
+        let txids: Vec<Txid> = (0..=MAX_CACHED_PROOFS as u32)
+            .map(|i| Txid::hash(&i.to_le_bytes()))
+            .collect();
+        let tree = MerkleTree::build(&txids);
+        let blockhash = BlockHash::hash(&[0]);
+        let get = |txid: Txid| cache.get_proof(blockhash, txid, Proof::clone);
+        let add = |txid: Txid| cache.add_proof(blockhash, txid, || tree.find(&txid).unwrap());
+
+        assert!(get(txids[0]).is_none());
+        add(txids[0]);
+        assert!(get(txids[0]).is_some());
+
+        for &txid in &txids[1..MAX_CACHED_PROOFS] {
+            add(txid);
+        }
+        get(txids[0]); // most recently used
+        add(txids[MAX_CACHED_PROOFS]);
+        assert_eq!(cache.proofs.lock().len(), MAX_CACHED_PROOFS);
+        assert!(get(txids[0]).is_some());
+        assert!(get(txids[1]).is_none()); // least recently used is evicted
+    }
+
+    #[test]
+    fn test_history_cache_capacity() {
+        let mut cache = HistoryCache::new(2, 1000);
+        let statushash = StatusHash::hash(&[0]);
+        let scripthashes: Vec<ScriptHash> = (1..=3u8).map(|i| ScriptHash::hash(&[i])).collect();
+        for (i, scripthash) in scripthashes.iter().enumerate() {
+            cache.insert(*scripthash, statushash, json!(i).into());
+        }
+        assert_eq!(cache.entries.len(), 2);
+        assert!(cache.get(scripthashes[0], statushash).is_none()); // evicted
+        assert_eq!(*cache.get(scripthashes[2], statushash).unwrap(), json!(2));
+
+        // replacing an entry doesn't evict others
+        cache.insert(scripthashes[2], StatusHash::hash(&[1]), json!(3).into());
+        assert!(cache.get(scripthashes[2], statushash).is_none());
+        assert_eq!(*cache.get(scripthashes[1], statushash).unwrap(), json!(1));
+        assert_eq!(cache.order.len(), 2);
+    }
+
+    #[test]
+    fn test_history_cache_size() {
+        let mut cache = HistoryCache::new(10, 10);
+        let statushash = StatusHash::hash(&[0]);
+        let scripthashes: Vec<ScriptHash> = (1..=3u8).map(|i| ScriptHash::hash(&[i])).collect();
+        cache.insert(scripthashes[0], statushash, json!("abcd").into()); // 6 bytes
+        cache.insert(scripthashes[1], statushash, json!("ab").into()); // 4 bytes
+        assert_eq!(cache.total_bytes, 10);
+
+        cache.insert(scripthashes[2], statushash, json!(1).into());
+        assert!(cache.get(scripthashes[0], statushash).is_none()); // evicted
+        assert_eq!(cache.total_bytes, 5);
+
+        // too large to be cached
+        cache.insert(scripthashes[0], statushash, json!("abcdefghij").into());
+        assert!(cache.get(scripthashes[0], statushash).is_none());
+        assert_eq!(cache.entries.len(), 2);
+        assert_eq!(cache.total_bytes, 5);
+    }
 }
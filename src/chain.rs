@@ -47,6 +47,11 @@ impl Chain {
         };
         let genesis_header_bytes = Vec::from_hex(genesis_header_hex).unwrap();
         let genesis: BlockHeader = deserialize(&genesis_header_bytes).unwrap();
+        Self::with_genesis(genesis)
+    }
+
+    // create an empty chain, starting at a custom genesis header
+    pub(crate) fn with_genesis(genesis: BlockHeader) -> Self {
         assert_eq!(genesis.prev_blockhash, BlockHash::default());
         Self {
             headers: vec![(genesis.block_hash(), genesis)],
@@ -101,6 +106,19 @@ impl Chain {
         self.heights.get(blockhash).copied()
     }
 
+    /// Iterate over the headers from the given block back to genesis (empty for unknown hashes)
+    pub(crate) fn ancestors(
+        &self,
+        start: BlockHash,
+    ) -> impl Iterator<Item = (usize, BlockHash, &BlockHeader)> {
+        let end = self.get_block_height(&start).map_or(0, |height| height + 1);
+        self.headers[..end]
+            .iter()
+            .enumerate()
+            .rev()
+            .map(|(height, (hash, header))| (height, *hash, header))
+    }
+
     /// Update the chain with a list of new headers (possibly a reorg)
     pub(crate) fn update(&mut self, headers: Vec<NewHeader>) {
         if let Some(first_height) = headers.first().map(|h| h.height) {
@@ -135,17 +153,18 @@ impl Chain {
     /// see https://en.bitcoin.it/wiki/Protocol_documentation#getblocks
     pub(crate) fn locator(&self) -> Vec<BlockHash> {
         let mut result = vec![];
-        let mut index = self.headers.len() - 1;
         let mut step = 1;
-        loop {
+        let mut ancestors = self.ancestors(self.tip());
+        while let Some((height, blockhash, _header)) = ancestors.next() {
             if result.len() >= 10 {
                 step *= 2;
             }
-            result.push(self.headers[index].0);
-            if index == 0 {
-                break;
+            result.push(blockhash);
+            // skip to the next locator block (the last one is genesis)
+            let skip = std::cmp::min(step, height);
+            if skip > 1 {
+                ancestors.nth(skip - 2);
             }
-            index = index.saturating_sub(step);
         }
         result
     }
@@ -154,6 +173,7 @@ impl Chain {
 #[cfg(test)]
 mod tests {
     use super::{Chain, NewHeader};
+    use crate::tests::make_blocks;
     use bitcoin::consensus::deserialize;
     use bitcoin::hashes::hex::{FromHex, ToHex};
     use bitcoin::network::constants::Network::Regtest;
@@ -169,6 +189,26 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_custom_genesis() {
+        let mut genesis = *Chain::new(Regtest).get_block_header(0).unwrap();
+        genesis.time += 1;
+        let genesis_hash = genesis.block_hash();
+
+        let mut custom = Chain::with_genesis(genesis);
+        assert_eq!(custom.height(), 0);
+        assert_eq!(custom.tip(), genesis_hash);
+        assert_eq!(custom.get_block_height(&genesis_hash), Some(0));
+        assert_eq!(custom.locator(), vec![genesis_hash]);
+
+        let mut header = genesis;
+        header.prev_blockhash = genesis_hash;
+        custom.update(vec![NewHeader::from((header, 1))]);
+        assert_eq!(custom.height(), 1);
+        assert_eq!(custom.tip(), header.block_hash());
+        assert_eq!(custom.get_block_hash(0), Some(genesis_hash));
+    }
+
     #[test]
     fn test_updates() {
         let hex_headers = vec![
@@ -253,4 +293,95 @@ mod tests {
             "0e16637fe0700a7c52e9a6eaa58bd6ac7202652103be8f778680c66f51ad2e9b"
         );
     }
+
+    #[test]
+    fn test_locator() {
+        let blocks = make_blocks(vec![vec![]; 1000]);
+        let mut regtest = Chain::new(Regtest);
+        regtest.update(
+            blocks
+                .iter()
+                .zip(1..)
+                .map(|(block, height)| NewHeader::from((block.header, height)))
+                .collect(),
+        );
+
+        let heights: Vec<usize> = regtest
+            .locator()
+            .iter()
+            .map(|blockhash| regtest.get_block_height(blockhash).unwrap())
+            .collect();
+        assert_eq!(heights.first(), Some(&1000)); // starts from the tip
+        assert_eq!(heights.last(), Some(&0)); // ends with genesis
+        assert_eq!(
+            &heights[..10],
+            &[1000, 999, 998, 997, 996, 995, 994, 993, 992, 991]
+        );
+
+        // the density decreases exponentially
+        let gaps: Vec<usize> = heights.windows(2).map(|w| w[0] - w[1]).collect();
+        assert!(gaps.windows(2).all(|w| w[0] <= w[1]));
+        assert!(gaps[..gaps.len() - 1]
+            .windows(2)
+            .skip(9)
+            .all(|w| w[1] == 2 * w[0]));
+        assert!(heights.len() < 30);
+    }
+
+    #[test]
+    fn test_ancestors() {
+        let blocks = make_blocks(vec![vec![]; 10]);
+        let mut regtest = Chain::new(Regtest);
+        regtest.update(
+            blocks
+                .iter()
+                .zip(1..)
+                .map(|(block, height)| NewHeader::from((block.header, height)))
+                .collect(),
+        );
+
+        let ancestors: Vec<_> = regtest.ancestors(regtest.tip()).collect();
+        assert_eq!(ancestors.len(), 11);
+        for (expected_height, (height, blockhash, header)) in (0..=10).rev().zip(ancestors.iter()) {
+            assert_eq!(*height, expected_height);
+            assert_eq!(regtest.get_block_hash(*height), Some(*blockhash));
+            assert_eq!(header.block_hash(), *blockhash);
+        }
+        // each header points to the next one (ending with genesis)
+        for pair in ancestors.windows(2) {
+            assert_eq!(pair[0].2.prev_blockhash, pair[1].1);
+        }
+        assert_eq!(
+            ancestors.last().unwrap().1,
+            regtest.get_block_hash(0).unwrap()
+        );
+
+        let middle = regtest.get_block_hash(4).unwrap();
+        let heights: Vec<usize> = regtest
+            .ancestors(middle)
+            .map(|(height, ..)| height)
+            .collect();
+        assert_eq!(heights, vec![4, 3, 2, 1, 0]);
+
+        assert_eq!(regtest.ancestors(Default::default()).count(), 0);
+    }
+
+    #[test]
+    fn test_hash_height_lookup() {
+        let blocks = make_blocks(vec![vec![]; 10]);
+        let mut regtest = Chain::new(Regtest);
+        regtest.update(
+            blocks
+                .iter()
+                .zip(1..)
+                .map(|(block, height)| NewHeader::from((block.header, height)))
+                .collect(),
+        );
+        for height in 0..=regtest.height() {
+            let blockhash = regtest.get_block_hash(height).unwrap();
+            assert_eq!(regtest.get_block_height(&blockhash), Some(height));
+        }
+        assert_eq!(regtest.get_block_hash(11), None);
+        assert_eq!(regtest.get_block_height(&Default::default()), None);
+    }
 }
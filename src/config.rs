@@ -1,18 +1,36 @@
+use bitcoin::hashes::hex::FromHex;
 use bitcoin::network::constants::Network;
+use bitcoin::BlockHash;
 use bitcoincore_rpc::Auth;
 use dirs_next::home_dir;
+use serde_json::{json, Value};
 
+use std::collections::HashSet;
+use std::convert::TryFrom;
 use std::ffi::{OsStr, OsString};
 use std::fmt;
-use std::net::SocketAddr;
+use std::io::Write;
 use std::net::ToSocketAddrs;
-use std::path::PathBuf;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 
 use std::env::consts::{ARCH, OS};
 use std::time::Duration;
 
+use crate::electrum::is_known_method;
+use crate::logfile::RotatingFile;
+use crate::types::ScriptHash;
+
 pub const ELECTRS_VERSION: &str = env!("CARGO_PKG_VERSION");
+/// Including the git commit and the build date (as SemVer build metadata, set by `build.rs`)
+pub const ELECTRS_VERSION_RICH: &str = concat!(
+    env!("CARGO_PKG_VERSION"),
+    "+",
+    env!("ELECTRS_GIT_COMMIT"),
+    ".",
+    env!("ELECTRS_BUILD_DATE")
+);
 const DEFAULT_SERVER_ADDRESS: [u8; 4] = [127, 0, 0, 1]; // by default, serve on IPv4 localhost
 
 mod internal {
@@ -90,6 +108,35 @@ impl ResolvAddr {
             std::process::exit(1)
         })
     }
+
+    /// Resolves a monitoring address, which may also be a Unix socket (`unix:/path`).
+    fn resolve_monitoring(self) -> std::result::Result<MonitoringAddr, AddressError> {
+        if let Some(path) = self.0.strip_prefix(UNIX_ADDR_PREFIX) {
+            if path.is_empty() {
+                return Err(AddressError::NoAddrError(self.0));
+            }
+            return Ok(MonitoringAddr::Unix(PathBuf::from(path)));
+        }
+        self.resolve().map(MonitoringAddr::Tcp)
+    }
+}
+
+const UNIX_ADDR_PREFIX: &str = "unix:";
+
+/// Address to serve Prometheus metrics on
+#[derive(Clone, Debug, PartialEq)]
+pub enum MonitoringAddr {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl fmt::Display for MonitoringAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MonitoringAddr::Tcp(addr) => write!(f, "{}", addr),
+            MonitoringAddr::Unix(path) => write!(f, "{}{}", UNIX_ADDR_PREFIX, path.display()),
+        }
+    }
 }
 
 /// This newtype implements `ParseArg` for `Network`.
@@ -122,28 +169,621 @@ impl From<BitcoinNetwork> for Network {
     }
 }
 
+/// Genesis block hash of a custom chain
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(try_from = "String")]
+pub struct GenesisHash(BlockHash);
+
+impl FromStr for GenesisHash {
+    type Err = String;
+
+    fn from_str(string: &str) -> std::result::Result<Self, Self::Err> {
+        BlockHash::from_hex(string)
+            .map(GenesisHash)
+            .map_err(|err| format!("invalid genesis hash {:?}: {}", string, err))
+    }
+}
+
+impl TryFrom<String> for GenesisHash {
+    type Error = String;
+
+    fn try_from(string: String) -> std::result::Result<Self, Self::Error> {
+        string.parse()
+    }
+}
+
+impl ::configure_me::parse_arg::ParseArgFromStr for GenesisHash {
+    fn describe_type<W: fmt::Write>(mut writer: W) -> fmt::Result {
+        write!(writer, "a block hash (64 hex characters)")
+    }
+}
+
+/// p2p message start bytes of a custom chain (as shown by bitcoind, e.g. '0a03cf40')
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(try_from = "String")]
+pub struct NetworkMagic(u32);
+
+impl FromStr for NetworkMagic {
+    type Err = String;
+
+    fn from_str(string: &str) -> std::result::Result<Self, Self::Err> {
+        match Vec::<u8>::from_hex(string).as_deref() {
+            Ok(&[b0, b1, b2, b3]) => Ok(NetworkMagic(u32::from_le_bytes([b0, b1, b2, b3]))),
+            _ => Err(format!("invalid network magic: {:?}", string)),
+        }
+    }
+}
+
+impl TryFrom<String> for NetworkMagic {
+    type Error = String;
+
+    fn try_from(string: String) -> std::result::Result<Self, Self::Error> {
+        string.parse()
+    }
+}
+
+impl ::configure_me::parse_arg::ParseArgFromStr for NetworkMagic {
+    fn describe_type<W: fmt::Write>(mut writer: W) -> fmt::Result {
+        write!(writer, "4 bytes in hex (e.g. '0a03cf40')")
+    }
+}
+
+/// Electrum protocol version (e.g. '1.4' or '1.4.2')
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(try_from = "String")]
+pub struct ProtocolVersion(Vec<u32>);
+
+impl FromStr for ProtocolVersion {
+    type Err = String;
+
+    fn from_str(string: &str) -> std::result::Result<Self, Self::Err> {
+        string
+            .split('.')
+            .map(u32::from_str)
+            .collect::<std::result::Result<Vec<u32>, _>>()
+            .map(ProtocolVersion)
+            .map_err(|_| format!("invalid protocol version: {:?}", string))
+    }
+}
+
+impl TryFrom<String> for ProtocolVersion {
+    type Error = String;
+
+    fn try_from(string: String) -> std::result::Result<Self, Self::Error> {
+        string.parse()
+    }
+}
+
+impl fmt::Display for ProtocolVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let parts: Vec<String> = self.0.iter().map(u32::to_string).collect();
+        write!(f, "{}", parts.join("."))
+    }
+}
+
+impl ::configure_me::parse_arg::ParseArgFromStr for ProtocolVersion {
+    fn describe_type<W: fmt::Write>(mut writer: W) -> fmt::Result {
+        write!(writer, "a protocol version (e.g. '1.4')")
+    }
+}
+
+/// bitcoind version (e.g. '0.21.1' or '22.0'), encoded as `getnetworkinfo` reports it
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DaemonVersion(pub usize);
+
+impl FromStr for DaemonVersion {
+    type Err = String;
+
+    fn from_str(string: &str) -> std::result::Result<Self, Self::Err> {
+        let err = || format!("invalid bitcoind version: {:?}", string);
+        let mut parts = string
+            .split('.')
+            .map(usize::from_str)
+            .collect::<std::result::Result<Vec<usize>, _>>()
+            .map_err(|_| err())?;
+        if parts.first() == Some(&0) {
+            parts.remove(0); // versions before 22.0 were formatted as '0.X.Y'
+        }
+        if parts.is_empty() || parts.len() > 3 || parts[1..].iter().any(|part| *part >= 100) {
+            return Err(err());
+        }
+        parts.resize(3, 0);
+        parts[0]
+            .checked_mul(10000)
+            .and_then(|version| version.checked_add(parts[1] * 100 + parts[2]))
+            .map(DaemonVersion)
+            .ok_or_else(err)
+    }
+}
+
+impl fmt::Display for DaemonVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (major, minor, patch) = (self.0 / 10000, self.0 / 100 % 100, self.0 % 100);
+        if major < 22 {
+            write!(f, "0.{}.{}", major, minor)?;
+        } else {
+            write!(f, "{}.{}", major, minor)?;
+        }
+        if patch > 0 {
+            write!(f, ".{}", patch)?;
+        }
+        Ok(())
+    }
+}
+
+impl ::configure_me::parse_arg::ParseArgFromStr for DaemonVersion {
+    fn describe_type<W: fmt::Write>(mut writer: W) -> fmt::Result {
+        write!(writer, "a bitcoind version (e.g. '22.0')")
+    }
+}
+
+/// IP address range (e.g. '10.0.0.0/8', 'fd00::/8' or a single address)
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct IpNet {
+    addr: IpAddr,
+    prefix_len: u32,
+}
+
+impl IpNet {
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        match (self.addr, canonical_ip(addr)) {
+            (IpAddr::V4(net), IpAddr::V4(addr)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix_len).unwrap_or(0);
+                u32::from(net) & mask == u32::from(addr) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(addr)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix_len).unwrap_or(0);
+                u128::from(net) & mask == u128::from(addr) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+// IPv4 clients of a dual-stack listener have IPv4-mapped IPv6 addresses (e.g. '::ffff:10.0.0.1')
+fn canonical_ip(addr: IpAddr) -> IpAddr {
+    if let IpAddr::V6(v6) = addr {
+        if let [0, 0, 0, 0, 0, 0xffff, hi, lo] = v6.segments() {
+            return IpAddr::V4(Ipv4Addr::from((u32::from(hi) << 16) | u32::from(lo)));
+        }
+    }
+    addr
+}
+
+impl FromStr for IpNet {
+    type Err = String;
+
+    fn from_str(string: &str) -> std::result::Result<Self, Self::Err> {
+        let mut parts = string.splitn(2, '/');
+        let addr: IpAddr = parts
+            .next()
+            .unwrap_or_default()
+            .parse()
+            .map_err(|_| format!("invalid IP address: {:?}", string))?;
+        let max_len = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match parts.next() {
+            Some(len) => len
+                .parse()
+                .ok()
+                .filter(|len| *len <= max_len)
+                .ok_or_else(|| format!("invalid prefix length: {:?}", string))?,
+            None => max_len,
+        };
+        Ok(IpNet { addr, prefix_len })
+    }
+}
+
+/// Comma-separated list of allowed IP address ranges
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(try_from = "String")]
+pub struct IpAllowlist(Vec<IpNet>);
+
+impl IpAllowlist {
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        self.0.iter().any(|net| net.contains(addr))
+    }
+}
+
+impl FromStr for IpAllowlist {
+    type Err = String;
+
+    fn from_str(string: &str) -> std::result::Result<Self, Self::Err> {
+        string
+            .split(',')
+            .map(|net| net.trim().parse())
+            .collect::<std::result::Result<Vec<IpNet>, _>>()
+            .map(IpAllowlist)
+    }
+}
+
+impl TryFrom<String> for IpAllowlist {
+    type Error = String;
+
+    fn try_from(string: String) -> std::result::Result<Self, Self::Error> {
+        string.parse()
+    }
+}
+
+impl ::configure_me::parse_arg::ParseArgFromStr for IpAllowlist {
+    fn describe_type<W: fmt::Write>(mut writer: W) -> fmt::Result {
+        write!(
+            writer,
+            "comma-separated IP addresses or CIDR ranges (e.g. '127.0.0.1,10.0.0.0/8,fd00::/8')"
+        )
+    }
+}
+
+/// Comma-separated list of Electrum RPC method names
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(try_from = "String")]
+pub struct MethodList(Vec<String>);
+
+impl FromStr for MethodList {
+    type Err = String;
+
+    fn from_str(string: &str) -> std::result::Result<Self, Self::Err> {
+        string
+            .split(',')
+            .map(|method| match method.trim() {
+                "" => Err(format!("empty method name in {:?}", string)),
+                method => Ok(method.to_owned()),
+            })
+            .collect::<std::result::Result<Vec<String>, _>>()
+            .map(MethodList)
+    }
+}
+
+impl TryFrom<String> for MethodList {
+    type Error = String;
+
+    fn try_from(string: String) -> std::result::Result<Self, Self::Error> {
+        string.parse()
+    }
+}
+
+impl ::configure_me::parse_arg::ParseArgFromStr for MethodList {
+    fn describe_type<W: fmt::Write>(mut writer: W) -> fmt::Result {
+        write!(
+            writer,
+            "comma-separated Electrum RPC methods (e.g. 'blockchain.transaction.broadcast,mempool.get_fee_histogram')"
+        )
+    }
+}
+
+/// Comma-separated list of (hex-encoded) scripthashes
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(try_from = "String")]
+pub struct ScriptHashList(Vec<ScriptHash>);
+
+impl FromStr for ScriptHashList {
+    type Err = String;
+
+    fn from_str(string: &str) -> std::result::Result<Self, Self::Err> {
+        string
+            .split(',')
+            .map(|hex| {
+                ScriptHash::from_hex(hex.trim())
+                    .map_err(|err| format!("invalid scripthash {:?}: {}", hex, err))
+            })
+            .collect::<std::result::Result<Vec<ScriptHash>, _>>()
+            .map(ScriptHashList)
+    }
+}
+
+impl TryFrom<String> for ScriptHashList {
+    type Error = String;
+
+    fn try_from(string: String) -> std::result::Result<Self, Self::Error> {
+        string.parse()
+    }
+}
+
+impl ::configure_me::parse_arg::ParseArgFromStr for ScriptHashList {
+    fn describe_type<W: fmt::Write>(mut writer: W) -> fmt::Result {
+        write!(writer, "comma-separated scripthashes (in hex)")
+    }
+}
+
+/// Logging output format
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+impl Default for LogFormat {
+    fn default() -> Self {
+        LogFormat::Text
+    }
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(string: &str) -> std::result::Result<Self, Self::Err> {
+        match string {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("unknown log format: {:?}", string)),
+        }
+    }
+}
+
+impl ::configure_me::parse_arg::ParseArgFromStr for LogFormat {
+    fn describe_type<W: fmt::Write>(mut writer: W) -> fmt::Result {
+        write!(writer, "either 'text' or 'json'")
+    }
+}
+
+/// Handling of blocks pruned by `bitcoind`, when queried (see `--on-pruned-block`)
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum OnPrunedBlock {
+    /// Fail the query with an Electrum error
+    Error,
+    /// Skip the pruned blocks (returning a partial result)
+    Skip,
+}
+
+impl FromStr for OnPrunedBlock {
+    type Err = String;
+
+    fn from_str(string: &str) -> std::result::Result<Self, Self::Err> {
+        match string {
+            "error" => Ok(OnPrunedBlock::Error),
+            "skip" => Ok(OnPrunedBlock::Skip),
+            _ => Err(format!("unknown pruned block behavior: {:?}", string)),
+        }
+    }
+}
+
+impl ::configure_me::parse_arg::ParseArgFromStr for OnPrunedBlock {
+    fn describe_type<W: fmt::Write>(mut writer: W) -> fmt::Result {
+        write!(writer, "either 'error' or 'skip'")
+    }
+}
+
+/// Where bitcoind's blocks are fetched from (see `--block-source`)
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum BlockSourceMode {
+    /// Use the p2p interface, falling back to JSON-RPC on failures
+    Auto,
+    /// Use only the p2p interface
+    P2p,
+    /// Use only the JSON-RPC interface (`getblock`)
+    Rpc,
+}
+
+impl Default for BlockSourceMode {
+    fn default() -> Self {
+        BlockSourceMode::Auto
+    }
+}
+
+impl FromStr for BlockSourceMode {
+    type Err = String;
+
+    fn from_str(string: &str) -> std::result::Result<Self, Self::Err> {
+        match string {
+            "auto" => Ok(BlockSourceMode::Auto),
+            "p2p" => Ok(BlockSourceMode::P2p),
+            "rpc" => Ok(BlockSourceMode::Rpc),
+            _ => Err(format!("unknown block source: {:?}", string)),
+        }
+    }
+}
+
+impl ::configure_me::parse_arg::ParseArgFromStr for BlockSourceMode {
+    fn describe_type<W: fmt::Write>(mut writer: W) -> fmt::Result {
+        write!(writer, "either 'auto', 'p2p' or 'rpc'")
+    }
+}
+
+/// Which rows are written by the indexer (see `--index-mode`)
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum IndexMode {
+    /// Both funding and spending rows (required for scripthash queries)
+    Full,
+    /// Only funding rows (scripthash outputs)
+    FundingOnly,
+    /// Only spending rows (spent outpoints)
+    SpendingOnly,
+}
+
+impl IndexMode {
+    pub(crate) fn indexes_funding(self) -> bool {
+        self != IndexMode::SpendingOnly
+    }
+
+    pub(crate) fn indexes_spending(self) -> bool {
+        self != IndexMode::FundingOnly
+    }
+}
+
+impl Default for IndexMode {
+    fn default() -> Self {
+        IndexMode::Full
+    }
+}
+
+impl FromStr for IndexMode {
+    type Err = String;
+
+    fn from_str(string: &str) -> std::result::Result<Self, Self::Err> {
+        match string {
+            "full" => Ok(IndexMode::Full),
+            "funding-only" => Ok(IndexMode::FundingOnly),
+            "spending-only" => Ok(IndexMode::SpendingOnly),
+            _ => Err(format!("unknown index mode: {:?}", string)),
+        }
+    }
+}
+
+impl fmt::Display for IndexMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            IndexMode::Full => "full",
+            IndexMode::FundingOnly => "funding-only",
+            IndexMode::SpendingOnly => "spending-only",
+        })
+    }
+}
+
+impl ::configure_me::parse_arg::ParseArgFromStr for IndexMode {
+    fn describe_type<W: fmt::Write>(mut writer: W) -> fmt::Result {
+        write!(writer, "either 'full', 'funding-only' or 'spending-only'")
+    }
+}
+
+/// Index statistics output format
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum StatsFormat {
+    Table,
+    Json,
+}
+
+impl FromStr for StatsFormat {
+    type Err = String;
+
+    fn from_str(string: &str) -> std::result::Result<Self, Self::Err> {
+        match string {
+            "table" => Ok(StatsFormat::Table),
+            "json" => Ok(StatsFormat::Json),
+            _ => Err(format!("unknown stats format: {:?}", string)),
+        }
+    }
+}
+
+impl ::configure_me::parse_arg::ParseArgFromStr for StatsFormat {
+    fn describe_type<W: fmt::Write>(mut writer: W) -> fmt::Result {
+        write!(writer, "either 'table' or 'json'")
+    }
+}
+
+/// History export format
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    Csv,
+    Json,
+}
+
+impl Default for ExportFormat {
+    fn default() -> Self {
+        ExportFormat::Csv
+    }
+}
+
+impl FromStr for ExportFormat {
+    type Err = String;
+
+    fn from_str(string: &str) -> std::result::Result<Self, Self::Err> {
+        match string {
+            "csv" => Ok(ExportFormat::Csv),
+            "json" => Ok(ExportFormat::Json),
+            _ => Err(format!("unknown export format: {:?}", string)),
+        }
+    }
+}
+
+impl ::configure_me::parse_arg::ParseArgFromStr for ExportFormat {
+    fn describe_type<W: fmt::Write>(mut writer: W) -> fmt::Result {
+        write!(writer, "either 'csv' or 'json'")
+    }
+}
+
+/// A scripthash history export request (see `--export-scripthash`)
+#[derive(Debug)]
+pub struct Export {
+    pub scripthash: ScriptHash,
+    pub format: ExportFormat,
+    pub file: Option<PathBuf>,
+}
+
+fn log_record_json(ts: impl fmt::Display, record: &log::Record) -> Value {
+    json!({
+        "ts": ts.to_string(),
+        "level": record.level().to_string(),
+        "target": record.target(),
+        "msg": record.args().to_string(),
+    })
+}
+
 /// Parsed and post-processed configuration
 #[derive(Debug)]
 pub struct Config {
     // See below for the documentation of each field:
     pub network: Network,
+    pub genesis_hash: Option<BlockHash>,
+    pub network_magic: u32,
     pub db_path: PathBuf,
     pub daemon_dir: PathBuf,
     pub daemon_auth: SensitiveAuth,
     pub daemon_rpc_addr: SocketAddr,
     pub daemon_p2p_addr: SocketAddr,
+    pub daemon_parallelism: usize,
+    pub max_block_size: usize,
     pub electrum_rpc_addr: SocketAddr,
-    pub monitoring_addr: SocketAddr,
-    pub wait_duration: Duration,
+    pub electrum_ws_addr: Option<SocketAddr>,
+    pub rpc_allow_ip: Option<IpAllowlist>,
+    pub disabled_methods: HashSet<String>,
+    pub monitoring_addr: MonitoringAddr,
+    pub monitoring_token: Option<SensitiveToken>,
+    pub ready_lag_blocks: usize,
+    pub tip_poll_interval: Duration,
+    pub mempool_poll_interval: Duration,
     pub jsonrpc_timeout: Duration,
+    pub jsonrpc_max_retries: usize,
     pub index_batch_size: usize,
+    pub index_threads: Option<usize>,
+    pub query_threads: Option<usize>,
     pub index_lookup_limit: Option<usize>,
+    pub keep_blocks: Option<usize>,
+    pub max_subscriptions_per_connection: Option<usize>,
+    pub max_batch_scripthashes: Option<usize>,
+    pub max_serving_lag_blocks: Option<usize>,
+    pub max_history: Option<usize>,
+    pub min_confirmations: usize,
+    pub coinbase_maturity: bool,
+    pub max_response_size: Option<usize>,
+    pub max_request_line_bytes: Option<usize>,
+    pub client_read_buffer_bytes: usize,
+    pub notify_coalesce: Option<Duration>,
+    pub client_write_timeout: Option<Duration>,
+    pub slow_query: Option<Duration>,
     pub reindex_last_blocks: usize,
     pub auto_reindex: bool,
+    pub wait_for_ibd: bool,
+    pub db_compact: bool,
+    pub db_compact_after_blocks: Option<usize>,
+    pub index_filters: bool,
+    pub index_opreturn: bool,
+    pub index_mode: IndexMode,
+    pub chain_stats: bool,
     pub ignore_mempool: bool,
+    pub mempool_announcements: bool,
+    pub mempool_min_fee_rate: Option<f64>,
     pub sync_once: bool,
+    pub verify: bool,
+    pub index_stats: Option<StatsFormat>,
+    pub export: Option<Export>,
+    pub snapshot_to: Option<PathBuf>,
+    pub snapshot_from: Option<PathBuf>,
+    pub backup_dir: Option<PathBuf>,
+    pub watch_scripthashes: Vec<ScriptHash>,
+    pub watch_file: Option<PathBuf>,
+    pub prime_on_block: usize,
+    pub on_pruned_block: Option<OnPrunedBlock>,
+    pub block_source: BlockSourceMode,
+    pub require_daemon_version: Option<DaemonVersion>,
     pub disable_electrum_rpc: bool,
     pub server_banner: String,
+    pub protocol_min: ProtocolVersion,
+    pub protocol_max: ProtocolVersion,
     pub args: Vec<String>,
 }
 
@@ -155,6 +795,24 @@ impl SensitiveAuth {
     }
 }
 
+/// Bearer token required for accessing the monitoring endpoint (hidden from logs)
+#[derive(Clone)]
+pub struct SensitiveToken(pub(crate) String);
+
+impl SensitiveToken {
+    /// Compare in constant time (for a given length), to avoid leaking the token via timing.
+    pub(crate) fn matches(&self, token: &str) -> bool {
+        let (a, b) = (self.0.as_bytes(), token.as_bytes());
+        a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+    }
+}
+
+impl fmt::Debug for SensitiveToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "<sensitive>")
+    }
+}
+
 impl fmt::Debug for SensitiveAuth {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self.0 {
@@ -178,6 +836,96 @@ fn default_daemon_dir() -> PathBuf {
     home
 }
 
+/// Returns the index directory (before appending the network subdirectory):
+/// an explicitly given `db_dir`, or a sibling of an explicitly given `daemon_dir`
+/// (e.g. `/var/lib/bitcoin` -> `/var/lib/electrs`), or `./db` otherwise.
+fn default_db_dir(db_dir: Option<PathBuf>, daemon_dir: Option<&Path>) -> PathBuf {
+    if let Some(db_dir) = db_dir {
+        return db_dir;
+    }
+    match daemon_dir.and_then(Path::parent) {
+        Some(parent) => parent.join("electrs"),
+        None => PathBuf::from("./db"),
+    }
+}
+
+/// Returns bitcoind's network-specific data directory (containing the `.cookie` file)
+fn daemon_network_dir(mut daemon_dir: PathBuf, network: Network) -> PathBuf {
+    match network {
+        Network::Bitcoin => (),
+        Network::Testnet => daemon_dir.push("testnet3"),
+        Network::Regtest => daemon_dir.push("regtest"),
+        Network::Signet => daemon_dir.push("signet"),
+    }
+    daemon_dir
+}
+
+/// Network-specific monitoring port (so that instances for different networks don't collide)
+fn default_monitoring_port(network: Network) -> u16 {
+    match network {
+        Network::Bitcoin => 4224,
+        Network::Testnet => 14224,
+        Network::Regtest => 24224,
+        Network::Signet => 34224,
+    }
+}
+
+/// Find `rpcport` in bitcoind's configuration file. Similar to bitcoind, an option outside of
+/// any section applies only to mainnet - other networks must set it in their own section.
+fn bitcoind_conf_rpc_port(conf: &str, network: Network) -> Option<u16> {
+    let network_section = match network {
+        Network::Bitcoin => "main",
+        Network::Testnet => "test",
+        Network::Regtest => "regtest",
+        Network::Signet => "signet",
+    };
+    let mut section = None;
+    let mut port = None;
+    for line in conf.lines().map(str::trim) {
+        if line.starts_with('#') {
+            continue;
+        }
+        if line.starts_with('[') && line.ends_with(']') {
+            section = Some(line[1..line.len() - 1].trim().to_owned());
+            continue;
+        }
+        let mut parts = line.splitn(2, '=').map(str::trim);
+        if parts.next() != Some("rpcport") {
+            continue;
+        }
+        let applies = match &section {
+            None => network == Network::Bitcoin,
+            Some(section) => section == network_section,
+        };
+        if applies {
+            port = parts.next().and_then(|value| value.parse().ok()).or(port);
+        }
+    }
+    port
+}
+
+/// Make sure the index can be created and written, before connecting to bitcoind.
+/// Nothing is created: if `dir` doesn't exist yet, its nearest existing ancestor is checked.
+fn check_writable(dir: &Path) -> std::io::Result<()> {
+    let existing = dir
+        .ancestors()
+        .find(|path| path.exists())
+        .unwrap_or_else(|| Path::new("."));
+    let probe = existing.join(".electrs-write-check");
+    std::fs::File::create(&probe)?;
+    std::fs::remove_file(&probe)
+}
+
+/// Before `daemon_dir` was used for deriving the default `db_dir`, the index was stored in `./db`.
+/// Returns the old index path, if it exists while the new one doesn't (to avoid silently reindexing).
+fn find_moved_db(legacy_path: &Path, db_path: &Path) -> Option<PathBuf> {
+    if legacy_path != db_path && legacy_path.exists() && !db_path.exists() {
+        Some(legacy_path.to_path_buf())
+    } else {
+        None
+    }
+}
+
 fn default_config_files() -> Vec<OsString> {
     #[cfg(not(feature = "ignore_default_config_files"))]
     {
@@ -212,14 +960,31 @@ impl Config {
             Network::Signet => "signet",
         };
 
-        config.db_dir.push(db_subdir);
+        let explicit_db_dir = config.db_dir.is_some();
+        let mut db_dir = default_db_dir(config.db_dir, config.daemon_dir.as_deref());
+        db_dir.push(db_subdir);
+        if !explicit_db_dir {
+            if let Some(legacy_path) = find_moved_db(&Path::new("./db").join(db_subdir), &db_dir) {
+                eprintln!(
+                    "Error: found an existing index at {}, but the default DB directory is now {} \
+                    (since daemon_dir is set): please set db_dir explicitly, or move the index",
+                    legacy_path.display(),
+                    db_dir.display()
+                );
+                std::process::exit(1);
+            }
+        }
 
-        let default_daemon_rpc_port = match config.network {
+        let daemon_base_dir = config.daemon_dir.unwrap_or_else(default_daemon_dir);
+        let daemon_conf_rpc_port = std::fs::read_to_string(daemon_base_dir.join("bitcoin.conf"))
+            .ok()
+            .and_then(|conf| bitcoind_conf_rpc_port(&conf, config.network));
+        let default_daemon_rpc_port = daemon_conf_rpc_port.unwrap_or(match config.network {
             Network::Bitcoin => 8332,
             Network::Testnet => 18332,
             Network::Regtest => 18443,
             Network::Signet => 38332,
-        };
+        });
         let default_daemon_p2p_port = match config.network {
             Network::Bitcoin => 8333,
             Network::Testnet => 18333,
@@ -232,12 +997,7 @@ impl Config {
             Network::Regtest => 60401,
             Network::Signet => 60601,
         };
-        let default_monitoring_port = match config.network {
-            Network::Bitcoin => 4224,
-            Network::Testnet => 14224,
-            Network::Regtest => 24224,
-            Network::Signet => 34224,
-        };
+        let default_monitoring_port = default_monitoring_port(config.network);
 
         let daemon_rpc_addr: SocketAddr = config.daemon_rpc_addr.map_or(
             (DEFAULT_SERVER_ADDRESS, default_daemon_rpc_port).into(),
@@ -251,26 +1011,48 @@ impl Config {
             (DEFAULT_SERVER_ADDRESS, default_electrum_port).into(),
             ResolvAddr::resolve_or_exit,
         );
+        let electrum_ws_addr: Option<SocketAddr> =
+            config.electrum_ws_addr.map(ResolvAddr::resolve_or_exit);
         #[cfg(not(feature = "metrics"))]
         {
             if config.monitoring_addr.is_some() {
                 eprintln!("Error: enable \"metrics\" feature to specify monitoring_addr");
                 std::process::exit(1);
             }
+            if config.monitoring_token.is_some() {
+                eprintln!("Error: enable \"metrics\" feature to specify monitoring_token");
+                std::process::exit(1);
+            }
         }
-        let monitoring_addr: SocketAddr = config.monitoring_addr.map_or(
-            (DEFAULT_SERVER_ADDRESS, default_monitoring_port).into(),
-            ResolvAddr::resolve_or_exit,
+        let monitoring_addr = config.monitoring_addr.map_or(
+            MonitoringAddr::Tcp((DEFAULT_SERVER_ADDRESS, default_monitoring_port).into()),
+            |addr| {
+                addr.resolve_monitoring().unwrap_or_else(|err| {
+                    eprintln!("Error: {}", err);
+                    std::process::exit(1)
+                })
+            },
         );
 
-        match config.network {
-            Network::Bitcoin => (),
-            Network::Testnet => config.daemon_dir.push("testnet3"),
-            Network::Regtest => config.daemon_dir.push("regtest"),
-            Network::Signet => config.daemon_dir.push("signet"),
-        }
+        let monitoring_token = match config.monitoring_token {
+            Some(token) if token.is_empty() => {
+                eprintln!("Error: monitoring_token must not be empty");
+                std::process::exit(1);
+            }
+            token => token.map(SensitiveToken),
+        };
+
+        let export = config.export_scripthash.map(|scripthash| Export {
+            scripthash: ScriptHash::from_hex(&scripthash).unwrap_or_else(|err| {
+                eprintln!("Error: invalid export_scripthash {:?}: {}", scripthash, err);
+                std::process::exit(1)
+            }),
+            format: config.export_format,
+            file: config.export_file,
+        });
+
+        let daemon_dir = daemon_network_dir(daemon_base_dir, config.network);
 
-        let daemon_dir = &config.daemon_dir;
         let daemon_auth = SensitiveAuth(match (config.auth, config.cookie_file) {
             (None, None) => Auth::CookieFile(daemon_dir.join(".cookie")),
             (None, Some(cookie_file)) => Auth::CookieFile(cookie_file),
@@ -293,16 +1075,159 @@ impl Config {
             std::process::exit(1);
         }
         let log_filters = config.log_filters;
+        let log_format = config.log_format;
+        let log_file = config.log_file.map(|path| {
+            let max_size = match config.log_max_size_mb {
+                0 => None,
+                mb => Some(mb.saturating_mul(1 << 20)),
+            };
+            RotatingFile::open(&path, max_size, config.log_keep_files).unwrap_or_else(|err| {
+                eprintln!("Error: failed to open log file {}: {}", path.display(), err);
+                std::process::exit(1)
+            })
+        });
 
         let index_lookup_limit = match config.index_lookup_limit {
             0 => None,
             _ => Some(config.index_lookup_limit),
         };
 
-        if config.jsonrpc_timeout_secs <= config.wait_duration_secs {
+        let keep_blocks = match config.keep_blocks {
+            0 => None,
+            _ => Some(config.keep_blocks),
+        };
+
+        // watched and primed statuses are synced using both funding and spending rows
+        let watching = config.watch_scripthashes.is_some() || config.watch_file.is_some();
+        if config.index_mode != IndexMode::Full && (watching || config.prime_on_block > 0) {
             eprintln!(
-                "Error: jsonrpc_timeout_secs ({}) must be higher than wait_duration_secs ({})",
-                config.jsonrpc_timeout_secs, config.wait_duration_secs
+                "Error: index_mode {} doesn't support watch_scripthashes, watch_file or prime_on_block",
+                config.index_mode
+            );
+            std::process::exit(1);
+        }
+
+        let max_subscriptions_per_connection = match config.max_subscriptions_per_connection {
+            0 => None,
+            _ => Some(config.max_subscriptions_per_connection),
+        };
+
+        let max_history = match config.max_history {
+            0 => None,
+            _ => Some(config.max_history),
+        };
+        let max_serving_lag_blocks = match config.max_serving_lag_blocks {
+            0 => None,
+            _ => Some(config.max_serving_lag_blocks),
+        };
+        let max_batch_scripthashes = match config.max_batch_scripthashes {
+            0 => None,
+            _ => Some(config.max_batch_scripthashes),
+        };
+
+        let index_threads = match config.index_threads {
+            0 => None,
+            n => Some(n),
+        };
+        let query_threads = match config.query_threads {
+            0 => None,
+            n => Some(n),
+        };
+
+        let max_response_size = match config.max_response_mb {
+            0 => None,
+            mb => Some(mb.saturating_mul(1 << 20)),
+        };
+
+        let max_request_line_bytes = match config.max_request_line_bytes {
+            0 => None,
+            bytes => Some(bytes),
+        };
+        if config.client_read_buffer_bytes == 0 {
+            eprintln!("Error: client read buffer must be non-empty");
+            std::process::exit(1);
+        }
+
+        let disabled_methods: HashSet<String> = config
+            .disable_methods
+            .map_or_else(HashSet::new, |methods| methods.0.into_iter().collect());
+        for method in &disabled_methods {
+            if !is_known_method(method) {
+                eprintln!("Error: unknown method {:?} in disable_methods", method);
+                std::process::exit(1);
+            }
+        }
+
+        let notify_coalesce = match config.notify_coalesce_ms {
+            0 => None,
+            _ => Some(Duration::from_millis(config.notify_coalesce_ms)),
+        };
+
+        let client_write_timeout = match config.client_write_timeout_secs {
+            0 => None,
+            _ => Some(Duration::from_secs(config.client_write_timeout_secs)),
+        };
+
+        let slow_query = match config.slow_query_ms {
+            0 => None,
+            _ => Some(Duration::from_millis(config.slow_query_ms)),
+        };
+
+        if config.mempool_min_fee_rate.is_nan() || config.mempool_min_fee_rate < 0.0 {
+            eprintln!("Error: mempool_min_fee_rate must be non-negative");
+            std::process::exit(1);
+        }
+        let mempool_min_fee_rate = if config.mempool_min_fee_rate > 0.0 {
+            Some(config.mempool_min_fee_rate)
+        } else {
+            None
+        };
+
+        let db_compact_after_blocks = match config.db_compact_after_blocks {
+            0 => None,
+            _ => Some(config.db_compact_after_blocks),
+        };
+
+        if config.daemon_parallelism == 0 {
+            eprintln!("Error: daemon_parallelism must be positive");
+            std::process::exit(1);
+        }
+
+        if config.max_block_size_mb == 0 {
+            eprintln!("Error: max_block_size_mb must be positive");
+            std::process::exit(1);
+        }
+
+        if config.protocol_min > config.protocol_max {
+            eprintln!(
+                "Error: protocol_min ({}) must not be higher than protocol_max ({})",
+                config.protocol_min, config.protocol_max
+            );
+            std::process::exit(1);
+        }
+
+        if let Some(wait_duration_secs) = config.wait_duration_secs {
+            eprintln!(
+                "Warning: wait_duration_secs is deprecated, use tip_poll_interval_secs and mempool_poll_interval_secs instead"
+            );
+            config.tip_poll_interval_secs = wait_duration_secs;
+            config.mempool_poll_interval_secs = wait_duration_secs;
+        }
+
+        if config.tip_poll_interval_secs == 0 {
+            eprintln!("Error: tip_poll_interval_secs must be positive");
+            std::process::exit(1);
+        }
+
+        if config.mempool_poll_interval_secs == 0 {
+            eprintln!("Error: mempool_poll_interval_secs must be positive");
+            std::process::exit(1);
+        }
+
+        if config.jsonrpc_timeout_secs <= config.tip_poll_interval_secs {
+            eprintln!(
+                "Error: jsonrpc_timeout_secs ({}) must be higher than tip_poll_interval_secs ({})",
+                config.jsonrpc_timeout_secs, config.tip_poll_interval_secs
             );
             std::process::exit(1);
         }
@@ -311,37 +1236,119 @@ impl Config {
             println!("v{}", ELECTRS_VERSION);
             std::process::exit(0);
         }
+        if config.version_rich {
+            println!("v{}", ELECTRS_VERSION_RICH);
+            std::process::exit(0);
+        }
+
+        // `--index-stats` and `--export-scripthash` open the DB read-only
+        if config.index_stats.is_none() && export.is_none() {
+            if let Err(err) = check_writable(&db_dir) {
+                eprintln!(
+                    "Error: DB directory {} is not writable: {}",
+                    db_dir.display(),
+                    err
+                );
+                std::process::exit(1);
+            }
+        }
 
         let config = Config {
             network: config.network,
-            db_path: config.db_dir,
-            daemon_dir: config.daemon_dir,
+            genesis_hash: config.genesis_hash.map(|hash| hash.0),
+            network_magic: config
+                .network_magic
+                .map_or_else(|| config.network.magic(), |magic| magic.0),
+            db_path: db_dir,
+            daemon_dir,
             daemon_auth,
             daemon_rpc_addr,
             daemon_p2p_addr,
+            daemon_parallelism: config.daemon_parallelism,
+            max_block_size: config.max_block_size_mb.saturating_mul(1 << 20),
             electrum_rpc_addr,
+            electrum_ws_addr,
+            rpc_allow_ip: config.rpc_allow_ip,
+            disabled_methods,
             monitoring_addr,
-            wait_duration: Duration::from_secs(config.wait_duration_secs),
+            monitoring_token,
+            ready_lag_blocks: config.ready_lag_blocks,
+            tip_poll_interval: Duration::from_secs(config.tip_poll_interval_secs),
+            mempool_poll_interval: Duration::from_secs(config.mempool_poll_interval_secs),
             jsonrpc_timeout: Duration::from_secs(config.jsonrpc_timeout_secs),
+            jsonrpc_max_retries: config.jsonrpc_max_retries,
             index_batch_size: config.index_batch_size,
+            index_threads,
+            query_threads,
             index_lookup_limit,
+            keep_blocks,
+            max_subscriptions_per_connection,
+            max_batch_scripthashes,
+            max_serving_lag_blocks,
+            max_history,
+            min_confirmations: config.min_confirmations,
+            coinbase_maturity: config.coinbase_maturity,
+            max_response_size,
+            max_request_line_bytes,
+            client_read_buffer_bytes: config.client_read_buffer_bytes,
+            notify_coalesce,
+            client_write_timeout,
+            slow_query,
             reindex_last_blocks: config.reindex_last_blocks,
             auto_reindex: config.auto_reindex,
+            wait_for_ibd: config.wait_for_ibd,
+            db_compact: config.db_compact,
+            db_compact_after_blocks,
+            index_filters: config.index_filters,
+            index_opreturn: config.index_opreturn,
+            index_mode: config.index_mode,
+            chain_stats: config.chain_stats,
             ignore_mempool: config.ignore_mempool,
+            mempool_announcements: config.mempool_announcements,
+            mempool_min_fee_rate,
             sync_once: config.sync_once,
+            verify: config.verify,
+            index_stats: config.index_stats,
+            export,
+            snapshot_to: config.snapshot_to,
+            snapshot_from: config.snapshot_from,
+            backup_dir: config.backup_dir,
+            watch_scripthashes: config
+                .watch_scripthashes
+                .map_or_else(Vec::new, |scripthashes| scripthashes.0),
+            watch_file: config.watch_file,
+            prime_on_block: config.prime_on_block,
+            on_pruned_block: config.on_pruned_block,
+            block_source: config.block_source,
+            require_daemon_version: config.require_daemon_version,
             disable_electrum_rpc: config.disable_electrum_rpc,
             server_banner: config.server_banner,
+            protocol_min: config.protocol_min,
+            protocol_max: config.protocol_max,
             args: args.map(|a| a.into_string().unwrap()).collect(),
         };
         eprintln!(
             "Starting electrs {} on {} {} with {:?}",
-            ELECTRS_VERSION, ARCH, OS, config
+            ELECTRS_VERSION_RICH, ARCH, OS, config
         );
         let mut builder = env_logger::Builder::from_default_env();
-        builder.default_format().format_timestamp_millis();
+        match log_format {
+            LogFormat::Text => {
+                builder.default_format().format_timestamp_millis();
+            }
+            LogFormat::Json => {
+                builder.format(|buf, record| {
+                    let value = log_record_json(buf.timestamp_millis(), record);
+                    writeln!(buf, "{}", value)
+                });
+            }
+        }
         if let Some(log_filters) = &log_filters {
             builder.parse_filters(log_filters);
         }
+        if let Some(log_file) = log_file {
+            builder.target(env_logger::Target::Pipe(Box::new(log_file)));
+        }
         builder.init();
 
         config
@@ -350,8 +1357,17 @@ impl Config {
 
 #[cfg(test)]
 mod tests {
-    use super::{Auth, SensitiveAuth};
+    use super::{
+        bitcoind_conf_rpc_port, check_writable, daemon_network_dir, default_db_dir,
+        default_monitoring_port, find_moved_db, log_record_json, Auth, DaemonVersion, GenesisHash,
+        IpAllowlist, IpNet, LogFormat, MonitoringAddr, NetworkMagic, ProtocolVersion, ResolvAddr,
+        SensitiveAuth, StatsFormat, ELECTRS_VERSION, ELECTRS_VERSION_RICH,
+    };
+    use bitcoin::network::constants::Network;
+    use log::{Level, Record};
+    use serde_json::Value;
     use std::path::Path;
+    use std::str::FromStr;
 
     #[test]
     fn test_auth_debug() {
@@ -370,4 +1386,247 @@ mod tests {
             "UserPass(\"user\", \"<sensitive>\")"
         );
     }
+
+    #[test]
+    fn test_default_db_dir() {
+        let explicit = Some(Path::new("/data/index").to_path_buf());
+        let daemon_dir = Some(Path::new("/var/lib/bitcoin"));
+        assert_eq!(
+            default_db_dir(explicit.clone(), daemon_dir),
+            Path::new("/data/index")
+        );
+        assert_eq!(default_db_dir(explicit, None), Path::new("/data/index"));
+        assert_eq!(
+            default_db_dir(None, daemon_dir),
+            Path::new("/var/lib/electrs")
+        );
+        assert_eq!(
+            default_db_dir(None, Some(Path::new("/var/lib/bitcoin/"))),
+            Path::new("/var/lib/electrs")
+        );
+        assert_eq!(
+            default_db_dir(None, Some(Path::new("/"))),
+            Path::new("./db")
+        );
+        assert_eq!(default_db_dir(None, None), Path::new("./db"));
+    }
+
+    #[test]
+    fn test_daemon_dir() {
+        let daemon_dir = Path::new("/home/user/.bitcoin");
+        let cookie =
+            |network| daemon_network_dir(daemon_dir.to_path_buf(), network).join(".cookie");
+        assert_eq!(cookie(Network::Bitcoin), daemon_dir.join(".cookie"));
+        assert_eq!(
+            cookie(Network::Testnet),
+            daemon_dir.join("testnet3/.cookie")
+        );
+        assert_eq!(cookie(Network::Regtest), daemon_dir.join("regtest/.cookie"));
+        assert_eq!(cookie(Network::Signet), daemon_dir.join("signet/.cookie"));
+
+        let conf =
+            "# rpcport=1111\nrpcport = 2222\n[test]\nrpcport=3333\n[regtest]\nrpcbind=127.0.0.1\n";
+        assert_eq!(bitcoind_conf_rpc_port(conf, Network::Bitcoin), Some(2222));
+        assert_eq!(bitcoind_conf_rpc_port(conf, Network::Testnet), Some(3333));
+        assert_eq!(bitcoind_conf_rpc_port(conf, Network::Regtest), None);
+        assert_eq!(bitcoind_conf_rpc_port(conf, Network::Signet), None);
+        assert_eq!(
+            bitcoind_conf_rpc_port("[main]\nrpcport=4444\n", Network::Bitcoin),
+            Some(4444)
+        );
+        assert_eq!(
+            bitcoind_conf_rpc_port("rpcport=abc\n", Network::Bitcoin),
+            None
+        );
+    }
+
+    #[test]
+    fn test_check_writable() {
+        let dir = tempfile::tempdir().unwrap();
+        let db_dir = dir.path().join("electrs").join("bitcoin");
+        check_writable(&db_dir).unwrap();
+        assert!(!db_dir.exists()); // directories are not created
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0); // probe file is removed
+
+        let file = dir.path().join("file");
+        std::fs::File::create(&file).unwrap();
+        assert!(check_writable(&file.join("db")).is_err());
+    }
+
+    #[test]
+    fn test_find_moved_db() {
+        let dir = tempfile::tempdir().unwrap();
+        let legacy_path = dir.path().join("db").join("bitcoin");
+        let db_path = dir.path().join("electrs").join("bitcoin");
+        assert_eq!(find_moved_db(&legacy_path, &db_path), None);
+
+        std::fs::create_dir_all(&legacy_path).unwrap();
+        assert_eq!(
+            find_moved_db(&legacy_path, &db_path),
+            Some(legacy_path.clone())
+        );
+        assert_eq!(find_moved_db(&legacy_path, &legacy_path), None);
+
+        std::fs::create_dir_all(&db_path).unwrap();
+        assert_eq!(find_moved_db(&legacy_path, &db_path), None);
+    }
+
+    #[test]
+    fn test_custom_chain() {
+        let magic = |s: &str| NetworkMagic::from_str(s).map(|magic| magic.0);
+        assert_eq!(magic("0a03cf40"), Ok(Network::Signet.magic()));
+        assert_eq!(magic("fabfb5da"), Ok(Network::Regtest.magic()));
+        assert!(magic("0a03cf").is_err());
+        assert!(magic("0a03cf4000").is_err());
+        assert!(magic("xyz").is_err());
+
+        let hash = "0f9188f13cb7b2c71f2a335e3a4fc328bf5beb436012afca590b1a11466e2206";
+        assert_eq!(GenesisHash::from_str(hash).unwrap().0.to_string(), hash);
+        assert!(GenesisHash::from_str(&hash[1..]).is_err());
+        assert!(GenesisHash::from_str("").is_err());
+    }
+
+    #[test]
+    fn test_ip_allowlist() {
+        let ip = |s: &str| s.parse().unwrap();
+        let allowlist = IpAllowlist::from_str("127.0.0.1, 10.0.0.0/8,fd00::/8").unwrap();
+        assert!(allowlist.contains(ip("127.0.0.1")));
+        assert!(!allowlist.contains(ip("127.0.0.2")));
+        assert!(allowlist.contains(ip("10.1.2.3")));
+        assert!(!allowlist.contains(ip("11.0.0.1")));
+        assert!(allowlist.contains(ip("fd12::1")));
+        assert!(!allowlist.contains(ip("fe80::1")));
+        assert!(!allowlist.contains(ip("::1")));
+        assert!(allowlist.contains(ip("::ffff:10.0.0.1"))); // IPv4-mapped IPv6 address
+
+        assert!(IpNet::from_str("0.0.0.0/0")
+            .unwrap()
+            .contains(ip("1.2.3.4")));
+        assert!(!IpNet::from_str("0.0.0.0/0").unwrap().contains(ip("::2")));
+        assert!(IpNet::from_str("::/0").unwrap().contains(ip("::2")));
+        assert!(IpNet::from_str("::1").unwrap().contains(ip("::1")));
+
+        assert!(IpNet::from_str("10.0.0.0/33").is_err());
+        assert!(IpNet::from_str("fd00::/129").is_err());
+        assert!(IpNet::from_str("10.0.0/8").is_err());
+        assert!(IpAllowlist::from_str("").is_err());
+    }
+
+    #[test]
+    fn test_version_rich() {
+        let mut parts = ELECTRS_VERSION_RICH.splitn(2, '+');
+        assert_eq!(parts.next(), Some(ELECTRS_VERSION));
+        let build: Vec<&str> = parts.next().unwrap().split('.').collect();
+        let (commit, date) = match build.as_slice() {
+            [commit, date] => (commit, date),
+            _ => panic!("invalid build metadata: {:?}", build),
+        };
+        assert!(*commit == "unknown" || commit.chars().all(|c| c.is_ascii_hexdigit()));
+        let date: Vec<u32> = date.split('-').map(|part| part.parse().unwrap()).collect();
+        assert!(matches!(date.as_slice(), [year, 1..=12, 1..=31] if *year >= 2021));
+    }
+
+    #[test]
+    fn test_log_format() {
+        assert_eq!(LogFormat::from_str("text").unwrap(), LogFormat::Text);
+        assert_eq!(LogFormat::from_str("json").unwrap(), LogFormat::Json);
+        assert!(LogFormat::from_str("xml").is_err());
+    }
+
+    #[test]
+    fn test_stats_format() {
+        assert_eq!(StatsFormat::from_str("table").unwrap(), StatsFormat::Table);
+        assert_eq!(StatsFormat::from_str("json").unwrap(), StatsFormat::Json);
+        assert!(StatsFormat::from_str("csv").is_err());
+    }
+
+    #[test]
+    fn test_monitoring_addr() {
+        let resolve = |addr: &str| ResolvAddr(addr.to_owned()).resolve_monitoring().ok();
+        assert_eq!(
+            resolve("127.0.0.1:4224"),
+            Some(MonitoringAddr::Tcp("127.0.0.1:4224".parse().unwrap()))
+        );
+        assert_eq!(
+            resolve("[::1]:4224"),
+            Some(MonitoringAddr::Tcp("[::1]:4224".parse().unwrap()))
+        );
+        let unix = MonitoringAddr::Unix(Path::new("/run/electrs/metrics.sock").to_path_buf());
+        assert_eq!(
+            resolve("unix:/run/electrs/metrics.sock"),
+            Some(unix.clone())
+        );
+        assert_eq!(unix.to_string(), "unix:/run/electrs/metrics.sock");
+
+        assert_eq!(resolve("unix:"), None);
+        assert_eq!(resolve("127.0.0.1"), None); // missing port
+    }
+
+    #[test]
+    fn test_default_monitoring_port() {
+        let networks = [
+            Network::Bitcoin,
+            Network::Testnet,
+            Network::Regtest,
+            Network::Signet,
+        ];
+        let ports: std::collections::HashSet<u16> = networks
+            .iter()
+            .map(|network| default_monitoring_port(*network))
+            .collect();
+        assert_eq!(ports.len(), networks.len()); // co-located instances don't collide
+        assert_ne!(
+            default_monitoring_port(Network::Bitcoin),
+            default_monitoring_port(Network::Testnet)
+        );
+    }
+
+    #[test]
+    fn test_daemon_version() {
+        let parse = |s: &str| s.parse::<DaemonVersion>().ok();
+        assert_eq!(parse("0.21.1"), Some(DaemonVersion(210100)));
+        assert_eq!(parse("0.21"), Some(DaemonVersion(210000)));
+        assert_eq!(parse("22.0"), Some(DaemonVersion(220000)));
+        assert_eq!(parse("25.1.2"), Some(DaemonVersion(250102)));
+        assert_eq!(parse("25.100"), None);
+        assert_eq!(parse("25.x"), None);
+        assert_eq!(parse(""), None);
+        assert_eq!(parse(&format!("{}.0", usize::MAX / 10000 + 1)), None); // overflow
+        assert_eq!(parse(&format!("{}.99", usize::MAX / 10000)), None);
+
+        for version in &["0.21.1", "0.21.0", "22.0", "25.1.2"] {
+            assert_eq!(parse(version).unwrap().to_string(), *version);
+        }
+    }
+
+    #[test]
+    fn test_protocol_version() {
+        let v1_4 = ProtocolVersion::from_str("1.4").unwrap();
+        let v1_4_2 = ProtocolVersion::from_str("1.4.2").unwrap();
+        assert!(ProtocolVersion::from_str("1.2").unwrap() < v1_4);
+        assert!(v1_4 < v1_4_2);
+        assert!(v1_4_2 < ProtocolVersion::from_str("1.10").unwrap());
+        assert_eq!(v1_4_2.to_string(), "1.4.2");
+        assert!(ProtocolVersion::from_str("1.x").is_err());
+        assert!(ProtocolVersion::from_str("").is_err());
+    }
+
+    #[test]
+    fn test_log_record_json() {
+        let line = log_record_json(
+            "2021-11-20T10:30:00.123Z",
+            &Record::builder()
+                .args(format_args!("indexing {} blocks", 10))
+                .level(Level::Info)
+                .target("electrs::index")
+                .build(),
+        )
+        .to_string();
+        let value: Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(value["ts"], "2021-11-20T10:30:00.123Z");
+        assert_eq!(value["level"], "INFO");
+        assert_eq!(value["target"], "electrs::index");
+        assert_eq!(value["msg"], "indexing 10 blocks");
+        assert_eq!(value.as_object().unwrap().len(), 4);
+    }
 }
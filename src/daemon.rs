@@ -1,21 +1,35 @@
 use anyhow::{Context, Result};
 
 use bitcoin::{
-    consensus::serialize, hashes::hex::ToHex, Amount, Block, BlockHash, Transaction, Txid,
+    consensus::serialize, hashes::hex::ToHex, Amount, Block, BlockHash, BlockHeader, Transaction,
+    Txid,
+};
+use bitcoincore_rpc::{
+    json,
+    jsonrpc::{self, simple_http},
+    Auth, Client, RpcApi,
 };
-use bitcoincore_rpc::{json, jsonrpc, Auth, Client, RpcApi};
 use crossbeam_channel::Receiver;
 use parking_lot::Mutex;
 use serde_json::{json, Value};
 
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+use std::fmt;
 use std::fs::File;
-use std::io::Read;
-use std::path::Path;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+use std::time::Duration;
 
 use crate::{
     chain::{Chain, NewHeader},
-    config::Config,
-    metrics::Metrics,
+    config::{BlockSourceMode, Config, DaemonVersion, OnPrunedBlock},
+    errors::QueryError,
+    metrics::{Counter, Metrics},
     p2p::Connection,
     signals::ExitFlag,
 };
@@ -25,23 +39,45 @@ enum PollResult {
     Retry,
 }
 
-fn rpc_poll(client: &mut Client) -> PollResult {
+/// Describe the download progress, if bitcoind is not synced yet (so indexing should wait).
+fn download_progress(
+    initial_block_download: bool,
+    blocks: u64,
+    headers: u64,
+    verification_progress: f64,
+) -> Option<String> {
+    let left_blocks = headers.saturating_sub(blocks);
+    if !initial_block_download && left_blocks == 0 {
+        return None;
+    }
+    Some(format!(
+        "{} blocks to download{} ({:.2}% verified)",
+        left_blocks,
+        if initial_block_download { " (IBD)" } else { "" },
+        verification_progress * 100.0
+    ))
+}
+
+fn rpc_poll(client: &mut Client, wait_for_ibd: bool) -> PollResult {
     match client.get_blockchain_info() {
         Ok(info) => {
-            let left_blocks = info.headers - info.blocks;
-            if info.initial_block_download || left_blocks > 0 {
-                info!(
-                    "waiting for {} blocks to download{}",
-                    left_blocks,
-                    if info.initial_block_download {
-                        " (IBD)"
-                    } else {
-                        ""
-                    }
-                );
-                return PollResult::Retry;
+            let progress = download_progress(
+                info.initial_block_download,
+                info.blocks,
+                info.headers,
+                info.verification_progress,
+            );
+            match progress {
+                Some(progress) if wait_for_ibd => {
+                    info!("waiting for {}", progress);
+                    PollResult::Retry
+                }
+                Some(progress) => {
+                    warn!("indexing a partial chain, bitcoind has {}", progress);
+                    PollResult::Done(Ok(()))
+                }
+                None => PollResult::Done(Ok(())),
             }
-            PollResult::Done(Ok(()))
         }
         Err(err) => {
             if let Some(e) = extract_bitcoind_error(&err) {
@@ -73,29 +109,331 @@ fn read_cookie(path: &Path) -> Result<(String, String)> {
     Ok((parts[0].to_owned(), parts[1].to_owned()))
 }
 
-fn rpc_connect(config: &Config) -> Result<Client> {
+/// bitcoind RPC credentials. The cookie file is re-read after authentication failures,
+/// since bitcoind creates a new cookie whenever it is restarted.
+struct RpcAuth {
+    cookie_file: Option<PathBuf>,
+    current: Mutex<Option<(String, String)>>,
+    generation: AtomicUsize, // incremented whenever the credentials change
+}
+
+impl RpcAuth {
+    fn new(auth: Auth) -> Result<Self> {
+        let (cookie_file, current) = match auth {
+            Auth::None => (None, None),
+            Auth::UserPass(user, pass) => (None, Some((user, pass))),
+            Auth::CookieFile(path) => {
+                let cookie = read_cookie(&path)?;
+                (Some(path), Some(cookie))
+            }
+        };
+        Ok(Self {
+            cookie_file,
+            current: Mutex::new(current),
+            generation: AtomicUsize::new(0),
+        })
+    }
+
+    fn get(&self) -> Option<(String, String)> {
+        self.current.lock().clone()
+    }
+
+    fn generation(&self) -> usize {
+        self.generation.load(Ordering::SeqCst)
+    }
+
+    /// Re-read the cookie file, returning whether the credentials have changed.
+    fn reload(&self) -> bool {
+        let path = match &self.cookie_file {
+            Some(path) => path,
+            None => return false,
+        };
+        match read_cookie(path) {
+            Ok(cookie) => {
+                let mut current = self.current.lock();
+                if current.as_ref() == Some(&cookie) {
+                    return false;
+                }
+                *current = Some(cookie);
+                self.generation.fetch_add(1, Ordering::SeqCst);
+                true
+            }
+            Err(e) => {
+                warn!("failed to reload bitcoind cookie: {:#}", e);
+                false
+            }
+        }
+    }
+}
+
+/// Bounded exponential backoff for retrying transient bitcoind RPC failures
+#[derive(Clone, Copy)]
+struct RetryPolicy {
+    max_retries: usize,
+    initial_delay: Duration,
+    max_delay: Duration,
+}
+
+// A timed-out broadcast may have been accepted by bitcoind, so it is not re-sent
+const NON_RETRYABLE_METHODS: &[&str] = &["sendrawtransaction", "submitpackage"];
+
+// Clients' queries shouldn't block the serving loop during a long bitcoind outage
+const MAX_QUERY_RETRIES: usize = 1;
+
+// e.g. `gettxoutsetinfo` scans bitcoind's whole UTXO set
+const SLOW_RPC_TIMEOUT: Duration = Duration::from_secs(60 * 60);
+
+impl RetryPolicy {
+    fn for_method(self, method: &str) -> Self {
+        if NON_RETRYABLE_METHODS.contains(&method) {
+            return Self {
+                max_retries: 0,
+                ..self
+            };
+        }
+        self
+    }
+
+    fn call<T>(
+        &self,
+        mut func: impl FnMut() -> Result<T, jsonrpc::Error>,
+        mut on_retry: impl FnMut(),
+    ) -> Result<T, jsonrpc::Error> {
+        let mut delay = self.initial_delay;
+        let mut retries = 0;
+        loop {
+            match func() {
+                Err(e) if retries < self.max_retries && is_retryable(&e) => {
+                    warn!("retrying bitcoind RPC in {:?}: {}", delay, e);
+                    on_retry();
+                    std::thread::sleep(delay);
+                    delay = std::cmp::min(delay * 2, self.max_delay);
+                    retries += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+/// Connection failures and timeouts are retried, while other errors (e.g. authentication) are fatal.
+fn is_retryable(err: &jsonrpc::Error) -> bool {
+    let err = match err {
+        jsonrpc::Error::Transport(e) => e,
+        _ => return false,
+    };
+    match err.downcast_ref::<simple_http::Error>() {
+        Some(simple_http::Error::SocketError(e)) => matches!(
+            e.kind(),
+            io::ErrorKind::ConnectionRefused
+                | io::ErrorKind::ConnectionReset
+                | io::ErrorKind::ConnectionAborted
+                | io::ErrorKind::BrokenPipe
+                | io::ErrorKind::TimedOut
+                | io::ErrorKind::WouldBlock
+                | io::ErrorKind::UnexpectedEof
+        ),
+        Some(simple_http::Error::Timeout) => true,
+        _ => false,
+    }
+}
+
+fn is_unauthorized(err: &jsonrpc::Error) -> bool {
+    match err {
+        jsonrpc::Error::Transport(e) => matches!(
+            e.downcast_ref::<simple_http::Error>(),
+            Some(simple_http::Error::HttpErrorCode(401))
+        ),
+        _ => false,
+    }
+}
+
+/// Retry a call rejected as unauthorized (e.g. after bitcoind was restarted with a new cookie),
+/// if the credentials were reloaded.
+fn call_with_reauth<T>(
+    mut call: impl FnMut() -> Result<T, jsonrpc::Error>,
+    reload: impl FnOnce() -> bool,
+) -> Result<T, jsonrpc::Error> {
+    match call() {
+        Err(e) if is_unauthorized(&e) && reload() => call(),
+        result => result,
+    }
+}
+
+/// Reuses idle transports (and their connections) between requests, allowing concurrent
+/// requests from multiple threads (e.g. during mempool sync).
+///
+/// Each transport is tagged with the credentials' generation it was created with, so the ones
+/// with stale credentials are dropped (both idle and in-use ones) after the credentials change.
+struct TransportPool<T> {
+    idle: Mutex<Vec<(usize, T)>>,
+    max_idle: usize,
+    generation: Box<dyn Fn() -> usize + Send + Sync>,
+    new_transport: Box<dyn Fn() -> T + Send + Sync>,
+}
+
+impl<T> TransportPool<T> {
+    fn new(
+        max_idle: usize,
+        generation: impl Fn() -> usize + Send + Sync + 'static,
+        new_transport: impl Fn() -> T + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            idle: Mutex::new(vec![]),
+            max_idle,
+            generation: Box::new(generation),
+            new_transport: Box::new(new_transport),
+        }
+    }
+
+    fn with<R>(&self, func: impl FnOnce(&T) -> R) -> R {
+        // read before creating a transport, so its tag is never newer than its credentials
+        let generation = (self.generation)();
+        let transport = {
+            let mut idle = self.idle.lock();
+            idle.retain(|(tag, _transport)| *tag == generation);
+            idle.pop()
+        };
+        let (tag, transport) = transport.unwrap_or_else(|| (generation, (self.new_transport)()));
+        let result = func(&transport);
+        if tag == (self.generation)() {
+            let mut idle = self.idle.lock();
+            if idle.len() < self.max_idle {
+                idle.push((tag, transport));
+            }
+        }
+        result
+    }
+}
+
+type TransportFactory = dyn Fn(Duration) -> simple_http::SimpleHttpTransport + Send + Sync;
+
+#[derive(Clone)]
+struct RetryTransport {
+    pool: Arc<TransportPool<simple_http::SimpleHttpTransport>>,
+    new_transport: Arc<TransportFactory>, // using the given timeout
+    policy: RetryPolicy,
+    auth: Arc<RpcAuth>,
+    retries: Counter,
+    reconnections: Counter,
+}
+
+impl RetryTransport {
+    /// Share the connections (and credentials) of this transport, using fewer retries.
+    fn with_max_retries(&self, max_retries: usize) -> Self {
+        let mut transport = self.clone();
+        transport.policy.max_retries = std::cmp::min(max_retries, self.policy.max_retries);
+        transport
+    }
+
+    /// Use separate connections (sharing the credentials) with the given timeout, without retries.
+    fn for_slow_calls(&self, timeout: Duration) -> Self {
+        let mut transport = self.with_max_retries(0);
+        let new_transport = Arc::clone(&self.new_transport);
+        let auth = Arc::clone(&self.auth);
+        transport.pool = Arc::new(TransportPool::new(
+            1,
+            move || auth.generation(),
+            move || new_transport(timeout),
+        ));
+        transport
+    }
+
+    fn call<T>(
+        &self,
+        method: &str,
+        func: impl Fn(&simple_http::SimpleHttpTransport) -> Result<T, jsonrpc::Error>,
+    ) -> Result<T, jsonrpc::Error> {
+        let policy = self.policy.for_method(method);
+        call_with_reauth(
+            || policy.call(|| self.pool.with(&func), || self.retries.inc(method)),
+            || {
+                let reloaded = self.auth.reload();
+                if reloaded {
+                    // the pooled transports are replaced, since their generation is stale
+                    info!("reconnecting to bitcoind using its new cookie");
+                    self.reconnections.inc("cookie");
+                }
+                reloaded
+            },
+        )
+    }
+}
+
+impl jsonrpc::Transport for RetryTransport {
+    fn send_request(&self, req: jsonrpc::Request) -> Result<jsonrpc::Response, jsonrpc::Error> {
+        self.call(req.method, |t| t.send_request(req.clone()))
+    }
+
+    fn send_batch(
+        &self,
+        reqs: &[jsonrpc::Request],
+    ) -> Result<Vec<jsonrpc::Response>, jsonrpc::Error> {
+        self.call("batch", |t| t.send_batch(reqs))
+    }
+
+    fn fmt_target(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.pool.with(|t| t.fmt_target(f))
+    }
+}
+
+fn rpc_transport(config: &Config, metrics: &Metrics) -> Result<RetryTransport> {
     let rpc_url = format!("http://{}", config.daemon_rpc_addr);
     // Allow `wait_for_new_block` to take a bit longer before timing out.
     // See https://github.com/romanz/electrs/issues/495 for more details.
-    let builder = jsonrpc::simple_http::SimpleHttpTransport::builder()
-        .url(&rpc_url)?
-        .timeout(config.jsonrpc_timeout);
-    let builder = match config.daemon_auth.get_auth() {
-        Auth::None => builder,
-        Auth::UserPass(user, pass) => builder.auth(user, Some(pass)),
-        Auth::CookieFile(path) => {
-            let (user, pass) = read_cookie(&path)?;
-            builder.auth(user, Some(pass))
+    let timeout = config.jsonrpc_timeout;
+    let auth = Arc::new(RpcAuth::new(config.daemon_auth.get_auth())?);
+    simple_http::SimpleHttpTransport::builder().url(&rpc_url)?; // fail early on invalid URL
+    let transport_auth = Arc::clone(&auth);
+    let new_transport: Arc<TransportFactory> = Arc::new(move |timeout: Duration| {
+        let builder = simple_http::SimpleHttpTransport::builder()
+            .url(&rpc_url)
+            .expect("invalid URL")
+            .timeout(timeout);
+        match transport_auth.get() {
+            None => builder,
+            Some((user, pass)) => builder.auth(user, Some(pass)),
         }
+        .build()
+    });
+    let pool_transport = Arc::clone(&new_transport);
+    let pool_auth = Arc::clone(&auth);
+    let transport = RetryTransport {
+        pool: Arc::new(TransportPool::new(
+            rayon::current_num_threads(),
+            move || pool_auth.generation(),
+            move || pool_transport(timeout),
+        )),
+        new_transport,
+        policy: RetryPolicy {
+            max_retries: config.jsonrpc_max_retries,
+            initial_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(10),
+        },
+        auth,
+        retries: metrics.counter("daemon_rpc_retries", "# of bitcoind RPC retries", "method"),
+        reconnections: metrics.counter(
+            "daemon_rpc_reconnections",
+            "# of bitcoind RPC reconnections (e.g. using a new cookie, after bitcoind restarts)",
+            "reason",
+        ),
     };
-    Ok(Client::from_jsonrpc(jsonrpc::Client::with_transport(
-        builder.build(),
-    )))
+    Ok(transport)
+}
+
+fn rpc_client(transport: RetryTransport) -> Client {
+    Client::from_jsonrpc(jsonrpc::Client::with_transport(transport))
 }
 
 pub struct Daemon {
     p2p: Mutex<Connection>,
     rpc: Client,
+    query_rpc: Client, // used for serving clients' queries (with fewer retries)
+    transport: RetryTransport,
+    on_pruned_block: Option<OnPrunedBlock>, // set only for a pruned node
+    prune_height: AtomicUsize, // blocks below it were pruned (see `update_prune_height`)
+    block_source: BlockSourceMode,
+    block_source_fallbacks: Counter,
 }
 
 impl Daemon {
@@ -104,13 +442,15 @@ impl Daemon {
         exit_flag: &ExitFlag,
         metrics: &Metrics,
     ) -> Result<Self> {
-        let mut rpc = rpc_connect(config)?;
+        let transport = rpc_transport(config, metrics)?;
+        let query_rpc = rpc_client(transport.with_max_retries(MAX_QUERY_RETRIES));
+        let mut rpc = rpc_client(transport.clone());
 
         loop {
             exit_flag
                 .poll()
                 .context("bitcoin RPC polling interrupted")?;
-            match rpc_poll(&mut rpc) {
+            match rpc_poll(&mut rpc, config.wait_for_ibd) {
                 PollResult::Done(result) => {
                     result.context("bitcoind RPC polling failed")?;
                     break; // on success, finish polling
@@ -122,54 +462,96 @@ impl Daemon {
         }
 
         let network_info = rpc.get_network_info()?;
-        if network_info.version < 21_00_00 {
-            bail!("electrs requires bitcoind 0.21+");
-        }
+        let version = DaemonVersion(network_info.version);
+        info!(
+            "connected to bitcoind {} ({})",
+            version, network_info.subversion
+        );
+        check_daemon_version(version, config.require_daemon_version)?;
         if !network_info.network_active {
             bail!("electrs requires active bitcoind p2p network");
         }
         let info = rpc.get_blockchain_info()?;
-        if info.pruned {
-            bail!("electrs requires non-pruned bitcoind node");
+        if info.pruned && config.on_pruned_block.is_none() {
+            bail!("electrs requires non-pruned bitcoind node (unless `--on-pruned-block` is set)");
         }
+        let on_pruned_block = if info.pruned {
+            config.on_pruned_block
+        } else {
+            None
+        };
+        let prune_height =
+            usize::try_from(info.prune_height.unwrap_or(0)).context("invalid prune height")?;
 
         let p2p = Mutex::new(Connection::connect(
             config.network,
+            config.network_magic,
             config.daemon_p2p_addr,
             metrics,
+            config.daemon_parallelism,
+            config.max_block_size,
+            config.mempool_announcements && !config.ignore_mempool,
         )?);
-        Ok(Self { p2p, rpc })
+        let block_source_fallbacks = metrics.counter(
+            "daemon_block_source_fallbacks",
+            "# of block fetches which failed via p2p, and were retried via JSON-RPC (see `--block-source`)",
+            "source",
+        );
+        Ok(Self {
+            p2p,
+            rpc,
+            query_rpc,
+            transport,
+            on_pruned_block,
+            prune_height: AtomicUsize::new(prune_height),
+            block_source: config.block_source,
+            block_source_fallbacks,
+        })
     }
 
     pub(crate) fn estimate_fee(&self, nblocks: u16) -> Result<Option<Amount>> {
         Ok(self
-            .rpc
+            .query_rpc
             .estimate_smart_fee(nblocks, None)
             .context("failed to estimate fee")?
             .fee_rate)
     }
 
+    pub(crate) fn get_block_count(&self) -> Result<usize> {
+        let count = self
+            .rpc
+            .get_block_count()
+            .context("failed to get block count")?;
+        Ok(usize::try_from(count)?)
+    }
+
     pub(crate) fn get_relay_fee(&self) -> Result<Amount> {
         Ok(self
-            .rpc
+            .query_rpc
             .get_network_info()
             .context("failed to get relay fee")?
             .relay_fee)
     }
 
     pub(crate) fn broadcast(&self, tx: &Transaction) -> Result<Txid> {
-        self.rpc
+        self.query_rpc
             .send_raw_transaction(tx)
             .context("failed to broadcast transaction")
     }
 
+    pub(crate) fn get_block_header(&self, blockhash: BlockHash) -> Result<BlockHeader> {
+        self.query_rpc
+            .get_block_header(&blockhash)
+            .context("failed to get block header")
+    }
+
     pub(crate) fn get_transaction_info(
         &self,
         txid: &Txid,
         blockhash: Option<BlockHash>,
     ) -> Result<Value> {
         // No need to parse the resulting JSON, just return it as-is to the client.
-        self.rpc
+        self.query_rpc
             .call(
                 "getrawtransaction",
                 &[json!(txid), json!(true), json!(blockhash)],
@@ -182,7 +564,10 @@ impl Daemon {
         txid: &Txid,
         blockhash: Option<BlockHash>,
     ) -> Result<Value> {
-        let tx = self.get_transaction(txid, blockhash)?;
+        let tx = self
+            .query_rpc
+            .get_raw_transaction(txid, blockhash.as_ref())
+            .context("failed to get transaction")?;
         Ok(json!(serialize(&tx).to_hex()))
     }
 
@@ -198,7 +583,7 @@ impl Daemon {
 
     pub(crate) fn get_block_txids(&self, blockhash: BlockHash) -> Result<Vec<Txid>> {
         Ok(self
-            .rpc
+            .query_rpc
             .get_block_info(&blockhash)
             .context("failed to get block txids")?
             .tx)
@@ -210,6 +595,13 @@ impl Daemon {
             .context("failed to get mempool txids")
     }
 
+    /// Get all mempool entries in a single `getrawmempool true` call.
+    pub(crate) fn get_mempool_entries(&self) -> Result<HashMap<Txid, json::GetMempoolEntryResult>> {
+        self.rpc
+            .call("getrawmempool", &[json!(true)])
+            .context("failed to get mempool entries")
+    }
+
     pub(crate) fn get_mempool_entry(&self, txid: &Txid) -> Result<json::GetMempoolEntryResult> {
         self.rpc
             .get_mempool_entry(txid)
@@ -220,21 +612,247 @@ impl Daemon {
         self.p2p.lock().get_new_headers(chain)
     }
 
-    pub(crate) fn for_blocks<B, F>(&self, blockhashes: B, func: F) -> Result<()>
+    /// Fetch the blocks (failing if any of them was pruned).
+    /// Blocks missing from `chain` (e.g. the ones being indexed) are assumed to be available.
+    pub(crate) fn for_blocks<B, F>(&self, chain: &Chain, blockhashes: B, func: F) -> Result<()>
     where
         B: IntoIterator<Item = BlockHash>,
         F: FnMut(BlockHash, Block),
     {
-        self.p2p.lock().for_blocks(blockhashes, func)
+        let blockhashes: Vec<BlockHash> = blockhashes.into_iter().collect();
+        let pruned = self.pruned_blocks(chain, &blockhashes);
+        let blockhashes = available_blocks(blockhashes, &pruned, OnPrunedBlock::Error)?;
+        self.fetch_blocks(&blockhashes, func)
+    }
+
+    /// Fetch the blocks for a query, handling pruned blocks as configured by `--on-pruned-block`.
+    pub(crate) fn for_query_blocks<B, F>(
+        &self,
+        chain: &Chain,
+        blockhashes: B,
+        func: F,
+    ) -> Result<()>
+    where
+        B: IntoIterator<Item = BlockHash>,
+        F: FnMut(BlockHash, Block),
+    {
+        let blockhashes: Vec<BlockHash> = blockhashes.into_iter().collect();
+        let on_pruned_block = match self.on_pruned_block {
+            Some(on_pruned_block) => on_pruned_block,
+            None => return self.fetch_blocks(&blockhashes, func),
+        };
+        let pruned = self.pruned_blocks(chain, &blockhashes);
+        let blockhashes = available_blocks(blockhashes, &pruned, on_pruned_block)?;
+        self.fetch_blocks(&blockhashes, func)
+    }
+
+    /// Fetch the blocks from the configured source (see `--block-source`).
+    fn fetch_blocks<F>(&self, blockhashes: &[BlockHash], mut func: F) -> Result<()>
+    where
+        F: FnMut(BlockHash, Block),
+    {
+        let p2p_blocks = |blockhashes: &[BlockHash], func: &mut dyn FnMut(BlockHash, Block)| {
+            self.p2p
+                .lock()
+                .for_blocks(blockhashes.iter().copied(), func)
+        };
+        match self.block_source {
+            BlockSourceMode::P2p => p2p_blocks(blockhashes, &mut func),
+            BlockSourceMode::Rpc => self.rpc_blocks(blockhashes, &mut func),
+            BlockSourceMode::Auto => fetch_with_fallback(
+                blockhashes,
+                func,
+                p2p_blocks,
+                |blockhashes, func| self.rpc_blocks(blockhashes, func),
+                |e, left| {
+                    warn!(
+                        "failed to fetch blocks via p2p: {}, fetching {} blocks via JSON-RPC",
+                        e, left
+                    );
+                    self.block_source_fallbacks.inc("rpc");
+                },
+            ),
+        }
+    }
+
+    fn rpc_blocks(
+        &self,
+        blockhashes: &[BlockHash],
+        func: &mut dyn FnMut(BlockHash, Block),
+    ) -> Result<()> {
+        for blockhash in blockhashes {
+            let block = self
+                .rpc
+                .get_block(blockhash)
+                .with_context(|| format!("failed to get block {}", blockhash))?;
+            func(*blockhash, block);
+        }
+        Ok(())
+    }
+
+    /// Blocks below the prune height (which bitcoind can't serve anymore).
+    fn pruned_blocks(&self, chain: &Chain, blockhashes: &[BlockHash]) -> HashSet<BlockHash> {
+        if self.on_pruned_block.is_none() {
+            return HashSet::new();
+        }
+        pruned_blocks(
+            chain,
+            blockhashes,
+            self.prune_height.load(Ordering::Relaxed),
+        )
+    }
+
+    /// Refresh the cached prune height (bitcoind prunes its blocks as the chain grows).
+    pub(crate) fn update_prune_height(&self) -> Result<()> {
+        if self.on_pruned_block.is_none() {
+            return Ok(());
+        }
+        let prune_height = self.rpc.get_blockchain_info()?.prune_height.unwrap_or(0);
+        let prune_height = usize::try_from(prune_height).context("invalid prune height")?;
+        self.prune_height.store(prune_height, Ordering::Relaxed);
+        Ok(())
     }
 
     pub(crate) fn new_block_notification(&self) -> Receiver<()> {
         self.p2p.lock().new_block_notification()
     }
+
+    pub(crate) fn new_txs_notification(&self) -> Receiver<Vec<Txid>> {
+        self.p2p.lock().new_txs_notification()
+    }
+
+    /// A separate bitcoind RPC client for slow calls (e.g. from a background thread).
+    pub(crate) fn slow_rpc(&self) -> SlowRpc {
+        SlowRpc {
+            rpc: rpc_client(self.transport.for_slow_calls(SLOW_RPC_TIMEOUT)),
+        }
+    }
+}
+
+/// Uses a long timeout, and doesn't retry (since the calls are expensive for bitcoind).
+pub(crate) struct SlowRpc {
+    rpc: Client,
+}
+
+impl SlowRpc {
+    /// May take a while, since bitcoind scans its whole UTXO set (skipping its hash computation).
+    pub(crate) fn get_utxo_set_info(&self) -> Result<UtxoSetInfo> {
+        self.rpc
+            .call("gettxoutsetinfo", &[json!("none")])
+            .context("failed to get UTXO set info")
+    }
 }
 
 pub(crate) type RpcError = bitcoincore_rpc::jsonrpc::error::RpcError;
 
+/// The relevant fields of `gettxoutsetinfo` result
+#[derive(Deserialize)]
+pub(crate) struct UtxoSetInfo {
+    pub txouts: u64,
+    pub total_amount: f64, // [BTC]
+}
+
+const MIN_DAEMON_VERSION: DaemonVersion = DaemonVersion(21_00_00);
+
+/// Refuse bitcoind versions older than the supported (or the required) one.
+fn check_daemon_version(version: DaemonVersion, required: Option<DaemonVersion>) -> Result<()> {
+    if version < MIN_DAEMON_VERSION {
+        bail!("electrs requires bitcoind {}+", MIN_DAEMON_VERSION);
+    }
+    match required {
+        Some(required) if version < required => bail!(
+            "bitcoind {} is older than the required version {} (see `--require-daemon-version`)",
+            version,
+            required
+        ),
+        _ => Ok(()),
+    }
+}
+
+/// Fetch the blocks using `primary`, and the ones it failed to fetch using `fallback`
+/// (so each block is passed to `func` exactly once).
+fn fetch_with_fallback<F, P, S>(
+    blockhashes: &[BlockHash],
+    mut func: F,
+    primary: P,
+    fallback: S,
+    on_fallback: impl FnOnce(&anyhow::Error, usize),
+) -> Result<()>
+where
+    F: FnMut(BlockHash, Block),
+    P: FnOnce(&[BlockHash], &mut dyn FnMut(BlockHash, Block)) -> Result<()>,
+    S: FnOnce(&[BlockHash], &mut dyn FnMut(BlockHash, Block)) -> Result<()>,
+{
+    let mut fetched = HashSet::new();
+    let result = primary(blockhashes, &mut |blockhash, block| {
+        fetched.insert(blockhash);
+        func(blockhash, block);
+    });
+    let err = match result {
+        Ok(()) => return Ok(()),
+        Err(err) => err,
+    };
+    let left: Vec<BlockHash> = blockhashes
+        .iter()
+        .filter(|blockhash| !fetched.contains(blockhash))
+        .copied()
+        .collect();
+    on_fallback(&err, left.len());
+    fallback(&left, &mut func)
+}
+
+/// The blocks below `prune_height` (using their heights in the local chain).
+fn pruned_blocks(
+    chain: &Chain,
+    blockhashes: &[BlockHash],
+    prune_height: usize,
+) -> HashSet<BlockHash> {
+    blockhashes
+        .iter()
+        .filter(|blockhash| {
+            chain
+                .get_block_height(blockhash)
+                .map_or(false, |height| height < prune_height)
+        })
+        .copied()
+        .collect()
+}
+
+/// Drop the pruned blocks (if configured to skip them), or fail on the first one.
+fn available_blocks(
+    blockhashes: Vec<BlockHash>,
+    pruned: &HashSet<BlockHash>,
+    on_pruned_block: OnPrunedBlock,
+) -> Result<Vec<BlockHash>> {
+    if pruned.is_empty() {
+        return Ok(blockhashes);
+    }
+    match on_pruned_block {
+        OnPrunedBlock::Error => {
+            let missing = blockhashes
+                .iter()
+                .find(|blockhash| pruned.contains(blockhash))
+                .expect("missing pruned block");
+            Err(QueryError::PrunedBlock(*missing).into())
+        }
+        OnPrunedBlock::Skip => {
+            let available: Vec<BlockHash> = blockhashes
+                .into_iter()
+                .filter(|blockhash| !pruned.contains(blockhash))
+                .collect();
+            warn!("skipping {} pruned blocks", pruned.len());
+            Ok(available)
+        }
+    }
+}
+
+/// `bitcoind` failed to find the requested transaction (`RPC_INVALID_ADDRESS_OR_KEY`).
+pub(crate) fn is_tx_not_found(err: &anyhow::Error) -> bool {
+    err.downcast_ref::<bitcoincore_rpc::Error>()
+        .and_then(extract_bitcoind_error)
+        .map_or(false, |e| e.code == -5)
+}
+
 pub(crate) fn extract_bitcoind_error(err: &bitcoincore_rpc::Error) -> Option<&RpcError> {
     use bitcoincore_rpc::{
         jsonrpc::error::Error::Rpc as ServerError, Error::JsonRpc as JsonRpcError,
@@ -244,3 +862,406 @@ pub(crate) fn extract_bitcoind_error(err: &bitcoincore_rpc::Error) -> Option<&Rp
         _ => None,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        available_blocks, call_with_reauth, check_daemon_version, download_progress,
+        fetch_with_fallback, is_retryable, is_unauthorized, pruned_blocks, RetryPolicy, RpcAuth,
+        TransportPool,
+    };
+    use crate::config::{DaemonVersion, OnPrunedBlock};
+    use crate::errors::QueryError;
+    use crate::tests::{coinbase, make_blocks, make_chain, script, txout};
+    use bitcoin::{hashes::Hash, Block, BlockHash};
+    use bitcoincore_rpc::{
+        jsonrpc::{self, simple_http},
+        Auth,
+    };
+
+    use rayon::prelude::*;
+
+    use std::collections::HashSet;
+    use std::io;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    fn socket_error(kind: io::ErrorKind) -> jsonrpc::Error {
+        let err = simple_http::Error::SocketError(io::Error::from(kind));
+        jsonrpc::Error::Transport(Box::new(err))
+    }
+
+    #[test]
+    fn test_download_progress() {
+        // indexing is deferred during IBD, even if all known headers' blocks were downloaded
+        assert_eq!(
+            download_progress(true, 100, 100, 0.5),
+            Some("0 blocks to download (IBD) (50.00% verified)".to_owned())
+        );
+        assert_eq!(
+            download_progress(true, 100, 150, 0.25),
+            Some("50 blocks to download (IBD) (25.00% verified)".to_owned())
+        );
+        assert_eq!(
+            download_progress(false, 149, 150, 0.999),
+            Some("1 blocks to download (99.90% verified)".to_owned())
+        );
+        assert_eq!(download_progress(false, 150, 150, 1.0), None);
+    }
+
+    fn policy(max_retries: usize) -> RetryPolicy {
+        RetryPolicy {
+            max_retries,
+            initial_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(2),
+        }
+    }
+
+    #[test]
+    fn test_pruned_blocks() {
+        let blockhashes: Vec<BlockHash> = (0..4u8).map(|i| BlockHash::hash(&[i])).collect();
+        let pruned: HashSet<BlockHash> = vec![blockhashes[2], blockhashes[1]].into_iter().collect();
+
+        for &on_pruned_block in &[OnPrunedBlock::Error, OnPrunedBlock::Skip] {
+            let all = available_blocks(blockhashes.clone(), &HashSet::new(), on_pruned_block);
+            assert_eq!(all.unwrap(), blockhashes);
+        }
+
+        // the query fails with the first pruned block
+        let err = available_blocks(blockhashes.clone(), &pruned, OnPrunedBlock::Error).unwrap_err();
+        match err.downcast_ref::<QueryError>() {
+            Some(QueryError::PrunedBlock(blockhash)) => assert_eq!(*blockhash, blockhashes[1]),
+            _ => panic!("unexpected error: {}", err),
+        }
+
+        // or the pruned blocks are skipped
+        let available = available_blocks(blockhashes.clone(), &pruned, OnPrunedBlock::Skip);
+        assert_eq!(available.unwrap(), vec![blockhashes[0], blockhashes[3]]);
+    }
+
+    #[test]
+    fn test_pruned_blocks_heights() {
+        let blocks = make_blocks(
+            (1..=3)
+                .map(|i| vec![coinbase(i, vec![txout(1, script(1))])])
+                .collect(),
+        );
+        let chain = make_chain(&blocks);
+        let mut blockhashes: Vec<BlockHash> = blocks.iter().map(Block::block_hash).collect();
+        blockhashes.push(BlockHash::hash(b"unknown")); // e.g. a block being indexed
+
+        let pruned = |prune_height| {
+            let mut pruned: Vec<BlockHash> = pruned_blocks(&chain, &blockhashes, prune_height)
+                .into_iter()
+                .collect();
+            pruned.sort_unstable();
+            pruned
+        };
+        assert!(pruned(0).is_empty());
+        assert!(pruned(1).is_empty()); // the blocks' heights are 1..=3
+        assert_eq!(pruned(2), vec![blockhashes[0]]);
+        let mut expected = blockhashes[..3].to_vec();
+        expected.sort_unstable();
+        assert_eq!(pruned(10), expected);
+    }
+
+    #[test]
+    fn test_check_daemon_version() {
+        let version = |s: &str| s.parse::<DaemonVersion>().unwrap();
+        let check = |daemon: &str, required: Option<&str>| {
+            check_daemon_version(version(daemon), required.map(version)).map_err(|e| e.to_string())
+        };
+        assert_eq!(
+            check("0.20.1", None),
+            Err("electrs requires bitcoind 0.21.0+".to_owned())
+        );
+        assert_eq!(check("0.21.0", None), Ok(()));
+        assert_eq!(check("25.0", None), Ok(()));
+
+        assert_eq!(
+            check("23.1", Some("24.0")),
+            Err(
+                "bitcoind 23.1 is older than the required version 24.0 (see `--require-daemon-version`)"
+                    .to_owned()
+            )
+        );
+        assert_eq!(check("24.0", Some("24.0")), Ok(()));
+        assert_eq!(
+            check("0.20.1", Some("0.19")),
+            Err("electrs requires bitcoind 0.21.0+".to_owned())
+        );
+    }
+
+    #[test]
+    fn test_fetch_with_fallback() {
+        let blocks = make_blocks(
+            (1..=4)
+                .map(|height| vec![coinbase(height, vec![txout(50, script(1))])])
+                .collect(),
+        );
+        let blockhashes: Vec<BlockHash> = blocks.iter().map(Block::block_hash).collect();
+        let serve = |served: &[BlockHash], func: &mut dyn FnMut(BlockHash, Block)| {
+            for blockhash in served {
+                let block = blocks
+                    .iter()
+                    .find(|b| b.block_hash() == *blockhash)
+                    .unwrap();
+                func(*blockhash, block.clone());
+            }
+        };
+
+        // the primary source fails after returning the first 2 blocks
+        let mut fetched = vec![];
+        let mut fallbacks = 0;
+        fetch_with_fallback(
+            &blockhashes,
+            |blockhash, block| fetched.push((blockhash, block)),
+            |hashes, func| {
+                serve(&hashes[..2], func);
+                bail!("connection reset")
+            },
+            |hashes, func| {
+                assert_eq!(hashes, &blockhashes[2..]);
+                serve(hashes, func);
+                Ok(())
+            },
+            |err, left| {
+                assert_eq!(err.to_string(), "connection reset");
+                assert_eq!(left, 2);
+                fallbacks += 1;
+            },
+        )
+        .unwrap();
+        assert_eq!(fallbacks, 1);
+        assert_eq!(fetched.len(), blocks.len()); // each block is fetched exactly once
+        for ((blockhash, block), expected) in fetched.iter().zip(&blocks) {
+            assert_eq!(*blockhash, expected.block_hash());
+            assert_eq!(block, expected);
+        }
+
+        // no fallback is needed on success
+        let mut fetched = 0;
+        fetch_with_fallback(
+            &blockhashes,
+            |_, _| fetched += 1,
+            |hashes, func| {
+                serve(hashes, func);
+                Ok(())
+            },
+            |_, _| panic!("unexpected fallback"),
+            |_, _| panic!("unexpected fallback"),
+        )
+        .unwrap();
+        assert_eq!(fetched, blocks.len());
+
+        // the fallback's error is returned
+        let result = fetch_with_fallback(
+            &blockhashes,
+            |_, _| (),
+            |_, _| bail!("p2p failed"),
+            |_, _| bail!("rpc failed"),
+            |_, _| (),
+        );
+        assert_eq!(result.unwrap_err().to_string(), "rpc failed");
+    }
+
+    #[test]
+    fn test_retryable_errors() {
+        assert!(is_retryable(&socket_error(
+            io::ErrorKind::ConnectionRefused
+        )));
+        assert!(is_retryable(&socket_error(io::ErrorKind::TimedOut)));
+        assert!(!is_retryable(&socket_error(
+            io::ErrorKind::PermissionDenied
+        )));
+        let unauthorized = simple_http::Error::HttpErrorCode(401);
+        assert!(!is_retryable(&jsonrpc::Error::Transport(Box::new(
+            unauthorized
+        ))));
+    }
+
+    #[test]
+    fn test_retry_transient_failure() {
+        let mut calls = 0;
+        let mut retries = 0;
+        let result = policy(3).call(
+            || {
+                calls += 1;
+                if calls < 3 {
+                    Err(socket_error(io::ErrorKind::ConnectionRefused))
+                } else {
+                    Ok(calls)
+                }
+            },
+            || retries += 1,
+        );
+        assert_eq!(result.unwrap(), 3);
+        assert_eq!(retries, 2);
+    }
+
+    #[test]
+    fn test_retry_limit() {
+        let mut calls = 0;
+        let result: Result<(), _> = policy(2).call(
+            || {
+                calls += 1;
+                Err(socket_error(io::ErrorKind::ConnectionReset))
+            },
+            || (),
+        );
+        assert!(result.is_err());
+        assert_eq!(calls, 3);
+    }
+
+    #[test]
+    fn test_fatal_failure() {
+        let mut calls = 0;
+        let result: Result<(), _> = policy(5).call(
+            || {
+                calls += 1;
+                Err(socket_error(io::ErrorKind::PermissionDenied))
+            },
+            || (),
+        );
+        assert!(result.is_err());
+        assert_eq!(calls, 1);
+    }
+
+    #[test]
+    fn test_broadcast_not_retried() {
+        let mut calls = 0;
+        let result: Result<(), _> = policy(5).for_method("sendrawtransaction").call(
+            || {
+                calls += 1;
+                Err(socket_error(io::ErrorKind::TimedOut))
+            },
+            || (),
+        );
+        assert!(result.is_err());
+        assert_eq!(calls, 1);
+        assert_eq!(policy(5).for_method("getblock").max_retries, 5);
+    }
+
+    fn unauthorized() -> jsonrpc::Error {
+        jsonrpc::Error::Transport(Box::new(simple_http::Error::HttpErrorCode(401)))
+    }
+
+    #[test]
+    fn test_reload_cookie() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(".cookie");
+        std::fs::write(&path, "__cookie__:first").unwrap();
+        let auth = RpcAuth::new(Auth::CookieFile(path.clone())).unwrap();
+        let cookie = |pass: &str| Some(("__cookie__".to_owned(), pass.to_owned()));
+        assert_eq!(auth.get(), cookie("first"));
+        assert!(!auth.reload()); // unchanged
+
+        // bitcoind was restarted
+        std::fs::write(&path, "__cookie__:second").unwrap();
+        assert!(auth.reload());
+        assert_eq!(auth.get(), cookie("second"));
+
+        // bitcoind is still down, so the current credentials are kept
+        std::fs::remove_file(&path).unwrap();
+        assert!(!auth.reload());
+        assert_eq!(auth.get(), cookie("second"));
+
+        let auth = RpcAuth::new(Auth::UserPass("user".to_owned(), "pass".to_owned())).unwrap();
+        assert!(!auth.reload());
+        assert_eq!(auth.get(), Some(("user".to_owned(), "pass".to_owned())));
+    }
+
+    #[test]
+    fn test_reauth_after_restart() {
+        assert!(is_unauthorized(&unauthorized()));
+        assert!(!is_unauthorized(&socket_error(
+            io::ErrorKind::ConnectionRefused
+        )));
+
+        // the connection drops (and is retried), then bitcoind rejects the stale cookie
+        let mut responses = vec![
+            Err(socket_error(io::ErrorKind::ConnectionRefused)),
+            Err(unauthorized()),
+            Ok(42),
+        ]
+        .into_iter();
+        let mut reloads = 0;
+        let result = call_with_reauth(
+            || {
+                policy(3).call(
+                    || responses.next().unwrap_or_else(|| Err(unauthorized())),
+                    || (),
+                )
+            },
+            || {
+                reloads += 1;
+                true
+            },
+        );
+        assert_eq!(result.unwrap(), 42);
+        assert_eq!(reloads, 1);
+
+        // without new credentials, the call fails
+        let result: Result<(), _> = call_with_reauth(|| Err(unauthorized()), || false);
+        assert!(is_unauthorized(&result.unwrap_err()));
+
+        // other errors don't reload the credentials
+        let result: Result<(), _> = call_with_reauth(
+            || Err(socket_error(io::ErrorKind::PermissionDenied)),
+            || panic!("unexpected reload"),
+        );
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_transport_pool() {
+        let created = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&created);
+        let pool = TransportPool::new(4, || 0, move || counter.fetch_add(1, Ordering::SeqCst));
+
+        // sequential calls reuse the same transport
+        for _ in 0..100 {
+            assert_eq!(pool.with(|id| *id), 0);
+        }
+        assert_eq!(created.load(Ordering::SeqCst), 1);
+
+        // concurrent calls use multiple transports
+        let ids: Vec<usize> = (0..100)
+            .into_par_iter()
+            .map(|_| pool.with(|id| *id))
+            .collect();
+        assert_eq!(ids.len(), 100);
+        assert!(pool.idle.lock().len() <= 4);
+    }
+
+    #[test]
+    fn test_transport_pool_generation() {
+        let generation = Arc::new(AtomicUsize::new(0));
+        let current = Arc::clone(&generation);
+        let created = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&created);
+        let pool = TransportPool::new(
+            4,
+            move || current.load(Ordering::SeqCst),
+            move || counter.fetch_add(1, Ordering::SeqCst),
+        );
+        assert_eq!(pool.with(|id| *id), 0);
+        assert_eq!(pool.with(|id| *id), 0); // reused
+
+        // an idle transport is dropped after the credentials change
+        generation.fetch_add(1, Ordering::SeqCst);
+        assert_eq!(pool.with(|id| *id), 1);
+        assert_eq!(pool.with(|id| *id), 1);
+
+        // as well as an in-use one
+        let id = pool.with(|id| {
+            generation.fetch_add(1, Ordering::SeqCst);
+            *id
+        });
+        assert_eq!(id, 1);
+        assert!(pool.idle.lock().is_empty());
+        assert_eq!(pool.with(|id| *id), 2);
+        assert_eq!(created.load(Ordering::SeqCst), 3);
+    }
+}
@@ -1,8 +1,21 @@
 use anyhow::{Context, Result};
+use bitcoin::hashes::hex::ToHex;
 use electrs_rocksdb as rocksdb;
+use parking_lot::Mutex;
 
-use std::path::Path;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use crate::{
+    config::IndexMode,
+    thread::spawn,
+    types::{HashPrefixRow, HASH_PREFIX_LEN},
+};
 
 pub(crate) type Row = Box<[u8]>;
 
@@ -13,6 +26,8 @@ pub(crate) struct WriteBatch {
     pub(crate) funding_rows: Vec<Row>,
     pub(crate) spending_rows: Vec<Row>,
     pub(crate) txid_rows: Vec<Row>,
+    pub(crate) filter_rows: Vec<(Row, Row)>,
+    pub(crate) data_rows: Vec<Row>,
 }
 
 impl WriteBatch {
@@ -21,13 +36,20 @@ impl WriteBatch {
         self.funding_rows.sort_unstable();
         self.spending_rows.sort_unstable();
         self.txid_rows.sort_unstable();
+        self.filter_rows.sort_unstable();
+        self.data_rows.sort_unstable();
     }
 }
 
 /// RocksDB wrapper for index storage
 pub struct DBStore {
-    db: rocksdb::DB,
+    db: Arc<rocksdb::DB>,
     bulk_import: AtomicBool,
+    compact_after_blocks: Option<usize>,
+    compaction_requested: AtomicBool,
+    blocks_since_compaction: AtomicUsize,
+    compacting: Arc<AtomicBool>,
+    compaction: Mutex<Option<JoinHandle<()>>>,
 }
 
 const CONFIG_CF: &str = "config";
@@ -35,8 +57,21 @@ const HEADERS_CF: &str = "headers";
 const TXID_CF: &str = "txid";
 const FUNDING_CF: &str = "funding";
 const SPENDING_CF: &str = "spending";
+const FILTERS_CF: &str = "filters";
+const DATA_CF: &str = "data";
+
+const COLUMN_FAMILIES: &[&str] = &[
+    CONFIG_CF,
+    HEADERS_CF,
+    TXID_CF,
+    FUNDING_CF,
+    SPENDING_CF,
+    FILTERS_CF,
+    DATA_CF,
+];
 
-const COLUMN_FAMILIES: &[&str] = &[CONFIG_CF, HEADERS_CF, TXID_CF, FUNDING_CF, SPENDING_CF];
+// Hash-prefixed rows, looked up by prefix scans
+const PREFIX_SCANNED_CFS: &[&str] = &[TXID_CF, FUNDING_CF, SPENDING_CF, DATA_CF];
 
 const CONFIG_KEY: &str = "C";
 const TIP_KEY: &[u8] = b"T";
@@ -78,19 +113,98 @@ const DB_PROPERIES: &[&str] = &[
     "rocksdb.block-cache-pinned-usage",
 ];
 
+/// Row statistics of a single column family
+#[derive(Debug, PartialEq, Serialize)]
+pub(crate) struct RowStats {
+    pub(crate) name: &'static str,
+    pub(crate) rows: u64,
+    /// Rows sharing their hash prefix with the previous row (in TXID, SPENDING and FUNDING CFs)
+    pub(crate) repeated_prefixes: u64,
+    /// Only defined where a repeated prefix means a collision (i.e. TXID and SPENDING rows)
+    pub(crate) collision_rate: Option<f64>,
+    /// Total SST files' size (in bytes)
+    pub(crate) size: u64,
+}
+
+/// Index statistics (printed by `--index-stats`)
+#[derive(Debug, Serialize)]
+pub(crate) struct StoreStats {
+    pub(crate) column_families: Vec<RowStats>,
+    pub(crate) size: u64,
+    /// Lowest and highest indexed heights (of TXID rows)
+    pub(crate) heights: Option<(usize, usize)>,
+}
+
+impl fmt::Display for StoreStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "{:<10} {:>14} {:>14} {:>10} {:>10}",
+            "CF", "rows", "repeated", "collision", "size (MB)"
+        )?;
+        for cf in &self.column_families {
+            let collision_rate = match cf.collision_rate {
+                Some(rate) => format!("{:.2e}", rate),
+                None => "-".to_owned(),
+            };
+            writeln!(
+                f,
+                "{:<10} {:>14} {:>14} {:>10} {:>10.1}",
+                cf.name,
+                cf.rows,
+                cf.repeated_prefixes,
+                collision_rate,
+                cf.size as f64 / 1e6
+            )?;
+        }
+        writeln!(f, "total size: {:.1} MB", self.size as f64 / 1e6)?;
+        match self.heights {
+            Some((first, last)) => writeln!(f, "indexed heights: {}..={}", first, last),
+            None => writeln!(f, "indexed heights: none"),
+        }
+    }
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 struct Config {
     compacted: bool,
     format: u64,
+    #[serde(default = "legacy_hash_prefix_len")]
+    hash_prefix_len: usize,
+    #[serde(default)] // DBs created before storing the index mode contain all rows
+    index_mode: IndexMode,
+    #[serde(default)]
+    index_filters: bool, // all blocks have filters (so their headers' chain is complete)
+    #[serde(default)]
+    pruned_height: usize, // rows below this height were deleted (see `--keep-blocks`)
 }
 
 const CURRENT_FORMAT: u64 = 0;
 
+const SNAPSHOT_METADATA_FILE: &str = "electrs-snapshot.json";
+
+/// Stored next to a snapshot's DB files, to be checked before importing it
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+struct SnapshotMetadata {
+    format: u64,
+    hash_prefix_len: usize,
+    tip: Option<String>, // hex-encoded
+}
+
+// DBs created before storing the prefix length in their config used 8-byte prefixes
+fn legacy_hash_prefix_len() -> usize {
+    8
+}
+
 impl Default for Config {
     fn default() -> Self {
         Config {
             compacted: false,
             format: CURRENT_FORMAT,
+            hash_prefix_len: HASH_PREFIX_LEN,
+            index_mode: IndexMode::default(),
+            index_filters: false,
+            pruned_height: 0,
         }
     }
 }
@@ -105,7 +219,21 @@ fn default_opts() -> rocksdb::Options {
     opts.set_write_buffer_size(256 << 20);
     opts.set_disable_auto_compactions(true); // for initial bulk load
     opts.set_advise_random_on_open(false); // bulk load uses sequential I/O
-    opts.set_prefix_extractor(rocksdb::SliceTransform::create_fixed_prefix(8));
+    opts.set_prefix_extractor(rocksdb::SliceTransform::create_fixed_prefix(
+        HASH_PREFIX_LEN,
+    ));
+    opts
+}
+
+fn cf_opts(name: &str) -> rocksdb::Options {
+    let mut opts = default_opts();
+    if PREFIX_SCANNED_CFS.contains(&name) {
+        // most lookups are for missing prefixes (e.g. new scripthashes), so they can skip
+        // reading the SST files whose bloom filter doesn't contain the prefix
+        let mut block_opts = rocksdb::BlockBasedOptions::default();
+        block_opts.set_bloom_filter(10, false);
+        opts.set_block_based_table_factory(&block_opts);
+    }
     opts
 }
 
@@ -113,7 +241,7 @@ impl DBStore {
     fn create_cf_descriptors() -> Vec<rocksdb::ColumnFamilyDescriptor> {
         COLUMN_FAMILIES
             .iter()
-            .map(|&name| rocksdb::ColumnFamilyDescriptor::new(name, default_opts()))
+            .map(|&name| rocksdb::ColumnFamilyDescriptor::new(name, cf_opts(name)))
             .collect()
     }
 
@@ -133,8 +261,13 @@ impl DBStore {
             live_files.iter().map(|f| f.num_entries).sum::<u64>() as f64 / 1e9
         );
         let store = DBStore {
-            db,
+            db: Arc::new(db),
             bulk_import: AtomicBool::new(true),
+            compact_after_blocks: None,
+            compaction_requested: AtomicBool::new(false),
+            blocks_since_compaction: AtomicUsize::new(0),
+            compacting: Arc::new(AtomicBool::new(false)),
+            compaction: Mutex::new(None),
         };
         Ok(store)
     }
@@ -150,17 +283,27 @@ impl DBStore {
     /// Opens a new RocksDB at the specified location.
     pub fn open(path: &Path, auto_reindex: bool) -> Result<Self> {
         let mut store = Self::open_internal(path)?;
-        let config = store.get_config();
+        let (config, invalid_config) = match store.try_get_config() {
+            Ok(config) => (config, None),
+            Err(e) => (None, Some(format!("{:#}", e))),
+        };
         debug!("DB {:?}", config);
         let mut config = config.unwrap_or_default(); // use default config when DB is empty
 
-        let reindex_cause = if store.is_legacy_format() {
+        let reindex_cause = if invalid_config.is_some() {
+            invalid_config
+        } else if store.is_legacy_format() {
             Some("legacy format".to_owned())
         } else if config.format != CURRENT_FORMAT {
             Some(format!(
                 "unsupported format {} != {}",
                 config.format, CURRENT_FORMAT
             ))
+        } else if config.hash_prefix_len != HASH_PREFIX_LEN {
+            Some(format!(
+                "unsupported hash prefix length {} != {}",
+                config.hash_prefix_len, HASH_PREFIX_LEN
+            ))
         } else {
             None
         };
@@ -191,6 +334,41 @@ impl DBStore {
         Ok(store)
     }
 
+    /// Opens an existing RocksDB at the specified location, without modifying it.
+    pub(crate) fn open_read_only(path: &Path) -> Result<Self> {
+        let db = rocksdb::DB::open_cf_for_read_only(&default_opts(), path, COLUMN_FAMILIES, false)
+            .with_context(|| format!("failed to open DB: {}", path.display()))?;
+        let store = DBStore {
+            db: Arc::new(db),
+            bulk_import: AtomicBool::new(false),
+            compact_after_blocks: None,
+            compaction_requested: AtomicBool::new(false),
+            blocks_since_compaction: AtomicUsize::new(0),
+            compacting: Arc::new(AtomicBool::new(false)),
+            compaction: Mutex::new(None),
+        };
+        match store.try_get_config()? {
+            Some(config) if config.hash_prefix_len != HASH_PREFIX_LEN => bail!(
+                "unsupported hash prefix length {} != {}",
+                config.hash_prefix_len,
+                HASH_PREFIX_LEN
+            ),
+            Some(config) if config.format != CURRENT_FORMAT => {
+                bail!("unsupported format {} != {}", config.format, CURRENT_FORMAT)
+            }
+            Some(_) => Ok(store),
+            None => bail!("missing DB config: {}", path.display()),
+        }
+    }
+
+    /// Full compactions (after the initial one) are performed if `requested`,
+    /// or after writing `after_blocks` new blocks.
+    pub(crate) fn set_compaction(&mut self, after_blocks: Option<usize>, requested: bool) {
+        self.compact_after_blocks = after_blocks;
+        self.compaction_requested
+            .store(requested, Ordering::Relaxed);
+    }
+
     fn config_cf(&self) -> &rocksdb::ColumnFamily {
         self.db.cf_handle(CONFIG_CF).expect("missing CONFIG_CF")
     }
@@ -211,6 +389,14 @@ impl DBStore {
         self.db.cf_handle(HEADERS_CF).expect("missing HEADERS_CF")
     }
 
+    fn filters_cf(&self) -> &rocksdb::ColumnFamily {
+        self.db.cf_handle(FILTERS_CF).expect("missing FILTERS_CF")
+    }
+
+    fn data_cf(&self) -> &rocksdb::ColumnFamily {
+        self.db.cf_handle(DATA_CF).expect("missing DATA_CF")
+    }
+
     pub(crate) fn iter_funding(&self, prefix: Row) -> impl Iterator<Item = Row> + '_ {
         self.iter_prefix_cf(self.funding_cf(), prefix)
     }
@@ -223,6 +409,19 @@ impl DBStore {
         self.iter_prefix_cf(self.txid_cf(), prefix)
     }
 
+    pub(crate) fn iter_data(&self, prefix: Row) -> impl Iterator<Item = Row> + '_ {
+        self.iter_prefix_cf(self.data_cf(), prefix)
+    }
+
+    /// Scan all the TXID rows, without filling the block cache (which is used by queries).
+    pub(crate) fn iter_txid_rows(&self) -> impl Iterator<Item = Row> + '_ {
+        let mut opts = rocksdb::ReadOptions::default();
+        opts.fill_cache(false);
+        self.db
+            .iterator_cf_opt(self.txid_cf(), opts, rocksdb::IteratorMode::Start)
+            .map(|(key, _value)| key)
+    }
+
     fn iter_prefix_cf(
         &self,
         cf: &rocksdb::ColumnFamily,
@@ -252,6 +451,67 @@ impl DBStore {
             .expect("get_tip failed")
     }
 
+    pub(crate) fn get_filter(&self, blockhash: &[u8]) -> Option<Vec<u8>> {
+        self.db
+            .get_cf(self.filters_cf(), blockhash)
+            .expect("get_filter failed")
+    }
+
+    /// Count the rows of `batch` which are missing (or different) in the DB, by column family.
+    pub(crate) fn find_missing(&self, batch: &WriteBatch) -> Vec<(&'static str, usize)> {
+        let missing = |cf: &rocksdb::ColumnFamily, rows: &[Row]| {
+            rows.iter()
+                .filter(|key| self.db.get_cf(cf, key).expect("get_cf failed").is_none())
+                .count()
+        };
+        let missing_filters = batch
+            .filter_rows
+            .iter()
+            .filter(|(key, value)| self.get_filter(key).as_deref() != Some(&value[..]))
+            .count();
+        vec![
+            (HEADERS_CF, missing(self.headers_cf(), &batch.header_rows)),
+            (TXID_CF, missing(self.txid_cf(), &batch.txid_rows)),
+            (FUNDING_CF, missing(self.funding_cf(), &batch.funding_rows)),
+            (
+                SPENDING_CF,
+                missing(self.spending_cf(), &batch.spending_rows),
+            ),
+            (FILTERS_CF, missing_filters),
+            (DATA_CF, missing(self.data_cf(), &batch.data_rows)),
+        ]
+        .into_iter()
+        .filter(|(_cf, count)| *count > 0)
+        .collect()
+    }
+
+    /// Delete funding, spending, txid and data rows matching `predicate` (requires a full scan).
+    pub(crate) fn delete_rows(&self, predicate: impl Fn(&[u8]) -> bool) -> usize {
+        const DELETE_BATCH_SIZE: usize = 1_000_000;
+        let mut deleted = 0;
+        let cfs = vec![
+            self.funding_cf(),
+            self.spending_cf(),
+            self.txid_cf(),
+            self.data_cf(),
+        ];
+        for cf in cfs {
+            let mut db_batch = rocksdb::WriteBatch::default();
+            for (key, _value) in self.db.iterator_cf(cf, rocksdb::IteratorMode::Start) {
+                if predicate(&key) {
+                    db_batch.delete_cf(cf, key);
+                    deleted += 1;
+                }
+                if db_batch.len() >= DELETE_BATCH_SIZE {
+                    let db_batch = std::mem::take(&mut db_batch);
+                    self.db.write(db_batch).expect("failed to delete rows");
+                }
+            }
+            self.db.write(db_batch).expect("failed to delete rows");
+        }
+        deleted
+    }
+
     pub(crate) fn write(&self, batch: &WriteBatch) {
         let mut db_batch = rocksdb::WriteBatch::default();
         for key in &batch.funding_rows {
@@ -266,7 +526,15 @@ impl DBStore {
         for key in &batch.header_rows {
             db_batch.put_cf(self.headers_cf(), key, b"");
         }
+        for (key, value) in &batch.filter_rows {
+            db_batch.put_cf(self.filters_cf(), key, value);
+        }
+        for key in &batch.data_rows {
+            db_batch.put_cf(self.data_cf(), key, b"");
+        }
         db_batch.put_cf(self.headers_cf(), TIP_KEY, &batch.tip_row);
+        self.blocks_since_compaction
+            .fetch_add(batch.header_rows.len(), Ordering::Relaxed);
 
         let mut opts = rocksdb::WriteOptions::new();
         let bulk_import = self.bulk_import.load(Ordering::Relaxed);
@@ -277,19 +545,11 @@ impl DBStore {
 
     pub(crate) fn flush(&self) {
         let mut config = self.get_config().unwrap_or_default();
-        for name in COLUMN_FAMILIES {
-            let cf = self.db.cf_handle(name).expect("missing CF");
-            self.db.flush_cf(cf).expect("CF flush failed");
-        }
+        self.flush_memtables();
         if !config.compacted {
-            for name in COLUMN_FAMILIES {
-                info!("starting {} compaction", name);
-                let cf = self.db.cf_handle(name).expect("missing CF");
-                self.db.compact_range_cf(cf, None::<&[u8]>, None::<&[u8]>);
-            }
+            compact(&self.db);
             config.compacted = true;
             self.set_config(config);
-            info!("finished full compaction");
             self.start_compactions();
         }
         if log_enabled!(log::Level::Trace) {
@@ -304,6 +564,141 @@ impl DBStore {
         }
     }
 
+    fn flush_memtables(&self) {
+        for name in COLUMN_FAMILIES {
+            let cf = self.db.cf_handle(name).expect("missing CF");
+            self.db.flush_cf(cf).expect("CF flush failed");
+        }
+    }
+
+    fn compaction_needed(&self) -> bool {
+        if self.bulk_import.load(Ordering::Relaxed) {
+            return false; // initial compaction is performed by `flush()`
+        }
+        if self.compaction_requested.load(Ordering::Relaxed) {
+            return true;
+        }
+        match self.compact_after_blocks {
+            Some(after_blocks) => {
+                self.blocks_since_compaction.load(Ordering::Relaxed) >= after_blocks
+            }
+            None => false,
+        }
+    }
+
+    /// Return `true` if a full compaction was started (in a background thread).
+    pub(crate) fn compact_if_needed(&self) -> bool {
+        if self.compacting.load(Ordering::Acquire) || !self.compaction_needed() {
+            return false;
+        }
+        // blocks written during the compaction are counted towards the next one
+        self.compaction_requested.store(false, Ordering::Relaxed);
+        self.blocks_since_compaction.store(0, Ordering::Relaxed);
+        self.compacting.store(true, Ordering::Release);
+
+        let db = Arc::clone(&self.db);
+        let compacting = Arc::clone(&self.compacting);
+        let handle = spawn("compaction", move || {
+            compact(&db);
+            compacting.store(false, Ordering::Release);
+            Ok(())
+        });
+        if let Some(prev) = self.compaction.lock().replace(handle) {
+            prev.join().expect("compaction thread panicked");
+        }
+        true
+    }
+
+    /// Block until the running full compaction (if any) is finished.
+    pub(crate) fn wait_for_compaction(&self) {
+        if let Some(handle) = self.compaction.lock().take() {
+            if self.compacting.load(Ordering::Acquire) {
+                info!("waiting for full compaction to finish");
+            }
+            handle.join().expect("compaction thread panicked");
+        }
+    }
+
+    fn total_sst_files_size(&self) -> u64 {
+        total_sst_files_size(&self.db)
+    }
+
+    fn cf_sst_files_size(&self, name: &str) -> Option<u64> {
+        cf_sst_files_size(&self.db, name)
+    }
+
+    /// Returns a function counting the TXID rows, i.e. the indexed transactions (requires a full
+    /// scan), which can be called from another thread.
+    pub(crate) fn txid_rows_counter(&self) -> impl Fn() -> u64 + Send + 'static {
+        let db = Arc::clone(&self.db);
+        move || count_txid_rows(&db)
+    }
+
+    /// Scan all index rows (may take a while for a large DB).
+    pub(crate) fn stats(&self) -> StoreStats {
+        let mut heights = None;
+        let column_families = [
+            HEADERS_CF,
+            TXID_CF,
+            SPENDING_CF,
+            FUNDING_CF,
+            FILTERS_CF,
+            DATA_CF,
+        ]
+        .iter()
+        .map(|&name| {
+            let mut opts = rocksdb::ReadOptions::default();
+            opts.fill_cache(false);
+            let cf = self.db.cf_handle(name).expect("missing CF");
+            let mut rows = 0;
+            let mut repeated_prefixes = 0;
+            let mut prev: Option<Row> = None;
+            let prefixed = matches!(name, TXID_CF | SPENDING_CF | FUNDING_CF | DATA_CF);
+            for (key, _value) in self
+                .db
+                .iterator_cf_opt(cf, opts, rocksdb::IteratorMode::Start)
+            {
+                if name == HEADERS_CF && &key[..] == TIP_KEY {
+                    continue;
+                }
+                rows += 1;
+                if name == TXID_CF {
+                    let height = HashPrefixRow::from_db_row(&key).height();
+                    heights = match heights {
+                        None => Some((height, height)),
+                        Some((first, last)) => Some((first.min(height), last.max(height))),
+                    };
+                }
+                if !prefixed {
+                    continue;
+                }
+                if let Some(prev) = &prev {
+                    if key[..HASH_PREFIX_LEN] == prev[..HASH_PREFIX_LEN] {
+                        repeated_prefixes += 1;
+                    }
+                }
+                prev = Some(key);
+            }
+            let collision_rate = match name {
+                TXID_CF | SPENDING_CF if rows > 0 => Some(repeated_prefixes as f64 / rows as f64),
+                _ => None,
+            };
+            RowStats {
+                name,
+                rows,
+                repeated_prefixes,
+                collision_rate,
+                size: self.cf_sst_files_size(name).unwrap_or(0),
+            }
+        })
+        .collect();
+        StoreStats {
+            column_families,
+            size: self.total_sst_files_size(),
+            heights,
+        }
+    }
+
     pub(crate) fn get_properties(
         &self,
     ) -> impl Iterator<Item = (&'static str, &'static str, u64)> + '_ {
@@ -330,6 +725,38 @@ impl DBStore {
         debug!("auto-compactions enabled");
     }
 
+    /// The rows written by the indexer (see `--index-mode`).
+    pub(crate) fn index_mode(&self) -> IndexMode {
+        self.get_config().unwrap_or_default().index_mode
+    }
+
+    pub(crate) fn set_index_mode(&self, index_mode: IndexMode) {
+        let mut config = self.get_config().unwrap_or_default();
+        config.index_mode = index_mode;
+        self.set_config(config);
+    }
+
+    /// Whether all the indexed blocks have filters (see `--index-filters`).
+    pub(crate) fn index_filters(&self) -> bool {
+        self.get_config().unwrap_or_default().index_filters
+    }
+
+    pub(crate) fn set_index_filters(&self, index_filters: bool) {
+        let mut config = self.get_config().unwrap_or_default();
+        config.index_filters = index_filters;
+        self.set_config(config);
+    }
+
+    pub(crate) fn pruned_height(&self) -> usize {
+        self.get_config().unwrap_or_default().pruned_height
+    }
+
+    pub(crate) fn set_pruned_height(&self, pruned_height: usize) {
+        let mut config = self.get_config().unwrap_or_default();
+        config.pruned_height = pruned_height;
+        self.set_config(config);
+    }
+
     fn set_config(&self, config: Config) {
         let mut opts = rocksdb::WriteOptions::default();
         opts.set_sync(true);
@@ -341,22 +768,203 @@ impl DBStore {
     }
 
     fn get_config(&self) -> Option<Config> {
+        self.try_get_config().expect("failed to deserialize Config")
+    }
+
+    fn try_get_config(&self) -> Result<Option<Config>> {
         self.db
             .get_cf(self.config_cf(), CONFIG_KEY)
             .expect("DB::get failed")
-            .map(|value| serde_json::from_slice(&value).expect("failed to deserialize Config"))
+            .map(|value| serde_json::from_slice(&value))
+            .transpose()
+            .context("invalid config")
+    }
+
+    fn snapshot_metadata(&self) -> SnapshotMetadata {
+        let config = self.get_config().unwrap_or_default();
+        SnapshotMetadata {
+            format: config.format,
+            hash_prefix_len: config.hash_prefix_len,
+            tip: self.get_tip().map(|tip| tip.to_hex()),
+        }
+    }
+
+    /// Create a consistent snapshot of the DB in a new directory, using a RocksDB checkpoint
+    /// (which flushes the memtables first, and hard-links the SST files if possible).
+    pub(crate) fn export_snapshot(&self, dir: &Path) -> Result<()> {
+        let checkpoint = rocksdb::checkpoint::Checkpoint::new(&self.db)
+            .context("failed to create checkpoint")?;
+        checkpoint
+            .create_checkpoint(dir)
+            .with_context(|| format!("failed to create snapshot: {}", dir.display()))?;
+        let metadata = serde_json::to_vec_pretty(&self.snapshot_metadata())?;
+        fs::write(dir.join(SNAPSHOT_METADATA_FILE), metadata)
+            .context("failed to write snapshot metadata")?;
+        Ok(())
+    }
+
+    /// Create a point-in-time backup in a new `backup-<UNIX timestamp>.<nanoseconds>` subdirectory
+    /// of `dir` (failing if it already exists).
+    ///
+    /// Since SST files are immutable, they are hard-linked (when `dir` is on the same filesystem),
+    /// so each backup takes space only for the files written since the previous one.
+    /// The DB must not be written meanwhile (i.e. electrs must be stopped).
+    pub(crate) fn backup(&self, dir: &Path) -> Result<PathBuf> {
+        self.flush_memtables(); // so the checkpoint doesn't depend on the (possibly disabled) WAL
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?;
+        fs::create_dir_all(dir)?;
+        let path = dir.join(format!(
+            "backup-{}.{:09}",
+            timestamp.as_secs(),
+            timestamp.subsec_nanos()
+        ));
+        if path.exists() {
+            bail!("backup already exists: {}", path.display());
+        }
+        self.export_snapshot(&path)?;
+        Ok(path)
+    }
+
+    /// Copy a snapshot (created by `export_snapshot`) into a new DB directory,
+    /// verifying that its format is supported and that it contains the expected tip.
+    ///
+    /// The snapshot is copied into a sibling temporary directory, which is renamed into place
+    /// only after it's verified, so an interrupted import doesn't leave a partial DB behind.
+    /// Returns `false` (without importing) if the DB already exists.
+    pub(crate) fn import_snapshot(snapshot: &Path, path: &Path) -> Result<bool> {
+        if path.exists() && fs::read_dir(path)?.next().is_some() {
+            info!(
+                "DB already exists at {}, skipping snapshot import",
+                path.display()
+            );
+            return Ok(false);
+        }
+        let metadata_path = snapshot.join(SNAPSHOT_METADATA_FILE);
+        let metadata: SnapshotMetadata = serde_json::from_slice(
+            &fs::read(&metadata_path)
+                .with_context(|| format!("failed to read {}", metadata_path.display()))?,
+        )
+        .context("invalid snapshot metadata")?;
+        if metadata.format != CURRENT_FORMAT {
+            bail!(
+                "unsupported snapshot format {} != {}",
+                metadata.format,
+                CURRENT_FORMAT
+            );
+        }
+        if metadata.hash_prefix_len != HASH_PREFIX_LEN {
+            bail!(
+                "unsupported snapshot hash prefix length {} != {}",
+                metadata.hash_prefix_len,
+                HASH_PREFIX_LEN
+            );
+        }
+        let name = path
+            .file_name()
+            .with_context(|| format!("invalid DB path: {}", path.display()))?;
+        let mut tmp_name = name.to_owned();
+        tmp_name.push(".importing");
+        let tmp_path = path.with_file_name(tmp_name);
+        if tmp_path.exists() {
+            // left by an interrupted import
+            fs::remove_dir_all(&tmp_path)
+                .with_context(|| format!("failed to remove {}", tmp_path.display()))?;
+        }
+        fs::create_dir_all(&tmp_path)?;
+        if let Err(e) = Self::copy_snapshot(snapshot, &tmp_path, &metadata) {
+            let _ = fs::remove_dir_all(&tmp_path);
+            return Err(e);
+        }
+        if path.exists() {
+            fs::remove_dir(path)?; // must be empty (checked above)
+        }
+        fs::rename(&tmp_path, path).with_context(|| {
+            format!(
+                "failed to rename {} to {}",
+                tmp_path.display(),
+                path.display()
+            )
+        })?;
+        info!(
+            "imported snapshot from {} (tip: {})",
+            snapshot.display(),
+            metadata.tip.as_deref().unwrap_or("none")
+        );
+        Ok(true)
+    }
+
+    fn copy_snapshot(snapshot: &Path, path: &Path, metadata: &SnapshotMetadata) -> Result<()> {
+        for entry in fs::read_dir(snapshot)? {
+            let entry = entry?;
+            if entry.file_name() != SNAPSHOT_METADATA_FILE {
+                fs::copy(entry.path(), path.join(entry.file_name()))
+                    .with_context(|| format!("failed to copy {}", entry.path().display()))?;
+            }
+        }
+        let imported = Self::open_read_only(path)?.snapshot_metadata();
+        if imported != *metadata {
+            bail!(
+                "imported snapshot {:?} doesn't match its metadata {:?}",
+                imported,
+                metadata
+            );
+        }
+        Ok(())
     }
 }
 
 impl Drop for DBStore {
     fn drop(&mut self) {
+        self.wait_for_compaction();
         info!("closing DB at {}", self.db.path().display());
     }
 }
 
+fn count_txid_rows(db: &rocksdb::DB) -> u64 {
+    let mut opts = rocksdb::ReadOptions::default();
+    opts.fill_cache(false);
+    let cf = db.cf_handle(TXID_CF).expect("missing TXID_CF");
+    db.iterator_cf_opt(cf, opts, rocksdb::IteratorMode::Start)
+        .count() as u64
+}
+
+fn compact(db: &rocksdb::DB) {
+    let start = Instant::now();
+    let size_before = total_sst_files_size(db);
+    for name in COLUMN_FAMILIES {
+        info!("starting {} compaction", name);
+        let cf = db.cf_handle(name).expect("missing CF");
+        db.compact_range_cf(cf, None::<&[u8]>, None::<&[u8]>);
+    }
+    let size_after = total_sst_files_size(db);
+    info!(
+        "finished full compaction in {:.3}s: {:.3} GB -> {:.3} GB ({:.3} GB reclaimed)",
+        start.elapsed().as_secs_f64(),
+        size_before as f64 / 1e9,
+        size_after as f64 / 1e9,
+        size_before.saturating_sub(size_after) as f64 / 1e9,
+    );
+}
+
+fn total_sst_files_size(db: &rocksdb::DB) -> u64 {
+    COLUMN_FAMILIES
+        .iter()
+        .filter_map(|name| cf_sst_files_size(db, name))
+        .sum()
+}
+
+fn cf_sst_files_size(db: &rocksdb::DB, name: &str) -> Option<u64> {
+    let cf = db.cf_handle(name).expect("missing CF");
+    db.property_int_value_cf(cf, "rocksdb.total-sst-files-size")
+        .expect("failed to get property")
+}
+
 #[cfg(test)]
 mod tests {
-    use super::{rocksdb, DBStore, WriteBatch, CURRENT_FORMAT};
+    use super::{
+        rocksdb, Config, DBStore, WriteBatch, CONFIG_KEY, CURRENT_FORMAT, HASH_PREFIX_LEN,
+    };
+    use crate::config::IndexMode;
 
     #[test]
     fn test_reindex_new_format() {
@@ -384,6 +992,80 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_reopen_current_format() {
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let store = DBStore::open(dir.path(), false).unwrap();
+            store.flush();
+        };
+        let store = DBStore::open(dir.path(), false).unwrap();
+        let config = store.get_config().unwrap();
+        assert_eq!(config.format, CURRENT_FORMAT);
+        assert_eq!(config.compacted, true); // DB was not re-indexed
+    }
+
+    #[test]
+    fn test_reindex_invalid_config() {
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let store = DBStore::open(dir.path(), false).unwrap();
+            store
+                .db
+                .put_cf(store.config_cf(), CONFIG_KEY, b"{\"format\": \"v2\"}")
+                .unwrap();
+        };
+        let err = DBStore::open(dir.path(), false).err().unwrap().to_string();
+        assert!(err.starts_with("re-index required due to invalid config: "));
+        {
+            let store = DBStore::open(dir.path(), true).unwrap();
+            let config = store.get_config().unwrap();
+            assert_eq!(config.format, CURRENT_FORMAT);
+        }
+    }
+
+    #[test]
+    fn test_reindex_hash_prefix_len() {
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let store = DBStore::open(dir.path(), false).unwrap();
+            let mut config = store.get_config().unwrap();
+            config.hash_prefix_len += 1;
+            store.set_config(config);
+        };
+        assert_eq!(
+            DBStore::open(dir.path(), false).err().unwrap().to_string(),
+            format!(
+                "re-index required due to unsupported hash prefix length {} != {}",
+                HASH_PREFIX_LEN + 1,
+                HASH_PREFIX_LEN
+            )
+        );
+        {
+            let store = DBStore::open(dir.path(), true).unwrap();
+            let config = store.get_config().unwrap();
+            assert_eq!(config.hash_prefix_len, HASH_PREFIX_LEN);
+        }
+    }
+
+    #[test]
+    fn test_index_mode_config() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = DBStore::open(dir.path(), false).unwrap();
+        assert_eq!(store.index_mode(), IndexMode::Full);
+        store.set_index_mode(IndexMode::FundingOnly);
+        assert_eq!(store.index_mode(), IndexMode::FundingOnly);
+
+        // stored by name (as in DBs created before the mode was stored as an enum)
+        let value = serde_json::to_value(store.get_config().unwrap()).unwrap();
+        assert_eq!(value["index_mode"], "funding-only");
+
+        // legacy DBs (without a stored mode) contain all rows
+        let legacy = r#"{"compacted": true, "format": 0}"#;
+        let config: Config = serde_json::from_str(legacy).unwrap();
+        assert_eq!(config.index_mode, IndexMode::Full);
+    }
+
     #[test]
     fn test_reindex_legacy_format() {
         let dir = tempfile::tempdir().unwrap();
@@ -405,6 +1087,62 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_compaction_threshold() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = DBStore::open(dir.path(), true).unwrap();
+        let mut batch = WriteBatch::default();
+        batch.header_rows = to_rows(&[b"a"]);
+
+        store.set_compaction(Some(2), false);
+        store.write(&batch);
+        store.write(&batch);
+        assert!(!store.compact_if_needed()); // initial compaction is not done yet
+        store.flush();
+
+        store.write(&batch);
+        assert!(!store.compact_if_needed());
+        store.write(&batch);
+        assert!(store.compact_if_needed());
+        assert!(!store.compact_if_needed());
+        store.wait_for_compaction();
+
+        store.set_compaction(None, false);
+        store.write(&batch);
+        store.write(&batch);
+        assert!(!store.compact_if_needed());
+
+        store.set_compaction(None, true);
+        assert!(store.compact_if_needed());
+        assert!(!store.compact_if_needed());
+        store.wait_for_compaction();
+        assert!(!store.compact_if_needed());
+    }
+
+    #[test]
+    fn test_find_missing() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = DBStore::open(dir.path(), true).unwrap();
+        let mut batch = WriteBatch::default();
+        batch.header_rows = to_rows(&[b"header"]);
+        batch.funding_rows = to_rows(&[b"funding1", b"funding2"]);
+        batch.spending_rows = to_rows(&[b"spending"]);
+        batch.filter_rows = vec![(to_row(b"key"), to_row(b"filter"))];
+        store.write(&batch);
+        assert_eq!(store.find_missing(&batch), vec![]);
+
+        // tamper with the stored rows
+        store.db.delete_cf(store.funding_cf(), b"funding2").unwrap();
+        store
+            .db
+            .put_cf(store.filters_cf(), b"key", b"other")
+            .unwrap();
+        assert_eq!(
+            store.find_missing(&batch),
+            vec![(super::FUNDING_CF, 1), (super::FILTERS_CF, 1)]
+        );
+    }
+
     #[test]
     fn test_db_prefix_scan() {
         let dir = tempfile::tempdir().unwrap();
@@ -422,17 +1160,216 @@ mod tests {
         ];
 
         let mut batch = WriteBatch::default();
-        batch.txid_rows = to_rows(&items);
+        batch.txid_rows = to_rows(items);
         store.write(&batch);
 
         let rows = store.iter_txid(b"abcdefgh".to_vec().into_boxed_slice());
         assert_eq!(rows.collect::<Vec<_>>(), to_rows(&items[1..5]));
     }
 
-    fn to_rows(values: &[&[u8]]) -> Vec<Box<[u8]>> {
-        values
+    #[test]
+    fn test_column_families() {
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let store = DBStore::open(dir.path(), true).unwrap();
+            let mut batch = WriteBatch::default();
+            batch.tip_row = to_row(b"tip");
+            batch.header_rows = to_rows(&[b"header1"]);
+            batch.txid_rows = to_rows(&[b"txid1234"]);
+            batch.funding_rows = to_rows(&[b"funding1"]);
+            batch.spending_rows = to_rows(&[b"spending"]);
+            batch.data_rows = to_rows(&[b"data1234"]);
+            batch.filter_rows = vec![(to_row(b"blockhash"), to_row(b"filter"))];
+            store.write(&batch);
+            store.flush();
+        }
+        let store = DBStore::open(dir.path(), false).unwrap();
+        assert_eq!(store.get_tip().as_deref(), Some(&b"tip"[..]));
+        assert_eq!(store.read_headers(), to_rows(&[b"header1"]));
+        assert_eq!(
+            store.iter_txid(to_row(b"txid1234")).collect::<Vec<_>>(),
+            to_rows(&[b"txid1234"])
+        );
+        assert_eq!(
+            store.iter_funding(to_row(b"funding1")).collect::<Vec<_>>(),
+            to_rows(&[b"funding1"])
+        );
+        assert_eq!(
+            store.iter_spending(to_row(b"spending")).collect::<Vec<_>>(),
+            to_rows(&[b"spending"])
+        );
+        assert_eq!(
+            store.iter_data(to_row(b"data1234")).collect::<Vec<_>>(),
+            to_rows(&[b"data1234"])
+        );
+        assert_eq!(
+            store.get_filter(b"blockhash").as_deref(),
+            Some(&b"filter"[..])
+        );
+        // rows are not shared between column families
+        assert_eq!(store.iter_txid(to_row(b"funding1")).count(), 0);
+        assert_eq!(store.get_filter(b"header1"), None);
+    }
+
+    #[test]
+    fn test_stats() {
+        let dir = tempfile::tempdir().unwrap();
+        let row = |prefix: &[u8; 8], height: u32| {
+            let mut row = prefix.to_vec();
+            row.extend_from_slice(&height.to_le_bytes());
+            row.into_boxed_slice()
+        };
+        {
+            let store = DBStore::open(dir.path(), true).unwrap();
+            let mut batch = WriteBatch::default();
+            batch.tip_row = to_row(b"tip");
+            batch.header_rows = to_rows(&[b"header1", b"header2", b"header3"]);
+            // the first two TXID rows' prefixes collide
+            batch.txid_rows = vec![
+                row(b"aaaaaaaa", 1),
+                row(b"aaaaaaaa", 2),
+                row(b"bbbbbbbb", 3),
+            ];
+            batch.spending_rows = vec![row(b"cccccccc", 2), row(b"dddddddd", 3)];
+            // the same scripthash is funded at different heights
+            batch.funding_rows = vec![
+                row(b"eeeeeeee", 1),
+                row(b"eeeeeeee", 2),
+                row(b"eeeeeeee", 3),
+                row(b"ffffffff", 3),
+            ];
+            store.write(&batch);
+            store.flush();
+        }
+        let store = DBStore::open_read_only(dir.path()).unwrap();
+        let stats = store.stats();
+        let counts: Vec<_> = stats
+            .column_families
             .iter()
-            .map(|v| v.to_vec().into_boxed_slice())
-            .collect()
+            .map(|cf| (cf.name, cf.rows, cf.repeated_prefixes, cf.collision_rate))
+            .collect();
+        assert_eq!(
+            counts,
+            vec![
+                (super::HEADERS_CF, 3, 0, None),
+                (super::TXID_CF, 3, 1, Some(1.0 / 3.0)),
+                (super::SPENDING_CF, 2, 0, Some(0.0)),
+                (super::FUNDING_CF, 4, 2, None),
+                (super::FILTERS_CF, 0, 0, None),
+                (super::DATA_CF, 0, 0, None),
+            ]
+        );
+        assert_eq!(stats.heights, Some((1, 3)));
+        assert!(stats.size >= stats.column_families.iter().map(|cf| cf.size).sum::<u64>());
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let dir = tempfile::tempdir().unwrap();
+        let (source, snapshot, target) = (
+            dir.path().join("source"),
+            dir.path().join("snapshot"),
+            dir.path().join("target"),
+        );
+        {
+            let store = DBStore::open(&source, true).unwrap();
+            let mut batch = WriteBatch::default();
+            batch.tip_row = to_row(b"tip");
+            batch.header_rows = to_rows(&[b"header1", b"header2"]);
+            batch.txid_rows = to_rows(&[b"txid1234"]);
+            batch.funding_rows = to_rows(&[b"funding1", b"funding1x", b"funding2"]);
+            batch.filter_rows = vec![(to_row(b"blockhash"), to_row(b"filter"))];
+            store.write(&batch); // not flushed yet (and not logged during bulk import)
+            store.export_snapshot(&snapshot).unwrap();
+        }
+        assert!(DBStore::import_snapshot(&snapshot, &target).unwrap());
+        assert!(!dir.path().join("target.importing").exists());
+
+        let (source, target) = (
+            DBStore::open(&source, false).unwrap(),
+            DBStore::open(&target, false).unwrap(),
+        );
+        assert_eq!(target.get_tip().as_deref(), Some(&b"tip"[..]));
+        assert_eq!(target.read_headers(), source.read_headers());
+        assert_eq!(
+            target.iter_funding(to_row(b"funding1")).collect::<Vec<_>>(),
+            source.iter_funding(to_row(b"funding1")).collect::<Vec<_>>(),
+        );
+        assert_eq!(
+            target.iter_txid(to_row(b"txid1234")).collect::<Vec<_>>(),
+            to_rows(&[b"txid1234"])
+        );
+        assert_eq!(
+            target.get_filter(b"blockhash"),
+            source.get_filter(b"blockhash")
+        );
+        assert_eq!(target.snapshot_metadata(), source.snapshot_metadata());
+
+        // an existing DB is not overwritten
+        assert!(!DBStore::import_snapshot(&snapshot, target.db.path()).unwrap());
+    }
+
+    #[test]
+    fn test_snapshot_unsupported_format() {
+        let dir = tempfile::tempdir().unwrap();
+        let snapshot = dir.path().join("snapshot");
+        {
+            let store = DBStore::open(&dir.path().join("source"), true).unwrap();
+            let mut config = store.get_config().unwrap();
+            config.format += 1;
+            store.set_config(config);
+            store.export_snapshot(&snapshot).unwrap();
+        }
+        let target = dir.path().join("target");
+        assert_eq!(
+            DBStore::import_snapshot(&snapshot, &target)
+                .unwrap_err()
+                .to_string(),
+            format!(
+                "unsupported snapshot format {} != {}",
+                CURRENT_FORMAT + 1,
+                CURRENT_FORMAT
+            )
+        );
+        assert!(!target.exists()); // nothing was imported
+        assert!(!dir.path().join("target.importing").exists());
+    }
+
+    #[test]
+    fn test_backup() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = DBStore::open(&dir.path().join("db"), true).unwrap();
+        let mut batch = WriteBatch::default();
+        batch.tip_row = to_row(b"tip");
+        batch.header_rows = to_rows(&[b"header1"]);
+        store.write(&batch);
+
+        let backup_dir = dir.path().join("backups");
+        let path = store.backup(&backup_dir).unwrap();
+        assert!(path.starts_with(&backup_dir));
+
+        let backup = DBStore::open_read_only(&path).unwrap();
+        assert_eq!(backup.get_tip().as_deref(), Some(&b"tip"[..]));
+        assert_eq!(backup.read_headers(), to_rows(&[b"header1"]));
+        assert_eq!(backup.snapshot_metadata(), store.snapshot_metadata());
+
+        // consecutive backups don't collide
+        let other = store.backup(&backup_dir).unwrap();
+        assert_ne!(other, path);
+        assert!(DBStore::open_read_only(&other).is_ok());
+    }
+
+    #[test]
+    fn test_open_read_only_missing() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(DBStore::open_read_only(dir.path()).is_err());
+    }
+
+    fn to_rows<T: AsRef<[u8]>>(values: &[T]) -> Vec<Box<[u8]>> {
+        values.iter().map(to_row).collect()
+    }
+
+    fn to_row(value: impl AsRef<[u8]>) -> Box<[u8]> {
+        value.as_ref().to_vec().into_boxed_slice()
     }
 }
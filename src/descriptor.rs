@@ -0,0 +1,248 @@
+use anyhow::{Context, Result};
+use bitcoin::{
+    hashes::{hash160, Hash},
+    secp256k1::Secp256k1,
+    util::bip32::{ChildNumber, ExtendedPubKey},
+    PubkeyHash, Script, WPubkeyHash,
+};
+
+use std::ops::Range;
+use std::str::FromStr;
+
+use crate::types::ScriptHash;
+
+// see https://github.com/bitcoin/bips/blob/master/bip-0380.mediawiki#checksum
+const INPUT_CHARSET: &str = "0123456789()[],'/*abcdefgh@:$%{}IJKLMNOPQRSTUVWXYZ&+-.;<=>?!^_|~ijklmnopqrstuvwxyzABCDEFGH`#\"\\ ";
+const CHECKSUM_CHARSET: &[u8] = b"qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Kind {
+    Pkh,
+    Wpkh,
+    ShWpkh,
+}
+
+/// A ranged output descriptor, having a single extended public key ending with a wildcard,
+/// e.g. `wpkh([d34db33f/84h/0h/0h]xpub.../0/*)`.
+///
+/// Only `pkh(KEY)`, `wpkh(KEY)` and `sh(wpkh(KEY))` are supported (and the optional checksum
+/// is verified). The key origin is ignored, since derivation starts from the given xpub.
+#[derive(Debug)]
+pub(crate) struct Descriptor {
+    kind: Kind,
+    xpub: ExtendedPubKey,
+    path: Vec<ChildNumber>, // unhardened derivation steps, before the wildcard
+}
+
+impl Descriptor {
+    /// Derive the scripthashes of the given child indices' output scripts.
+    pub(crate) fn derive_scripthashes(&self, range: Range<u32>) -> Result<Vec<ScriptHash>> {
+        let secp = Secp256k1::verification_only();
+        let xpub = self
+            .xpub
+            .derive_pub(&secp, &self.path)
+            .context("failed to derive xpub")?;
+        range
+            .map(|index| {
+                let child = ChildNumber::from_normal_idx(index)
+                    .with_context(|| format!("invalid child index {}", index))?;
+                let pubkey = xpub
+                    .derive_pub(&secp, &[child])
+                    .with_context(|| format!("failed to derive child {}", index))?
+                    .public_key
+                    .to_bytes();
+                Ok(ScriptHash::new(&self.script(&pubkey)))
+            })
+            .collect()
+    }
+
+    fn script(&self, pubkey: &[u8]) -> Script {
+        match self.kind {
+            Kind::Pkh => Script::new_p2pkh(&PubkeyHash::from_hash(hash160::Hash::hash(pubkey))),
+            Kind::Wpkh => Script::new_v0_wpkh(&WPubkeyHash::from_hash(hash160::Hash::hash(pubkey))),
+            Kind::ShWpkh => {
+                let redeem_script =
+                    Script::new_v0_wpkh(&WPubkeyHash::from_hash(hash160::Hash::hash(pubkey)));
+                Script::new_p2sh(&bitcoin::ScriptHash::hash(redeem_script.as_bytes()))
+            }
+        }
+    }
+}
+
+impl FromStr for Descriptor {
+    type Err = anyhow::Error;
+
+    fn from_str(string: &str) -> Result<Self> {
+        let desc = match string.rfind('#') {
+            Some(pos) => {
+                let (desc, expected) = (&string[..pos], &string[pos + 1..]);
+                let actual = checksum(desc).context("invalid descriptor character")?;
+                if actual != expected {
+                    bail!(
+                        "invalid descriptor checksum {} (expected {})",
+                        expected,
+                        actual
+                    );
+                }
+                desc
+            }
+            None => string,
+        };
+        let (kind, key) = if let Some(inner) = unwrap_fn(desc, "sh") {
+            let key = unwrap_fn(inner, "wpkh").context("only sh(wpkh(...)) is supported")?;
+            (Kind::ShWpkh, key)
+        } else if let Some(key) = unwrap_fn(desc, "wpkh") {
+            (Kind::Wpkh, key)
+        } else if let Some(key) = unwrap_fn(desc, "pkh") {
+            (Kind::Pkh, key)
+        } else {
+            bail!("unsupported descriptor: {}", desc);
+        };
+
+        let key = if key.starts_with('[') {
+            let end = key.find(']').context("unterminated key origin")?;
+            &key[end + 1..]
+        } else {
+            key
+        };
+        let mut parts = key.split('/');
+        let xpub = parts.next().unwrap_or_default();
+        let xpub = ExtendedPubKey::from_str(xpub)
+            .with_context(|| format!("invalid extended public key: {}", xpub))?;
+        let steps: Vec<&str> = parts.collect();
+        let path = match steps.split_last() {
+            Some((&"*", path)) => path
+                .iter()
+                .map(|step| {
+                    step.parse()
+                        .ok()
+                        .and_then(|index| ChildNumber::from_normal_idx(index).ok())
+                        .with_context(|| format!("invalid unhardened derivation step: {}", step))
+                })
+                .collect::<Result<Vec<ChildNumber>>>()?,
+            _ => bail!("descriptor key must end with an unhardened wildcard ('/*')"),
+        };
+        Ok(Descriptor { kind, xpub, path })
+    }
+}
+
+// "func(inner)" -> "inner"
+fn unwrap_fn<'a>(desc: &'a str, func: &str) -> Option<&'a str> {
+    desc.strip_prefix(func)?
+        .strip_prefix('(')?
+        .strip_suffix(')')
+}
+
+fn polymod(c: u64, val: u64) -> u64 {
+    const GENERATORS: [u64; 5] = [
+        0xf5dee51989,
+        0xa9fdca3312,
+        0x1bab10e32d,
+        0x3706b1677a,
+        0x644d626ffd,
+    ];
+    let c0 = c >> 35;
+    let mut c = ((c & 0x7ffffffff) << 5) ^ val;
+    for (i, generator) in GENERATORS.iter().enumerate() {
+        if (c0 >> i) & 1 != 0 {
+            c ^= generator;
+        }
+    }
+    c
+}
+
+/// Compute the descriptor checksum (returning `None` on invalid characters).
+fn checksum(desc: &str) -> Option<String> {
+    let mut c = 1;
+    let mut cls = 0;
+    let mut cls_count = 0;
+    for ch in desc.chars() {
+        let pos = INPUT_CHARSET.find(ch)? as u64;
+        c = polymod(c, pos & 31);
+        cls = cls * 3 + (pos >> 5);
+        cls_count += 1;
+        if cls_count == 3 {
+            c = polymod(c, cls);
+            cls = 0;
+            cls_count = 0;
+        }
+    }
+    if cls_count > 0 {
+        c = polymod(c, cls);
+    }
+    for _ in 0..8 {
+        c = polymod(c, 0);
+    }
+    c ^= 1;
+    Some(
+        (0..8)
+            .map(|j| char::from(CHECKSUM_CHARSET[((c >> (5 * (7 - j))) & 31) as usize]))
+            .collect(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{checksum, Descriptor};
+    use crate::tests::{coinbase, make_blocks, test_index, txout};
+    use crate::types::ScriptHash;
+    use bitcoin::{Address, BlockHash};
+
+    use std::str::FromStr;
+
+    // BIP-84 test vector: account 0 of "abandon abandon ... about" (as an xpub)
+    const XPUB: &str = "xpub6CatWdiZiodmUeTDp8LT5or8nmbKNcuyvz7WyksVFkKB4RHwCD3XyuvPEbvqAQY3rAPshWcMLoP2fMFMKHPJ4ZeZXYVUhLv1VMrjPC7PW6V";
+
+    fn address_scripthash(address: &str) -> ScriptHash {
+        ScriptHash::new(&Address::from_str(address).unwrap().script_pubkey())
+    }
+
+    #[test]
+    fn test_checksum() {
+        assert_eq!(checksum("raw(deadbeef)").unwrap(), "89f8spxm");
+        assert_eq!(checksum("raw(deadbeef)\u{e9}"), None);
+    }
+
+    #[test]
+    fn test_descriptor_scan() {
+        let desc = format!("wpkh([73c5da0a/84h/0h/0h]{}/0/*)#afwvtk2s", XPUB);
+        let scripthashes = Descriptor::from_str(&desc)
+            .unwrap()
+            .derive_scripthashes(0..2)
+            .unwrap();
+        assert_eq!(
+            scripthashes,
+            vec![
+                address_scripthash("bc1qcr8te4kr609gcawutmrza0j4xv80jy8z306fyu"),
+                address_scripthash("bc1qnjg0jd8228aq7egyzacy8cys3knf9xvrerkf9g"),
+            ]
+        );
+
+        // only the 2nd derived script is funded
+        let funding = Address::from_str("bc1qnjg0jd8228aq7egyzacy8cys3knf9xvrerkf9g").unwrap();
+        let blocks = make_blocks(vec![vec![coinbase(
+            1,
+            vec![txout(100, funding.script_pubkey())],
+        )]]);
+        let test = test_index(blocks.clone());
+        let found: Vec<Vec<BlockHash>> = scripthashes
+            .iter()
+            .map(|scripthash| test.index.filter_by_funding(*scripthash).collect())
+            .collect();
+        assert_eq!(found, vec![vec![], vec![blocks[0].block_hash()]]);
+    }
+
+    #[test]
+    fn test_invalid_descriptor() {
+        let invalid = |desc: String| Descriptor::from_str(&desc).is_err();
+        assert!(invalid(format!("wpkh({}/0/*)#afwvtk2s", XPUB))); // bad checksum
+        assert!(invalid(format!("wpkh({}/0/0)", XPUB))); // not ranged
+        assert!(invalid(format!("wpkh({}/0h/*)", XPUB))); // hardened step
+        assert!(invalid(format!("tr({}/0/*)", XPUB))); // unsupported
+        assert!(invalid(format!("sh(pkh({}/0/*))", XPUB))); // unsupported
+        assert!(invalid("wpkh(xpub/0/*)".to_owned()));
+
+        assert!(!invalid(format!("pkh({}/0/*)", XPUB)));
+        assert!(!invalid(format!("sh(wpkh({}/1/*))", XPUB)));
+    }
+}
@@ -2,31 +2,54 @@ use anyhow::{bail, Context, Result};
 use bitcoin::{
     consensus::{deserialize, serialize},
     hashes::hex::{FromHex, ToHex},
-    BlockHash, Txid,
+    util::address::Payload,
+    Address, BlockHash, Network, OutPoint, Script, TxOut, Txid,
 };
 use crossbeam_channel::Receiver;
 use rayon::prelude::*;
+use serde::ser::{Serialize, SerializeMap, Serializer};
 use serde_derive::Deserialize;
 use serde_json::{self, json, Value};
 
-use std::collections::{hash_map::Entry, HashMap};
-use std::iter::FromIterator;
+use std::collections::{hash_map::Entry, HashMap, HashSet};
+use std::convert::TryFrom;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::iter::{once, FromIterator};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use crate::{
     cache::Cache,
-    config::{Config, ELECTRS_VERSION},
+    chain::Chain,
+    config::{Config, Export, ProtocolVersion, ELECTRS_VERSION_RICH},
     daemon::{self, extract_bitcoind_error, Daemon},
+    descriptor::Descriptor,
+    errors::QueryError,
     merkle::Proof,
-    metrics::{self, Histogram, Metrics},
+    metrics::{self, Gauge, Histogram, Metrics},
     signals::Signal,
-    status::ScriptHashStatus,
-    tracker::Tracker,
-    types::ScriptHash,
+    status::{write_history_deltas, ScriptHashStatus},
+    thread::build_pool,
+    tracker::{self, ChainStats, Tracker},
+    types::{FilterRow, ScriptHash, HASH_PREFIX_LEN},
 };
 
-const PROTOCOL_VERSION: &str = "1.4";
 const UNKNOWN_FEE: isize = -1; // (allowed by Electrum protocol)
 
+// `blockchain.block.get_txid_prefixes` scans all the txid rows (whatever the range's size),
+// so the range is limited only to bound the response's size
+const MAX_TXID_PREFIXES_BLOCKS: usize = 100;
+
+// `blockchain.descriptor.scan` derives (and syncs) the scripthashes, so their number is always limited
+const MAX_DESCRIPTOR_SCAN_COUNT: usize = 1000;
+
+// each history entry is serialized into at least `{"height":0,"tx_hash":"<64 hex digits>"}`
+const MIN_HISTORY_ENTRY_SIZE: usize = 89;
+
 const UNSUBSCRIBED_QUERY_MESSAGE: &str = "your wallet uses less efficient method of querying electrs, consider contacting the developer of your wallet. Reason:";
 
 /// Per-client Electrum protocol state
@@ -34,6 +57,168 @@ const UNSUBSCRIBED_QUERY_MESSAGE: &str = "your wallet uses less efficient method
 pub struct Client {
     tip: Option<BlockHash>,
     scripthashes: HashMap<ScriptHash, ScriptHashStatus>,
+    pending: HashMap<ScriptHash, Instant>, // first unnotified status change (when coalescing)
+    disconnect: bool,                      // e.g. due to an unsupported protocol version
+    guard: Option<ClientGuard>,            // released when the client is dropped
+    batch_notifications: bool,             // see `server.batch_notifications`
+}
+
+/// Counts the connected clients. Released on drop, so a client's state is accounted for
+/// deterministically - including when it is dropped due to an error or during a panic.
+struct ClientGuard {
+    connected: Arc<AtomicUsize>,
+    gauge: Gauge,
+}
+
+impl ClientGuard {
+    fn new(connected: &Arc<AtomicUsize>, gauge: &Gauge) -> Self {
+        let count = connected.fetch_add(1, Ordering::SeqCst) + 1;
+        gauge.set("connected", count as f64);
+        Self {
+            connected: Arc::clone(connected),
+            gauge: gauge.clone(),
+        }
+    }
+}
+
+impl Drop for ClientGuard {
+    fn drop(&mut self) {
+        let count = self.connected.fetch_sub(1, Ordering::SeqCst) - 1;
+        self.gauge.set("connected", count as f64);
+    }
+}
+
+impl Client {
+    /// Number of new subscriptions allowed before reaching the specified limit.
+    fn allowed_subscriptions(&self, limit: Option<usize>) -> usize {
+        match limit {
+            Some(limit) => limit.saturating_sub(self.scripthashes.len()),
+            None => usize::MAX,
+        }
+    }
+
+    pub(crate) fn subscriptions(&self) -> usize {
+        self.scripthashes.len()
+    }
+
+    /// Scripthashes whose status should be synced for subscribing (in request order).
+    /// Existing subscriptions (e.g. re-subscribed after a reconnect) use their current status,
+    /// and new subscriptions beyond the specified limit are rejected.
+    fn new_subscriptions(
+        &self,
+        scripthashes: &[ScriptHash],
+        limit: Option<usize>,
+    ) -> Vec<ScriptHash> {
+        let mut seen = HashSet::new();
+        scripthashes
+            .iter()
+            .copied()
+            .filter(|scripthash| !self.scripthashes.contains_key(scripthash))
+            .filter(|scripthash| seen.insert(*scripthash))
+            .take(self.allowed_subscriptions(limit))
+            .collect()
+    }
+
+    /// Subscribe to the specified scripthashes, using `sync` (in parallel) for the new ones.
+    /// Return the statushashes in request order (or an error for rejected subscriptions).
+    fn subscribe<'a, F>(
+        &'a mut self,
+        scripthashes: &'a [ScriptHash],
+        limit: Option<usize>,
+        sync: F,
+    ) -> impl Iterator<Item = Result<Value>> + 'a
+    where
+        F: Fn(ScriptHash) -> Result<ScriptHashStatus> + Send + Sync,
+    {
+        let new_scripthashes = self.new_subscriptions(scripthashes, limit);
+        let mut results: HashMap<ScriptHash, Result<ScriptHashStatus>> = new_scripthashes
+            .into_par_iter()
+            .map(|scripthash| (scripthash, sync(scripthash)))
+            .collect();
+
+        scripthashes.iter().map(move |scripthash| {
+            let statushash = match self.scripthashes.entry(*scripthash) {
+                Entry::Occupied(e) => e.get().statushash(),
+                Entry::Vacant(e) => {
+                    let status = match results.remove(scripthash) {
+                        Some(result) => result?, // return an error for failed subscriptions
+                        None => bail!("too many subscriptions (limit: {})", limit.unwrap()),
+                    };
+                    e.insert(status).statushash()
+                }
+            };
+            Ok(json!(statushash))
+        })
+    }
+
+    /// The client should be disconnected (after sending the pending responses).
+    pub(crate) fn should_disconnect(&self) -> bool {
+        self.disconnect
+    }
+
+    /// Return the scripthashes to be notified now, delaying each status change by `window`
+    /// (so that a burst of changes results in a single notification).
+    fn coalesce(
+        &mut self,
+        changed: Vec<ScriptHash>,
+        window: Option<Duration>,
+        now: Instant,
+    ) -> Vec<ScriptHash> {
+        let window = match window {
+            Some(window) => window,
+            None => return changed,
+        };
+        for scripthash in changed {
+            self.pending.entry(scripthash).or_insert(now);
+        }
+        let ready: Vec<ScriptHash> = self
+            .pending
+            .iter()
+            .filter(|(_, since)| now.saturating_duration_since(**since) >= window)
+            .map(|(scripthash, _)| *scripthash)
+            .collect();
+        for scripthash in &ready {
+            self.pending.remove(scripthash);
+        }
+        ready
+    }
+
+    /// Notify the changed scripthashes' statuses - by a single notification if requested
+    /// by the client, otherwise by a standard notification per scripthash.
+    fn status_notifications(&self, changed: Vec<ScriptHash>) -> Vec<Value> {
+        let statuses: Vec<(ScriptHash, Value)> = changed
+            .into_iter()
+            .filter_map(|scripthash| {
+                let status = self.scripthashes.get(&scripthash)?; // may be unsubscribed
+                Some((scripthash, json!(status.statushash())))
+            })
+            .collect();
+        if !self.batch_notifications {
+            return statuses
+                .into_iter()
+                .map(|(scripthash, statushash)| {
+                    notification(
+                        "blockchain.scripthash.subscribe",
+                        &[json!(scripthash), statushash],
+                    )
+                })
+                .collect();
+        }
+        if statuses.is_empty() {
+            return vec![];
+        }
+        let pairs: Vec<Value> = statuses
+            .into_iter()
+            .map(|(scripthash, statushash)| json!([scripthash, statushash]))
+            .collect();
+        vec![notification("blockchain.scripthashes.status", &pairs)]
+    }
+
+    /// When the next coalesced notification is due (if any).
+    fn next_notification(&self, window: Option<Duration>) -> Option<Instant> {
+        let window = window?;
+        self.pending.values().min().map(|since| *since + window)
+    }
 }
 
 #[derive(Deserialize)]
@@ -66,6 +251,80 @@ enum TxGetArgs {
     TxidVerbose(Txid, bool),
 }
 
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum TxGetMerkleArgs {
+    Txid((Txid,)),
+    TxidHeight(Txid, usize),
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum GetBalanceArgs {
+    ScriptHash((ScriptHash,)),
+    ScriptHashMinConf(ScriptHash, usize),
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ListUnspentArgs {
+    ScriptHash((ScriptHash,)),
+    ScriptHashVerbose(ScriptHash, bool),
+    ScriptHashVerboseMinConf(ScriptHash, bool, usize),
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ListUnspentAtArgs {
+    ScriptHashHeight(ScriptHash, usize),
+    ScriptHashHeightMinHeight(ScriptHash, usize, usize),
+}
+
+impl From<&GetBalanceArgs> for (ScriptHash, Option<usize>) {
+    fn from(args: &GetBalanceArgs) -> Self {
+        match args {
+            GetBalanceArgs::ScriptHash((scripthash,)) => (*scripthash, None),
+            GetBalanceArgs::ScriptHashMinConf(scripthash, min_conf) => {
+                (*scripthash, Some(*min_conf))
+            }
+        }
+    }
+}
+
+impl From<&ListUnspentArgs> for (ScriptHash, bool, Option<usize>) {
+    fn from(args: &ListUnspentArgs) -> Self {
+        match args {
+            ListUnspentArgs::ScriptHash((scripthash,)) => (*scripthash, false, None),
+            ListUnspentArgs::ScriptHashVerbose(scripthash, verbose) => {
+                (*scripthash, *verbose, None)
+            }
+            ListUnspentArgs::ScriptHashVerboseMinConf(scripthash, verbose, min_conf) => {
+                (*scripthash, *verbose, Some(*min_conf))
+            }
+        }
+    }
+}
+
+impl From<&ListUnspentAtArgs> for (ScriptHash, usize, usize) {
+    fn from(args: &ListUnspentAtArgs) -> Self {
+        match args {
+            ListUnspentAtArgs::ScriptHashHeight(scripthash, height) => (*scripthash, *height, 0),
+            ListUnspentAtArgs::ScriptHashHeightMinHeight(scripthash, height, min_height) => {
+                (*scripthash, *height, *min_height)
+            }
+        }
+    }
+}
+
+impl From<&TxGetMerkleArgs> for (Txid, Option<usize>) {
+    fn from(args: &TxGetMerkleArgs) -> Self {
+        match args {
+            TxGetMerkleArgs::Txid((txid,)) => (*txid, None),
+            TxGetMerkleArgs::TxidHeight(txid, height) => (*txid, Some(*height)),
+        }
+    }
+}
+
 impl From<&TxGetArgs> for (Txid, bool) {
     fn from(args: &TxGetArgs) -> Self {
         match args {
@@ -75,6 +334,7 @@ impl From<&TxGetArgs> for (Txid, bool) {
     }
 }
 
+#[derive(Debug)]
 enum StandardError {
     ParseError,
     InvalidRequest,
@@ -88,7 +348,11 @@ enum RpcError {
     // Electrum-specific errors
     BadRequest(anyhow::Error),
     DaemonError(daemon::RpcError),
+    Query(QueryError),
     UnavailableIndex,
+    Syncing(usize),
+    ResponseTooLarge(usize),
+    MethodDisabled,
 }
 
 impl RpcError {
@@ -108,10 +372,99 @@ impl RpcError {
             },
             RpcError::BadRequest(err) => json!({"code": 1, "message": err.to_string()}),
             RpcError::DaemonError(err) => json!({"code": 2, "message": err.message}),
+            // same codes as ElectrumX: a missing transaction is reported by bitcoind
+            // (i.e. as a daemon error), and a too large history as a bad request
+            RpcError::Query(err) => match err {
+                QueryError::TxNotFound(_) | QueryError::PrunedBlock(_) => {
+                    json!({"code": 2, "message": err.to_string()})
+                }
+                QueryError::HistoryTooLarge(_) | QueryError::HistoryTooLong(_) => {
+                    json!({"code": 1, "message": err.to_string()})
+                }
+                // same as `RpcError::ResponseTooLarge`
+                QueryError::ResponseTooLarge(_) => {
+                    json!({"code": -32603, "message": err.to_string()})
+                }
+            },
             RpcError::UnavailableIndex => {
                 // Internal JSON-RPC error (https://www.jsonrpc.org/specification#error_object)
                 json!({"code": -32603, "message": "unavailable index"})
             }
+            RpcError::Syncing(lag) => json!({
+                "code": -32603,
+                "message": format!("server is syncing ({} blocks behind)", lag)
+            }),
+            RpcError::ResponseTooLarge(limit) => json!({
+                "code": -32603,
+                "message": format!("response too large (limit: {} bytes)", limit)
+            }),
+            RpcError::MethodDisabled => json!({"code": -32601, "message": "method disabled"}),
+        }
+    }
+}
+
+/// A JSON-RPC response (or a batch of them), possibly sharing its result with a cache.
+pub enum Reply {
+    Value(Value),
+    Shared { id: Value, result: Arc<Value> }, // serialized without copying the result
+    Batch(Vec<Reply>),
+}
+
+impl Reply {
+    fn id(&self) -> &Value {
+        match self {
+            Reply::Value(value) => &value["id"],
+            Reply::Shared { id, .. } => id,
+            Reply::Batch(_) => &Value::Null,
+        }
+    }
+}
+
+impl Serialize for Reply {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        match self {
+            Reply::Value(value) => value.serialize(serializer),
+            Reply::Shared { id, result } => {
+                // same keys order as `result_msg()`
+                let mut map = serializer.serialize_map(Some(3))?;
+                map.serialize_entry("id", id)?;
+                map.serialize_entry("jsonrpc", "2.0")?;
+                map.serialize_entry("result", result.as_ref())?;
+                map.end()
+            }
+            Reply::Batch(replies) => serializer.collect_seq(replies),
+        }
+    }
+}
+
+/// A single line sent to an Electrum client (a response or a notification)
+pub enum Response {
+    Serialized(String), // e.g. after checking its size
+    Streamed(Reply),    // serialized while being written, so the whole line is not buffered
+}
+
+impl Response {
+    /// Both variants result in exactly the same bytes (compact JSON, terminated by a newline).
+    pub(crate) fn write_line(&self, mut out: impl Write) -> io::Result<()> {
+        match self {
+            Response::Serialized(line) => out.write_all(line.as_bytes())?,
+            Response::Streamed(value) => serde_json::to_writer(&mut out, value)?,
+        }
+        out.write_all(b"\n")
+    }
+}
+
+impl fmt::Display for Response {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Response::Serialized(line) => write!(f, "{}", line),
+            Response::Streamed(reply) => {
+                let line = serde_json::to_string(reply).map_err(|_| fmt::Error)?;
+                write!(f, "{}", line)
+            }
         }
     }
 }
@@ -121,10 +474,25 @@ pub struct Rpc {
     tracker: Tracker,
     cache: Cache,
     rpc_duration: Histogram,
+    subscriptions: Gauge,
+    clients: Gauge,
+    connected: Arc<AtomicUsize>,
+    index_pool: rayon::ThreadPool, // used for indexing and mempool syncing
+    query_pool: rayon::ThreadPool, // used for Electrum queries and notifications
     daemon: Daemon,
     signal: Signal,
     banner: String,
     port: u16,
+    network: Network,
+    disabled_methods: HashSet<String>,
+    max_subscriptions: Option<usize>,
+    max_batch_scripthashes: Option<usize>,
+    min_confirmations: usize,
+    chain_stats: Option<ChainStats>, // see `--chain-stats`
+    max_response_size: Option<usize>,
+    notify_coalesce: Option<Duration>,
+    protocol_min: ProtocolVersion,
+    protocol_max: ProtocolVersion,
 }
 
 impl Rpc {
@@ -136,19 +504,57 @@ impl Rpc {
             "method",
             metrics::default_duration_buckets(),
         );
+        let subscriptions = metrics.gauge(
+            "electrum_subscriptions",
+            "# of active Electrum subscriptions (across all clients)",
+            "type",
+        );
+        let clients = metrics.gauge("electrum_clients", "# of Electrum clients", "state");
 
-        let tracker = Tracker::new(config, metrics)?;
         let signal = Signal::new();
-        let daemon = Daemon::connect(config, signal.exit_flag(), tracker.metrics())?;
+        let daemon = Daemon::connect(config, signal.exit_flag(), &metrics)?;
+        let genesis = match config.genesis_hash {
+            Some(hash) => {
+                let header = daemon
+                    .get_block_header(hash)
+                    .with_context(|| format!("failed to get custom genesis block {}", hash))?;
+                if header.prev_blockhash != BlockHash::default() {
+                    bail!("custom genesis block {} has a parent", hash);
+                }
+                Some(header)
+            }
+            None => None,
+        };
+        let tracker = Tracker::new(config, metrics, genesis)?;
         let cache = Cache::new(tracker.metrics());
+        let chain_stats = if config.chain_stats {
+            Some(tracker.start_chain_stats(&daemon))
+        } else {
+            None
+        };
         Ok(Self {
             tracker,
             cache,
             rpc_duration,
+            subscriptions,
+            clients,
+            connected: Arc::new(AtomicUsize::new(0)),
+            index_pool: build_pool("index", config.index_threads)?,
+            query_pool: build_pool("query", config.query_threads)?,
             daemon,
             signal,
             banner: config.server_banner.clone(),
             port: config.electrum_rpc_addr.port(),
+            network: config.network,
+            disabled_methods: config.disabled_methods.clone(),
+            max_subscriptions: config.max_subscriptions_per_connection,
+            max_batch_scripthashes: config.max_batch_scripthashes,
+            min_confirmations: config.min_confirmations,
+            chain_stats,
+            max_response_size: config.max_response_size,
+            notify_coalesce: config.notify_coalesce,
+            protocol_min: config.protocol_min.clone(),
+            protocol_max: config.protocol_max.clone(),
         })
     }
 
@@ -160,31 +566,125 @@ impl Rpc {
         self.daemon.new_block_notification()
     }
 
-    pub fn sync(&mut self) -> Result<bool> {
-        self.tracker.sync(&self.daemon, self.signal.exit_flag())
+    pub(crate) fn new_txs_notification(&self) -> Receiver<Vec<Txid>> {
+        self.daemon.new_txs_notification()
+    }
+
+    pub(crate) fn add_mempool_txids(&mut self, txids: Vec<Txid>) {
+        let (tracker, daemon) = (&mut self.tracker, &self.daemon);
+        self.index_pool
+            .install(|| tracker.add_mempool_txids(daemon, txids))
+    }
+
+    pub(crate) fn warmup_mempool(&mut self, timeout: Duration) {
+        let (tracker, daemon) = (&mut self.tracker, &self.daemon);
+        self.index_pool
+            .install(|| tracker.warmup_mempool(daemon, timeout))
+    }
+
+    pub fn sync(&mut self, poll_mempool: bool) -> Result<bool> {
+        let (tracker, daemon, cache, exit_flag, chain_stats) = (
+            &mut self.tracker,
+            &self.daemon,
+            &self.cache,
+            self.signal.exit_flag(),
+            &mut self.chain_stats,
+        );
+        self.index_pool.install(|| {
+            let done = tracker.sync(daemon, exit_flag, poll_mempool)?;
+            if done {
+                if let Some(chain_stats) = chain_stats {
+                    tracker.refresh_chain_stats(chain_stats);
+                }
+                tracker.sync_watched(daemon, cache);
+            }
+            Ok(done)
+        })
+    }
+
+    /// Re-sync the recently queried statuses (after the peers are notified about a new block).
+    pub(crate) fn prime(&self) {
+        let (tracker, daemon, cache) = (&self.tracker, &self.daemon, &self.cache);
+        self.index_pool
+            .install(|| tracker.sync_primed(daemon, cache))
+    }
+
+    pub fn verify_index(&self) -> Result<()> {
+        self.tracker
+            .verify_index(&self.daemon, self.signal.exit_flag())
+    }
+
+    pub fn compact_if_needed(&self) {
+        self.tracker.compact_if_needed()
     }
 
-    pub fn update_client(&self, client: &mut Client) -> Result<Vec<String>> {
+    /// Export the confirmed history of a scripthash (using the already indexed blocks).
+    pub(crate) fn export_history(&self, export: &Export) -> Result<()> {
+        let mut status = ScriptHashStatus::new(export.scripthash);
+        self.tracker
+            .update_scripthash_status(&mut status, &self.daemon, &self.cache)?;
+        let deltas = self.tracker.get_history_deltas(&status);
+        info!(
+            "exporting {} transactions of {}",
+            deltas.len(),
+            export.scripthash
+        );
+        match &export.file {
+            Some(path) => {
+                let file = File::create(path)
+                    .with_context(|| format!("failed to create {}", path.display()))?;
+                write_history_deltas(&deltas, export.format, BufWriter::new(file))
+            }
+            None => {
+                let stdout = io::stdout();
+                write_history_deltas(&deltas, export.format, stdout.lock())
+            }
+        }
+    }
+
+    /// Create a new client state, whose subscriptions are released when it is dropped.
+    pub(crate) fn new_client(&self) -> Client {
+        Client {
+            guard: Some(ClientGuard::new(&self.connected, &self.clients)),
+            ..Client::default()
+        }
+    }
+
+    pub(crate) fn observe_subscriptions<'a>(&self, clients: impl Iterator<Item = &'a Client>) {
+        let total: usize = clients.map(Client::subscriptions).sum();
+        self.subscriptions.set("scripthash", total as f64);
+    }
+
+    /// When the server should call `update_client()` to send coalesced notifications.
+    pub(crate) fn next_notification(&self, client: &Client) -> Option<Instant> {
+        client.next_notification(self.notify_coalesce)
+    }
+
+    pub fn update_client(&self, client: &mut Client) -> Result<Vec<Response>> {
+        self.query_pool.install(|| self.notifications(client))
+    }
+
+    fn notifications(&self, client: &mut Client) -> Result<Vec<Response>> {
         let chain = self.tracker.chain();
-        let mut notifications = client
+        let changed = client
             .scripthashes
             .par_iter_mut()
-            .filter_map(|(scripthash, status)| -> Option<Result<Value>> {
+            .filter_map(|(scripthash, status)| -> Option<Result<ScriptHash>> {
                 match self
                     .tracker
                     .update_scripthash_status(status, &self.daemon, &self.cache)
                 {
-                    Ok(true) => Some(Ok(notification(
-                        "blockchain.scripthash.subscribe",
-                        &[json!(scripthash), json!(status.statushash())],
-                    ))),
+                    Ok(true) => Some(Ok(*scripthash)),
                     Ok(false) => None, // statushash is the same
                     Err(e) => Some(Err(e)),
                 }
             })
-            .collect::<Result<Vec<Value>>>()
+            .collect::<Result<Vec<ScriptHash>>>()
             .context("failed to update status")?;
 
+        let changed = client.coalesce(changed, self.notify_coalesce, Instant::now());
+        let mut notifications = client.status_notifications(changed);
+
         if let Some(old_tip) = client.tip {
             let new_tip = self.tracker.chain().tip();
             if old_tip != new_tip {
@@ -197,7 +697,10 @@ impl Rpc {
                 ));
             }
         }
-        Ok(notifications.into_iter().map(|v| v.to_string()).collect())
+        Ok(notifications
+            .into_iter()
+            .map(|value| Response::Streamed(Reply::Value(value)))
+            .collect())
     }
 
     fn headers_subscribe(&self, client: &mut Client) -> Result<Value> {
@@ -208,6 +711,10 @@ impl Rpc {
         Ok(json!({"hex": serialize(header).to_hex(), "height": height}))
     }
 
+    fn chain_tip(&self) -> Result<Value> {
+        Ok(tip_json(self.tracker.chain()))
+    }
+
     fn block_header(&self, (height,): (usize,)) -> Result<Value> {
         let chain = self.tracker.chain();
         let header = match chain.get_block_header(height) {
@@ -217,6 +724,18 @@ impl Rpc {
         Ok(json!(serialize(header).to_hex()))
     }
 
+    fn block_get_hash(&self, (height,): (usize,)) -> Result<Value> {
+        let chain = self.tracker.chain();
+        if height > chain.height() {
+            bail!("height {} is above the tip ({})", height, chain.height());
+        }
+        Ok(json!(chain.get_block_hash(height)))
+    }
+
+    fn block_get_height(&self, (blockhash,): (BlockHash,)) -> Result<Value> {
+        Ok(json!(self.tracker.chain().get_block_height(&blockhash)))
+    }
+
     fn block_headers(&self, (start_height, count): (usize, usize)) -> Result<Value> {
         let chain = self.tracker.chain();
         let max_count = 2016usize;
@@ -233,6 +752,49 @@ impl Rpc {
         Ok(json!({"count": count, "hex": hex_headers, "max": max_count}))
     }
 
+    fn block_get_txid_prefixes(&self, (start_height, count): (usize, usize)) -> Result<Value> {
+        let chain = self.tracker.chain();
+        if start_height > chain.height() {
+            bail!(
+                "height {} is above the tip ({})",
+                start_height,
+                chain.height()
+            );
+        }
+        let count = std::cmp::min(
+            std::cmp::min(count, MAX_TXID_PREFIXES_BLOCKS),
+            chain.height() - start_height + 1,
+        );
+        if count == 0 {
+            return Ok(json!([]));
+        }
+        let heights = start_height..=(start_height + count - 1);
+        let prefixes = self.tracker.get_txid_prefixes_in_range(heights)?;
+        Ok(json!(group_txid_prefixes_by_height(prefixes)))
+    }
+
+    fn block_filter_row(&self, height: usize) -> Result<FilterRow> {
+        let blockhash = match self.tracker.chain().get_block_hash(height) {
+            None => bail!("no header at {}", height),
+            Some(blockhash) => blockhash,
+        };
+        match self.tracker.get_block_filter(blockhash) {
+            None => bail!("no filter for block {} (use --index-filters)", blockhash),
+            Some(row) => Ok(row),
+        }
+    }
+
+    /// Non-standard extension, returning the block's output scripts filter (see `doc/schema.md`).
+    /// It uses BIP158's encoding, but not its contents (spent scripts are missing),
+    /// so it's not served as a BIP157 "basic" filter.
+    fn block_output_filter(&self, (height,): (usize,)) -> Result<Value> {
+        Ok(json!(self.block_filter_row(height)?.filter.to_hex()))
+    }
+
+    fn block_output_filter_header(&self, (height,): (usize,)) -> Result<Value> {
+        Ok(json!(self.block_filter_row(height)?.header))
+    }
+
     fn estimate_fee(&self, (nblocks,): (u16,)) -> Result<Value> {
         Ok(self
             .daemon
@@ -241,64 +803,216 @@ impl Rpc {
             .unwrap_or_else(|| json!(UNKNOWN_FEE)))
     }
 
+    /// Served from the last background computation (see `Tracker::refresh_chain_stats`).
+    fn chain_stats(&self) -> Result<Value> {
+        match &self.chain_stats {
+            Some(chain_stats) => chain_stats
+                .get()
+                .context("chain statistics are not computed yet"),
+            None => bail!("chain statistics are disabled (see `--chain-stats`)"),
+        }
+    }
+
     fn relayfee(&self) -> Result<Value> {
         Ok(json!(self.daemon.get_relay_fee()?.as_btc())) // [BTC/kB]
     }
 
-    fn scripthash_get_balance(
-        &self,
-        client: &Client,
-        (scripthash,): &(ScriptHash,),
-    ) -> Result<Value> {
-        let balance = match client.scripthashes.get(scripthash) {
-            Some(status) => self.tracker.get_balance(status),
+    fn scripthash_get_balance(&self, client: &Client, args: &GetBalanceArgs) -> Result<Value> {
+        let (scripthash, min_conf) = args.into();
+        let min_conf = min_conf.unwrap_or(self.min_confirmations);
+        let balance = match client.scripthashes.get(&scripthash) {
+            Some(status) => self.tracker.get_balance(status, min_conf),
             None => {
                 info!(
                     "{} blockchain.scripthash.get_balance called for unsubscribed scripthash: {}",
                     UNSUBSCRIBED_QUERY_MESSAGE, scripthash
                 );
-                self.tracker.get_balance(&self.new_status(*scripthash)?)
+                self.tracker
+                    .get_balance(&self.new_status(scripthash)?, min_conf)
             }
         };
         Ok(json!(balance))
     }
 
+    fn scripthashes_get_balance(
+        &self,
+        client: &Client,
+        (scripthashes,): &(Vec<ScriptHash>,),
+    ) -> Result<Value> {
+        let new_statuses = self.new_statuses(client, scripthashes)?;
+        let statuses: Vec<&ScriptHashStatus> = scripthashes
+            .iter()
+            .map(|scripthash| {
+                client
+                    .scripthashes
+                    .get(scripthash)
+                    .unwrap_or_else(|| &*new_statuses[scripthash])
+            })
+            .collect();
+        Ok(json!(self
+            .tracker
+            .get_balances(&statuses, self.min_confirmations)))
+    }
+
+    /// Sync the unsubscribed scripthashes (in parallel), up to `max_batch_scripthashes`.
+    fn new_statuses(
+        &self,
+        client: &Client,
+        scripthashes: &[ScriptHash],
+    ) -> Result<HashMap<ScriptHash, Arc<ScriptHashStatus>>> {
+        if let Some(limit) = self.max_batch_scripthashes {
+            if scripthashes.len() > limit {
+                bail!("too many scripthashes: {} > {}", scripthashes.len(), limit);
+            }
+        }
+        scripthashes
+            .par_iter()
+            .filter(|scripthash| !client.scripthashes.contains_key(scripthash))
+            .map(|scripthash| Ok((*scripthash, self.new_status(*scripthash)?)))
+            .collect()
+    }
+
+    /// Non-standard extension, deriving `count` scripthashes (starting from the `start` child
+    /// index) of a ranged output descriptor, and returning their balances and histories.
+    fn descriptor_scan(
+        &self,
+        client: &Client,
+        (descriptor, start, count): &(String, u32, u32),
+    ) -> Result<Value> {
+        let descriptor: Descriptor = descriptor.parse()?;
+        let limit = descriptor_scan_limit(self.max_batch_scripthashes);
+        if *count as usize > limit {
+            bail!("too many scripthashes: {} > {}", count, limit);
+        }
+        let end = start
+            .checked_add(*count)
+            .context("invalid descriptor range")?;
+        let scripthashes = descriptor.derive_scripthashes(*start..end)?;
+        let new_statuses = self.new_statuses(client, &scripthashes)?;
+        let result = scripthashes
+            .iter()
+            .zip(*start..)
+            .map(|(scripthash, index)| {
+                let status = client
+                    .scripthashes
+                    .get(scripthash)
+                    .unwrap_or_else(|| &*new_statuses[scripthash]);
+                Ok(json!({
+                    "index": index,
+                    "scripthash": scripthash,
+                    "balance": self.tracker.get_balance(status, self.min_confirmations),
+                    "history": *self.tracker.get_history(status, &self.cache)?,
+                }))
+            })
+            .collect::<Result<Vec<Value>>>()?;
+        Ok(json!(result))
+    }
+
     fn scripthash_get_history(
         &self,
         client: &Client,
         (scripthash,): &(ScriptHash,),
-    ) -> Result<Value> {
+    ) -> Result<Arc<Value>> {
         let history_entries = match client.scripthashes.get(scripthash) {
-            Some(status) => json!(status.get_history()),
+            Some(status) => {
+                check_history_size(status.get_history().len(), self.max_response_size)?;
+                self.tracker.get_history(status, &self.cache)?
+            }
             None => {
                 info!(
                     "{} blockchain.scripthash.get_history called for unsubscribed scripthash: {}",
                     UNSUBSCRIBED_QUERY_MESSAGE, scripthash
                 );
-                json!(self.new_status(*scripthash)?.get_history())
+                let status = self.new_status(*scripthash)?;
+                check_history_size(status.get_history().len(), self.max_response_size)?;
+                self.tracker.get_history(&status, &self.cache)?
             }
         };
         Ok(history_entries)
     }
 
-    fn scripthash_list_unspent(
+    /// Non-standard extension, returning the earliest confirmed transaction of the scripthash
+    /// (or null, if it has none) - without fetching its whole history.
+    fn scripthash_get_first_use(
         &self,
         client: &Client,
         (scripthash,): &(ScriptHash,),
     ) -> Result<Value> {
-        let unspent_entries = match client.scripthashes.get(scripthash) {
-            Some(status) => self.tracker.get_unspent(status),
+        let first_use = match client.scripthashes.get(scripthash) {
+            Some(status) => status.get_first_use(self.tracker.chain()),
+            None => self.tracker.get_first_use(&self.daemon, *scripthash)?,
+        };
+        Ok(first_use.map_or(
+            Value::Null,
+            |(height, txid)| json!({"tx_hash": txid, "height": height}),
+        ))
+    }
+
+    fn scripthash_get_mempool(
+        &self,
+        client: &Client,
+        (scripthash,): &(ScriptHash,),
+    ) -> Result<Value> {
+        // confirmed entries are not synced for unsubscribed scripthashes
+        let mempool_entries = match client.scripthashes.get(scripthash) {
+            Some(status) => self.tracker.get_mempool_history(status, &self.cache),
+            None => self
+                .tracker
+                .get_mempool_history(&ScriptHashStatus::new(*scripthash), &self.cache),
+        };
+        Ok(json!(mempool_entries))
+    }
+
+    fn scripthash_list_unspent(&self, client: &Client, args: &ListUnspentArgs) -> Result<Value> {
+        // unlike `get_balance`, shallow outputs are excluded only if the client asks for it
+        let (scripthash, verbose, min_conf) = args.into();
+        let new_status;
+        let status = match client.scripthashes.get(&scripthash) {
+            Some(status) => status,
             None => {
                 info!(
                     "{} blockchain.scripthash.listunspent called for unsubscribed scripthash: {}",
                     UNSUBSCRIBED_QUERY_MESSAGE, scripthash
                 );
-                self.tracker.get_unspent(&self.new_status(*scripthash)?)
+                new_status = self.new_status(scripthash)?;
+                &*new_status
             }
         };
+        let unspent_entries = if verbose {
+            // non-standard extension, including each output's scriptPubKey (and address)
+            self.tracker
+                .get_unspent_verbose(status, min_conf, &self.cache)
+        } else {
+            self.tracker.get_unspent(status, min_conf)
+        };
         Ok(json!(unspent_entries))
     }
 
+    /// Non-standard extension, returning the UTXO set of the scripthash as of the given height
+    /// (optionally, only the outputs funded since `min_height`), excluding mempool transactions.
+    fn scripthash_list_unspent_at(
+        &self,
+        client: &Client,
+        args: &ListUnspentAtArgs,
+    ) -> Result<Value> {
+        let (scripthash, height, min_height) = args.into();
+        let tip_height = self.tracker.chain().height();
+        if height > tip_height {
+            bail!("height {} is above the tip ({})", height, tip_height);
+        }
+        let new_status;
+        let status = match client.scripthashes.get(&scripthash) {
+            Some(status) => status,
+            None => {
+                new_status = self.new_status(scripthash)?;
+                &*new_status
+            }
+        };
+        Ok(json!(self
+            .tracker
+            .get_unspent_at(status, min_height, height)))
+    }
+
     fn scripthash_subscribe(
         &self,
         client: &mut Client,
@@ -314,33 +1028,41 @@ impl Rpc {
         client: &'a mut Client,
         scripthashes: &'a [ScriptHash],
     ) -> impl Iterator<Item = Result<Value>> + 'a {
-        let new_scripthashes: Vec<ScriptHash> = scripthashes
-            .iter()
-            .copied()
-            .filter(|scripthash| !client.scripthashes.contains_key(scripthash))
-            .collect();
-
-        let mut results: HashMap<ScriptHash, Result<ScriptHashStatus>> = new_scripthashes
-            .into_par_iter()
-            .map(|scripthash| (scripthash, self.new_status(scripthash)))
-            .collect();
-
-        scripthashes.iter().map(move |scripthash| {
-            let statushash = match client.scripthashes.entry(*scripthash) {
-                Entry::Occupied(e) => e.get().statushash(),
-                Entry::Vacant(e) => {
-                    let status = results
-                        .remove(scripthash)
-                        .expect("missing scripthash status")?; // return an error for failed subscriptions
-                    e.insert(status).statushash()
-                }
-            };
-            Ok(json!(statushash))
+        client.subscribe(scripthashes, self.max_subscriptions, move |scripthash| {
+            self.subscribed_status(scripthash)
         })
     }
 
-    fn new_status(&self, scripthash: ScriptHash) -> Result<ScriptHashStatus> {
-        let mut status = ScriptHashStatus::new(scripthash);
+    /// The status of an unsubscribed query, kept for priming (see `--prime-on-block`).
+    /// Watched statuses are already synced, so they are shared (instead of being copied).
+    fn new_status(&self, scripthash: ScriptHash) -> Result<Arc<ScriptHashStatus>> {
+        if let Some(status) = self.tracker.get_watched(&scripthash) {
+            return Ok(status);
+        }
+        // a primed status only needs to sync the mempool (and the blocks since its priming)
+        let mut status = self
+            .tracker
+            .take_primed(&scripthash)
+            .unwrap_or_else(|| Arc::new(ScriptHashStatus::new(scripthash)));
+        self.tracker.update_scripthash_status(
+            Arc::make_mut(&mut status),
+            &self.daemon,
+            &self.cache,
+        )?;
+        self.tracker.add_primed(Arc::clone(&status));
+        Ok(status)
+    }
+
+    /// A new subscription's status, which is synced by `update_client` (so it isn't primed).
+    fn subscribed_status(&self, scripthash: ScriptHash) -> Result<ScriptHashStatus> {
+        let status = self
+            .tracker
+            .get_watched(&scripthash)
+            .or_else(|| self.tracker.get_primed(&scripthash));
+        let mut status = match status {
+            Some(status) => ScriptHashStatus::clone(&status),
+            None => ScriptHashStatus::new(scripthash),
+        };
         self.tracker
             .update_scripthash_status(&mut status, &self.daemon, &self.cache)?;
         Ok(status)
@@ -378,45 +1100,132 @@ impl Rpc {
         Ok(json!(self.daemon.get_transaction_hex(&txid, None)?))
     }
 
-    fn transaction_get_merkle(&self, (txid, height): &(Txid, usize)) -> Result<Value> {
-        let chain = self.tracker.chain();
-        let blockhash = match chain.get_block_hash(*height) {
+    fn transaction_get_confirmed_blockhash(&self, (txid,): &(Txid,)) -> Result<Value> {
+        Ok(
+            match self.tracker.get_confirmed_blockhash(&self.daemon, *txid)? {
+                Some((height, blockhash)) => {
+                    json!({"block_height": height, "block_hash": blockhash})
+                }
+                None => Value::Null, // unknown or unconfirmed transaction
+            },
+        )
+    }
+
+    fn opreturn_get_history(&self, (data_hex,): &(String,)) -> Result<Value> {
+        let data = Vec::from_hex(data_hex).context("invalid OP_RETURN data hex")?;
+        let txids = self.tracker.get_opreturn_txids(&self.daemon, &data)?;
+        Ok(json!(txids
+            .into_iter()
+            .map(|(height, txid)| json!({"tx_hash": txid, "height": height}))
+            .collect::<Vec<Value>>()))
+    }
+
+    fn outpoint_get_spender(&self, (txid, vout): &(Txid, u32)) -> Result<Value> {
+        let outpoint = OutPoint::new(*txid, *vout);
+        Ok(json!(self
+            .tracker
+            .get_spending_txid(&self.daemon, outpoint)?))
+    }
+
+    /// Get an SPV-verifiable proof of an (unspent) output: its funding transaction's merkle proof,
+    /// and the headers from the confirming block towards the tip (at most 2016 of them).
+    ///
+    /// Given a valid header chain, the client can verify that the output was confirmed without
+    /// trusting electrs. However, its spending status is best-effort: it is based on electrs' index
+    /// and bitcoind's mempool, and must be trusted.
+    fn outpoint_get_proof(&self, (txid, vout): &(Txid, u32)) -> Result<Value> {
+        let (blockhash, tx) = match self.tracker.lookup_transaction(&self.daemon, *txid)? {
+            Some(result) => result,
+            None => bail!("unknown confirmed transaction {}", txid),
+        };
+        let height = match self.tracker.chain().get_block_height(&blockhash) {
+            Some(height) => height,
+            None => bail!("missing block {}", blockhash),
+        };
+        let txout = match tx.output.get(usize::try_from(*vout)?) {
+            Some(txout) => txout,
+            None => bail!("missing output {} of transaction {}", vout, txid),
+        };
+        let proof = self
+            .tracker
+            .get_merkle_proof(&self.daemon, &self.cache, *txid, blockhash)?;
+        let spender = self
+            .tracker
+            .get_spending_txid(&self.daemon, OutPoint::new(*txid, *vout))?;
+        let headers = self.block_headers((height, usize::MAX))?;
+        Ok(outpoint_proof(height, &proof, txout, headers, spender))
+    }
+
+    fn transaction_get_merkle(&self, args: &TxGetMerkleArgs) -> Result<Value> {
+        let (txid, height) = args.into();
+        // checked first, instead of fetching the block at the given height (which lacks the txid)
+        self.tracker.ensure_not_in_mempool(txid)?;
+        let height = match height {
+            Some(height) => height,
+            None => {
+                self.tracker
+                    .get_tx_position(&self.daemon, &self.cache, txid)?
+                    .0
+            }
+        };
+        let blockhash = match self.tracker.chain().get_block_hash(height) {
             None => bail!("missing block at {}", height),
             Some(blockhash) => blockhash,
         };
-        let txids = self.daemon.get_block_txids(blockhash)?;
-        match txids.iter().position(|current_txid| *current_txid == *txid) {
-            None => bail!("missing txid {} in block {}", txid, blockhash),
-            Some(position) => {
-                let proof = Proof::create(&txids, position);
-                Ok(json!({
-                "block_height": height,
-                "pos": proof.position(),
-                "merkle": proof.to_hex(),
-                }))
-            }
-        }
+        let proof = self
+            .tracker
+            .get_merkle_proof(&self.daemon, &self.cache, txid, blockhash)?;
+        Ok(json!({
+            "block_height": height,
+            "pos": proof.position(),
+            "merkle": proof.to_hex(),
+        }))
     }
 
     fn get_fee_histogram(&self) -> Result<Value> {
         Ok(json!(self.tracker.fees_histogram()))
     }
 
+    /// Non-standard extension, returning the mempool transactions sorted by fee rate
+    /// (with their cumulative vsize), until `max_vsize` is reached.
+    fn mempool_get_by_fee_rate(&self, (max_vsize,): &(u64,)) -> Result<Value> {
+        Ok(json!(self.tracker.get_mempool_by_fee_rate(*max_vsize)))
+    }
+
+    fn mempool_get_package(&self, (txid,): &(Txid,)) -> Result<Value> {
+        match self.tracker.get_mempool_package(&self.daemon, txid)? {
+            Some(package) => Ok(json!(package)),
+            None => bail!("transaction {} not found in mempool", txid),
+        }
+    }
+
+    fn mempool_get_first_seen(&self, (txid,): &(Txid,)) -> Result<Value> {
+        Ok(json!(self.tracker.get_mempool_first_seen(txid)))
+    }
+
+    fn mempool_get_replacement(&self, (txid,): &(Txid,)) -> Result<Value> {
+        Ok(json!(self.tracker.get_mempool_replacement(txid)))
+    }
+
     fn server_id(&self) -> String {
-        format!("electrs/{}", ELECTRS_VERSION)
+        format!("electrs/{}", ELECTRS_VERSION_RICH)
     }
 
-    fn version(&self, (client_id, client_version): &(String, Version)) -> Result<Value> {
-        match client_version {
-            Version::Single(v) if v == PROTOCOL_VERSION => {
-                Ok(json!([self.server_id(), PROTOCOL_VERSION]))
-            }
-            _ => {
+    fn version(
+        &self,
+        client: &mut Client,
+        (client_id, client_version): &(String, Version),
+    ) -> Result<Value> {
+        match negotiate_version(client_version, &self.protocol_min, &self.protocol_max) {
+            Some(version) => Ok(json!([self.server_id(), version.to_string()])),
+            None => {
+                client.disconnect = true;
                 bail!(
-                    "{} requested {:?}, server supports {}",
+                    "{} requested {:?}, server supports {}-{}",
                     client_id,
                     client_version,
-                    PROTOCOL_VERSION
+                    self.protocol_min,
+                    self.protocol_max
                 );
             }
         }
@@ -426,41 +1235,62 @@ impl Rpc {
         Ok(json!({
             "genesis_hash": self.tracker.chain().get_block_hash(0),
             "hosts": { "tcp_port": self.port },
-            "protocol_max": PROTOCOL_VERSION,
-            "protocol_min": PROTOCOL_VERSION,
-            "pruning": null,
+            "protocol_max": self.protocol_max.to_string(),
+            "protocol_min": self.protocol_min.to_string(),
+            "pruning": self.tracker.pruned_height(), // older history is not returned
             "server_version": self.server_id(),
-            "hash_function": "sha256"
+            "hash_function": "sha256",
+            "batch_notifications": true,
         }))
     }
 
-    pub fn handle_requests(&self, client: &mut Client, lines: &[String]) -> Vec<String> {
+    /// Send all the changed statuses in a single `blockchain.scripthashes.status` notification,
+    /// whose params are `[scripthash, statushash]` pairs (instead of a notification per change).
+    fn batch_notifications(&self, client: &mut Client, (enable,): &(bool,)) -> Result<Value> {
+        client.batch_notifications = *enable;
+        Ok(json!(enable))
+    }
+
+    pub fn handle_requests(&self, client: &mut Client, lines: &[String]) -> Vec<Response> {
+        self.query_pool
+            .install(|| self.handle_request_lines(client, lines))
+    }
+
+    fn handle_request_lines(&self, client: &mut Client, lines: &[String]) -> Vec<Response> {
         lines
             .iter()
             .map(|line| {
                 parse_requests(line)
-                    .map(Calls::parse)
+                    .map(|requests| Calls::parse(requests, self.network, &self.disabled_methods))
                     .map_err(error_msg_no_id)
             })
-            .map(|calls| self.handle_calls(client, calls).to_string())
+            .map(|calls| {
+                let response = self.handle_calls(client, calls);
+                match self.max_response_size {
+                    Some(limit) => Response::Serialized(limit_response(response, limit)),
+                    None => Response::Streamed(response),
+                }
+            })
             .collect()
     }
 
-    fn handle_calls(&self, client: &mut Client, calls: Result<Calls, Value>) -> Value {
+    fn handle_calls(&self, client: &mut Client, calls: Result<Calls, Value>) -> Reply {
         let calls: Calls = match calls {
             Ok(calls) => calls,
-            Err(response) => return response, // JSON parsing failed - the response does not contain request id
+            Err(response) => return Reply::Value(response), // JSON parsing failed - the response does not contain request id
         };
 
         match calls {
             Calls::Batch(batch) => {
                 if let Some(result) = self.try_multi_call(client, &batch) {
-                    return json!(result);
+                    return Reply::Batch(result.into_iter().map(Reply::Value).collect());
                 }
-                json!(batch
-                    .into_iter()
-                    .map(|result| self.single_call(client, result))
-                    .collect::<Vec<Value>>())
+                Reply::Batch(
+                    batch
+                        .into_iter()
+                        .map(|result| self.single_call(client, result))
+                        .collect(),
+                )
             }
             Calls::Single(result) => self.single_call(client, result),
         }
@@ -497,44 +1327,78 @@ impl Rpc {
         )
     }
 
-    fn single_call(&self, client: &mut Client, call: Result<Call, Value>) -> Value {
+    fn single_call(&self, client: &mut Client, call: Result<Call, Value>) -> Reply {
         let call = match call {
             Ok(call) => call,
-            Err(response) => return response, // params parsing may fail - the response contains request id
+            Err(response) => return Reply::Value(response), // params parsing may fail - the response contains request id
         };
         self.rpc_duration.observe_duration(&call.method, || {
-            if self.tracker.status().is_err() {
+            if let Err(err) = self.tracker.status() {
                 // Allow only a few RPC (for sync status notification) not requiring index DB being compacted.
                 match &call.params {
                     Params::BlockHeader(_)
                     | Params::BlockHeaders(_)
+                    | Params::ChainTip
                     | Params::HeadersSubscribe
                     | Params::Version(_) => (),
-                    _ => return error_msg(&call.id, RpcError::UnavailableIndex),
+                    _ => {
+                        let error = match err {
+                            tracker::Error::NotReady => RpcError::UnavailableIndex,
+                            tracker::Error::Lagging(lag) => RpcError::Syncing(lag),
+                        };
+                        return Reply::Value(error_msg(&call.id, error));
+                    }
                 };
             }
             let result = match &call.params {
                 Params::Banner => Ok(json!(self.banner)),
+                Params::BatchNotifications(args) => self.batch_notifications(client, args),
+                Params::BlockOutputFilter(args) => self.block_output_filter(*args),
+                Params::BlockOutputFilterHeader(args) => self.block_output_filter_header(*args),
+                Params::BlockGetHash(args) => self.block_get_hash(*args),
+                Params::BlockGetTxidPrefixes(args) => self.block_get_txid_prefixes(*args),
+                Params::BlockGetHeight(args) => self.block_get_height(*args),
                 Params::BlockHeader(args) => self.block_header(*args),
                 Params::BlockHeaders(args) => self.block_headers(*args),
+                Params::ChainStats => self.chain_stats(),
+                Params::ChainTip => self.chain_tip(),
                 Params::Donation => Ok(Value::Null),
                 Params::EstimateFee(args) => self.estimate_fee(*args),
                 Params::Features => self.features(),
                 Params::HeadersSubscribe => self.headers_subscribe(client),
                 Params::MempoolFeeHistogram => self.get_fee_histogram(),
+                Params::MempoolGetByFeeRate(args) => self.mempool_get_by_fee_rate(args),
+                Params::MempoolGetFirstSeen(args) => self.mempool_get_first_seen(args),
+                Params::MempoolGetPackage(args) => self.mempool_get_package(args),
+                Params::MempoolGetReplacement(args) => self.mempool_get_replacement(args),
+                Params::DescriptorScan(args) => self.descriptor_scan(client, args),
+                Params::OpReturnGetHistory(args) => self.opreturn_get_history(args),
+                Params::OutPointGetProof(args) => self.outpoint_get_proof(args),
+                Params::OutPointGetSpender(args) => self.outpoint_get_spender(args),
                 Params::PeersSubscribe => Ok(json!([])),
                 Params::Ping => Ok(Value::Null),
                 Params::RelayFee => self.relayfee(),
                 Params::ScriptHashGetBalance(args) => self.scripthash_get_balance(client, args),
-                Params::ScriptHashGetHistory(args) => self.scripthash_get_history(client, args),
+                Params::ScriptHashGetFirstUse(args) => self.scripthash_get_first_use(client, args),
+                Params::ScriptHashGetHistory(args) => {
+                    return call.shared_response(self.scripthash_get_history(client, args))
+                }
+                Params::ScriptHashGetMempool(args) => self.scripthash_get_mempool(client, args),
                 Params::ScriptHashListUnspent(args) => self.scripthash_list_unspent(client, args),
+                Params::ScriptHashListUnspentAt(args) => {
+                    self.scripthash_list_unspent_at(client, args)
+                }
                 Params::ScriptHashSubscribe(args) => self.scripthash_subscribe(client, args),
+                Params::ScriptHashesGetBalance(args) => self.scripthashes_get_balance(client, args),
                 Params::TransactionBroadcast(args) => self.transaction_broadcast(args),
                 Params::TransactionGet(args) => self.transaction_get(args),
+                Params::TransactionGetConfirmedBlockhash(args) => {
+                    self.transaction_get_confirmed_blockhash(args)
+                }
                 Params::TransactionGetMerkle(args) => self.transaction_get_merkle(args),
-                Params::Version(args) => self.version(args),
+                Params::Version(args) => self.version(client, args),
             };
-            call.response(result)
+            Reply::Value(call.response(result))
         })
     }
 }
@@ -542,54 +1406,158 @@ impl Rpc {
 #[derive(Deserialize)]
 enum Params {
     Banner,
+    BatchNotifications((bool,)),
+    BlockOutputFilter((usize,)),
+    BlockOutputFilterHeader((usize,)),
+    BlockGetHash((usize,)),
+    BlockGetTxidPrefixes((usize, usize)),
+    BlockGetHeight((BlockHash,)),
     BlockHeader((usize,)),
     BlockHeaders((usize, usize)),
     TransactionBroadcast((String,)),
+    ChainStats,
+    ChainTip,
+    DescriptorScan((String, u32, u32)),
     Donation,
     EstimateFee((u16,)),
     Features,
     HeadersSubscribe,
     MempoolFeeHistogram,
+    MempoolGetByFeeRate((u64,)),
+    MempoolGetFirstSeen((Txid,)),
+    MempoolGetPackage((Txid,)),
+    MempoolGetReplacement((Txid,)),
+    OpReturnGetHistory((String,)),
+    OutPointGetProof((Txid, u32)),
+    OutPointGetSpender((Txid, u32)),
     PeersSubscribe,
     Ping,
     RelayFee,
-    ScriptHashGetBalance((ScriptHash,)),
+    ScriptHashGetBalance(GetBalanceArgs),
+    ScriptHashGetFirstUse((ScriptHash,)),
     ScriptHashGetHistory((ScriptHash,)),
-    ScriptHashListUnspent((ScriptHash,)),
+    ScriptHashGetMempool((ScriptHash,)),
+    ScriptHashListUnspent(ListUnspentArgs),
+    ScriptHashListUnspentAt(ListUnspentAtArgs),
     ScriptHashSubscribe((ScriptHash,)),
+    ScriptHashesGetBalance((Vec<ScriptHash>,)),
     TransactionGet(TxGetArgs),
-    TransactionGetMerkle((Txid, usize)),
+    TransactionGetConfirmedBlockhash((Txid,)),
+    TransactionGetMerkle(TxGetMerkleArgs),
     Version((String, Version)),
 }
 
 impl Params {
-    fn parse(method: &str, params: Value) -> std::result::Result<Params, StandardError> {
+    fn parse(
+        method: &str,
+        params: Value,
+        network: Network,
+    ) -> std::result::Result<Params, StandardError> {
         Ok(match method {
+            // extensions for clients having an address instead of its scripthash
+            "blockchain.address.get_balance" => Params::ScriptHashGetBalance(
+                GetBalanceArgs::ScriptHash(convert_address(params, network)?),
+            ),
+            "blockchain.address.get_history" => {
+                Params::ScriptHashGetHistory(convert_address(params, network)?)
+            }
+            "blockchain.address.get_mempool" => {
+                Params::ScriptHashGetMempool(convert_address(params, network)?)
+            }
+            "blockchain.address.listunspent" => Params::ScriptHashListUnspent(
+                ListUnspentArgs::ScriptHash(convert_address(params, network)?),
+            ),
+            "blockchain.block.output_filter" => Params::BlockOutputFilter(convert(params)?),
+            "blockchain.block.output_filter_header" => {
+                Params::BlockOutputFilterHeader(convert(params)?)
+            }
+            "blockchain.block.get_hash" => Params::BlockGetHash(convert(params)?),
+            "blockchain.block.get_txid_prefixes" => Params::BlockGetTxidPrefixes(convert(params)?),
+            "blockchain.block.get_height" => Params::BlockGetHeight(convert(params)?),
             "blockchain.block.header" => Params::BlockHeader(convert(params)?),
             "blockchain.block.headers" => Params::BlockHeaders(convert(params)?),
+            "blockchain.descriptor.scan" => Params::DescriptorScan(convert(params)?),
             "blockchain.estimatefee" => Params::EstimateFee(convert(params)?),
             "blockchain.headers.subscribe" => Params::HeadersSubscribe,
+            "blockchain.opreturn.get_history" => Params::OpReturnGetHistory(convert(params)?),
+            "blockchain.outpoint.get_proof" => Params::OutPointGetProof(convert(params)?),
+            "blockchain.outpoint.get_spender" => Params::OutPointGetSpender(convert(params)?),
             "blockchain.relayfee" => Params::RelayFee,
+            "blockchain.stats" => Params::ChainStats,
+            "blockchain.tip" => Params::ChainTip,
             "blockchain.scripthash.get_balance" => Params::ScriptHashGetBalance(convert(params)?),
+            "blockchain.scripthash.get_first_use" => {
+                Params::ScriptHashGetFirstUse(convert(params)?)
+            }
             "blockchain.scripthash.get_history" => Params::ScriptHashGetHistory(convert(params)?),
+            "blockchain.scripthash.get_mempool" => Params::ScriptHashGetMempool(convert(params)?),
             "blockchain.scripthash.listunspent" => Params::ScriptHashListUnspent(convert(params)?),
+            "blockchain.scripthash.listunspent_at" => {
+                Params::ScriptHashListUnspentAt(convert(params)?)
+            }
             "blockchain.scripthash.subscribe" => Params::ScriptHashSubscribe(convert(params)?),
+            "blockchain.scripthashes.get_balance" => {
+                Params::ScriptHashesGetBalance(convert(params)?)
+            }
+            // extensions for clients having a scriptPubKey (e.g. a non-standard one) instead of its scripthash
+            "blockchain.scriptpubkey.get_balance" => {
+                Params::ScriptHashGetBalance(GetBalanceArgs::ScriptHash(convert_script(params)?))
+            }
+            "blockchain.scriptpubkey.get_history" => {
+                Params::ScriptHashGetHistory(convert_script(params)?)
+            }
+            "blockchain.scriptpubkey.get_mempool" => {
+                Params::ScriptHashGetMempool(convert_script(params)?)
+            }
+            "blockchain.scriptpubkey.listunspent" => {
+                Params::ScriptHashListUnspent(ListUnspentArgs::ScriptHash(convert_script(params)?))
+            }
             "blockchain.transaction.broadcast" => Params::TransactionBroadcast(convert(params)?),
             "blockchain.transaction.get" => Params::TransactionGet(convert(params)?),
+            "blockchain.transaction.get_confirmed_blockhash" => {
+                Params::TransactionGetConfirmedBlockhash(convert(params)?)
+            }
             "blockchain.transaction.get_merkle" => Params::TransactionGetMerkle(convert(params)?),
+            "mempool.get_by_fee_rate" => Params::MempoolGetByFeeRate(convert(params)?),
             "mempool.get_fee_histogram" => Params::MempoolFeeHistogram,
+            "mempool.get_first_seen" => Params::MempoolGetFirstSeen(convert(params)?),
+            "mempool.get_package" => Params::MempoolGetPackage(convert(params)?),
+            "mempool.get_replacement" => Params::MempoolGetReplacement(convert(params)?),
             "server.banner" => Params::Banner,
+            "server.batch_notifications" => Params::BatchNotifications(convert(params)?),
             "server.donation_address" => Params::Donation,
             "server.features" => Params::Features,
             "server.peers.subscribe" => Params::PeersSubscribe,
             "server.ping" => Params::Ping,
             "server.version" => Params::Version(convert(params)?),
-            _ => {
-                warn!("unknown method {}", method);
-                return Err(StandardError::MethodNotFound);
-            }
+            _ => return Err(StandardError::MethodNotFound),
         })
     }
+
+    /// The methods whose results are (also) returned by this call, so disabling any of them
+    /// disables the call too (e.g. `blockchain.address.get_history` is served via
+    /// `blockchain.scripthash.get_history`).
+    fn served_methods(&self) -> &'static [&'static str] {
+        match self {
+            Params::DescriptorScan(_) => &[
+                "blockchain.scripthash.get_balance",
+                "blockchain.scripthash.get_history",
+            ],
+            Params::ScriptHashGetBalance(_) => &["blockchain.scripthash.get_balance"],
+            Params::ScriptHashGetHistory(_) => &["blockchain.scripthash.get_history"],
+            Params::ScriptHashGetMempool(_) => &["blockchain.scripthash.get_mempool"],
+            Params::ScriptHashListUnspent(_) => &["blockchain.scripthash.listunspent"],
+            _ => &[],
+        }
+    }
+}
+
+/// Used for validating the configured `disable_methods`.
+pub(crate) fn is_known_method(method: &str) -> bool {
+    !matches!(
+        Params::parse(method, Value::Null, Network::Regtest),
+        Err(StandardError::MethodNotFound)
+    )
 }
 
 struct Call {
@@ -599,15 +1567,32 @@ struct Call {
 }
 
 impl Call {
-    fn parse(request: Request) -> Result<Call, Value> {
-        match Params::parse(&request.method, request.params) {
-            Ok(params) => Ok(Call {
-                id: request.id,
-                method: request.method,
-                params,
-            }),
-            Err(e) => Err(error_msg(&request.id, RpcError::Standard(e))),
+    fn parse(
+        request: Request,
+        network: Network,
+        disabled: &HashSet<String>,
+    ) -> Result<Call, Value> {
+        let params = match Params::parse(&request.method, request.params, network) {
+            Ok(params) => params,
+            Err(e) => {
+                if let StandardError::MethodNotFound = e {
+                    warn!("unknown method {}", request.method);
+                }
+                return Err(error_msg(&request.id, RpcError::Standard(e)));
+            }
+        };
+        // aliases are disabled together with the methods they are serving
+        let mut methods =
+            once(request.method.as_str()).chain(params.served_methods().iter().copied());
+        if methods.any(|method| disabled.contains(method)) {
+            warn!("disabled method {}", request.method);
+            return Err(error_msg(&request.id, RpcError::MethodDisabled));
         }
+        Ok(Call {
+            id: request.id,
+            method: request.method,
+            params,
+        })
     }
 
     fn response(&self, result: Result<Value>) -> Value {
@@ -615,6 +1600,10 @@ impl Call {
             Ok(value) => result_msg(&self.id, value),
             Err(err) => {
                 warn!("RPC {} failed: {:#}", self.method, err);
+                // the typed error may be wrapped by additional context
+                if let Some(e) = err.downcast_ref::<QueryError>() {
+                    return error_msg(&self.id, RpcError::Query(e.clone()));
+                }
                 match err
                     .downcast_ref::<bitcoincore_rpc::Error>()
                     .and_then(extract_bitcoind_error)
@@ -625,6 +1614,17 @@ impl Call {
             }
         }
     }
+
+    /// The (possibly large) cached result is not copied into the response.
+    fn shared_response(&self, result: Result<Arc<Value>>) -> Reply {
+        match result {
+            Ok(result) => Reply::Shared {
+                id: self.id.clone(),
+                result,
+            },
+            Err(err) => Reply::Value(self.response(Err(err))),
+        }
+    }
 }
 
 enum Calls {
@@ -633,12 +1633,15 @@ enum Calls {
 }
 
 impl Calls {
-    fn parse(requests: Requests) -> Calls {
+    fn parse(requests: Requests, network: Network, disabled: &HashSet<String>) -> Calls {
         match requests {
-            Requests::Single(request) => Calls::Single(Call::parse(request)),
-            Requests::Batch(batch) => {
-                Calls::Batch(batch.into_iter().map(Call::parse).collect::<Vec<_>>())
-            }
+            Requests::Single(request) => Calls::Single(Call::parse(request, network, disabled)),
+            Requests::Batch(batch) => Calls::Batch(
+                batch
+                    .into_iter()
+                    .map(|request| Call::parse(request, network, disabled))
+                    .collect::<Vec<_>>(),
+            ),
         }
     }
 }
@@ -654,6 +1657,112 @@ where
     })
 }
 
+/// Parse a hex-encoded scriptPubKey and compute its scripthash.
+/// Any bytes are accepted (e.g. truncated pushes), since they may be used as an output script.
+fn convert_script(params: Value) -> std::result::Result<(ScriptHash,), StandardError> {
+    let (script_hex,): (String,) = convert(params)?;
+    let script = Script::from(Vec::from_hex(&script_hex).map_err(|err| {
+        warn!("invalid scriptPubKey hex {}: {}", script_hex, err);
+        StandardError::InvalidParams
+    })?);
+    Ok((ScriptHash::new(&script),))
+}
+
+/// Supports all standard address types, including P2TR (bech32m-encoded witness v1 programs).
+fn convert_address(
+    params: Value,
+    network: Network,
+) -> std::result::Result<(ScriptHash,), StandardError> {
+    let (address,): (String,) = convert(params)?;
+    // also rejects bech32 checksums for witness v1+ programs (and bech32m ones for v0), see BIP-350
+    let addr = Address::from_str(&address).map_err(|err| {
+        warn!("invalid address {}: {}", address, err);
+        StandardError::InvalidParams
+    })?;
+    if !is_address_network(&addr, network) {
+        warn!("address {} is not valid for {}", address, network);
+        return Err(StandardError::InvalidParams);
+    }
+    Ok((ScriptHash::new(&addr.script_pubkey()),))
+}
+
+// testnet addresses are also used by signet, and by regtest (except for bech32 ones, using 'bcrt')
+fn is_address_network(address: &Address, network: Network) -> bool {
+    match (address.network, network) {
+        (Network::Testnet, Network::Signet) => true,
+        (Network::Testnet, Network::Regtest) => {
+            !matches!(address.payload, Payload::WitnessProgram { .. })
+        }
+        (address_network, network) => address_network == network,
+    }
+}
+
+/// Choose the highest protocol version supported by both the client and the server.
+fn negotiate_version(
+    client_version: &Version,
+    server_min: &ProtocolVersion,
+    server_max: &ProtocolVersion,
+) -> Option<ProtocolVersion> {
+    let (client_min, client_max) = match client_version {
+        Version::Single(version) => (version, version),
+        Version::Range(min, max) => (min, max),
+    };
+    let client_min: ProtocolVersion = client_min.parse().ok()?;
+    let client_max: ProtocolVersion = client_max.parse().ok()?;
+    let version = std::cmp::min(client_max, server_max.clone());
+    if version >= client_min && version >= *server_min {
+        Some(version)
+    } else {
+        None
+    }
+}
+
+/// Group consecutive (height, txid prefix) pairs into per-block entries.
+fn group_txid_prefixes_by_height(prefixes: Vec<(usize, [u8; HASH_PREFIX_LEN])>) -> Vec<Value> {
+    let mut blocks: Vec<(usize, Vec<String>)> = vec![];
+    for (height, prefix) in prefixes {
+        match blocks.last_mut() {
+            Some((last, block_prefixes)) if *last == height => {
+                block_prefixes.push(prefix[..].to_hex())
+            }
+            _ => blocks.push((height, vec![prefix[..].to_hex()])),
+        }
+    }
+    blocks
+        .into_iter()
+        .map(|(height, prefixes)| json!({"height": height, "txid_prefixes": prefixes}))
+        .collect()
+}
+
+/// The indexed tip (which may be behind bitcoind's tip, while syncing).
+fn tip_json(chain: &Chain) -> Value {
+    let height = chain.height();
+    let header = chain.get_block_header(height).expect("missing tip header");
+    json!({
+        "height": height,
+        "hash": chain.tip(),
+        "header_hex": serialize(header).to_hex(),
+    })
+}
+
+fn outpoint_proof(
+    height: usize,
+    proof: &Proof,
+    txout: &TxOut,
+    headers: Value,
+    spender: Option<Txid>,
+) -> Value {
+    json!({
+        "block_height": height,
+        "pos": proof.position(),
+        "merkle": proof.to_hex(),
+        "headers": headers,
+        "value": txout.value,
+        "script": txout.script_pubkey.as_bytes().to_hex(),
+        "spent_by": spender, // best-effort (not verifiable)
+    })
+}
+
 fn notification(method: &str, params: &[Value]) -> Value {
     json!({"jsonrpc": "2.0", "method": method, "params": params})
 }
@@ -666,6 +1775,62 @@ fn error_msg(id: &Value, error: RpcError) -> Value {
     json!({"jsonrpc": "2.0", "id": id, "error": error.to_value()})
 }
 
+/// Fails (without allocating more than `limit` bytes) if the output would be larger.
+struct LimitedWriter {
+    buf: Vec<u8>,
+    limit: usize,
+}
+
+impl Write for LimitedWriter {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        if self.buf.len() + data.len() > self.limit {
+            return Err(io::Error::new(io::ErrorKind::Other, "limit exceeded"));
+        }
+        self.buf.extend_from_slice(data);
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// `max_batch_scripthashes` may lower the hard limit, but can't disable it.
+fn descriptor_scan_limit(max_batch_scripthashes: Option<usize>) -> usize {
+    match max_batch_scripthashes {
+        Some(limit) => std::cmp::min(limit, MAX_DESCRIPTOR_SCAN_COUNT),
+        None => MAX_DESCRIPTOR_SCAN_COUNT,
+    }
+}
+
+/// Fails early (before the history response is built), if it would surely exceed `limit` bytes.
+fn check_history_size(entries: usize, limit: Option<usize>) -> Result<()> {
+    match limit {
+        Some(limit) if entries.saturating_mul(MIN_HISTORY_ENTRY_SIZE) > limit => {
+            Err(QueryError::ResponseTooLarge(limit).into())
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Serialize a response, replacing it by error(s) if it is larger than `limit` bytes.
+fn limit_response(response: Reply, limit: usize) -> String {
+    let mut writer = LimitedWriter {
+        buf: Vec::new(),
+        limit,
+    };
+    if serde_json::to_writer(&mut writer, &response).is_ok() {
+        return String::from_utf8(writer.buf).expect("invalid UTF-8 JSON");
+    }
+    let error = |response: &Reply| error_msg(response.id(), RpcError::ResponseTooLarge(limit));
+    let response = match &response {
+        Reply::Batch(responses) => json!(responses.iter().map(error).collect::<Vec<Value>>()),
+        response => error(response),
+    };
+    warn!("dropped a response larger than {} bytes", limit);
+    response.to_string()
+}
+
 fn error_msg_no_id(err: StandardError) -> Value {
     error_msg(&Value::Null, RpcError::Standard(err))
 }
@@ -687,3 +1852,559 @@ fn parse_requests(line: &str) -> Result<Requests, StandardError> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        check_history_size, convert_address, convert_script, descriptor_scan_limit,
+        group_txid_prefixes_by_height, is_known_method, limit_response, negotiate_version,
+        outpoint_proof, tip_json, Call, Client, ClientGuard, Params, Reply, Request, Response,
+        Version, MAX_DESCRIPTOR_SCAN_COUNT, MIN_HISTORY_ENTRY_SIZE,
+    };
+    use crate::chain::Chain;
+    use crate::config::ProtocolVersion;
+    use crate::errors::QueryError;
+    use crate::{
+        merkle::{merkle_root, Proof},
+        status::ScriptHashStatus,
+        tests::{coinbase, load_block, make_blocks, make_chain, metrics, script, txout},
+        types::{ScriptHash, HASH_PREFIX_LEN},
+    };
+    use bitcoin::{
+        consensus::{deserialize, serialize},
+        hashes::{hex::FromHex, hex::ToHex, Hash},
+        BlockHash, BlockHeader, Network, Script, Txid,
+    };
+    use serde_json::{json, Value};
+
+    use std::collections::HashSet;
+    use std::panic::AssertUnwindSafe;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_streamed_response() {
+        let history: Vec<Value> = (0..10_000)
+            .map(|i| json!({"tx_hash": Txid::hash(&[i as u8]), "height": i, "fee": null}))
+            .collect();
+        let value = json!({"jsonrpc": "2.0", "id": "a\"b", "result": history});
+
+        let mut buffered = Vec::new();
+        Response::Serialized(value.to_string())
+            .write_line(&mut buffered)
+            .unwrap();
+        let mut streamed = Vec::new();
+        Response::Streamed(Reply::Value(value.clone()))
+            .write_line(&mut streamed)
+            .unwrap();
+        assert_eq!(streamed, buffered);
+        assert_eq!(buffered, format!("{}\n", value).into_bytes());
+        assert_eq!(
+            Response::Streamed(Reply::Value(value.clone())).to_string(),
+            value.to_string()
+        );
+
+        // a shared result is serialized exactly like an owned one
+        let shared = Reply::Shared {
+            id: json!("a\"b"),
+            result: Arc::new(json!(history)),
+        };
+        let mut streamed = Vec::new();
+        Response::Streamed(shared)
+            .write_line(&mut streamed)
+            .unwrap();
+        assert_eq!(streamed, buffered);
+    }
+
+    #[test]
+    fn test_limit_response() {
+        let response = json!({"jsonrpc": "2.0", "id": 7, "result": ["a".repeat(100)]});
+        let serialized = response.to_string();
+        assert_eq!(
+            limit_response(Reply::Value(response.clone()), serialized.len()),
+            serialized
+        );
+
+        let error = json!({"code": -32603, "message": "response too large (limit: 100 bytes)"});
+        let rejected = limit_response(Reply::Value(response.clone()), 100);
+        let rejected: Value = serde_json::from_str(&rejected).unwrap();
+        assert_eq!(rejected, json!({"jsonrpc": "2.0", "id": 7, "error": error}));
+
+        // all batched responses are rejected
+        let batch = Reply::Batch(vec![
+            Reply::Value(response),
+            Reply::Shared {
+                id: json!(8),
+                result: Arc::new(Value::Null),
+            },
+        ]);
+        let rejected: Value = serde_json::from_str(&limit_response(batch, 100)).unwrap();
+        assert_eq!(
+            rejected,
+            json!([
+                {"jsonrpc": "2.0", "id": 7, "error": error},
+                {"jsonrpc": "2.0", "id": 8, "error": error},
+            ])
+        );
+    }
+
+    #[test]
+    fn test_descriptor_scan_limit() {
+        assert_eq!(descriptor_scan_limit(Some(10)), 10);
+        assert_eq!(
+            descriptor_scan_limit(Some(100_000)),
+            MAX_DESCRIPTOR_SCAN_COUNT
+        );
+        assert_eq!(descriptor_scan_limit(None), MAX_DESCRIPTOR_SCAN_COUNT); // always bounded
+    }
+
+    #[test]
+    fn test_check_history_size() {
+        let entry = json!({"height": 0, "tx_hash": Txid::hash(&[0])});
+        assert_eq!(entry.to_string().len(), MIN_HISTORY_ENTRY_SIZE);
+
+        assert!(check_history_size(1_000_000, None).is_ok());
+        assert!(check_history_size(10, Some(10 * MIN_HISTORY_ENTRY_SIZE)).is_ok());
+        let err = check_history_size(11, Some(10 * MIN_HISTORY_ENTRY_SIZE)).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<QueryError>(),
+            Some(QueryError::ResponseTooLarge(890))
+        ));
+        assert!(check_history_size(usize::MAX, Some(usize::MAX - 1)).is_err()); // no overflow
+    }
+
+    #[test]
+    fn test_subscriptions_limit() {
+        let mut client = Client::default();
+        let limit = Some(3);
+        for i in 0..3u8 {
+            assert!(client.allowed_subscriptions(limit) > 0);
+            let scripthash = ScriptHash::hash(&[i]);
+            client
+                .scripthashes
+                .insert(scripthash, ScriptHashStatus::new(scripthash));
+        }
+        assert_eq!(client.subscriptions(), 3);
+        assert_eq!(client.allowed_subscriptions(limit), 0); // the 4th subscription is rejected
+        assert_eq!(client.allowed_subscriptions(None), usize::MAX);
+
+        client.scripthashes.remove(&ScriptHash::hash(&[0]));
+        assert_eq!(client.allowed_subscriptions(limit), 1);
+    }
+
+    #[test]
+    fn test_subscribe_rejects_above_limit() {
+        let mut client = Client::default();
+        let scripthashes: Vec<ScriptHash> = (0..4u8).map(|i| ScriptHash::hash(&[i])).collect();
+        let sync = |scripthash: ScriptHash| Ok(ScriptHashStatus::new(scripthash));
+
+        let results: Vec<_> = client.subscribe(&scripthashes, Some(3), sync).collect();
+        assert!(results[..3].iter().all(|result| result.is_ok()));
+        let err = results[3].as_ref().unwrap_err();
+        assert_eq!(err.to_string(), "too many subscriptions (limit: 3)");
+        assert_eq!(client.subscriptions(), 3);
+
+        // existing subscriptions are still served, and sync errors are returned
+        let failed = |_: ScriptHash| Err(anyhow!("sync failed"));
+        let results: Vec<_> = client.subscribe(&scripthashes, Some(4), failed).collect();
+        assert!(results[..3].iter().all(|result| result.is_ok()));
+        assert_eq!(results[3].as_ref().unwrap_err().to_string(), "sync failed");
+        assert_eq!(client.subscriptions(), 3);
+    }
+
+    #[test]
+    fn test_duplicate_subscriptions() {
+        let mut client = Client::default();
+        let scripthashes: Vec<ScriptHash> = (0..3u8).map(|i| ScriptHash::hash(&[i])).collect();
+        // duplicates in a single batch are synced once
+        let request = [scripthashes[0], scripthashes[1], scripthashes[0]];
+        assert_eq!(
+            client.new_subscriptions(&request, None),
+            vec![scripthashes[0], scripthashes[1]]
+        );
+        for scripthash in &scripthashes[..2] {
+            client
+                .scripthashes
+                .insert(*scripthash, ScriptHashStatus::new(*scripthash));
+        }
+
+        // subscribing again doesn't trigger a new sync
+        assert!(client.new_subscriptions(&request, None).is_empty());
+        assert_eq!(
+            client.new_subscriptions(&scripthashes, None),
+            vec![scripthashes[2]]
+        );
+        assert_eq!(
+            client.new_subscriptions(&scripthashes, Some(3)),
+            vec![scripthashes[2]]
+        );
+        // the limit is reached, but existing subscriptions are still served
+        assert!(client.new_subscriptions(&scripthashes, Some(2)).is_empty());
+    }
+
+    #[test]
+    fn test_group_txid_prefixes_by_height() {
+        let prefixes: Vec<[u8; HASH_PREFIX_LEN]> = (0..4u8).map(|i| [i; HASH_PREFIX_LEN]).collect();
+        assert_eq!(group_txid_prefixes_by_height(vec![]), Vec::<Value>::new());
+        assert_eq!(
+            json!(group_txid_prefixes_by_height(vec![
+                (5, prefixes[0]),
+                (5, prefixes[1]),
+                (6, prefixes[2]),
+                (8, prefixes[3]),
+            ])),
+            json!([
+                {"height": 5, "txid_prefixes": ["0000000000000000", "0101010101010101"]},
+                {"height": 6, "txid_prefixes": ["0202020202020202"]},
+                {"height": 8, "txid_prefixes": ["0303030303030303"]},
+            ])
+        );
+    }
+
+    #[test]
+    fn test_tip_json() {
+        let chain = Chain::new(Network::Regtest);
+        let genesis = chain.tip();
+        assert_eq!(tip_json(&chain)["hash"], json!(genesis));
+        assert_eq!(tip_json(&chain)["height"], json!(0));
+
+        let blocks = make_blocks(vec![
+            vec![coinbase(1, vec![txout(50, script(1))])],
+            vec![coinbase(2, vec![txout(50, script(2))])],
+        ]);
+        let chain = make_chain(&blocks);
+        assert_eq!(
+            tip_json(&chain),
+            json!({
+                "height": 2,
+                "hash": blocks[1].block_hash(),
+                "header_hex": serialize(&blocks[1].header).to_hex(),
+            })
+        );
+        assert_eq!(tip_json(&chain)["hash"], json!(chain.tip()));
+    }
+
+    #[test]
+    fn test_outpoint_proof() {
+        let block = load_block("000000000000000002d249a3d89f63ef3fee203adcca7c24008c13fd854513f2");
+        let txids: Vec<Txid> = block.txdata.iter().map(|tx| tx.txid()).collect();
+        let position = 6;
+        let tx = &block.txdata[position];
+        let headers = json!({"count": 1, "hex": serialize(&block.header).to_hex(), "max": 2016});
+        let proof = Proof::create(&txids, position);
+        let result = outpoint_proof(100, &proof, &tx.output[0], headers, None);
+
+        // verify the proof against the returned header (as an SPV client would)
+        let header_hex = result["headers"]["hex"].as_str().unwrap();
+        let header: BlockHeader = deserialize(&Vec::from_hex(&header_hex[..160]).unwrap()).unwrap();
+        let branch: Vec<String> = serde_json::from_value(result["merkle"].clone()).unwrap();
+        let position = result["pos"].as_u64().unwrap() as usize;
+        assert_eq!(
+            merkle_root(&tx.txid(), position, &branch),
+            header.merkle_root
+        );
+        assert_eq!(result["value"], json!(tx.output[0].value));
+        assert_eq!(result["spent_by"], Value::Null);
+    }
+
+    #[test]
+    fn test_convert_script() {
+        let script = script(1);
+        let (scripthash,) = convert_script(json!([script.to_hex()])).unwrap();
+        assert_eq!(scripthash, ScriptHash::new(&script));
+
+        let params = Params::parse(
+            "blockchain.scriptpubkey.get_history",
+            json!([script.to_hex()]),
+            Network::Regtest,
+        );
+        match params {
+            Ok(Params::ScriptHashGetHistory((scripthash,))) => {
+                assert_eq!(scripthash, ScriptHash::new(&script))
+            }
+            _ => panic!("unexpected params"),
+        }
+
+        assert!(convert_script(json!(["zz"])).is_err()); // not hex
+
+        // non-parsable scripts can still be used as outputs
+        let (scripthash,) = convert_script(json!(["4c"])).unwrap(); // truncated push
+        assert_eq!(scripthash, ScriptHash::new(&Script::from(vec![0x4c])));
+        assert!(convert_script(json!([1])).is_err());
+        assert!(convert_script(json!([])).is_err());
+    }
+
+    #[test]
+    fn test_convert_address() {
+        // BIP-350 test vector (P2TR)
+        let p2tr = "bc1p0xlxvlhemja6c4dqv22uapctqupfhlxm9h8z3k2e72q4k9hcz7vqzk5jj0";
+        let (scripthash,) = convert_address(json!([p2tr]), Network::Bitcoin).unwrap();
+        let script = Script::from(
+            Vec::from_hex("512079be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798")
+                .unwrap(),
+        );
+        assert_eq!(scripthash, ScriptHash::new(&script));
+        assert_eq!(
+            scripthash.to_hex(),
+            "a12cf1aa7c74a6e9f54984646526173abed2a9f4a4862dc83eb94e8e8ef5220a"
+        );
+
+        let params = Params::parse(
+            "blockchain.address.get_history",
+            json!([p2tr]),
+            Network::Bitcoin,
+        );
+        match params {
+            Ok(Params::ScriptHashGetHistory((params_scripthash,))) => {
+                assert_eq!(params_scripthash, scripthash)
+            }
+            _ => panic!("unexpected params"),
+        }
+
+        let invalid = |address: &str, network| convert_address(json!([address]), network).is_err();
+        // witness v1 program with a bech32 checksum
+        assert!(invalid(
+            "bc1p0xlxvlhemja6c4dqv22uapctqupfhlxm9h8z3k2e72q4k9hcz7vqh2y7hd",
+            Network::Bitcoin
+        ));
+        // witness v0 program with a bech32m checksum
+        assert!(invalid(
+            "bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kemeawh",
+            Network::Bitcoin
+        ));
+        assert!(invalid(p2tr, Network::Testnet));
+        assert!(invalid(p2tr, Network::Regtest));
+        assert!(invalid("", Network::Bitcoin));
+
+        let p2wpkh = "tb1qw508d6qejxtdg4y5r3zarvary0c5xw7kxpjzsx";
+        assert!(!invalid(p2wpkh, Network::Testnet));
+        assert!(!invalid(p2wpkh, Network::Signet));
+        assert!(invalid(p2wpkh, Network::Regtest));
+        assert!(invalid(p2wpkh, Network::Bitcoin));
+    }
+
+    #[test]
+    fn test_disabled_method() {
+        let disabled: HashSet<String> = vec![
+            "blockchain.transaction.broadcast".to_owned(),
+            "blockchain.scripthash.get_history".to_owned(),
+        ]
+        .into_iter()
+        .collect();
+        let parse = |method: &str, params: Value| {
+            let request = Request {
+                id: json!(7),
+                method: method.to_owned(),
+                params,
+            };
+            Call::parse(request, Network::Bitcoin, &disabled)
+        };
+
+        let err = parse("blockchain.transaction.broadcast", json!(["00"])).err();
+        assert_eq!(
+            err,
+            Some(json!({
+                "jsonrpc": "2.0",
+                "id": 7,
+                "error": {"code": -32601, "message": "method disabled"}
+            }))
+        );
+        assert!(parse("server.ping", json!([])).is_ok());
+        assert!(parse("blockchain.headers.subscribe", json!([])).is_ok());
+
+        // aliases are disabled together with the methods they are serving
+        let p2pkh = "1BvBMSEYstWetqTFn5Au4m4GFg7xJaNVN2";
+        let script = "76a914" /* OP_DUP OP_HASH160 */
+            .to_owned()
+            + "77bff20c60e522dfaa3350c39b030a5d004e839a"
+            + "88ac"; /* OP_EQUALVERIFY OP_CHECKSIG */
+        assert!(parse("blockchain.address.get_history", json!([p2pkh])).is_err());
+        assert!(parse("blockchain.scriptpubkey.get_history", json!([script])).is_err());
+        assert!(parse("blockchain.descriptor.scan", json!(["pkh(xpub/0/*)", 0, 1])).is_err());
+        assert!(parse("blockchain.address.get_balance", json!([p2pkh])).is_ok());
+        assert!(parse(
+            "blockchain.scripthash.get_balance",
+            json!(["00".repeat(32)])
+        )
+        .is_ok());
+
+        assert!(is_known_method("blockchain.address.get_history"));
+        assert!(is_known_method("server.ping"));
+        assert!(!is_known_method("blockchain.transaction.brodcast"));
+    }
+
+    #[test]
+    fn test_error_codes() {
+        let call = Call {
+            id: json!(3),
+            method: "blockchain.transaction.get_merkle".to_owned(),
+            params: Params::Ping,
+        };
+        let error = |err: anyhow::Error| call.response(Err(err))["error"].clone();
+
+        let txid =
+            Txid::from_hex("cf9bc1dd2c25d2ce4fab7bf5bcbbf94a3a1dbc7cc2d7e8cba3ee73e7edf8a9f3")
+                .unwrap();
+        // found even when wrapped by a context
+        let not_found = anyhow::Error::from(QueryError::TxNotFound(txid)).context("failed");
+        assert_eq!(
+            error(not_found),
+            json!({"code": 2, "message": format!("unknown transaction {}", txid)})
+        );
+        assert_eq!(
+            error(QueryError::HistoryTooLarge(100).into()),
+            json!({"code": 1, "message": "history too large (>100 index entries)"})
+        );
+        assert_eq!(
+            error(QueryError::HistoryTooLong(1000).into()),
+            json!({"code": 1, "message": "history too long (>1000 entries)"})
+        );
+        let blockhash = BlockHash::hash(&[1]);
+        assert_eq!(
+            error(QueryError::PrunedBlock(blockhash).into()),
+            json!({"code": 2, "message": format!("block {} was pruned by bitcoind", blockhash)})
+        );
+        assert_eq!(
+            error(QueryError::ResponseTooLarge(100).into()),
+            json!({"code": -32603, "message": "response too large (limit: 100 bytes)"})
+        );
+        assert_eq!(
+            error(anyhow!("something else")),
+            json!({"code": 1, "message": "something else"})
+        );
+    }
+
+    #[test]
+    fn test_negotiate_version() {
+        let v = |s: &str| s.parse::<ProtocolVersion>().unwrap();
+        let single = |s: &str| Version::Single(s.to_owned());
+        let range = |min: &str, max: &str| Version::Range(min.to_owned(), max.to_owned());
+
+        let (min, max) = (v("1.4"), v("1.4"));
+        assert_eq!(
+            negotiate_version(&single("1.4"), &min, &max),
+            Some(v("1.4"))
+        );
+        assert_eq!(
+            negotiate_version(&range("1.2", "1.5"), &min, &max),
+            Some(v("1.4"))
+        );
+        // unsupported versions are rejected
+        assert_eq!(negotiate_version(&single("1.2"), &min, &max), None);
+        assert_eq!(negotiate_version(&range("1.0", "1.2"), &min, &max), None);
+        assert_eq!(negotiate_version(&range("1.5", "2.0"), &min, &max), None);
+        assert_eq!(negotiate_version(&single("invalid"), &min, &max), None);
+
+        let (min, max) = (v("1.2"), v("1.4.2"));
+        assert_eq!(
+            negotiate_version(&single("1.3"), &min, &max),
+            Some(v("1.3"))
+        );
+        assert_eq!(
+            negotiate_version(&range("1.4", "1.5"), &min, &max),
+            Some(v("1.4.2"))
+        );
+    }
+
+    #[test]
+    fn test_batch_notifications() {
+        let scripthashes: Vec<ScriptHash> = (0..3).map(|i| ScriptHash::hash(&[i])).collect();
+        let mut client = Client::default();
+        for scripthash in &scripthashes {
+            client
+                .scripthashes
+                .insert(*scripthash, ScriptHashStatus::new(*scripthash));
+        }
+        let changed = vec![scripthashes[0], scripthashes[2]];
+
+        let notifications = client.status_notifications(changed.clone());
+        assert_eq!(notifications.len(), 2);
+        assert_eq!(
+            notifications[1],
+            json!({
+                "jsonrpc": "2.0",
+                "method": "blockchain.scripthash.subscribe",
+                "params": [scripthashes[2], null],
+            })
+        );
+
+        client.batch_notifications = true;
+        assert_eq!(
+            client.status_notifications(changed),
+            vec![json!({
+                "jsonrpc": "2.0",
+                "method": "blockchain.scripthashes.status",
+                "params": [[scripthashes[0], null], [scripthashes[2], null]],
+            })]
+        );
+        assert_eq!(client.status_notifications(vec![]), Vec::<Value>::new());
+
+        // unsubscribed scripthashes are not notified
+        client.scripthashes.remove(&scripthashes[0]);
+        let notifications = client.status_notifications(vec![scripthashes[0], scripthashes[1]]);
+        assert_eq!(notifications[0]["params"], json!([[scripthashes[1], null]]));
+    }
+
+    #[test]
+    fn test_client_guard() {
+        let connected = Arc::new(AtomicUsize::new(0));
+        let gauge = metrics().gauge("clients", "# of clients", "state");
+        let new_client = || Client {
+            guard: Some(ClientGuard::new(&connected, &gauge)),
+            ..Client::default()
+        };
+
+        let mut client = new_client();
+        for i in 0..3 {
+            let scripthash = ScriptHash::hash(&[i]);
+            client
+                .scripthashes
+                .insert(scripthash, ScriptHashStatus::new(scripthash));
+        }
+        let other = new_client();
+        assert_eq!(connected.load(Ordering::SeqCst), 2);
+        assert_eq!(client.subscriptions(), 3);
+
+        drop(client); // releases its subscriptions and its connection count
+        assert_eq!(connected.load(Ordering::SeqCst), 1);
+
+        // released also when unwinding
+        let result = std::panic::catch_unwind(AssertUnwindSafe(|| {
+            let _client = new_client();
+            assert_eq!(connected.load(Ordering::SeqCst), 2);
+            panic!("client handler failed");
+        }));
+        assert!(result.is_err());
+        assert_eq!(connected.load(Ordering::SeqCst), 1);
+
+        drop(other);
+        assert_eq!(connected.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn test_coalesce() {
+        let mut client = Client::default();
+        let (a, b) = (ScriptHash::hash(&[1]), ScriptHash::hash(&[2]));
+        let t0 = Instant::now();
+        let ms = Duration::from_millis;
+
+        // without coalescing, changes are notified immediately
+        assert_eq!(client.coalesce(vec![a], None, t0), vec![a]);
+        assert_eq!(client.next_notification(None), None);
+
+        // rapid changes result in a single notification
+        let window = Some(ms(100));
+        assert!(client.coalesce(vec![a], window, t0).is_empty());
+        assert!(client.coalesce(vec![a], window, t0 + ms(30)).is_empty());
+        assert!(client.coalesce(vec![a, b], window, t0 + ms(60)).is_empty());
+        assert_eq!(client.next_notification(window), Some(t0 + ms(100)));
+        // the final notification fires after the window, even without further changes
+        assert_eq!(client.coalesce(vec![], window, t0 + ms(100)), vec![a]);
+        assert_eq!(client.next_notification(window), Some(t0 + ms(160)));
+        assert!(client.coalesce(vec![], window, t0 + ms(120)).is_empty());
+        assert_eq!(client.coalesce(vec![], window, t0 + ms(160)), vec![b]);
+        assert_eq!(client.next_notification(window), None);
+        assert!(client.coalesce(vec![], window, t0 + ms(500)).is_empty());
+    }
+}
@@ -0,0 +1,44 @@
+use bitcoin::{BlockHash, Txid};
+
+use std::fmt;
+
+/// Query failures which are reported to Electrum clients with specific error codes
+/// (following ElectrumX), instead of the generic "bad request" one.
+///
+/// They are returned wrapped by `anyhow::Error` (possibly with additional context),
+/// and found by downcasting when the RPC response is created.
+#[derive(Clone, Debug)]
+pub(crate) enum QueryError {
+    /// The transaction is neither confirmed nor in the mempool
+    TxNotFound(Txid),
+    /// More index entries than `index_lookup_limit` would have to be scanned
+    HistoryTooLarge(usize),
+    /// The history has more (confirmed and mempool) entries than `--max-history`
+    HistoryTooLong(usize),
+    /// The block is not available, since it was pruned by `bitcoind`
+    PrunedBlock(BlockHash),
+    /// The response would be larger than `--max-response-mb` (checked before building it)
+    ResponseTooLarge(usize),
+}
+
+impl fmt::Display for QueryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            QueryError::TxNotFound(txid) => write!(f, "unknown transaction {}", txid),
+            QueryError::HistoryTooLarge(limit) => {
+                write!(f, "history too large (>{} index entries)", limit)
+            }
+            QueryError::HistoryTooLong(limit) => {
+                write!(f, "history too long (>{} entries)", limit)
+            }
+            QueryError::PrunedBlock(blockhash) => {
+                write!(f, "block {} was pruned by bitcoind", blockhash)
+            }
+            QueryError::ResponseTooLarge(limit) => {
+                write!(f, "response too large (limit: {} bytes)", limit)
+            }
+        }
+    }
+}
+
+impl std::error::Error for QueryError {}
@@ -1,14 +1,23 @@
 use anyhow::{Context, Result};
 use bitcoin::consensus::{deserialize, serialize};
+use bitcoin::util::bip158::BlockFilterWriter;
 use bitcoin::{Block, BlockHash, OutPoint, Txid};
 
+use std::ops::RangeInclusive;
+use std::time::Instant;
+
 use crate::{
     chain::{Chain, NewHeader},
+    config::{Config, IndexMode},
     daemon::Daemon,
     db::{DBStore, Row, WriteBatch},
-    metrics::{self, Gauge, Histogram, Metrics},
+    errors::QueryError,
+    metrics::{self, Gauge, Histogram, Metrics, Readiness},
     signals::ExitFlag,
-    types::{HashPrefixRow, HeaderRow, ScriptHash, ScriptHashRow, SpendingPrefixRow, TxidRow},
+    types::{
+        opreturn_data, DataRow, FilterHeader, FilterRow, HashPrefixRow, HeaderRow, ScriptHash,
+        ScriptHashRow, SpendingPrefixRow, TxidRow, HASH_PREFIX_LEN,
+    },
 };
 
 #[derive(Clone)]
@@ -17,6 +26,7 @@ struct Stats {
     update_size: Histogram,
     height: Gauge,
     db_properties: Gauge,
+    readiness: Readiness,
 }
 
 impl Stats {
@@ -36,6 +46,7 @@ impl Stats {
             ),
             height: metrics.gauge("index_height", "Indexed block height", "type"),
             db_properties: metrics.gauge("index_db_properties", "Index DB properties", "name"),
+            readiness: metrics.readiness(),
         }
     }
 
@@ -52,6 +63,7 @@ impl Stats {
         self.observe_size("write_spending_rows", &batch.spending_rows);
         self.observe_size("write_txid_rows", &batch.txid_rows);
         self.observe_size("write_header_rows", &batch.header_rows);
+        self.observe_size("write_data_rows", &batch.data_rows);
         debug!(
             "writing {} funding and {} spending rows from {} transactions, {} blocks",
             batch.funding_rows.len(),
@@ -96,14 +108,61 @@ impl IndexResult {
     }
 }
 
+// Pruning requires a full DB scan, so it is done at most once per this number of blocks
+const PRUNE_INTERVAL: usize = 144;
+
+/// Indexing options (see `--index-*`, `--keep-blocks` and `--reindex-last-blocks`)
+#[derive(Clone, Debug)]
+pub(crate) struct IndexConfig {
+    pub(crate) batch_size: usize,
+    pub(crate) lookup_limit: Option<usize>,
+    pub(crate) keep_blocks: Option<usize>,
+    pub(crate) reindex_last_blocks: usize,
+    pub(crate) index_filters: bool,
+    pub(crate) index_opreturn: bool,
+    pub(crate) index_mode: IndexMode,
+    pub(crate) track_lag: bool, // needed only for `--max-serving-lag-blocks`
+}
+
+impl IndexConfig {
+    pub(crate) fn new(config: &Config) -> Self {
+        Self {
+            batch_size: config.index_batch_size,
+            lookup_limit: config.index_lookup_limit,
+            keep_blocks: config.keep_blocks,
+            reindex_last_blocks: config.reindex_last_blocks,
+            index_filters: config.index_filters,
+            index_opreturn: config.index_opreturn,
+            index_mode: config.index_mode,
+            track_lag: config.max_serving_lag_blocks.is_some(),
+        }
+    }
+}
+
+impl Default for IndexConfig {
+    fn default() -> Self {
+        Self {
+            batch_size: 10,
+            lookup_limit: None,
+            keep_blocks: None,
+            reindex_last_blocks: 0,
+            index_filters: false,
+            index_opreturn: false,
+            index_mode: IndexMode::default(),
+            track_lag: false,
+        }
+    }
+}
+
 /// Confirmed transactions' address index
 pub struct Index {
     store: DBStore,
-    batch_size: usize,
-    lookup_limit: Option<usize>,
+    config: IndexConfig,
+    pruned_height: usize, // rows below this height were deleted (see `--keep-blocks`)
     chain: Chain,
     stats: Stats,
     is_ready: bool,
+    lag: usize, // number of blocks left to index (after the last sync)
 }
 
 impl Index {
@@ -111,11 +170,28 @@ impl Index {
         store: DBStore,
         mut chain: Chain,
         metrics: &Metrics,
-        batch_size: usize,
-        lookup_limit: Option<usize>,
-        reindex_last_blocks: usize,
+        config: IndexConfig,
     ) -> Result<Self> {
+        let index_mode = config.index_mode;
+        let index_filters = config.index_filters;
         if let Some(row) = store.get_tip() {
+            let stored_mode = store.index_mode();
+            if stored_mode != index_mode {
+                bail!(
+                    "re-index required due to index mode {} != {} (see `--index-mode`)",
+                    stored_mode,
+                    index_mode
+                );
+            }
+            if index_filters && !store.index_filters() {
+                bail!("re-index required for indexing block filters (see `--index-filters`)");
+            }
+            if !index_filters && store.index_filters() {
+                // the filters' chain will be missing the next blocks
+                store.set_index_filters(false);
+            }
+            // all headers are loaded in a single scan, so the chain is served from memory
+            let start = Instant::now();
             let tip = deserialize(&row).expect("invalid tip");
             let headers = store
                 .read_headers()
@@ -123,18 +199,31 @@ impl Index {
                 .map(|row| HeaderRow::from_db_row(&row).header)
                 .collect();
             chain.load(headers, tip);
-            chain.drop_last_headers(reindex_last_blocks);
-        };
+            info!(
+                "loaded {} headers in {:.3}s",
+                chain.height(),
+                start.elapsed().as_secs_f64()
+            );
+            chain.drop_last_headers(config.reindex_last_blocks);
+        } else {
+            store.set_index_mode(index_mode); // recorded by a new (empty) DB
+            store.set_index_filters(index_filters);
+        }
         let stats = Stats::new(metrics);
         stats.observe_chain(&chain);
         stats.observe_db(&store);
+        let pruned_height = store.pruned_height();
+        if pruned_height > 0 {
+            info!("index rows below height {} were pruned", pruned_height);
+        }
         Ok(Index {
             store,
-            batch_size,
-            lookup_limit,
+            config,
+            pruned_height,
             chain,
             stats,
             is_ready: false,
+            lag: 0,
         })
     }
 
@@ -144,12 +233,12 @@ impl Index {
 
     pub(crate) fn limit_result<T>(&self, entries: impl Iterator<Item = T>) -> Result<Vec<T>> {
         let mut entries = entries.fuse();
-        let result: Vec<T> = match self.lookup_limit {
+        let result: Vec<T> = match self.config.lookup_limit {
             Some(lookup_limit) => entries.by_ref().take(lookup_limit).collect(),
             None => entries.by_ref().collect(),
         };
         if entries.next().is_some() {
-            bail!(">{} index entries, query may take too long", result.len())
+            return Err(QueryError::HistoryTooLarge(result.len()).into());
         }
         Ok(result)
     }
@@ -181,6 +270,71 @@ impl Index {
             .filter_map(move |height| self.chain.get_block_hash(height))
     }
 
+    /// Blocks containing OP_RETURN outputs with the given data (requires `--index-opreturn`).
+    pub(crate) fn filter_by_data(&self, data: &[u8]) -> impl Iterator<Item = BlockHash> + '_ {
+        self.store
+            .iter_data(DataRow::scan_prefix(data))
+            .map(|row| HashPrefixRow::from_db_row(&row).height())
+            .filter_map(move |height| self.chain.get_block_hash(height))
+    }
+
+    /// The txid prefixes of the transactions confirmed at the given heights (ordered by height),
+    /// read from the TXID rows (so no block is fetched). Requires a full scan of the TXID rows,
+    /// and pruned blocks' transactions are skipped.
+    pub(crate) fn txid_prefixes_in_range(
+        &self,
+        heights: RangeInclusive<usize>,
+    ) -> Result<Vec<(usize, [u8; HASH_PREFIX_LEN])>> {
+        if *heights.end() > self.chain.height() {
+            bail!(
+                "height {} is above the tip ({})",
+                heights.end(),
+                self.chain.height()
+            );
+        }
+        let mut result: Vec<(usize, [u8; HASH_PREFIX_LEN])> = self
+            .store
+            .iter_txid_rows()
+            .map(|row| HashPrefixRow::from_db_row(&row))
+            .filter(|row| heights.contains(&row.height()))
+            .map(|row| (row.height(), row.prefix()))
+            .collect();
+        result.sort_by_key(|(height, _prefix)| *height); // stable, keeping the rows' order
+        Ok(result)
+    }
+
+    /// Counts the indexed transactions (excluding pruned blocks), requiring a full DB scan.
+    /// It doesn't borrow the index, so it can be used from a background thread.
+    pub(crate) fn transactions_counter(&self) -> impl Fn() -> u64 + Send + 'static {
+        self.store.txid_rows_counter()
+    }
+
+    pub(crate) fn indexes_opreturn(&self) -> bool {
+        self.config.index_opreturn
+    }
+
+    /// Fail queries requiring funding rows, if they are not indexed.
+    pub(crate) fn require_funding(&self) -> Result<()> {
+        if !self.config.index_mode.indexes_funding() {
+            bail!("funding rows are not indexed (see `--index-mode`)");
+        }
+        Ok(())
+    }
+
+    /// Fail queries requiring spending rows, if they are not indexed.
+    pub(crate) fn require_spending(&self) -> Result<()> {
+        if !self.config.index_mode.indexes_spending() {
+            bail!("spending rows are not indexed (see `--index-mode`)");
+        }
+        Ok(())
+    }
+
+    pub(crate) fn get_filter(&self, blockhash: BlockHash) -> Option<FilterRow> {
+        self.store
+            .get_filter(&serialize(&blockhash))
+            .map(|row| FilterRow::from_db_row(&row))
+    }
+
     // Return `Ok(true)` when the chain is fully synced and the index is compacted.
     pub(crate) fn sync(&mut self, daemon: &Daemon, exit_flag: &ExitFlag) -> Result<bool> {
         let new_headers = self
@@ -195,14 +349,18 @@ impl Index {
                     first.height(),
                     last.height()
                 );
+                self.stats.readiness.set(self.is_ready, count);
             }
             _ => {
+                self.prune_if_needed();
                 self.store.flush(); // full compaction is performed on the first flush call
                 self.is_ready = true;
+                self.lag = 0;
+                self.stats.readiness.set(true, 0);
                 return Ok(true); // no more blocks to index (done for now)
             }
         }
-        for chunk in new_headers.chunks(self.batch_size) {
+        for chunk in new_headers.chunks(self.config.batch_size) {
             exit_flag.poll().with_context(|| {
                 format!(
                     "indexing interrupted at height: {}",
@@ -213,6 +371,13 @@ impl Index {
         }
         self.chain.update(new_headers);
         self.stats.observe_chain(&self.chain);
+        // more headers may be left, since their p2p response is limited to 2000 headers
+        // (the lag matters only after the initial sync, and only if it's limited)
+        if self.is_ready && self.config.track_lag {
+            self.lag = daemon
+                .get_block_count()?
+                .saturating_sub(self.chain.height());
+        }
         Ok(false) // sync is not done
     }
 
@@ -221,11 +386,9 @@ impl Index {
         let mut heights = chunk.iter().map(|h| h.height());
 
         let mut batch = WriteBatch::default();
-        daemon.for_blocks(blockhashes, |_blockhash, block| {
+        daemon.for_blocks(&self.chain, blockhashes, |_blockhash, block| {
             let height = heights.next().expect("unexpected block");
-            self.stats.observe_duration("block", || {
-                index_single_block(block, height).extend(&mut batch)
-            });
+            self.index_block(&mut batch, block, height);
             self.stats.height.set("tip", height as f64);
         })?;
         let heights: Vec<_> = heights.collect();
@@ -242,16 +405,182 @@ impl Index {
         Ok(())
     }
 
+    fn index_block(&self, batch: &mut WriteBatch, block: Block, height: usize) {
+        if self.config.index_filters {
+            let key = serialize(&block.block_hash()).into_boxed_slice();
+            let prev_header = self.get_filter_header(batch, block.header.prev_blockhash);
+            let row = self.stats.observe_duration("filter", || {
+                FilterRow::new(compute_block_filter(&block), &prev_header)
+            });
+            batch.filter_rows.push((key, row.to_db_row()));
+        }
+        if self.config.index_opreturn {
+            let data_rows = self
+                .stats
+                .observe_duration("opreturn", || index_opreturn_data(&block, height));
+            batch
+                .data_rows
+                .extend(data_rows.iter().map(HashPrefixRow::to_db_row));
+        }
+        let index_mode = self.config.index_mode;
+        self.stats.observe_duration("block", || {
+            index_single_block(block, height, index_mode).extend(batch)
+        });
+    }
+
+    /// Look for the filter header in the current batch, then in the DB.
+    /// Missing headers (for genesis or blocks indexed without filters) are treated as zeroes.
+    fn get_filter_header(&self, batch: &WriteBatch, blockhash: BlockHash) -> FilterHeader {
+        let key = serialize(&blockhash);
+        batch
+            .filter_rows
+            .iter()
+            .find(|(row_key, _)| row_key[..] == key[..])
+            .map(|(_, row)| FilterRow::from_db_row(row).header)
+            .or_else(|| self.get_filter(blockhash).map(|row| row.header))
+            .unwrap_or_default()
+    }
+
+    /// Index the given blocks on top of the current tip (without fetching them from the daemon).
+    #[cfg(test)]
+    pub(crate) fn index_blocks(&mut self, blocks: Vec<Block>) {
+        let first_height = self.chain.height() + 1;
+        let new_headers: Vec<NewHeader> = blocks
+            .iter()
+            .zip(first_height..)
+            .map(|(block, height)| NewHeader::from((block.header, height)))
+            .collect();
+        let mut batch = WriteBatch::default();
+        for (block, height) in blocks.into_iter().zip(first_height..) {
+            self.index_block(&mut batch, block, height);
+        }
+        batch.sort();
+        self.store.write(&batch);
+        self.chain.update(new_headers);
+    }
+
+    /// Return `true` if older blocks' rows may be missing from the index.
+    pub(crate) fn is_pruned(&self) -> bool {
+        self.config.keep_blocks.is_some() || self.pruned_height > 0
+    }
+
+    /// The rows of the blocks below this height were deleted (so their queries' results are partial).
+    pub(crate) fn pruned_height(&self) -> Option<usize> {
+        match self.pruned_height {
+            0 => None,
+            height => Some(height),
+        }
+    }
+
+    fn prune_if_needed(&mut self) {
+        if let Some(keep_blocks) = self.config.keep_blocks {
+            let height = self.chain.height().saturating_sub(keep_blocks);
+            if height >= self.pruned_height + PRUNE_INTERVAL {
+                self.prune_below(height);
+            }
+        }
+    }
+
+    /// Delete the funding, spending and txid rows of blocks below `height` (keeping their headers).
+    fn prune_below(&mut self, height: usize) {
+        let start = Instant::now();
+        let deleted = self
+            .store
+            .delete_rows(|row| HashPrefixRow::from_db_row(row).height() < height);
+        self.pruned_height = height;
+        self.store.set_pruned_height(height); // the next pruning can skip the deleted rows
+        info!(
+            "pruned {} rows below height {} in {:.3}s",
+            deleted,
+            height,
+            start.elapsed().as_secs_f64()
+        );
+    }
+
+    /// Re-index the stored blocks, and verify that the resulting rows exist in the DB.
+    pub(crate) fn verify(&self, daemon: &Daemon, exit_flag: &ExitFlag) -> Result<()> {
+        let heights: Vec<usize> = (1..=self.chain.height()).collect();
+        let mut checked = 0;
+        let mut missing = 0;
+        for chunk in heights.chunks(self.config.batch_size) {
+            exit_flag
+                .poll()
+                .with_context(|| format!("verification interrupted at height: {}", chunk[0]))?;
+            let blockhashes = chunk.iter().map(|height| {
+                self.chain
+                    .get_block_hash(*height)
+                    .expect("missing block hash")
+            });
+            let mut heights = chunk.iter();
+            daemon.for_blocks(&self.chain, blockhashes, |blockhash, block| {
+                let height = *heights.next().expect("unexpected block");
+                for (cf, count) in self.verify_block(block, height) {
+                    warn!(
+                        "block {} at height {}: {} missing {} rows",
+                        blockhash, height, count, cf
+                    );
+                    missing += count;
+                }
+                checked += 1;
+            })?;
+        }
+        info!("verified {} blocks: {} missing rows", checked, missing);
+        if missing > 0 {
+            bail!("index verification failed: {} missing rows", missing);
+        }
+        Ok(())
+    }
+
+    fn verify_block(&self, block: Block, height: usize) -> Vec<(&'static str, usize)> {
+        let mut batch = WriteBatch::default();
+        self.index_block(&mut batch, block, height);
+        self.store.find_missing(&batch)
+    }
+
+    /// Full compactions run in a background thread, since they may take a while.
+    pub(crate) fn compact_if_needed(&self) {
+        if self.is_ready {
+            self.store.compact_if_needed();
+        }
+    }
+
     pub(crate) fn is_ready(&self) -> bool {
         self.is_ready
     }
+
+    pub(crate) fn lag(&self) -> usize {
+        self.lag
+    }
 }
 
 fn db_rows_size(rows: &[Row]) -> usize {
     rows.iter().map(|key| key.len()).sum()
 }
 
-fn index_single_block(block: Block, height: usize) -> IndexResult {
+/// Note that spent outputs' scripts are not available while indexing,
+/// so only the block's output scripts are added to the filter.
+fn compute_block_filter(block: &Block) -> Vec<u8> {
+    let mut filter = vec![];
+    let mut writer = BlockFilterWriter::new(&mut filter, block);
+    writer.add_output_scripts();
+    writer.finish().expect("failed to write block filter");
+    filter
+}
+
+/// OP_RETURN outputs are not indexed as funding rows (being provably unspendable),
+/// so their (non-empty) data is indexed separately (if enabled).
+fn index_opreturn_data(block: &Block, height: usize) -> Vec<HashPrefixRow> {
+    block
+        .txdata
+        .iter()
+        .flat_map(|tx| &tx.output)
+        .filter_map(|txo| opreturn_data(&txo.script_pubkey))
+        .filter(|data| !data.is_empty())
+        .map(|data| DataRow::row(&data, height))
+        .collect()
+}
+
+fn index_single_block(block: Block, height: usize, index_mode: IndexMode) -> IndexResult {
     let mut funding_rows = Vec::with_capacity(block.txdata.iter().map(|tx| tx.output.len()).sum());
     let mut spending_rows = Vec::with_capacity(block.txdata.iter().map(|tx| tx.input.len()).sum());
     let mut txid_rows = Vec::with_capacity(block.txdata.len());
@@ -259,24 +588,26 @@ fn index_single_block(block: Block, height: usize) -> IndexResult {
     for tx in &block.txdata {
         txid_rows.push(TxidRow::row(tx.txid(), height));
 
-        funding_rows.extend(
-            tx.output
-                .iter()
-                .filter(|txo| !txo.script_pubkey.is_provably_unspendable())
-                .map(|txo| {
-                    let scripthash = ScriptHash::new(&txo.script_pubkey);
-                    ScriptHashRow::row(scripthash, height)
-                }),
-        );
+        if index_mode.indexes_funding() {
+            funding_rows.extend(
+                tx.output
+                    .iter()
+                    .filter(|txo| !txo.script_pubkey.is_provably_unspendable())
+                    .map(|txo| {
+                        let scripthash = ScriptHash::new(&txo.script_pubkey);
+                        ScriptHashRow::row(scripthash, height)
+                    }),
+            );
+        }
 
-        if tx.is_coin_base() {
-            continue; // coinbase doesn't have inputs
+        // coinbase doesn't have inputs
+        if index_mode.indexes_spending() && !tx.is_coin_base() {
+            spending_rows.extend(
+                tx.input
+                    .iter()
+                    .map(|txin| SpendingPrefixRow::row(txin.previous_output, height)),
+            );
         }
-        spending_rows.extend(
-            tx.input
-                .iter()
-                .map(|txin| SpendingPrefixRow::row(txin.previous_output, height)),
-        );
     }
     IndexResult {
         funding_rows,
@@ -285,3 +616,324 @@ fn index_single_block(block: Block, height: usize) -> IndexResult {
         header_row: HeaderRow::new(block.header),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{compute_block_filter, Index, IndexConfig};
+    use crate::chain::Chain;
+    use crate::config::IndexMode;
+    use crate::db::DBStore;
+    use crate::tests::{coinbase, make_blocks, metrics, script, spend, test_index, txout};
+    use crate::types::{opreturn_data, ScriptHash, HASH_PREFIX_LEN};
+    use bitcoin::{
+        blockdata::{opcodes, script::Builder},
+        consensus::deserialize,
+        hashes::Hash,
+        util::bip158::BlockFilter,
+        Block, BlockHash, Network, OutPoint, Script, Txid,
+    };
+
+    #[test]
+    fn test_filter_by_txid() {
+        let tx1 = coinbase(1, vec![txout(100, script(1))]);
+        let tx2 = coinbase(2, vec![txout(200, script(2))]);
+        let tx3 = spend(
+            vec![OutPoint::new(tx1.txid(), 0)],
+            vec![txout(90, script(3))],
+        );
+        let (txid1, txid2, txid3) = (tx1.txid(), tx2.txid(), tx3.txid());
+        let blocks = make_blocks(vec![vec![tx1], vec![tx2, tx3]]);
+        let (hash1, hash2) = (blocks[0].block_hash(), blocks[1].block_hash());
+
+        let test = test_index(blocks);
+        let index = &test.index;
+        assert_eq!(index.chain().height(), 2);
+        assert_eq!(index.filter_by_txid(txid1).collect::<Vec<_>>(), vec![hash1]);
+        assert_eq!(index.filter_by_txid(txid2).collect::<Vec<_>>(), vec![hash2]);
+        assert_eq!(index.filter_by_txid(txid3).collect::<Vec<_>>(), vec![hash2]);
+        assert_eq!(index.filter_by_txid(Txid::hash(b"unknown")).count(), 0);
+    }
+
+    #[test]
+    fn test_txid_prefixes_in_range() {
+        let tx1 = coinbase(1, vec![txout(100, script(1))]);
+        let tx2 = coinbase(2, vec![txout(200, script(2))]);
+        let tx3 = spend(
+            vec![OutPoint::new(tx1.txid(), 0)],
+            vec![txout(90, script(3))],
+        );
+        let tx4 = coinbase(3, vec![txout(300, script(3))]);
+        let tx5 = coinbase(4, vec![txout(400, script(4))]);
+        let prefix = |txid: Txid| {
+            let mut prefix = [0u8; HASH_PREFIX_LEN];
+            prefix.copy_from_slice(&txid[..HASH_PREFIX_LEN]);
+            prefix
+        };
+        // rows of the same block are ordered by their prefixes
+        let mut expected = vec![
+            (1, prefix(tx1.txid())),
+            (2, prefix(tx2.txid())),
+            (2, prefix(tx3.txid())),
+            (3, prefix(tx4.txid())),
+        ];
+        expected.sort();
+        let blocks = make_blocks(vec![vec![tx1], vec![tx2, tx3], vec![tx4], vec![tx5]]);
+        let test = test_index(blocks);
+
+        let prefixes = |heights| test.index.txid_prefixes_in_range(heights);
+        assert_eq!(prefixes(1..=3).unwrap(), expected);
+        assert_eq!(prefixes(2..=2).unwrap(), expected[1..3].to_vec());
+        assert_eq!(
+            prefixes(3..=5).unwrap_err().to_string(),
+            "height 5 is above the tip (4)"
+        );
+    }
+
+    #[test]
+    fn test_count_transactions() {
+        let tx1 = coinbase(1, vec![txout(100, script(1))]);
+        let tx2 = coinbase(2, vec![txout(200, script(2))]);
+        let tx3 = spend(
+            vec![OutPoint::new(tx1.txid(), 0)],
+            vec![txout(90, script(3))],
+        );
+        let blocks = make_blocks(vec![vec![tx1], vec![tx2, tx3]]);
+        let test = test_index(blocks);
+        let count_transactions = test.index.transactions_counter();
+        assert_eq!(count_transactions(), 3);
+        assert_eq!(
+            count_transactions(),
+            test.index.store.stats().column_families[1].rows
+        );
+    }
+
+    #[test]
+    fn test_prune_below() {
+        let txs: Vec<_> = (1..=3)
+            .map(|i| coinbase(i, vec![txout(100, script(1))]))
+            .collect();
+        let txids: Vec<Txid> = txs.iter().map(|tx| tx.txid()).collect();
+        let blocks = make_blocks(txs.into_iter().map(|tx| vec![tx]).collect());
+        let blockhashes: Vec<BlockHash> = blocks.iter().map(Block::block_hash).collect();
+
+        let mut test = test_index(blocks);
+        test.index.prune_below(3);
+
+        assert_eq!(test.index.filter_by_txid(txids[0]).count(), 0);
+        assert_eq!(test.index.filter_by_txid(txids[1]).count(), 0);
+        assert_eq!(
+            test.index.filter_by_txid(txids[2]).collect::<Vec<_>>(),
+            vec![blockhashes[2]]
+        );
+        let scripthash = ScriptHash::new(&script(1));
+        assert_eq!(
+            test.index.filter_by_funding(scripthash).collect::<Vec<_>>(),
+            vec![blockhashes[2]]
+        );
+        // headers are kept
+        assert_eq!(test.index.store.read_headers().len(), 3);
+        assert_eq!(test.index.chain().get_block_hash(1), Some(blockhashes[0]));
+
+        // the pruned height is persisted (even if `--keep-blocks` is not set anymore)
+        let test = test.reload();
+        assert_eq!(test.index.pruned_height(), Some(3));
+        assert!(test.index.is_pruned());
+    }
+
+    #[test]
+    fn test_load_headers() {
+        let txs: Vec<_> = (1..=3)
+            .map(|i| coinbase(i, vec![txout(100, script(1))]))
+            .collect();
+        let blocks = make_blocks(txs.into_iter().map(|tx| vec![tx]).collect());
+        let test = test_index(blocks.clone()).reload();
+
+        let chain = test.index.chain();
+        assert_eq!(chain.height(), 3);
+        assert_eq!(chain.tip(), blocks[2].block_hash());
+        for (height, block) in (1..).zip(&blocks) {
+            let blockhash = block.block_hash();
+            assert_eq!(chain.get_block_hash(height), Some(blockhash));
+            assert_eq!(chain.get_block_height(&blockhash), Some(height));
+            assert_eq!(chain.get_block_header(height), Some(&block.header));
+        }
+    }
+
+    #[test]
+    fn test_verify_block() {
+        let tx1 = coinbase(1, vec![txout(100, script(1))]);
+        let tx2 = spend(
+            vec![OutPoint::new(tx1.txid(), 0)],
+            vec![txout(90, script(2))],
+        );
+        let blocks = make_blocks(vec![vec![tx1], vec![tx2]]);
+        let test = test_index(blocks[..1].to_vec());
+        assert_eq!(test.index.verify_block(blocks[0].clone(), 1), vec![]);
+
+        // the second block was not indexed
+        let missing = test.index.verify_block(blocks[1].clone(), 2);
+        assert_eq!(
+            missing,
+            vec![("headers", 1), ("txid", 1), ("funding", 1), ("spending", 1)]
+        );
+    }
+
+    #[test]
+    fn test_filter_by_data() {
+        let data = b"hello world";
+        let opreturn = Builder::new()
+            .push_opcode(opcodes::all::OP_RETURN)
+            .push_slice(&data[..5])
+            .push_slice(&data[5..])
+            .into_script();
+        let tx1 = coinbase(1, vec![txout(100, script(1))]);
+        let tx2 = coinbase(2, vec![txout(50, script(2)), txout(0, opreturn.clone())]);
+        let blocks = make_blocks(vec![vec![tx1], vec![tx2]]);
+
+        let dir = tempfile::tempdir().unwrap();
+        let store = DBStore::open(dir.path(), true).unwrap();
+        let chain = Chain::new(Network::Regtest);
+        let mut index = Index::load(
+            store,
+            chain,
+            &metrics(),
+            IndexConfig {
+                index_opreturn: true,
+                ..IndexConfig::default()
+            },
+        )
+        .unwrap();
+        index.index_blocks(blocks.clone());
+
+        // OP_RETURN payload is the concatenation of its pushes
+        assert_eq!(opreturn_data(&opreturn).as_deref(), Some(&data[..]));
+        assert_eq!(
+            index.filter_by_data(data).collect::<Vec<_>>(),
+            vec![blocks[1].block_hash()]
+        );
+        assert_eq!(index.filter_by_data(&data[..5]).count(), 0);
+        // regular outputs are not indexed as data
+        assert_eq!(opreturn_data(&script(1)), None);
+        assert_eq!(index.filter_by_data(&script(1)[..]).count(), 0);
+        assert_eq!(index.filter_by_data(&[]).count(), 0);
+
+        // data rows are written only if enabled
+        let test = test_index(blocks);
+        assert_eq!(test.index.filter_by_data(data).count(), 0);
+    }
+
+    #[test]
+    fn test_index_mode() {
+        let tx1 = coinbase(1, vec![txout(100, script(1))]);
+        let tx2 = spend(
+            vec![OutPoint::new(tx1.txid(), 0)],
+            vec![txout(90, script(2))],
+        );
+        let funding = OutPoint::new(tx1.txid(), 0);
+        let blocks = make_blocks(vec![vec![tx1], vec![tx2]]);
+
+        let dir = tempfile::tempdir().unwrap();
+        let load = |index_mode| {
+            let store = DBStore::open(dir.path(), false).unwrap();
+            let chain = Chain::new(Network::Regtest);
+            Index::load(
+                store,
+                chain,
+                &metrics(),
+                IndexConfig {
+                    index_mode,
+                    ..IndexConfig::default()
+                },
+            )
+        };
+        let mut index = load(IndexMode::SpendingOnly).unwrap();
+        index.index_blocks(blocks.clone());
+
+        // funding rows are not written
+        let rows = |name| {
+            let stats = index.store.stats();
+            stats
+                .column_families
+                .iter()
+                .find(|cf| cf.name == name)
+                .unwrap()
+                .rows
+        };
+        assert_eq!(rows("funding"), 0);
+        assert_eq!(rows("spending"), 1);
+        assert_eq!(
+            index.filter_by_funding(ScriptHash::new(&script(1))).count(),
+            0
+        );
+        assert_eq!(
+            index.filter_by_spending(funding).collect::<Vec<_>>(),
+            vec![blocks[1].block_hash()]
+        );
+        assert_eq!(
+            index.require_funding().unwrap_err().to_string(),
+            "funding rows are not indexed (see `--index-mode`)"
+        );
+        assert!(index.require_spending().is_ok());
+        drop(index);
+
+        // the mode is recorded in the DB, so it can't be changed without re-indexing
+        let err = load(IndexMode::Full).err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "re-index required due to index mode spending-only != full (see `--index-mode`)"
+        );
+        let index = load(IndexMode::SpendingOnly).unwrap();
+        assert_eq!(index.chain().height(), 2);
+    }
+
+    #[test]
+    fn test_enable_filters() {
+        let blocks = make_blocks(vec![vec![coinbase(1, vec![txout(100, script(1))])]]);
+        let dir = tempfile::tempdir().unwrap();
+        let load = |index_filters| {
+            let store = DBStore::open(dir.path(), false).unwrap();
+            let chain = Chain::new(Network::Regtest);
+            Index::load(
+                store,
+                chain,
+                &metrics(),
+                IndexConfig {
+                    index_filters,
+                    ..IndexConfig::default()
+                },
+            )
+        };
+        let mut index = load(true).unwrap();
+        index.index_blocks(blocks.clone());
+        assert!(index.get_filter(blocks[0].block_hash()).is_some());
+        drop(index);
+        drop(load(true).unwrap());
+
+        // after disabling the filters, their headers' chain would have a gap
+        drop(load(false).unwrap());
+        let err = load(true).err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "re-index required for indexing block filters (see `--index-filters`)"
+        );
+    }
+
+    #[test]
+    fn test_block_filter() {
+        let data = std::fs::read(
+            "src/tests/blocks/00000000000000001203c1ea455e38612bdf36e9967fdead11935c8e22283ecc",
+        )
+        .unwrap();
+        let block: Block = deserialize(&data).unwrap();
+        let blockhash = block.block_hash();
+        let filter = BlockFilter::new(&compute_block_filter(&block));
+
+        let script = &block.txdata[1].output[0].script_pubkey;
+        let mut query = std::iter::once(script.as_bytes());
+        assert!(filter.match_any(&blockhash, &mut query).unwrap());
+
+        let script = Script::from(vec![0x51]); // OP_TRUE
+        let mut query = std::iter::once(script.as_bytes());
+        assert!(!filter.match_any(&blockhash, &mut query).unwrap());
+    }
+}
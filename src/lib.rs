@@ -14,8 +14,11 @@ mod chain;
 mod config;
 mod daemon;
 mod db;
+mod descriptor;
 mod electrum;
+mod errors;
 mod index;
+mod logfile;
 mod mempool;
 mod merkle;
 mod metrics;
@@ -26,5 +29,9 @@ mod status;
 mod thread;
 mod tracker;
 mod types;
+mod websocket;
+
+#[cfg(test)]
+mod tests;
 
 pub use server::run;
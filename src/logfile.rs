@@ -0,0 +1,107 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// Log file, rotated when its size would exceed `max_size` bytes (if set):
+/// `electrs.log` is renamed to `electrs.log.1`, `electrs.log.1` to `electrs.log.2` and so on,
+/// keeping at most `keep` rotated files.
+///
+/// Each log record is written by a single `write_all()` call, which `env_logger` serializes
+/// (so concurrent logging threads never interleave, and never write during a rotation).
+pub(crate) struct RotatingFile {
+    path: PathBuf,
+    max_size: Option<u64>,
+    keep: usize,
+    file: File,
+    size: u64,
+}
+
+impl RotatingFile {
+    pub(crate) fn open(path: &Path, max_size: Option<u64>, keep: usize) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+        Ok(Self {
+            path: path.to_owned(),
+            max_size,
+            keep,
+            file,
+            size,
+        })
+    }
+
+    fn rotated_path(&self, index: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", index));
+        path.into()
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        if self.keep == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            for index in (1..self.keep).rev() {
+                let src = self.rotated_path(index);
+                if src.exists() {
+                    fs::rename(src, self.rotated_path(index + 1))?;
+                }
+            }
+            fs::rename(&self.path, self.rotated_path(1))?;
+        }
+        self.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if let Some(max_size) = self.max_size {
+            // a single record larger than `max_size` is written into an empty file
+            if self.size > 0 && self.size + buf.len() as u64 > max_size {
+                self.rotate()?;
+            }
+        }
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RotatingFile;
+    use std::io::Write;
+
+    #[test]
+    fn test_rotation() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("electrs.log");
+        let read = |name: &str| std::fs::read_to_string(dir.path().join(name)).ok();
+
+        let mut file = RotatingFile::open(&path, Some(10), 2).unwrap();
+        file.write_all(b"line 1\n").unwrap();
+        assert_eq!(read("electrs.log.1"), None);
+
+        for line in &[b"line 2\n", b"line 3\n", b"line 4\n"] {
+            file.write_all(*line).unwrap(); // exceeds the size threshold
+        }
+        assert_eq!(read("electrs.log").as_deref(), Some("line 4\n"));
+        assert_eq!(read("electrs.log.1").as_deref(), Some("line 3\n"));
+        assert_eq!(read("electrs.log.2").as_deref(), Some("line 2\n"));
+        assert_eq!(read("electrs.log.3"), None); // only 2 rotated files are kept
+
+        // appends to an existing log file
+        drop(file);
+        let mut file = RotatingFile::open(&path, None, 2).unwrap();
+        file.write_all(b"line 5\n").unwrap();
+        assert_eq!(read("electrs.log").as_deref(), Some("line 4\nline 5\n"));
+    }
+}
@@ -1,19 +1,21 @@
 use anyhow::Result;
 
-use std::collections::{BTreeSet, HashMap, HashSet};
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
 use std::convert::TryFrom;
 use std::iter::FromIterator;
 use std::ops::Bound;
+use std::time::Instant;
 
 use bitcoin::hashes::Hash;
 use bitcoin::{Amount, OutPoint, Transaction, Txid};
 use bitcoincore_rpc::json;
+use parking_lot::Mutex;
 use rayon::prelude::*;
 use serde::ser::{Serialize, SerializeSeq, Serializer};
 
 use crate::{
     daemon::Daemon,
-    metrics::{Gauge, Metrics},
+    metrics::{Counter, Gauge, Metrics},
     types::ScriptHash,
 };
 
@@ -23,6 +25,77 @@ pub(crate) struct Entry {
     pub fee: Amount,
     pub vsize: u64,
     pub has_unconfirmed_inputs: bool,
+    pub bip125_replaceable: bool,
+    pub package: Mutex<Option<PackageStats>>, // fetched from bitcoind on first query
+    pub first_seen: u64, // UNIX timestamp (in seconds) when it entered bitcoind's mempool
+}
+
+/// Ancestor/descendant statistics of a mempool transaction (including itself), from `getmempoolentry`
+#[derive(Clone, Serialize)]
+pub(crate) struct PackageStats {
+    ancestor_count: u64,
+    ancestor_size: u64,
+    #[serde(with = "bitcoin::util::amount::serde::as_sat")]
+    ancestor_fees: Amount,
+    descendant_count: u64,
+    descendant_size: u64,
+    #[serde(with = "bitcoin::util::amount::serde::as_sat")]
+    descendant_fees: Amount,
+}
+
+impl PackageStats {
+    fn new(entry: &json::GetMempoolEntryResult) -> Self {
+        Self {
+            ancestor_count: entry.ancestor_count,
+            ancestor_size: entry.ancestor_size,
+            ancestor_fees: entry.fees.ancestor,
+            descendant_count: entry.descendant_count,
+            descendant_size: entry.descendant_size,
+            descendant_fees: entry.fees.descendant,
+        }
+    }
+}
+
+/// Package fee data of a mempool transaction, to support CPFP fee bumping
+#[derive(Serialize)]
+pub(crate) struct Package {
+    #[serde(flatten)]
+    stats: PackageStats,
+    ancestors: BTreeSet<Txid>,   // in-mempool ancestors
+    descendants: BTreeSet<Txid>, // in-mempool descendants
+    bip125_replaceable: bool,    // signals opt-in RBF (so it may be fee-bumped by a replacement)
+}
+
+/// A mempool transaction, with the total vsize of the transactions paying at least its fee rate
+#[derive(Serialize)]
+pub(crate) struct FeeRateEntry {
+    #[serde(rename = "tx_hash")]
+    txid: Txid,
+    #[serde(with = "bitcoin::util::amount::serde::as_sat")]
+    fee: Amount,
+    vsize: u64,
+    fee_rate: f64, // [sat/vB]
+    cumulative_vsize: u64,
+}
+
+impl Entry {
+    /// Fee rate (in sat/vB)
+    fn fee_rate(&self) -> f64 {
+        self.fee.as_sat() as f64 / self.vsize as f64
+    }
+
+    fn new(txid: Txid, tx: Transaction, entry: &json::GetMempoolEntryResult) -> Self {
+        Self {
+            txid,
+            tx,
+            vsize: entry.vsize,
+            fee: entry.fees.base,
+            has_unconfirmed_inputs: !entry.depends.is_empty(),
+            bip125_replaceable: entry.bip125_replaceable,
+            package: Mutex::new(None),
+            first_seen: entry.time,
+        }
+    }
 }
 
 /// Mempool current state
@@ -30,12 +103,22 @@ pub(crate) struct Mempool {
     entries: HashMap<Txid, Entry>,
     by_funding: BTreeSet<(ScriptHash, Txid)>,
     by_spending: BTreeSet<(OutPoint, Txid)>,
+    replaced: HashMap<Txid, Txid>, // RBF-replaced txid -> its (in-mempool) replacement
+    removed_spends: HashMap<OutPoint, Txid>, // outpoints spent by recently removed transactions
+    removed_order: VecDeque<(OutPoint, Txid)>, // for evicting the oldest removed spends
+    ignored: HashSet<Txid>,        // transactions below the minimal fee rate
+    min_fee_rate: Option<f64>,     // [sat/vB]
     fees: FeeHistogram,
     // stats
     vsize: Gauge,
     count: Gauge,
+    evictions: Counter,
 }
 
+// A replacement may be fetched after the replaced transaction was removed (e.g. in a later sync),
+// so the outpoints spent by this number of recently removed transactions' inputs are remembered.
+const MAX_REMOVED_SPENDS: usize = 10_000;
+
 // Smallest possible txid
 fn txid_min() -> Txid {
     Txid::from_inner([0x00; 32])
@@ -52,6 +135,11 @@ impl Mempool {
             entries: Default::default(),
             by_funding: Default::default(),
             by_spending: Default::default(),
+            replaced: Default::default(),
+            removed_spends: Default::default(),
+            removed_order: Default::default(),
+            ignored: Default::default(),
+            min_fee_rate: None,
             fees: FeeHistogram::empty(),
             vsize: metrics.gauge(
                 "mempool_txs_vsize",
@@ -63,9 +151,19 @@ impl Mempool {
                 "Total number of mempool transactions",
                 "fee_rate",
             ),
+            evictions: metrics.counter(
+                "mempool_evictions",
+                "# of transactions removed from mempool (mined, expired or conflicted)",
+                "reason",
+            ),
         }
     }
 
+    /// Skip transactions paying less than the given fee rate (in sat/vB).
+    pub(crate) fn set_min_fee_rate(&mut self, min_fee_rate: Option<f64>) {
+        self.min_fee_rate = min_fee_rate;
+    }
+
     pub(crate) fn fees_histogram(&self) -> &FeeHistogram {
         &self.fees
     }
@@ -96,80 +194,356 @@ impl Mempool {
             .collect()
     }
 
+    /// When the transaction was first added to the mempool (kept while it stays there).
+    pub(crate) fn get_first_seen(&self, txid: &Txid) -> Option<u64> {
+        self.entries.get(txid).map(|e| e.first_seen)
+    }
+
+    /// Get the transaction replacing the given one (via RBF), if it's still in mempool.
+    pub(crate) fn get_replacement(&self, txid: &Txid) -> Option<Txid> {
+        self.replaced.get(txid).copied()
+    }
+
+    /// Mempool transactions sorted by fee rate (highest first), with their cumulative vsize.
+    /// Stops after `max_vsize` is reached (e.g. the transactions to be mined in the next block).
+    pub(crate) fn by_fee_rate(&self, max_vsize: u64) -> Vec<FeeRateEntry> {
+        let mut entries: Vec<&Entry> = self.entries.values().collect();
+        // compare `fee / vsize` exactly (ties are ordered by txid)
+        entries.sort_unstable_by(|a, b| {
+            let a_rate = u128::from(a.fee.as_sat()) * u128::from(b.vsize);
+            let b_rate = u128::from(b.fee.as_sat()) * u128::from(a.vsize);
+            b_rate.cmp(&a_rate).then_with(|| a.txid.cmp(&b.txid))
+        });
+        let mut result = vec![];
+        let mut cumulative_vsize = 0;
+        for entry in entries {
+            if cumulative_vsize >= max_vsize {
+                break;
+            }
+            cumulative_vsize += entry.vsize;
+            result.push(FeeRateEntry {
+                txid: entry.txid,
+                fee: entry.fee,
+                vsize: entry.vsize,
+                fee_rate: entry.fee_rate(),
+                cumulative_vsize,
+            });
+        }
+        result
+    }
+
+    /// Collect the in-mempool ancestors of a transaction.
+    pub(crate) fn ancestors(&self, txid: &Txid) -> BTreeSet<Txid> {
+        let mut result = BTreeSet::new();
+        let mut pending = vec![*txid];
+        while let Some(txid) = pending.pop() {
+            let entry = match self.get(&txid) {
+                Some(entry) => entry,
+                None => continue,
+            };
+            for txi in &entry.tx.input {
+                let parent = txi.previous_output.txid;
+                if self.entries.contains_key(&parent) && result.insert(parent) {
+                    pending.push(parent);
+                }
+            }
+        }
+        result
+    }
+
+    /// Collect the in-mempool descendants of a transaction.
+    pub(crate) fn descendants(&self, txid: &Txid) -> BTreeSet<Txid> {
+        let mut result = BTreeSet::new();
+        let mut pending = vec![*txid];
+        while let Some(txid) = pending.pop() {
+            let entry = match self.get(&txid) {
+                Some(entry) => entry,
+                None => continue,
+            };
+            for vout in 0..entry.tx.output.len() {
+                let outpoint = OutPoint::new(txid, u32::try_from(vout).unwrap());
+                for child in self.filter_by_spending(&outpoint) {
+                    if result.insert(child.txid) {
+                        pending.push(child.txid);
+                    }
+                }
+            }
+        }
+        result
+    }
+
+    /// Get the package fee data of a mempool transaction.
+    /// Its statistics are fetched from bitcoind on the first query (and cached until its package changes).
+    pub(crate) fn get_package(&self, txid: &Txid, daemon: &Daemon) -> Result<Option<Package>> {
+        let entry = match self.get(txid) {
+            Some(entry) => entry,
+            None => return Ok(None),
+        };
+        let stats = {
+            let mut package = entry.package.lock();
+            match &*package {
+                Some(stats) => stats.clone(),
+                None => {
+                    let stats = PackageStats::new(&daemon.get_mempool_entry(txid)?);
+                    *package = Some(stats.clone());
+                    stats
+                }
+            }
+        };
+        Ok(Some(Package {
+            stats,
+            ancestors: self.ancestors(txid),
+            descendants: self.descendants(txid),
+            bip125_replaceable: entry.bip125_replaceable,
+        }))
+    }
+
+    /// Adding or removing a transaction changes its ancestors' and descendants' package statistics.
+    fn invalidate_packages(&self, txid: &Txid) {
+        let ancestors = self.ancestors(txid);
+        let descendants = self.descendants(txid);
+        for related in ancestors.iter().chain(descendants.iter()) {
+            if let Some(entry) = self.get(related) {
+                *entry.package.lock() = None;
+            }
+        }
+    }
+
     pub fn sync(&mut self, daemon: &Daemon) {
-        let txids = match daemon.get_mempool_txids() {
-            Ok(txids) => txids,
+        self.sync_until(daemon, None)
+    }
+
+    /// Sync the mempool, fetching new transactions only until `deadline` (if set).
+    /// The transactions which were not fetched in time are fetched by the next sync.
+    pub(crate) fn sync_until(&mut self, daemon: &Daemon, deadline: Option<Instant>) {
+        // fetch all mempool entries at once, and only the new transactions one-by-one
+        let (added, removed) = match daemon.get_mempool_entries() {
+            Ok(mempool_entries) => {
+                let txids = mempool_entries.keys().copied().collect();
+                self.sync_txids(
+                    txids,
+                    fetch_until(deadline, |txid| {
+                        let tx = daemon.get_transaction(txid, None).ok()?;
+                        Some(Entry::new(*txid, tx, mempool_entries.get(txid)?))
+                    }),
+                )
+            }
             Err(e) => {
-                warn!("mempool sync failed: {}", e);
-                return;
+                // fallback to fetching each new mempool entry separately
+                warn!("failed to get mempool entries: {}", e);
+                let txids = match daemon.get_mempool_txids() {
+                    Ok(txids) => txids,
+                    Err(e) => {
+                        warn!("mempool sync failed: {}", e);
+                        return;
+                    }
+                };
+                self.sync_txids(
+                    HashSet::from_iter(txids),
+                    fetch_until(deadline, |txid| {
+                        match (
+                            daemon.get_transaction(txid, None),
+                            daemon.get_mempool_entry(txid),
+                        ) {
+                            (Ok(tx), Ok(entry)) => Some(Entry::new(*txid, tx, &entry)),
+                            _ => None,
+                        }
+                    }),
+                )
             }
         };
-        debug!("loading {} mempool transactions", txids.len());
+        self.update_fees();
+        debug!(
+            "{} mempool txs: {} added, {} removed",
+            self.entries.len(),
+            added,
+            removed,
+        );
+    }
+
+    /// Add transactions announced by bitcoind (without waiting for the next sync).
+    pub(crate) fn add_announced(&mut self, daemon: &Daemon, txids: Vec<Txid>) {
+        let added = self.add_txids(txids, |txid| {
+            match (
+                daemon.get_transaction(txid, None),
+                daemon.get_mempool_entry(txid),
+            ) {
+                (Ok(tx), Ok(entry)) => Some(Entry::new(*txid, tx, &entry)),
+                _ => None, // e.g. already confirmed or evicted
+            }
+        });
+        if added > 0 {
+            self.update_fees();
+            debug!(
+                "{} mempool txs: {} announced txs added",
+                self.entries.len(),
+                added
+            );
+        }
+    }
+
+    fn update_fees(&mut self) {
+        self.fees = FeeHistogram::new(self.entries.values().map(|e| (e.fee, e.vsize)));
+        for i in 1..FeeHistogram::BINS {
+            let bin_index = FeeHistogram::BINS - i - 1; // from 63 to 0
+            let limit = 1u64 << i;
+            let label = format!("[{:20.0}, {:20.0})", limit / 2, limit);
+            self.vsize.set(&label, self.fees.vsize[bin_index] as f64);
+            self.count.set(&label, self.fees.count[bin_index] as f64);
+        }
+    }
 
-        let new_txids = HashSet::<Txid>::from_iter(txids);
+    /// Sync with the given mempool txids, fetching only the new transactions.
+    /// Return the number of added and removed transactions.
+    fn sync_txids<F>(&mut self, new_txids: HashSet<Txid>, fetch: F) -> (usize, usize)
+    where
+        F: Fn(&Txid) -> Option<Entry> + Send + Sync,
+    {
+        debug!("loading {} mempool transactions", new_txids.len());
         let old_txids = HashSet::<Txid>::from_iter(self.entries.keys().copied());
 
-        let to_add = &new_txids - &old_txids;
+        // ignored transactions are not fetched again
+        self.ignored.retain(|txid| new_txids.contains(txid));
+        let to_add: HashSet<Txid> = &(&new_txids - &old_txids) - &self.ignored;
         let to_remove = &old_txids - &new_txids;
 
         let removed = to_remove.len();
-        for txid in to_remove {
-            self.remove_entry(txid);
+        let entries = self.fetch_entries(&to_add, fetch);
+        let added = entries.len();
+        self.update(to_remove, entries);
+        (added, removed)
+    }
+
+    /// Add the given (new) transactions, removing the ones they conflict with
+    /// (since they were replaced in bitcoind's mempool).
+    /// Return the number of added transactions.
+    fn add_txids<F>(&mut self, txids: Vec<Txid>, fetch: F) -> usize
+    where
+        F: Fn(&Txid) -> Option<Entry> + Send + Sync,
+    {
+        let to_add: HashSet<Txid> = txids
+            .into_iter()
+            .filter(|txid| !self.entries.contains_key(txid) && !self.ignored.contains(txid))
+            .collect();
+        let entries = self.fetch_entries(&to_add, fetch);
+        let mut to_remove = HashSet::new();
+        for entry in &entries {
+            for txi in &entry.tx.input {
+                for conflict in self.filter_by_spending(&txi.previous_output) {
+                    to_remove.insert(conflict.txid);
+                }
+            }
         }
-        let entries: Vec<_> = to_add
+        let added = entries.len();
+        self.update(to_remove, entries);
+        added
+    }
+
+    /// Fetch the given transactions (ignoring the ones paying less than the minimal fee rate).
+    fn fetch_entries<F>(&mut self, txids: &HashSet<Txid>, fetch: F) -> Vec<Entry>
+    where
+        F: Fn(&Txid) -> Option<Entry> + Send + Sync,
+    {
+        let min_fee_rate = self.min_fee_rate;
+        let (entries, ignored): (Vec<Entry>, Vec<Entry>) = txids
             .par_iter()
-            .filter_map(|txid| {
-                match (
-                    daemon.get_transaction(txid, None),
-                    daemon.get_mempool_entry(txid),
-                ) {
-                    (Ok(tx), Ok(entry)) => Some((txid, tx, entry)),
-                    _ => None,
+            .filter_map(fetch)
+            .partition(|entry| match min_fee_rate {
+                Some(min_fee_rate) => entry.fee_rate() >= min_fee_rate,
+                None => true,
+            });
+        if !ignored.is_empty() {
+            debug!("ignoring {} low-fee mempool transactions", ignored.len());
+        }
+        self.ignored
+            .extend(ignored.into_iter().map(|entry| entry.txid));
+        entries
+    }
+
+    /// Remove and add the given transactions, tracking RBF replacements
+    /// (i.e. a removed transaction conflicting with an added one, possibly in a later update).
+    fn update(&mut self, to_remove: HashSet<Txid>, entries: Vec<Entry>) {
+        for txid in &to_remove {
+            let entry = self.entries.get(txid).expect("missing tx from mempool");
+            for txi in &entry.tx.input {
+                self.removed_spends.insert(txi.previous_output, *txid);
+                self.removed_order.push_back((txi.previous_output, *txid));
+            }
+        }
+        while self.removed_order.len() > MAX_REMOVED_SPENDS {
+            let (outpoint, txid) = self.removed_order.pop_front().unwrap();
+            if self.removed_spends.get(&outpoint) == Some(&txid) {
+                self.removed_spends.remove(&outpoint);
+            }
+        }
+        let mut replacements = vec![];
+        for entry in &entries {
+            for txi in &entry.tx.input {
+                if let Some(&replaced) = self.removed_spends.get(&txi.previous_output) {
+                    if replaced != entry.txid && !replacements.contains(&(replaced, entry.txid)) {
+                        debug!("{} replaced by {}", replaced, entry.txid);
+                        replacements.push((replaced, entry.txid));
+                    }
                 }
-            })
-            .collect();
-        let added = entries.len();
-        for (txid, tx, entry) in entries {
-            self.add_entry(*txid, tx, entry);
+            }
         }
-        self.fees = FeeHistogram::new(self.entries.values().map(|e| (e.fee, e.vsize)));
-        for i in 1..FeeHistogram::BINS {
-            let bin_index = FeeHistogram::BINS - i - 1; // from 63 to 0
-            let limit = 1u64 << i;
-            let label = format!("[{:20.0}, {:20.0})", limit / 2, limit);
-            self.vsize.set(&label, self.fees.vsize[bin_index] as f64);
-            self.count.set(&label, self.fees.count[bin_index] as f64);
+        for (replaced, replacement) in replacements {
+            // keep pointing earlier replaced transactions to the latest replacement
+            for txid in self.replaced.values_mut() {
+                if *txid == replaced {
+                    *txid = replacement;
+                }
+            }
+            self.replaced.insert(replaced, replacement);
         }
-        debug!(
-            "{} mempool txs: {} added, {} removed",
-            self.entries.len(),
-            added,
-            removed,
-        );
+        for txid in to_remove {
+            let reason = if self.replaced.contains_key(&txid) {
+                "replaced"
+            } else {
+                "removed"
+            };
+            self.evictions.inc(reason);
+            self.remove_entry(txid);
+        }
+        for entry in entries {
+            self.add_entry(entry);
+        }
+        // replacements which are no longer in mempool (e.g. confirmed) are forgotten
+        let entries = &self.entries;
+        self.replaced
+            .retain(|_replaced, replacement| entries.contains_key(replacement));
+    }
+
+    #[cfg(test)]
+    pub(crate) fn add_entries(&mut self, entries: impl IntoIterator<Item = Entry>) {
+        for entry in entries {
+            self.add_entry(entry);
+        }
+    }
+
+    #[cfg(test)]
+    pub(crate) fn remove_entries(&mut self, txids: impl IntoIterator<Item = Txid>) {
+        self.update(txids.into_iter().collect(), vec![]);
     }
 
-    fn add_entry(&mut self, txid: Txid, tx: Transaction, entry: json::GetMempoolEntryResult) {
-        for txi in &tx.input {
+    fn add_entry(&mut self, entry: Entry) {
+        let txid = entry.txid;
+        for txi in &entry.tx.input {
             self.by_spending.insert((txi.previous_output, txid));
         }
-        for txo in &tx.output {
+        for txo in &entry.tx.output {
             let scripthash = ScriptHash::new(&txo.script_pubkey);
             self.by_funding.insert((scripthash, txid)); // may have duplicates
         }
-        let entry = Entry {
-            txid,
-            tx,
-            vsize: entry.vsize,
-            fee: entry.fees.base,
-            has_unconfirmed_inputs: !entry.depends.is_empty(),
-        };
         assert!(
             self.entries.insert(txid, entry).is_none(),
             "duplicate mempool txid"
         );
+        self.invalidate_packages(&txid);
     }
 
     fn remove_entry(&mut self, txid: Txid) {
+        self.invalidate_packages(&txid);
         let entry = self.entries.remove(&txid).expect("missing tx from mempool");
         for txi in entry.tx.input {
             self.by_spending.remove(&(txi.previous_output, txid));
@@ -181,6 +555,20 @@ impl Mempool {
     }
 }
 
+/// Skip fetching transactions after `deadline` (they are not added, so the next sync fetches them).
+fn fetch_until<F>(
+    deadline: Option<Instant>,
+    fetch: F,
+) -> impl Fn(&Txid) -> Option<Entry> + Send + Sync
+where
+    F: Fn(&Txid) -> Option<Entry> + Send + Sync,
+{
+    move |txid| match deadline {
+        Some(deadline) if Instant::now() >= deadline => None,
+        _ => fetch(txid),
+    }
+}
+
 pub(crate) struct FeeHistogram {
     /// bins[64-i] contains transactions' statistics inside the fee band of [2**(i-1), 2**i).
     /// bins[64] = [0, 1)
@@ -247,9 +635,26 @@ impl Serialize for FeeHistogram {
 
 #[cfg(test)]
 mod tests {
-    use super::FeeHistogram;
-    use bitcoin::Amount;
+    use super::{fetch_until, Entry, FeeHistogram, Mempool, PackageStats};
+    use crate::tests::{coinbase, metrics, script, spend, txout};
+    use bitcoin::{Amount, OutPoint, Transaction, Txid};
+    use parking_lot::Mutex;
     use serde_json::json;
+    use std::collections::HashSet;
+    use std::time::{Duration, Instant};
+
+    fn entry(tx: Transaction) -> Entry {
+        Entry {
+            txid: tx.txid(),
+            tx,
+            fee: Amount::from_sat(1000),
+            vsize: 100,
+            has_unconfirmed_inputs: false,
+            bip125_replaceable: true,
+            package: Default::default(),
+            first_seen: 0,
+        }
+    }
 
     #[test]
     fn test_histogram() {
@@ -270,4 +675,337 @@ mod tests {
             json!([[15, 10], [7, 40], [3, 20], [1, 10], [0, 100]])
         );
     }
+
+    #[test]
+    fn test_by_fee_rate() {
+        let fees_vsizes = vec![(1000, 100), (400, 200), (3000, 200), (400, 200), (400, 200)];
+        let mut mempool = Mempool::new(&metrics());
+        mempool.add_entries(fees_vsizes.iter().zip(1..).map(|(&(fee, vsize), i)| {
+            let mut entry = entry(coinbase(i, vec![txout(100, script(1))]));
+            entry.fee = Amount::from_sat(fee);
+            entry.vsize = vsize;
+            entry
+        }));
+
+        let entries = mempool.by_fee_rate(u64::MAX);
+        let rates: Vec<f64> = entries.iter().map(|e| e.fee_rate).collect();
+        assert_eq!(rates, vec![15.0, 10.0, 2.0, 2.0, 2.0]);
+        // ties are ordered by txid
+        assert!(entries[2].txid < entries[3].txid && entries[3].txid < entries[4].txid);
+        let cumulative: Vec<u64> = entries.iter().map(|e| e.cumulative_vsize).collect();
+        assert_eq!(cumulative, vec![200, 300, 500, 700, 900]);
+
+        // stop after the given vsize is reached
+        assert_eq!(mempool.by_fee_rate(300).len(), 2);
+        assert_eq!(mempool.by_fee_rate(301).len(), 3);
+        assert!(mempool.by_fee_rate(0).is_empty());
+    }
+
+    #[test]
+    fn test_ancestors() {
+        let confirmed = coinbase(1, vec![txout(100, script(1))]);
+        let parent = spend(
+            vec![OutPoint::new(confirmed.txid(), 0)],
+            vec![txout(90, script(2)), txout(5, script(3))],
+        );
+        let child = spend(
+            vec![OutPoint::new(parent.txid(), 0)],
+            vec![txout(80, script(4))],
+        );
+        let grandchild = spend(
+            vec![OutPoint::new(child.txid(), 0)],
+            vec![txout(70, script(5))],
+        );
+
+        let mut mempool = Mempool::new(&metrics());
+        mempool.add_entries(vec![entry(parent.clone()), entry(child.clone())]);
+
+        let ancestors: Vec<_> = mempool.ancestors(&child.txid()).into_iter().collect();
+        assert_eq!(ancestors, vec![parent.txid()]); // confirmed inputs are skipped
+        let descendants: Vec<_> = mempool.descendants(&parent.txid()).into_iter().collect();
+        assert_eq!(descendants, vec![child.txid()]);
+        assert!(mempool.ancestors(&parent.txid()).is_empty());
+        assert!(mempool.descendants(&child.txid()).is_empty());
+
+        // a new descendant invalidates the cached package statistics
+        let stats = PackageStats {
+            ancestor_count: 1,
+            ancestor_size: 100,
+            ancestor_fees: Amount::from_sat(1000),
+            descendant_count: 2,
+            descendant_size: 200,
+            descendant_fees: Amount::from_sat(2000),
+        };
+        *mempool.get(&parent.txid()).unwrap().package.lock() = Some(stats);
+        mempool.add_entries(vec![entry(grandchild.clone())]);
+        assert!(mempool
+            .get(&parent.txid())
+            .unwrap()
+            .package
+            .lock()
+            .is_none());
+
+        let ancestors = mempool.ancestors(&grandchild.txid());
+        assert!(ancestors.contains(&parent.txid()) && ancestors.contains(&child.txid()));
+        assert_eq!(mempool.descendants(&parent.txid()).len(), 2);
+    }
+
+    #[test]
+    fn test_replacement() {
+        let confirmed = coinbase(1, vec![txout(100, script(1))]);
+        let outpoint = OutPoint::new(confirmed.txid(), 0);
+        let original = spend(vec![outpoint], vec![txout(90, script(2))]);
+        let bumped = spend(vec![outpoint], vec![txout(80, script(2))]);
+        let bumped_again = spend(vec![outpoint], vec![txout(70, script(2))]);
+
+        let mut mempool = Mempool::new(&metrics());
+        mempool.add_entries(vec![entry(original.clone())]);
+        assert_eq!(mempool.get_replacement(&original.txid()), None);
+
+        let to_remove: HashSet<_> = vec![original.txid()].into_iter().collect();
+        mempool.update(to_remove, vec![entry(bumped.clone())]);
+        assert!(mempool.get(&original.txid()).is_none());
+        assert_eq!(
+            mempool.get_replacement(&original.txid()),
+            Some(bumped.txid())
+        );
+
+        // the original transaction points to the latest replacement
+        let to_remove: HashSet<_> = vec![bumped.txid()].into_iter().collect();
+        mempool.update(to_remove, vec![entry(bumped_again.clone())]);
+        assert_eq!(
+            mempool.get_replacement(&original.txid()),
+            Some(bumped_again.txid())
+        );
+        assert_eq!(
+            mempool.get_replacement(&bumped.txid()),
+            Some(bumped_again.txid())
+        );
+
+        // the replacement is confirmed (and removed from mempool)
+        let to_remove: HashSet<_> = vec![bumped_again.txid()].into_iter().collect();
+        mempool.update(to_remove, vec![]);
+        assert_eq!(mempool.get_replacement(&original.txid()), None);
+    }
+
+    #[test]
+    fn test_delayed_replacement() {
+        let confirmed = coinbase(1, vec![txout(100, script(1))]);
+        let outpoint = OutPoint::new(confirmed.txid(), 0);
+        let original = spend(vec![outpoint], vec![txout(90, script(2))]);
+        let bumped = spend(vec![outpoint], vec![txout(80, script(2))]);
+
+        let mut mempool = Mempool::new(&metrics());
+        mempool.add_entries(vec![entry(original.clone())]);
+
+        // the replacement is fetched only after the original transaction was removed
+        mempool.remove_entries(vec![original.txid()]);
+        assert_eq!(mempool.get_replacement(&original.txid()), None);
+        mempool.update(HashSet::new(), vec![entry(bumped.clone())]);
+        assert_eq!(
+            mempool.get_replacement(&original.txid()),
+            Some(bumped.txid())
+        );
+    }
+
+    #[test]
+    fn test_sync_txids() {
+        let confirmed = coinbase(1, vec![txout(100, script(1)), txout(50, script(1))]);
+        let tx1 = spend(
+            vec![OutPoint::new(confirmed.txid(), 0)],
+            vec![txout(90, script(2))],
+        );
+        let tx2 = spend(
+            vec![OutPoint::new(confirmed.txid(), 1)],
+            vec![txout(40, script(3))],
+        );
+        let tx3 = spend(
+            vec![OutPoint::new(tx2.txid(), 0)],
+            vec![txout(30, script(4))],
+        );
+        let txs = vec![tx1.clone(), tx2.clone(), tx3.clone()];
+
+        let mut mempool = Mempool::new(&metrics());
+        mempool.add_entries(vec![entry(tx1.clone()), entry(tx2.clone())]);
+
+        // only the new transaction is fetched
+        let fetched = Mutex::new(vec![]);
+        let txids: HashSet<_> = vec![tx2.txid(), tx3.txid()].into_iter().collect();
+        let result = mempool.sync_txids(txids, |txid| {
+            fetched.lock().push(*txid);
+            let tx = txs.iter().find(|tx| tx.txid() == *txid)?;
+            Some(entry(tx.clone()))
+        });
+        assert_eq!(result, (1, 1));
+        assert_eq!(fetched.into_inner(), vec![tx3.txid()]);
+        assert!(mempool.get(&tx1.txid()).is_none());
+        assert!(mempool.get(&tx2.txid()).is_some());
+        assert!(mempool.get(&tx3.txid()).is_some());
+    }
+
+    #[test]
+    fn test_fetch_until() {
+        let txs: Vec<Transaction> = (1..=3)
+            .map(|i| coinbase(i, vec![txout(100, script(1))]))
+            .collect();
+        let txids: HashSet<_> = txs.iter().map(Transaction::txid).collect();
+        let fetch = |txid: &Txid| {
+            let tx = txs.iter().find(|tx| tx.txid() == *txid)?;
+            Some(entry(tx.clone()))
+        };
+
+        // nothing is fetched after the deadline, so the next sync fetches the skipped transactions
+        let mut mempool = Mempool::new(&metrics());
+        let expired = Some(Instant::now());
+        assert_eq!(
+            mempool.sync_txids(txids.clone(), fetch_until(expired, fetch)),
+            (0, 0)
+        );
+        assert_eq!(
+            mempool.sync_txids(txids.clone(), fetch_until(None, fetch)),
+            (3, 0)
+        );
+
+        // the initial sync (see `Tracker::warmup_mempool`) contains all the daemon's transactions
+        let mut mempool = Mempool::new(&metrics());
+        let deadline = Some(Instant::now() + Duration::from_secs(60));
+        assert_eq!(
+            mempool.sync_txids(txids, fetch_until(deadline, fetch)),
+            (3, 0)
+        );
+        for tx in &txs {
+            assert!(mempool.get(&tx.txid()).is_some());
+        }
+    }
+
+    #[test]
+    fn test_first_seen() {
+        let confirmed = coinbase(1, vec![txout(100, script(1)), txout(50, script(1))]);
+        let tx1 = spend(
+            vec![OutPoint::new(confirmed.txid(), 0)],
+            vec![txout(90, script(2))],
+        );
+        let tx2 = spend(
+            vec![OutPoint::new(confirmed.txid(), 1)],
+            vec![txout(40, script(3))],
+        );
+        let txs = vec![tx1.clone(), tx2.clone()];
+        let fetch = |now: u64| {
+            let txs = &txs;
+            move |txid: &Txid| {
+                let tx = txs.iter().find(|tx| tx.txid() == *txid)?;
+                let mut result = entry(tx.clone());
+                result.first_seen = now;
+                Some(result)
+            }
+        };
+
+        let mut mempool = Mempool::new(&metrics());
+        let txids: HashSet<_> = vec![tx1.txid()].into_iter().collect();
+        assert_eq!(mempool.sync_txids(txids, fetch(100)), (1, 0));
+        assert_eq!(mempool.get_first_seen(&tx1.txid()), Some(100));
+
+        // persisting transactions keep their timestamp
+        let txids: HashSet<_> = vec![tx1.txid(), tx2.txid()].into_iter().collect();
+        assert_eq!(mempool.sync_txids(txids.clone(), fetch(200)), (1, 0));
+        assert_eq!(mempool.sync_txids(txids, fetch(300)), (0, 0));
+        assert_eq!(mempool.get_first_seen(&tx1.txid()), Some(100));
+        assert_eq!(mempool.get_first_seen(&tx2.txid()), Some(200));
+
+        // and it is dropped on eviction
+        let txids: HashSet<_> = vec![tx2.txid()].into_iter().collect();
+        assert_eq!(mempool.sync_txids(txids, fetch(400)), (0, 1));
+        assert_eq!(mempool.get_first_seen(&tx1.txid()), None);
+        assert_eq!(mempool.get_first_seen(&tx2.txid()), Some(200));
+    }
+
+    #[test]
+    fn test_min_fee_rate() {
+        let confirmed = coinbase(1, vec![txout(100, script(1)), txout(50, script(1))]);
+        let tx1 = spend(
+            vec![OutPoint::new(confirmed.txid(), 0)],
+            vec![txout(90, script(2))],
+        );
+        let tx2 = spend(
+            vec![OutPoint::new(confirmed.txid(), 1)],
+            vec![txout(40, script(3))],
+        );
+
+        let mut mempool = Mempool::new(&metrics());
+        mempool.set_min_fee_rate(Some(2.0));
+        let fetch = |txid: &Txid| {
+            let mut result = entry(if *txid == tx1.txid() {
+                tx1.clone() // 10 sat/vB
+            } else {
+                tx2.clone()
+            });
+            if result.txid == tx2.txid() {
+                result.fee = Amount::from_sat(100); // 1 sat/vB
+            }
+            Some(result)
+        };
+        let txids: HashSet<_> = vec![tx1.txid(), tx2.txid()].into_iter().collect();
+        assert_eq!(mempool.sync_txids(txids.clone(), fetch), (1, 0));
+        assert!(mempool.get(&tx1.txid()).is_some());
+        assert!(mempool.get(&tx2.txid()).is_none());
+        assert!(mempool
+            .filter_by_spending(&tx2.input[0].previous_output)
+            .is_empty());
+
+        // the ignored transaction is not fetched again
+        let fetched = Mutex::new(vec![]);
+        let result = mempool.sync_txids(txids, |txid| {
+            fetched.lock().push(*txid);
+            None
+        });
+        assert_eq!(result, (0, 0));
+        assert!(fetched.into_inner().is_empty());
+    }
+
+    #[test]
+    fn test_add_txids() {
+        let confirmed = coinbase(1, vec![txout(100, script(1)), txout(50, script(1))]);
+        let tx1 = spend(
+            vec![OutPoint::new(confirmed.txid(), 0)],
+            vec![txout(90, script(2))],
+        );
+        let tx2 = spend(
+            vec![OutPoint::new(confirmed.txid(), 1)],
+            vec![txout(40, script(3))],
+        );
+        // replaces `tx1`
+        let tx3 = spend(
+            vec![OutPoint::new(confirmed.txid(), 0)],
+            vec![txout(80, script(2))],
+        );
+        let txs = vec![tx1.clone(), tx2.clone(), tx3.clone()];
+        let fetched = Mutex::new(vec![]);
+        let fetch = |txid: &Txid| {
+            fetched.lock().push(*txid);
+            let tx = txs.iter().find(|tx| tx.txid() == *txid)?;
+            Some(entry(tx.clone()))
+        };
+
+        let mut mempool = Mempool::new(&metrics());
+        mempool.add_entries(vec![entry(tx1.clone())]);
+
+        // an announced transaction is added, without removing the others
+        assert_eq!(mempool.add_txids(vec![tx1.txid(), tx2.txid()], &fetch), 1);
+        assert_eq!(
+            fetched.lock().drain(..).collect::<Vec<_>>(),
+            vec![tx2.txid()]
+        );
+        assert!(mempool.get(&tx1.txid()).is_some());
+        assert!(mempool.get(&tx2.txid()).is_some());
+
+        // an announced replacement evicts the replaced transaction
+        assert_eq!(mempool.add_txids(vec![tx3.txid()], &fetch), 1);
+        assert!(mempool.get(&tx1.txid()).is_none());
+        assert!(mempool.get(&tx2.txid()).is_some());
+        assert!(mempool.get(&tx3.txid()).is_some());
+        assert_eq!(mempool.get_replacement(&tx1.txid()), Some(tx3.txid()));
+
+        // transactions which can't be fetched are skipped
+        assert_eq!(mempool.add_txids(vec![Txid::default()], &fetch), 0);
+    }
 }
@@ -1,14 +1,25 @@
+use anyhow::Result;
 use bitcoin::{
     hashes::{hex::ToHex, Hash},
     TxMerkleNode, Txid,
 };
+use rayon::prelude::*;
 
+// smaller levels are hashed serially, since they are cheap to compute
+const MIN_PARALLEL_LEVEL_SIZE: usize = 4096;
+
+// the block's txids are refetched if they don't match its header
+const MAX_FETCH_ATTEMPTS: usize = 2;
+
+#[derive(Clone)]
 pub(crate) struct Proof {
     proof: Vec<TxMerkleNode>,
     position: usize,
 }
 
 impl Proof {
+    /// Create a single proof (without keeping the tree), for verifying `MerkleTree` proofs.
+    #[cfg(test)]
     pub(crate) fn create(txids: &[Txid], position: usize) -> Self {
         assert!(position < txids.len());
         let mut offset = position;
@@ -30,15 +41,7 @@ impl Proof {
             };
             proof.push(hashes[offset]);
             offset /= 2;
-            hashes = hashes
-                .chunks(2)
-                .map(|pair| {
-                    let left = pair[0];
-                    let right = pair[1];
-                    let input = [&left[..], &right[..]].concat();
-                    TxMerkleNode::hash(&input)
-                })
-                .collect()
+            hashes = hashes.chunks(2).map(hash_pair).collect()
         }
         Self { proof, position }
     }
@@ -52,12 +55,121 @@ impl Proof {
     }
 }
 
+/// A block's merkle tree (computed once), for deriving the proofs of multiple transactions.
+pub(crate) struct MerkleTree {
+    levels: Vec<Vec<TxMerkleNode>>, // from the leaves (txids) up to the root
+    tx_count: usize,
+}
+
+impl MerkleTree {
+    pub(crate) fn build(txids: &[Txid]) -> Self {
+        assert!(!txids.is_empty());
+        let mut level: Vec<TxMerkleNode> = txids
+            .iter()
+            .map(|txid| TxMerkleNode::from_hash(txid.as_hash()))
+            .collect();
+        let mut levels = vec![];
+        while level.len() > 1 {
+            if level.len() % 2 != 0 {
+                let last = *level.last().unwrap();
+                level.push(last);
+            }
+            let next = if level.len() < MIN_PARALLEL_LEVEL_SIZE {
+                level.chunks(2).map(hash_pair).collect()
+            } else {
+                level.par_chunks(2).map(hash_pair).collect()
+            };
+            levels.push(level);
+            level = next;
+        }
+        levels.push(level); // the root
+        Self {
+            levels,
+            tx_count: txids.len(),
+        }
+    }
+
+    /// Build the tree from the fetched block txids, verifying it against the header's merkle root.
+    /// On mismatch (e.g. due to a corrupted response), the txids are fetched again.
+    pub(crate) fn fetch_verified<F>(merkle_root: TxMerkleNode, mut fetch: F) -> Result<Self>
+    where
+        F: FnMut() -> Result<Vec<Txid>>,
+    {
+        for attempt in 1..=MAX_FETCH_ATTEMPTS {
+            let txids = fetch()?;
+            if !txids.is_empty() {
+                let tree = Self::build(&txids);
+                if tree.root() == merkle_root {
+                    return Ok(tree);
+                }
+            }
+            warn!(
+                "fetched txids don't match merkle root {} (attempt {}/{})",
+                merkle_root, attempt, MAX_FETCH_ATTEMPTS
+            );
+        }
+        bail!("failed to fetch txids matching merkle root {}", merkle_root)
+    }
+
+    fn root(&self) -> TxMerkleNode {
+        self.levels.last().expect("empty tree")[0]
+    }
+
+    /// Create the proof of the transaction at the given position.
+    pub(crate) fn proof(&self, position: usize) -> Proof {
+        assert!(position < self.tx_count);
+        let mut offset = position;
+        let proof = self.levels[..self.levels.len() - 1]
+            .iter()
+            .map(|level| {
+                let sibling = level[offset ^ 1];
+                offset /= 2;
+                sibling
+            })
+            .collect();
+        Proof { proof, position }
+    }
+
+    /// Create the proof for the given txid (if it's included in this block).
+    pub(crate) fn find(&self, txid: &Txid) -> Option<Proof> {
+        let position = self.levels[0][..self.tx_count]
+            .iter()
+            .position(|node| node.as_hash() == txid.as_hash())?;
+        Some(self.proof(position))
+    }
+}
+
+fn hash_pair(pair: &[TxMerkleNode]) -> TxMerkleNode {
+    let input = [&pair[0][..], &pair[1][..]].concat();
+    TxMerkleNode::hash(&input)
+}
+
+/// Compute the merkle root from a transaction's (hex-encoded) merkle branch, as a client would.
+#[cfg(test)]
+pub(crate) fn merkle_root(txid: &Txid, position: usize, branch: &[String]) -> TxMerkleNode {
+    use bitcoin::hashes::hex::FromHex;
+
+    let mut node = TxMerkleNode::from_hash(txid.as_hash());
+    let mut offset = position;
+    for hex in branch {
+        let sibling = TxMerkleNode::from_hex(hex).unwrap();
+        let input = if offset % 2 == 0 {
+            [&node[..], &sibling[..]].concat()
+        } else {
+            [&sibling[..], &node[..]].concat()
+        };
+        node = TxMerkleNode::hash(&input);
+        offset /= 2;
+    }
+    node
+}
+
 #[cfg(test)]
 mod tests {
-    use bitcoin::{consensus::encode::deserialize, Block, Txid};
-    use std::path::Path;
+    use bitcoin::{hashes::Hash, Txid};
 
-    use super::Proof;
+    use super::{merkle_root, MerkleTree, Proof, MAX_FETCH_ATTEMPTS, MIN_PARALLEL_LEVEL_SIZE};
+    use crate::tests::load_block;
 
     #[test]
     fn test_merkle() {
@@ -101,13 +213,101 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_find() {
+        let txids =
+            load_block_txids("000000000000000002d249a3d89f63ef3fee203adcca7c24008c13fd854513f2");
+        let tree = MerkleTree::build(&txids);
+        for (position, txid) in txids.iter().enumerate() {
+            let proof = tree.find(txid).unwrap();
+            assert_eq!(proof.position(), position);
+            assert_eq!(proof.to_hex(), Proof::create(&txids, position).to_hex());
+        }
+        assert!(tree.find(&Txid::default()).is_none());
+    }
+
+    #[test]
+    fn test_merkle_root() {
+        let block = load_block("00000000000000001203c1ea455e38612bdf36e9967fdead11935c8e22283ecc");
+        let txids: Vec<Txid> = block.txdata.iter().map(|tx| tx.txid()).collect();
+        for (position, txid) in txids.iter().enumerate() {
+            let proof = Proof::create(&txids, position);
+            assert_eq!(
+                merkle_root(txid, proof.position(), &proof.to_hex()),
+                block.header.merkle_root
+            );
+        }
+    }
+
+    #[test]
+    fn test_merkle_tree() {
+        let block = load_block("00000000000000001203c1ea455e38612bdf36e9967fdead11935c8e22283ecc");
+        let txids: Vec<Txid> = block.txdata.iter().map(|tx| tx.txid()).collect();
+        let tree = MerkleTree::build(&txids);
+        for (position, txid) in txids.iter().enumerate() {
+            let proof = tree.proof(position);
+            assert_eq!(proof.to_hex(), Proof::create(&txids, position).to_hex());
+            assert_eq!(
+                merkle_root(txid, proof.position(), &proof.to_hex()),
+                block.header.merkle_root
+            );
+        }
+
+        // a single transaction is its own merkle root
+        let tree = MerkleTree::build(&txids[..1]);
+        assert!(tree.proof(0).to_hex().is_empty());
+    }
+
+    #[test]
+    fn test_fetch_verified() {
+        let block = load_block("00000000000000001203c1ea455e38612bdf36e9967fdead11935c8e22283ecc");
+        let txids: Vec<Txid> = block.txdata.iter().map(|tx| tx.txid()).collect();
+        let mut corrupted = txids.clone();
+        corrupted.swap(1, 2);
+
+        // the first (corrupted) fetch fails verification, and the txids are refetched
+        let mut fetched = vec![corrupted.clone(), txids.clone()];
+        let tree =
+            MerkleTree::fetch_verified(block.header.merkle_root, || Ok(fetched.remove(0))).unwrap();
+        assert!(fetched.is_empty());
+        assert_eq!(
+            tree.proof(157).to_hex(),
+            Proof::create(&txids, 157).to_hex()
+        );
+
+        // give up after all the fetches are corrupted
+        let mut fetches = 0;
+        let result = MerkleTree::fetch_verified(block.header.merkle_root, || {
+            fetches += 1;
+            Ok(corrupted.clone())
+        });
+        assert!(result.is_err());
+        assert_eq!(fetches, MAX_FETCH_ATTEMPTS);
+    }
+
+    #[test]
+    fn test_parallel_merkle_tree() {
+        // an odd number of leaves, to be hashed in parallel
+        let txids: Vec<Txid> = (0..MIN_PARALLEL_LEVEL_SIZE as u32 + 1)
+            .map(|i| Txid::hash(&i.to_le_bytes()))
+            .collect();
+        let tree = MerkleTree::build(&txids);
+        for &position in &[
+            0,
+            1,
+            1000,
+            MIN_PARALLEL_LEVEL_SIZE - 1,
+            MIN_PARALLEL_LEVEL_SIZE,
+        ] {
+            assert_eq!(
+                tree.proof(position).to_hex(),
+                Proof::create(&txids, position).to_hex()
+            );
+        }
+    }
+
     fn load_block_txids(block_hash_hex: &str) -> Vec<Txid> {
-        let path = Path::new("src")
-            .join("tests")
-            .join("blocks")
-            .join(block_hash_hex);
-        let data = std::fs::read(path).unwrap();
-        let block: Block = deserialize(&data).unwrap();
+        let block = load_block(block_hash_hex);
         block.txdata.iter().map(|tx| tx.txid()).collect()
     }
 }
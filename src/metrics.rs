@@ -6,42 +6,63 @@ mod metrics_impl {
     use prometheus::process_collector::ProcessCollector;
 
     use prometheus::{self, Encoder, HistogramOpts, HistogramVec, Registry};
-    use tiny_http::{Response, Server};
+    use tiny_http::{Header, Response, Server};
 
     use std::net::SocketAddr;
+    use std::sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    };
 
-    use crate::thread::spawn;
+    use crate::{
+        config::{MonitoringAddr, SensitiveToken},
+        thread::spawn,
+    };
 
     pub struct Metrics {
         reg: Registry,
+        readiness: Readiness,
     }
 
     impl Metrics {
-        pub fn new(addr: SocketAddr) -> Result<Self> {
+        pub fn new(
+            addr: MonitoringAddr,
+            token: Option<SensitiveToken>,
+            ready_lag_blocks: usize,
+        ) -> Result<Self> {
             let reg = Registry::new();
 
             #[cfg(feature = "metrics_process")]
             reg.register(Box::new(ProcessCollector::for_self()))
                 .expect("failed to register ProcessCollector");
 
-            let result = Self { reg };
-            let reg = result.reg.clone();
-            spawn("metrics", move || {
-                let server = Server::http(addr).unwrap();
-                for request in server.incoming_requests() {
-                    let mut buffer = vec![];
-                    prometheus::TextEncoder::new()
-                        .encode(&reg.gather(), &mut buffer)
-                        .context("failed to encode metrics")?;
-                    request
-                        .respond(Response::from_data(buffer))
-                        .context("failed to send HTTP response")?;
+            let authenticated = token.is_some();
+            let readiness = Readiness::default();
+            let handler = Handler {
+                reg: reg.clone(),
+                token,
+                readiness: readiness.clone(),
+                ready_lag_blocks,
+            };
+            match &addr {
+                MonitoringAddr::Tcp(tcp_addr) => serve_tcp(*tcp_addr, handler),
+                MonitoringAddr::Unix(path) => unix::serve(path, handler)?,
+            }
+
+            info!(
+                "serving Prometheus metrics on {}{}",
+                addr,
+                if authenticated {
+                    " (token required)"
+                } else {
+                    ""
                 }
-                Ok(())
-            });
+            );
+            Ok(Self { reg, readiness })
+        }
 
-            info!("serving Prometheus metrics on {}", addr);
-            Ok(result)
+        pub fn readiness(&self) -> Readiness {
+            self.readiness.clone()
         }
 
         pub fn histogram_vec(
@@ -67,6 +88,222 @@ mod metrics_impl {
                 .expect("failed to register Gauge");
             Gauge { gauge }
         }
+
+        pub fn counter(&self, name: &str, desc: &str, label: &str) -> Counter {
+            let opts = prometheus::Opts::new(name, desc);
+            let counter = prometheus::IntCounterVec::new(opts, &[label]).unwrap();
+            self.reg
+                .register(Box::new(counter.clone()))
+                .expect("failed to register Counter");
+            Counter { counter }
+        }
+    }
+
+    /// HTTP response to a metrics request
+    struct Reply {
+        status: u16,
+        body: Vec<u8>,
+    }
+
+    impl Reply {
+        fn reason(&self) -> &'static str {
+            match self.status {
+                200 => "OK",
+                401 => "Unauthorized",
+                503 => "Service Unavailable",
+                _ => "",
+            }
+        }
+
+        fn text(status: u16, text: &str) -> Self {
+            Self {
+                status,
+                body: format!("{}\n", text).into_bytes(),
+            }
+        }
+    }
+
+    /// Index sync state, reported via the `/ready` endpoint
+    #[derive(Clone, Default)]
+    pub struct Readiness {
+        synced: Arc<AtomicBool>, // initial sync is over
+        lag: Arc<AtomicUsize>,   // number of blocks left to index
+    }
+
+    impl Readiness {
+        pub fn set(&self, synced: bool, lag: usize) {
+            self.lag.store(lag, Ordering::SeqCst);
+            self.synced.store(synced, Ordering::SeqCst);
+        }
+
+        fn is_ready(&self, max_lag: usize) -> bool {
+            self.synced.load(Ordering::SeqCst) && self.lag.load(Ordering::SeqCst) <= max_lag
+        }
+    }
+
+    #[derive(Clone)]
+    struct Handler {
+        reg: Registry,
+        token: Option<SensitiveToken>,
+        readiness: Readiness,
+        ready_lag_blocks: usize,
+    }
+
+    impl Handler {
+        /// Liveness and readiness probes are unauthenticated, all other paths serve metrics.
+        fn handle(&self, path: &str, authorization: Option<&str>) -> Result<Reply> {
+            match path.split('?').next() {
+                Some("/health") => return Ok(Reply::text(200, "OK")),
+                Some("/ready") => {
+                    return Ok(if self.readiness.is_ready(self.ready_lag_blocks) {
+                        Reply::text(200, "OK")
+                    } else {
+                        Reply::text(503, "Not ready")
+                    })
+                }
+                _ => (),
+            }
+            if !self.authorized(authorization) {
+                return Ok(Reply::text(401, "Unauthorized"));
+            }
+            let mut body = vec![];
+            prometheus::TextEncoder::new()
+                .encode(&self.reg.gather(), &mut body)
+                .context("failed to encode metrics")?;
+            Ok(Reply { status: 200, body })
+        }
+
+        /// Metrics are served without authentication, unless a token is configured.
+        fn authorized(&self, authorization: Option<&str>) -> bool {
+            let token = match &self.token {
+                Some(token) => token,
+                None => return true,
+            };
+            authorization
+                .and_then(|value| value.strip_prefix("Bearer "))
+                .map_or(false, |value| token.matches(value.trim()))
+        }
+    }
+
+    fn serve_tcp(addr: SocketAddr, handler: Handler) {
+        spawn("metrics", move || {
+            let server = Server::http(addr).unwrap();
+            for request in server.incoming_requests() {
+                let authorization = request
+                    .headers()
+                    .iter()
+                    .find(|header| header.field.equiv("Authorization"))
+                    .map(|header| header.value.as_str().to_owned());
+                let reply = handler.handle(request.url(), authorization.as_deref())?;
+                let mut response = Response::from_data(reply.body).with_status_code(reply.status);
+                if reply.status == 401 {
+                    response.add_header(
+                        Header::from_bytes(&b"WWW-Authenticate"[..], &b"Bearer"[..]).unwrap(),
+                    );
+                }
+                request
+                    .respond(response)
+                    .context("failed to send HTTP response")?;
+            }
+            Ok(())
+        });
+    }
+
+    #[cfg(unix)]
+    mod unix {
+        use anyhow::{Context, Result};
+        use prometheus::{Encoder, TextEncoder};
+
+        use std::io::{BufRead, BufReader, Write};
+        use std::os::unix::fs::FileTypeExt;
+        use std::os::unix::net::{UnixListener, UnixStream};
+        use std::path::Path;
+
+        use super::Handler;
+        use crate::thread::spawn;
+
+        /// Serve metrics over HTTP on a Unix socket (e.g. for a sidecar scraper).
+        pub(super) fn serve(path: &Path, handler: Handler) -> Result<()> {
+            // a stale socket may remain after an unclean shutdown
+            if let Ok(metadata) = std::fs::symlink_metadata(path) {
+                if metadata.file_type().is_socket() {
+                    std::fs::remove_file(path)
+                        .with_context(|| format!("failed to remove {}", path.display()))?;
+                }
+            }
+            let listener = UnixListener::bind(path)
+                .with_context(|| format!("failed to bind {}", path.display()))?;
+            spawn("metrics", move || {
+                for stream in listener.incoming() {
+                    let stream = stream.context("failed to accept")?;
+                    if let Err(e) = respond(stream, &handler) {
+                        warn!("failed to serve metrics: {:#}", e);
+                    }
+                }
+                Ok(())
+            });
+            Ok(())
+        }
+
+        fn respond(mut stream: UnixStream, handler: &Handler) -> Result<()> {
+            // only the request line and headers are needed
+            let mut reader = BufReader::new(&stream);
+            let mut line = String::new();
+            reader.read_line(&mut line)?;
+            let path = line.split_whitespace().nth(1).unwrap_or("/").to_owned();
+            let mut authorization = None;
+            loop {
+                line.clear();
+                if reader.read_line(&mut line)? == 0 || line.trim_end().is_empty() {
+                    break;
+                }
+                let mut parts = line.splitn(2, ':');
+                if let (Some(name), Some(value)) = (parts.next(), parts.next()) {
+                    if name.eq_ignore_ascii_case("Authorization") {
+                        authorization = Some(value.trim().to_owned());
+                    }
+                }
+            }
+            let reply = handler.handle(&path, authorization.as_deref())?;
+            write!(
+                stream,
+                "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\n",
+                reply.status,
+                reply.reason(),
+                TextEncoder::new().format_type(),
+                reply.body.len()
+            )?;
+            if reply.status == 401 {
+                write!(stream, "WWW-Authenticate: Bearer\r\n")?;
+            }
+            write!(stream, "Connection: close\r\n\r\n")?;
+            stream.write_all(&reply.body)?;
+            Ok(())
+        }
+    }
+
+    #[cfg(not(unix))]
+    mod unix {
+        use anyhow::{bail, Result};
+
+        use std::path::Path;
+
+        use super::Handler;
+
+        pub(super) fn serve(path: &Path, _handler: Handler) -> Result<()> {
+            bail!("Unix sockets are not supported: {}", path.display())
+        }
+    }
+
+    #[derive(Clone)]
+    pub struct Counter {
+        counter: prometheus::IntCounterVec,
+    }
+
+    impl Counter {
+        pub fn inc(&self, label: &str) {
+            self.counter.with_label_values(&[label]).inc()
+        }
     }
 
     #[derive(Clone)]
@@ -99,25 +336,97 @@ mod metrics_impl {
                 .observe_closure_duration(func)
         }
     }
+
+    #[cfg(test)]
+    mod tests {
+        use super::{Counter, Handler, Readiness};
+        use crate::config::SensitiveToken;
+        use prometheus::{IntCounterVec, Opts, Registry};
+
+        fn make_handler(token: Option<&str>) -> Handler {
+            let reg = Registry::new();
+            let counter =
+                IntCounterVec::new(Opts::new("test_counter", "Test counter"), &["label"]).unwrap();
+            reg.register(Box::new(counter.clone())).unwrap();
+            Counter { counter }.inc("value");
+            Handler {
+                reg,
+                token: token.map(|t| SensitiveToken(t.to_owned())),
+                readiness: Readiness::default(),
+                ready_lag_blocks: 1,
+            }
+        }
+
+        fn status(handler: &Handler, authorization: Option<&str>) -> u16 {
+            handler.handle("/metrics", authorization).unwrap().status
+        }
+
+        #[test]
+        fn test_token() {
+            // backward compatible: no authentication by default
+            let handler = make_handler(None);
+            assert_eq!(status(&handler, None), 200);
+            assert_eq!(status(&handler, Some("Bearer whatever")), 200);
+
+            let handler = make_handler(Some("secret"));
+            assert_eq!(status(&handler, None), 401);
+            assert_eq!(status(&handler, Some("Bearer wrong")), 401);
+            assert_eq!(status(&handler, Some("Bearer secre")), 401);
+            assert_eq!(status(&handler, Some("Basic secret")), 401);
+            let reply = handler.handle("/metrics", Some("Bearer secret")).unwrap();
+            assert_eq!(reply.status, 200);
+            let body = String::from_utf8(reply.body).unwrap();
+            assert!(body.contains("test_counter{label=\"value\"} 1"), "{}", body);
+        }
+
+        #[test]
+        fn test_probes() {
+            let handler = make_handler(Some("secret"));
+            let status = |path| handler.handle(path, None).unwrap().status;
+            assert_eq!(status("/health"), 200);
+            assert_eq!(status("/ready"), 503); // initial sync is not over
+
+            handler.readiness.set(false, 1);
+            assert_eq!(status("/ready"), 503);
+            handler.readiness.set(true, 0);
+            assert_eq!(status("/ready"), 200);
+            assert_eq!(status("/ready?verbose"), 200);
+            handler.readiness.set(true, 1);
+            assert_eq!(status("/ready"), 200); // within `ready_lag_blocks`
+            handler.readiness.set(true, 2);
+            assert_eq!(status("/ready"), 503);
+            assert_eq!(status("/health"), 200);
+
+            assert_eq!(status("/metrics"), 401); // metrics still require the token
+        }
+    }
 }
 
 #[cfg(feature = "metrics")]
-pub use metrics_impl::{Gauge, Histogram, Metrics};
+pub use metrics_impl::{Counter, Gauge, Histogram, Metrics, Readiness};
 
 #[cfg(not(feature = "metrics"))]
 mod metrics_fake {
     use anyhow::Result;
 
-    use std::net::SocketAddr;
+    use crate::config::{MonitoringAddr, SensitiveToken};
 
     pub struct Metrics {}
 
     impl Metrics {
-        pub fn new(_addr: SocketAddr) -> Result<Self> {
+        pub fn new(
+            _addr: MonitoringAddr,
+            _token: Option<SensitiveToken>,
+            _ready_lag_blocks: usize,
+        ) -> Result<Self> {
             debug!("metrics collection is disabled");
             Ok(Self {})
         }
 
+        pub fn readiness(&self) -> Readiness {
+            Readiness {}
+        }
+
         pub fn histogram_vec(
             &self,
             _name: &str,
@@ -131,6 +440,17 @@ mod metrics_fake {
         pub fn gauge(&self, _name: &str, _desc: &str, _label: &str) -> Gauge {
             Gauge {}
         }
+
+        pub fn counter(&self, _name: &str, _desc: &str, _label: &str) -> Counter {
+            Counter {}
+        }
+    }
+
+    #[derive(Clone)]
+    pub struct Counter {}
+
+    impl Counter {
+        pub fn inc(&self, _label: &str) {}
     }
 
     #[derive(Clone)]
@@ -140,6 +460,13 @@ mod metrics_fake {
         pub fn set(&self, _label: &str, _value: f64) {}
     }
 
+    #[derive(Clone)]
+    pub struct Readiness {}
+
+    impl Readiness {
+        pub fn set(&self, _synced: bool, _lag: usize) {}
+    }
+
     #[derive(Clone)]
     pub struct Histogram {}
 
@@ -156,7 +483,7 @@ mod metrics_fake {
 }
 
 #[cfg(not(feature = "metrics"))]
-pub use metrics_fake::{Gauge, Histogram, Metrics};
+pub use metrics_fake::{Counter, Gauge, Histogram, Metrics, Readiness};
 
 pub(crate) fn default_duration_buckets() -> Vec<f64> {
     vec![
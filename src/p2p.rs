@@ -11,10 +11,12 @@ use bitcoin::{
         message_network,
     },
     secp256k1::{self, rand::Rng},
-    Block, BlockHash, BlockHeader, Network,
+    Block, BlockHash, BlockHeader, Network, Txid,
 };
 use crossbeam_channel::{bounded, select, Receiver, Sender};
 
+use std::cell::Cell;
+use std::fmt;
 use std::io::{self, ErrorKind, Write};
 use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpStream};
 use std::sync::Arc;
@@ -23,9 +25,11 @@ use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use crate::{
     chain::{Chain, NewHeader},
     config::ELECTRS_VERSION,
-    metrics::{default_duration_buckets, default_size_buckets, Histogram, Metrics},
+    metrics::{default_duration_buckets, default_size_buckets, Counter, Histogram, Metrics},
 };
 
+const MAX_BLOCK_ATTEMPTS: usize = 3;
+
 enum Request {
     GetNewHeaders(GetHeadersMessage),
     GetBlocks(Vec<Inventory>),
@@ -49,13 +53,98 @@ impl Request {
     }
 }
 
+/// Request `items` using a sliding window (having at most `window` in-flight items),
+/// and process them in order (the next item is requested before the current one is processed).
+fn pipelined<T, I: Copy>(
+    items: &[I],
+    window: usize,
+    mut request: impl FnMut(&[I]) -> Result<()>,
+    mut receive: impl FnMut(I) -> Result<T>,
+    mut process: impl FnMut(I, T),
+) -> Result<()> {
+    let mut requested = std::cmp::min(std::cmp::max(window, 1), items.len());
+    if requested > 0 {
+        request(&items[..requested])?;
+    }
+    for item in items {
+        let value = receive(*item)?;
+        if requested < items.len() {
+            request(&items[requested..requested + 1])?;
+            requested += 1;
+        }
+        process(*item, value);
+    }
+    Ok(())
+}
+
+/// A received block doesn't match the requested one (e.g. due to data corruption)
+#[derive(Debug)]
+struct InvalidBlock {
+    blockhash: BlockHash,
+    reason: &'static str,
+}
+
+impl fmt::Display for InvalidBlock {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid block {}: {}", self.blockhash, self.reason)
+    }
+}
+
+impl std::error::Error for InvalidBlock {}
+
+fn validate_block(block: &Block, blockhash: BlockHash) -> Result<(), InvalidBlock> {
+    let reason = if block.block_hash() != blockhash {
+        "unexpected block hash"
+    } else if !block.check_merkle_root() {
+        "invalid merkle root"
+    } else if !block.check_witness_commitment() {
+        "invalid witness commitment"
+    } else {
+        return Ok(());
+    };
+    Err(InvalidBlock { blockhash, reason })
+}
+
+/// Process `items` using `fetch` (which processes them in order, counting the processed ones),
+/// re-fetching the unprocessed items if an invalid block is received.
+/// Fails after `max_attempts` consecutive invalid blocks (without any progress).
+fn retry_invalid<I>(
+    items: &[I],
+    max_attempts: usize,
+    mut fetch: impl FnMut(&[I], &mut usize) -> Result<()>,
+    mut on_invalid: impl FnMut(&InvalidBlock),
+) -> Result<()> {
+    let mut processed = 0;
+    let mut attempts = 0;
+    loop {
+        let start = processed;
+        let err = match fetch(&items[start..], &mut processed) {
+            Ok(()) => return Ok(()),
+            Err(err) => err,
+        };
+        let invalid = match err.downcast_ref::<InvalidBlock>() {
+            Some(invalid) => invalid,
+            None => return Err(err),
+        };
+        on_invalid(invalid);
+        attempts = if processed > start { 1 } else { attempts + 1 };
+        if attempts >= max_attempts {
+            return Err(err.context(format!("failed after {} attempts", attempts)));
+        }
+    }
+}
+
 pub(crate) struct Connection {
     req_send: Sender<Request>,
     blocks_recv: Receiver<Block>,
     headers_recv: Receiver<Vec<BlockHeader>>,
     new_block_recv: Receiver<()>,
+    new_txs_recv: Receiver<Vec<Txid>>,
+    inflight_blocks: usize, // responses to be dropped (requested by a failed `for_blocks` call)
+    invalid_blocks: Counter,
 
     blocks_duration: Histogram,
+    max_inflight_blocks: usize, // requested blocks that were not received yet
 }
 
 impl Connection {
@@ -93,30 +182,73 @@ impl Connection {
         B: IntoIterator<Item = BlockHash>,
         F: FnMut(BlockHash, Block),
     {
-        self.blocks_duration.observe_duration("total", || {
+        let blocks_duration = self.blocks_duration.clone();
+        blocks_duration.observe_duration("total", || {
             let blockhashes: Vec<BlockHash> = blockhashes.into_iter().collect();
             if blockhashes.is_empty() {
                 return Ok(());
             }
-            self.blocks_duration.observe_duration("request", || {
-                debug!("loading {} blocks", blockhashes.len());
-                self.req_send.send(Request::get_blocks(&blockhashes))
-            })?;
+            debug!("loading {} blocks", blockhashes.len());
+            let invalid_blocks = self.invalid_blocks.clone();
+            retry_invalid(
+                &blockhashes,
+                MAX_BLOCK_ATTEMPTS,
+                |hashes, processed| self.get_blocks(hashes, &mut func, processed),
+                |invalid| {
+                    warn!("{}, retrying", invalid);
+                    invalid_blocks.inc(invalid.reason);
+                },
+            )
+        })
+    }
 
-            for hash in blockhashes {
-                let block = self.blocks_duration.observe_duration("response", || {
-                    let block = self
-                        .blocks_recv
+    fn get_blocks<F>(
+        &mut self,
+        blockhashes: &[BlockHash],
+        func: &mut F,
+        processed: &mut usize,
+    ) -> Result<()>
+    where
+        F: FnMut(BlockHash, Block),
+    {
+        // drop the responses to the requests of a failed call
+        while self.inflight_blocks > 0 {
+            self.blocks_recv.recv().context("failed to drop block")?;
+            self.inflight_blocks -= 1;
+        }
+        let inflight = Cell::new(0);
+        let req_send = &self.req_send;
+        let blocks_recv = &self.blocks_recv;
+        let blocks_duration = &self.blocks_duration;
+        let result = pipelined(
+            blockhashes,
+            self.max_inflight_blocks,
+            |hashes| {
+                blocks_duration.observe_duration("request", || {
+                    req_send
+                        .send(Request::get_blocks(hashes))
+                        .context("failed to request blocks")?;
+                    inflight.set(inflight.get() + hashes.len());
+                    Ok(())
+                })
+            },
+            |hash| {
+                blocks_duration.observe_duration("response", || {
+                    let block = blocks_recv
                         .recv()
                         .with_context(|| format!("failed to get block {}", hash))?;
-                    ensure!(block.block_hash() == hash, "got unexpected block");
+                    inflight.set(inflight.get() - 1);
+                    validate_block(&block, hash)?;
                     Ok(block)
-                })?;
-                self.blocks_duration
-                    .observe_duration("process", || func(hash, block));
-            }
-            Ok(())
-        })
+                })
+            },
+            |hash, block| {
+                blocks_duration.observe_duration("process", || func(hash, block));
+                *processed += 1;
+            },
+        );
+        self.inflight_blocks = inflight.get();
+        result
     }
 
     /// Note: only a single receiver will get the notification (https://github.com/romanz/electrs/pull/526#issuecomment-934687415).
@@ -124,10 +256,19 @@ impl Connection {
         self.new_block_recv.clone()
     }
 
+    /// Transactions announced by bitcoind (only if requested when connecting).
+    pub(crate) fn new_txs_notification(&self) -> Receiver<Vec<Txid>> {
+        self.new_txs_recv.clone()
+    }
+
     pub(crate) fn connect(
         network: Network,
+        magic: u32,
         address: SocketAddr,
         metrics: &Metrics,
+        max_inflight_blocks: usize,
+        max_message_size: usize,
+        relay_txs: bool,
     ) -> Result<Self> {
         let conn = Arc::new(
             TcpStream::connect(address)
@@ -186,7 +327,7 @@ impl Connection {
             send_duration.observe_duration("send", || {
                 trace!("send: {:?}", msg);
                 let raw_msg = message::RawNetworkMessage {
-                    magic: network.magic(),
+                    magic,
                     payload: msg,
                 };
                 (&*stream)
@@ -198,7 +339,7 @@ impl Connection {
         let stream = Arc::clone(&conn);
         crate::thread::spawn("p2p_recv", move || loop {
             let start = Instant::now();
-            let raw_msg = RawNetworkMessage::consensus_decode(&*stream);
+            let raw_msg = RawNetworkMessage::read(&*stream, max_message_size);
             {
                 let duration = duration_to_seconds(start.elapsed());
                 let label = format!(
@@ -212,7 +353,7 @@ impl Connection {
             }
             let raw_msg = match raw_msg {
                 Ok(raw_msg) => {
-                    assert_eq!(raw_msg.magic, network.magic());
+                    assert_eq!(raw_msg.magic, magic);
                     recv_size.observe(raw_msg.cmd.as_ref(), raw_msg.raw.len() as f64);
                     raw_msg
                 }
@@ -230,9 +371,10 @@ impl Connection {
         let (blocks_send, blocks_recv) = bounded::<Block>(10);
         let (headers_send, headers_recv) = bounded::<Vec<BlockHeader>>(1);
         let (new_block_send, new_block_recv) = bounded::<()>(0);
+        let (new_txs_send, new_txs_recv) = bounded::<Vec<Txid>>(100);
         let (init_send, init_recv) = bounded::<()>(0);
 
-        tx_send.send(build_version_message())?;
+        tx_send.send(build_version_message(relay_txs))?;
 
         crate::thread::spawn("p2p_loop", move || loop {
             select! {
@@ -260,9 +402,13 @@ impl Connection {
                         }
                         NetworkMessage::Inv(inventory) => {
                             debug!("peer inventory: {:?}", inventory);
-                            if inventory.iter().any(|inv| matches!(inv, Inventory::Block(_))) {
+                            if announces_block(&inventory) {
                                 let _ = new_block_send.try_send(()); // best-effort notification
                             }
+                            let txids = announced_txids(&inventory);
+                            if !txids.is_empty() {
+                                let _ = new_txs_send.try_send(txids); // best-effort (mempool is also polled)
+                            }
 
                         },
                         NetworkMessage::Ping(nonce) => {
@@ -302,12 +448,38 @@ impl Connection {
             blocks_recv,
             headers_recv,
             new_block_recv,
+            new_txs_recv,
+            inflight_blocks: 0,
+            invalid_blocks: metrics.counter(
+                "p2p_invalid_blocks",
+                "# of invalid blocks received via p2p protocol (and re-requested)",
+                "reason",
+            ),
             blocks_duration,
+            max_inflight_blocks,
         })
     }
 }
 
-fn build_version_message() -> NetworkMessage {
+/// New blocks are announced by bitcoind via `inv` messages, so there is no need to poll for them.
+fn announces_block(inventory: &[Inventory]) -> bool {
+    inventory
+        .iter()
+        .any(|inv| matches!(inv, Inventory::Block(_)))
+}
+
+/// New mempool transactions are announced by bitcoind only if `relay` is set in our version message.
+fn announced_txids(inventory: &[Inventory]) -> Vec<Txid> {
+    inventory
+        .iter()
+        .filter_map(|inv| match inv {
+            Inventory::Transaction(txid) | Inventory::WitnessTransaction(txid) => Some(*txid),
+            _ => None,
+        })
+        .collect()
+}
+
+fn build_version_message(relay: bool) -> NetworkMessage {
     let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 0);
     let timestamp = SystemTime::now()
         .duration_since(UNIX_EPOCH)
@@ -325,7 +497,7 @@ fn build_version_message() -> NetworkMessage {
         nonce: secp256k1::rand::thread_rng().gen(),
         user_agent: format!("/electrs:{}/", ELECTRS_VERSION),
         start_height: 0,
-        relay: false,
+        relay,
     })
 }
 
@@ -364,16 +536,30 @@ impl RawNetworkMessage {
         };
         Ok(payload)
     }
-}
 
-impl Decodable for RawNetworkMessage {
-    fn consensus_decode<D: io::Read>(mut d: D) -> Result<Self, encode::Error> {
+    /// Read a message, failing (before allocating its payload) if it is larger than `max_size`.
+    fn read<D: io::Read>(mut d: D, max_size: usize) -> Result<Self, encode::Error> {
         let magic = Decodable::consensus_decode(&mut d)?;
-        let cmd = Decodable::consensus_decode(&mut d)?;
+        let cmd: CommandString = Decodable::consensus_decode(&mut d)?;
 
-        let len = u32::consensus_decode(&mut d)?;
+        let len = u32::consensus_decode(&mut d)? as usize;
         let _checksum = <[u8; 4]>::consensus_decode(&mut d)?; // assume data is correct
-        let mut raw = vec![0u8; len as usize];
+        if len > max_size {
+            if cmd.as_ref() == "block" {
+                let header = BlockHeader::consensus_decode(&mut d)?;
+                error!(
+                    "block {} is too large: {} > {} bytes",
+                    header.block_hash(),
+                    len,
+                    max_size
+                );
+            }
+            return Err(encode::Error::OversizedVectorAllocation {
+                requested: len,
+                max: max_size,
+            });
+        }
+        let mut raw = vec![0u8; len];
         d.read_slice(&mut raw)?;
 
         Ok(RawNetworkMessage { magic, cmd, raw })
@@ -386,3 +572,226 @@ pub fn duration_to_seconds(d: Duration) -> f64 {
     let nanos = f64::from(d.subsec_nanos()) / 1e9;
     d.as_secs() as f64 + nanos
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        announced_txids, announces_block, pipelined, retry_invalid, validate_block, InvalidBlock,
+        RawNetworkMessage,
+    };
+    use anyhow::{bail, Result};
+    use bitcoin::{
+        blockdata::constants::genesis_block,
+        consensus::{encode, serialize},
+        hashes::Hash,
+        network::{
+            message::{self, NetworkMessage},
+            message_blockdata::Inventory,
+        },
+        BlockHash, Network, TxMerkleNode, Txid,
+    };
+
+    use std::cell::RefCell;
+    use std::collections::VecDeque;
+
+    #[test]
+    fn test_pipelined() {
+        let items: Vec<usize> = (0..20).collect();
+        for window in 0..25 {
+            let inflight = RefCell::new(VecDeque::new());
+            let mut max_inflight = 0;
+            let mut processed = vec![];
+            pipelined(
+                &items,
+                window,
+                |requested| {
+                    let mut inflight = inflight.borrow_mut();
+                    inflight.extend(requested.iter().copied());
+                    max_inflight = std::cmp::max(max_inflight, inflight.len());
+                    Ok(())
+                },
+                |item| {
+                    assert_eq!(inflight.borrow_mut().pop_front(), Some(item)); // responses arrive in order
+                    Ok(item * 10)
+                },
+                |item, value| processed.push((item, value)),
+            )
+            .unwrap();
+            assert!(inflight.borrow().is_empty());
+            assert!(max_inflight <= std::cmp::max(window, 1));
+            let expected: Vec<_> = items.iter().map(|i| (*i, i * 10)).collect();
+            assert_eq!(processed, expected); // all items are processed exactly once, in order
+        }
+    }
+
+    #[test]
+    fn test_pipelined_max_inflight() {
+        let items: Vec<usize> = (0..10).collect();
+        let inflight = RefCell::new(0);
+        let mut max_inflight = 0;
+        pipelined(
+            &items,
+            3,
+            |requested| {
+                *inflight.borrow_mut() += requested.len();
+                max_inflight = std::cmp::max(max_inflight, *inflight.borrow());
+                Ok(())
+            },
+            |_item| {
+                *inflight.borrow_mut() -= 1;
+                Ok(())
+            },
+            |_item, ()| (),
+        )
+        .unwrap();
+        assert_eq!(max_inflight, 3);
+    }
+
+    #[test]
+    fn test_max_message_size() {
+        let block = genesis_block(Network::Regtest);
+        let data = serialize(&message::RawNetworkMessage {
+            magic: Network::Regtest.magic(),
+            payload: NetworkMessage::Block(block.clone()),
+        });
+        let size = serialize(&block).len();
+
+        let msg = RawNetworkMessage::read(&data[..], size).unwrap();
+        assert_eq!(msg.cmd.as_ref(), "block");
+        match msg.parse().unwrap() {
+            NetworkMessage::Block(parsed) => assert_eq!(parsed, block),
+            msg => panic!("unexpected message: {:?}", msg),
+        }
+
+        // the payload is not read (nor allocated)
+        match RawNetworkMessage::read(&data[..], size - 1) {
+            Err(encode::Error::OversizedVectorAllocation { requested, max }) => {
+                assert_eq!((requested, max), (size, size - 1))
+            }
+            result => panic!("unexpected result: {:?}", result.map(|msg| msg.cmd)),
+        }
+    }
+
+    #[test]
+    fn test_announces_block() {
+        let blockhash = genesis_block(Network::Regtest).block_hash();
+        assert!(announces_block(&[Inventory::Block(blockhash)]));
+        assert!(announces_block(&[
+            Inventory::Transaction(Txid::default()),
+            Inventory::Block(blockhash)
+        ]));
+        assert!(!announces_block(&[Inventory::Transaction(Txid::default())]));
+        assert!(!announces_block(&[]));
+    }
+
+    #[test]
+    fn test_announced_txids() {
+        let txid = Txid::hash(b"tx");
+        let blockhash = genesis_block(Network::Regtest).block_hash();
+        assert_eq!(
+            announced_txids(&[
+                Inventory::Block(blockhash),
+                Inventory::Transaction(txid),
+                Inventory::WitnessTransaction(Txid::default()),
+            ]),
+            vec![txid, Txid::default()]
+        );
+        assert!(announced_txids(&[Inventory::Block(blockhash)]).is_empty());
+    }
+
+    #[test]
+    fn test_validate_block() {
+        let block = genesis_block(Network::Regtest);
+        let blockhash = block.block_hash();
+        assert!(validate_block(&block, blockhash).is_ok());
+
+        let other = genesis_block(Network::Testnet).block_hash();
+        assert_eq!(
+            validate_block(&block, other).unwrap_err().reason,
+            "unexpected block hash"
+        );
+
+        let mut corrupt = block.clone();
+        corrupt.txdata[0].lock_time = 1; // header is unchanged
+        assert_eq!(
+            validate_block(&corrupt, blockhash).unwrap_err().reason,
+            "invalid merkle root"
+        );
+
+        let mut corrupt = block;
+        corrupt.header.merkle_root = TxMerkleNode::default();
+        assert!(validate_block(&corrupt, blockhash).is_err());
+    }
+
+    fn invalid() -> InvalidBlock {
+        InvalidBlock {
+            blockhash: BlockHash::default(),
+            reason: "invalid merkle root",
+        }
+    }
+
+    #[test]
+    fn test_retry_invalid() {
+        let items: Vec<usize> = (0..10).collect();
+
+        // a corrupt block is re-fetched, instead of failing
+        let mut processed = vec![];
+        let mut failures = 0;
+        let mut invalid_count = 0;
+        retry_invalid(
+            &items,
+            3,
+            |items, count| -> Result<()> {
+                for item in items {
+                    if *item == 5 && failures < 2 {
+                        failures += 1;
+                        return Err(invalid().into());
+                    }
+                    processed.push(*item);
+                    *count += 1;
+                }
+                Ok(())
+            },
+            |_| invalid_count += 1,
+        )
+        .unwrap();
+        assert_eq!(processed, items); // all items are processed exactly once, in order
+        assert_eq!(invalid_count, 2);
+
+        // fail after too many attempts
+        let mut processed = vec![];
+        let mut invalid_count = 0;
+        let result = retry_invalid(
+            &items,
+            3,
+            |items, count| -> Result<()> {
+                for item in items {
+                    if *item == 5 {
+                        return Err(invalid().into());
+                    }
+                    processed.push(*item);
+                    *count += 1;
+                }
+                Ok(())
+            },
+            |_| invalid_count += 1,
+        );
+        assert!(result.unwrap_err().downcast_ref::<InvalidBlock>().is_some());
+        assert_eq!(processed, vec![0, 1, 2, 3, 4]);
+        assert_eq!(invalid_count, 3);
+
+        // other errors are not retried
+        let mut calls = 0;
+        let result = retry_invalid(
+            &items,
+            3,
+            |_, _| -> Result<()> {
+                calls += 1;
+                bail!("disconnected")
+            },
+            |_| panic!("unexpected invalid block"),
+        );
+        assert!(result.is_err());
+        assert_eq!(calls, 1);
+    }
+}
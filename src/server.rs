@@ -1,43 +1,119 @@
-use anyhow::{Context, Result};
-use crossbeam_channel::{select, unbounded, Sender};
+use anyhow::{anyhow, Context, Result};
+use bitcoin::Txid;
+use crossbeam_channel::{bounded, select, unbounded, Receiver, Sender, TrySendError};
+use parking_lot::Mutex;
 use rayon::prelude::*;
 
 use std::{
     collections::hash_map::HashMap,
-    io::{BufRead, BufReader, Write},
+    io::{self, BufRead, BufReader, BufWriter, Write},
     iter::once,
     net::{Shutdown, TcpListener, TcpStream},
+    panic::{catch_unwind, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
 };
 
 use crate::{
-    config::Config,
-    electrum::{Client, Rpc},
-    metrics::{self, Metrics},
+    config::{Config, IpAllowlist, MonitoringAddr, StatsFormat},
+    db::DBStore,
+    electrum::{Client, Response, Rpc},
+    metrics::{self, Counter, Metrics},
     signals::ExitError,
-    thread::spawn,
+    thread::{spawn, spawn_with_stack_size},
+    websocket::{self, Compression},
 };
 
+/// How the line-based Electrum protocol is carried over a connection.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Transport {
+    Tcp, // never compressed
+    // each request and response is sent as a separate text message
+    // (the listener's compression is allowed, and the connection's one is negotiated)
+    WebSocket(Compression),
+}
+
+// A client which doesn't read its responses can't make the server buffer more than these
+const MAX_QUEUED_SENDS: usize = 100;
+
+// the initial mempool fetch shouldn't delay serving clients (the rest is fetched by the next sync)
+const MEMPOOL_WARMUP_TIMEOUT: Duration = Duration::from_secs(30);
+
+// `send_loop` only writes the queued responses, so it doesn't need a full-size stack
+const SEND_STACK_SIZE: usize = 256 << 10;
+
+/// Queued for the peer's sending thread
+enum Outgoing {
+    Responses(Vec<Response>),
+    Pong(Vec<u8>), // WebSocket keepalive
+}
+
 struct Peer {
     id: usize,
     client: Client,
     stream: TcpStream,
+    tx: Sender<Outgoing>,
+    failure: Arc<Mutex<Option<anyhow::Error>>>, // why the sending thread has exited
 }
 
 impl Peer {
-    fn new(id: usize, stream: TcpStream) -> Self {
-        let client = Client::default();
-        Self { id, client, stream }
+    /// Responses are written by a separate thread, so a slow client (blocking until its write
+    /// timeout expires) doesn't block the serving loop.
+    fn new(id: usize, stream: TcpStream, transport: Transport, client: Client) -> Result<Self> {
+        let writer = stream.try_clone().context("failed to clone TCP stream")?;
+        let (tx, rx) = bounded(MAX_QUEUED_SENDS);
+        let failure = Arc::new(Mutex::new(None));
+        let result = Arc::clone(&failure);
+        spawn_with_stack_size("send_loop", SEND_STACK_SIZE, move || {
+            if let Err(e) = send_loop(id, &writer, transport, rx) {
+                let _ = writer.shutdown(Shutdown::Both); // the peer is dropped by `recv_loop`
+                *result.lock() = Some(e);
+            }
+            Ok(())
+        });
+        Ok(Self {
+            id,
+            client,
+            stream,
+            tx,
+            failure,
+        })
     }
 
-    fn send(&mut self, values: Vec<String>) -> Result<()> {
-        for mut value in values {
-            debug!("{}: send {}", self.id, value);
-            value += "\n";
-            self.stream
-                .write_all(value.as_bytes())
-                .with_context(|| format!("failed to send response: {:?}", value))?;
+    fn send(&mut self, responses: Vec<Response>) -> Result<()> {
+        if responses.is_empty() {
+            return Ok(());
         }
-        Ok(())
+        self.enqueue(Outgoing::Responses(responses))
+    }
+
+    fn pong(&mut self, payload: &[u8]) -> Result<()> {
+        self.enqueue(Outgoing::Pong(payload.to_vec()))
+    }
+
+    fn enqueue(&self, outgoing: Outgoing) -> Result<()> {
+        match self.tx.try_send(outgoing) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(_)) => Err(io::Error::new(
+                io::ErrorKind::WouldBlock,
+                format!("more than {} queued sends", MAX_QUEUED_SENDS),
+            ))
+            .context("failed to send"),
+            Err(TrySendError::Disconnected(_)) => Err(self
+                .failure
+                .lock()
+                .take()
+                .unwrap_or_else(|| anyhow!("sending thread has exited"))),
+        }
+    }
+
+    fn addr(&self) -> String {
+        self.stream
+            .peer_addr()
+            .map_or_else(|_| "unknown address".to_owned(), |addr| addr.to_string())
     }
 
     fn disconnect(self) {
@@ -47,6 +123,44 @@ impl Peer {
     }
 }
 
+/// Decides how long the server loop may wait (for new blocks and mempool polling).
+struct Poller {
+    tip_interval: Duration,
+    mempool_interval: Duration,
+    last_mempool_poll: Option<Instant>,
+}
+
+impl Poller {
+    fn new(tip_interval: Duration, mempool_interval: Duration) -> Self {
+        Self {
+            tip_interval,
+            mempool_interval,
+            last_mempool_poll: None,
+        }
+    }
+
+    fn mempool_due(&self, now: Instant) -> bool {
+        self.next_mempool_poll(now) <= now
+    }
+
+    fn mempool_polled(&mut self, now: Instant) {
+        self.last_mempool_poll = Some(now);
+    }
+
+    fn next_mempool_poll(&self, now: Instant) -> Instant {
+        match self.last_mempool_poll {
+            Some(last) => last + self.mempool_interval,
+            None => now,
+        }
+    }
+
+    /// Wait until the next tip or mempool polling (whichever comes first).
+    fn timeout(&self, now: Instant) -> Duration {
+        let until_mempool = self.next_mempool_poll(now).saturating_duration_since(now);
+        std::cmp::min(self.tip_interval, until_mempool)
+    }
+}
+
 pub fn run() -> Result<()> {
     let result = serve();
     if let Err(e) = &result {
@@ -60,16 +174,47 @@ pub fn run() -> Result<()> {
     result.context("electrs failed")
 }
 
+fn print_index_stats(config: &Config, format: StatsFormat) -> Result<()> {
+    let store = DBStore::open_read_only(&config.db_path)?;
+    let stats = store.stats();
+    match format {
+        StatsFormat::Table => print!("{}", stats),
+        StatsFormat::Json => println!("{}", serde_json::to_string_pretty(&stats)?),
+    }
+    Ok(())
+}
+
 fn serve() -> Result<()> {
     let config = Config::from_args();
-    let metrics = Metrics::new(config.monitoring_addr)?;
-
-    let (server_tx, server_rx) = unbounded();
-    if !config.disable_electrum_rpc {
-        let listener = TcpListener::bind(config.electrum_rpc_addr)?;
-        info!("serving Electrum RPC on {}", listener.local_addr()?);
-        spawn("accept_loop", || accept_loop(listener, server_tx)); // detach accepting thread
-    };
+    if let Some(format) = config.index_stats {
+        return print_index_stats(&config, format);
+    }
+    if let Some(export) = &config.export {
+        // avoid conflicting with the endpoints of a running instance
+        let metrics = Metrics::new(MonitoringAddr::Tcp(([127, 0, 0, 1], 0).into()), None, 0)?;
+        return Rpc::new(&config, metrics)?.export_history(export);
+    }
+    if let Some(dir) = &config.snapshot_to {
+        let store = DBStore::open(&config.db_path, false)?;
+        store.export_snapshot(dir)?;
+        info!("created DB snapshot at {}", dir.display());
+        return Ok(());
+    }
+    if let Some(dir) = &config.backup_dir {
+        let store = DBStore::open(&config.db_path, false)?;
+        let path = store.backup(dir)?;
+        info!("created DB backup at {}", path.display());
+        return Ok(());
+    }
+    if let Some(dir) = &config.snapshot_from {
+        // the index will catch up from the snapshot's tip
+        DBStore::import_snapshot(dir, &config.db_path)?;
+    }
+    let metrics = Metrics::new(
+        config.monitoring_addr.clone(),
+        config.monitoring_token.clone(),
+        config.ready_lag_blocks,
+    )?;
 
     let server_batch_size = metrics.histogram_vec(
         "server_batch_size",
@@ -83,21 +228,90 @@ fn serve() -> Result<()> {
         "step",
         metrics::default_duration_buckets(),
     );
+    let panics = metrics.counter(
+        "electrum_connection_panics",
+        "# of panics while handling Electrum connections (which were disconnected)",
+        "step",
+    );
+    let slow_clients = metrics.counter(
+        "electrum_slow_clients",
+        "# of Electrum clients disconnected for not reading their responses (see `client_write_timeout_secs`)",
+        "step",
+    );
+    let counters = Counters {
+        panics,
+        slow_clients,
+    };
     let mut rpc = Rpc::new(&config, metrics)?;
+    if config.verify {
+        return rpc.verify_index();
+    }
+    // so that the first clients' queries already include unconfirmed transactions
+    rpc.warmup_mempool(MEMPOOL_WARMUP_TIMEOUT);
+
+    let (server_tx, server_rx) = unbounded();
+    if !config.disable_electrum_rpc {
+        let listener = TcpListener::bind(config.electrum_rpc_addr)?;
+        info!("serving Electrum RPC on {}", listener.local_addr()?);
+        let allowlist = config.rpc_allow_ip.clone();
+        let write_timeout = config.client_write_timeout;
+        let limits = RecvLimits {
+            buffer_size: config.client_read_buffer_bytes,
+            max_line_bytes: config.max_request_line_bytes,
+        };
+        if let Some(addr) = config.electrum_ws_addr {
+            let listener = TcpListener::bind(addr)?;
+            info!(
+                "serving Electrum RPC over WebSocket on {}",
+                listener.local_addr()?
+            );
+            let (allowlist, server_tx) = (allowlist.clone(), server_tx.clone());
+            spawn("ws_accept_loop", move || {
+                accept_loop(
+                    listener,
+                    server_tx,
+                    allowlist,
+                    write_timeout,
+                    limits,
+                    Transport::WebSocket(Compression::Deflate),
+                )
+            }); // detach accepting thread
+        }
+        spawn("accept_loop", move || {
+            accept_loop(
+                listener,
+                server_tx,
+                allowlist,
+                write_timeout,
+                limits,
+                Transport::Tcp,
+            )
+        }); // detach accepting thread
+    };
 
     let new_block_rx = rpc.new_block_notification();
+    let new_txs_rx = rpc.new_txs_notification();
     let mut peers = HashMap::<usize, Peer>::new();
+    let mut poller = Poller::new(config.tip_poll_interval, config.mempool_poll_interval);
     loop {
         // initial sync and compaction may take a few hours
         while server_rx.is_empty() {
-            let done = duration.observe_duration("sync", || rpc.sync().context("sync failed"))?; // sync a batch of blocks
-            peers = duration.observe_duration("notify", || notify_peers(&rpc, peers)); // peers are disconnected on error
+            let poll_mempool = poller.mempool_due(Instant::now());
+            let done = duration.observe_duration("sync", || {
+                rpc.sync(poll_mempool).context("sync failed") // sync a batch of blocks
+            })?;
+            if done && poll_mempool {
+                poller.mempool_polled(Instant::now());
+            }
+            peers = duration.observe_duration("notify", || notify_peers(&rpc, peers, &counters)); // peers are disconnected on error
+            rpc.observe_subscriptions(peers.values().map(|peer| &peer.client));
             if !done {
                 continue; // more blocks to sync
             }
             if config.sync_once {
                 return Ok(()); // exit after initial sync is done
             }
+            duration.observe_duration("prime", || rpc.prime()); // after the peers are notified
             break;
         }
         duration.observe_duration("select", || -> Result<()> {
@@ -115,32 +329,172 @@ fn serve() -> Result<()> {
                         return Ok(());
                     }
                 },
+                // Handle new mempool transactions' announcements (if enabled)
+                recv(new_txs_rx) -> result => match result {
+                    Ok(txids) => {
+                        let rest = new_txs_rx.try_iter().take(new_txs_rx.len()).flatten();
+                        let txids: Vec<Txid> = txids.into_iter().chain(rest).collect();
+                        duration.observe_duration("mempool_announced", || rpc.add_mempool_txids(txids));
+                    }
+                    Err(_) => {
+                        info!("disconnected from bitcoind");
+                        return Ok(());
+                    }
+                },
                 // Handle Electrum RPC requests
                 recv(server_rx) -> event => {
                     let first = once(event.context("server disconnected")?);
                     let rest = server_rx.iter().take(server_rx.len());
                     let events: Vec<Event> = first.chain(rest).collect();
                     server_batch_size.observe("recv", events.len() as f64);
-                    duration.observe_duration("handle", || handle_events(&rpc, &mut peers, events));
+                    duration.observe_duration("handle", || handle_events(&rpc, &mut peers, events, &counters));
+                    rpc.observe_subscriptions(peers.values().map(|peer| &peer.client));
                 },
-                default(config.wait_duration) => (), // sync and update
+                default(timeout(&rpc, &peers, &poller)) => rpc.compact_if_needed(), // start a background compaction (if needed), sync and update
             };
             Ok(())
         })?;
     }
 }
 
-fn notify_peers(rpc: &Rpc, peers: HashMap<usize, Peer>) -> HashMap<usize, Peer> {
+/// Wake up for polling, or for sending coalesced notifications (whichever comes first).
+fn timeout(rpc: &Rpc, peers: &HashMap<usize, Peer>, poller: &Poller) -> Duration {
+    let now = Instant::now();
+    let timeout = poller.timeout(now);
     peers
-        .into_par_iter()
-        .filter_map(|(_, mut peer)| match notify_peer(rpc, &mut peer) {
-            Ok(()) => Some((peer.id, peer)),
-            Err(e) => {
-                error!("failed to notify peer {}: {}", peer.id, e);
-                peer.disconnect();
-                None
-            }
+        .values()
+        .filter_map(|peer| rpc.next_notification(&peer.client))
+        .min()
+        .map_or(timeout, |next| {
+            std::cmp::min(timeout, next.saturating_duration_since(now))
         })
+}
+
+/// Run a single peer's handler, so that a panic (e.g. due to a bug) affects only this peer.
+/// Note that `parking_lot` locks are not poisoned, so the shared state remains usable.
+fn catch_panic<T>(f: impl FnOnce() -> T) -> std::result::Result<T, String> {
+    catch_unwind(AssertUnwindSafe(f)).map_err(|payload| {
+        if let Some(msg) = payload.downcast_ref::<&str>() {
+            msg.to_string()
+        } else if let Some(msg) = payload.downcast_ref::<String>() {
+            msg.clone()
+        } else {
+            "unknown panic".to_owned()
+        }
+    })
+}
+
+/// Disconnected peers' counters (labelled by the step which failed)
+struct Counters {
+    panics: Counter,
+    slow_clients: Counter,
+}
+
+fn disconnect_after_panic(peer: Peer, step: &str, msg: &str, counters: &Counters) {
+    error!(
+        "{}: disconnecting {} due to panic during {}: {}",
+        peer.id,
+        peer.addr(),
+        step,
+        msg
+    );
+    counters.panics.inc(step);
+    peer.disconnect();
+}
+
+// A client which doesn't read its responses fills the connection's send buffer,
+// until sending times out (see `TcpStream::set_write_timeout()`), or its sending queue is full
+fn is_write_timeout(e: &anyhow::Error) -> bool {
+    e.chain()
+        .any(|cause| match cause.downcast_ref::<io::Error>() {
+            Some(err) => matches!(
+                err.kind(),
+                io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+            ),
+            None => false,
+        })
+}
+
+fn disconnect_after_error(peer: Peer, step: &str, e: &anyhow::Error, counters: &Counters) {
+    if is_write_timeout(e) {
+        warn!(
+            "{}: disconnecting slow client {} during {}",
+            peer.id,
+            peer.addr(),
+            step
+        );
+        counters.slow_clients.inc(step);
+    } else {
+        error!("{}: disconnecting due to {} during {}", peer.id, e, step);
+    }
+    peer.disconnect();
+}
+
+fn send_loop(
+    peer_id: usize,
+    stream: &TcpStream,
+    transport: Transport,
+    rx: Receiver<Outgoing>,
+) -> Result<()> {
+    for outgoing in rx.iter() {
+        match outgoing {
+            Outgoing::Responses(responses) => {
+                write_responses(peer_id, stream, transport, responses)?
+            }
+            Outgoing::Pong(payload) => {
+                let mut out = stream;
+                websocket::write_pong(&mut out, &payload).context("failed to send pong")?
+            }
+        }
+    }
+    Ok(()) // the peer was dropped
+}
+
+fn write_responses(
+    peer_id: usize,
+    stream: &TcpStream,
+    transport: Transport,
+    responses: Vec<Response>,
+) -> Result<()> {
+    // large responses are written incrementally (instead of being fully serialized first)
+    let mut out = BufWriter::new(stream);
+    for response in responses {
+        debug!("{}: send {}", peer_id, response);
+        match transport {
+            Transport::Tcp => response.write_line(&mut out),
+            Transport::WebSocket(compression) => {
+                // the frame's header contains its length, so the response is serialized first
+                let mut line = vec![];
+                response.write_line(&mut line)?;
+                line.pop(); // trailing newline
+                websocket::write_text(&mut out, &line, compression)
+            }
+        }
+        .context("failed to send response")?;
+    }
+    out.flush().context("failed to send responses")
+}
+
+fn notify_peers(
+    rpc: &Rpc,
+    peers: HashMap<usize, Peer>,
+    counters: &Counters,
+) -> HashMap<usize, Peer> {
+    peers
+        .into_par_iter()
+        .filter_map(
+            |(_, mut peer)| match catch_panic(|| notify_peer(rpc, &mut peer)) {
+                Ok(Ok(())) => Some((peer.id, peer)),
+                Ok(Err(e)) => {
+                    disconnect_after_error(peer, "notify", &e, counters);
+                    None
+                }
+                Err(msg) => {
+                    disconnect_after_panic(peer, "notify", &msg, counters);
+                    None
+                }
+            },
+        )
         .collect()
 }
 
@@ -158,18 +512,34 @@ struct Event {
 }
 
 enum Message {
-    New(TcpStream),
+    New(TcpStream, Transport),
     Request(String),
+    Ping(Vec<u8>), // WebSocket keepalive
     Done,
 }
 
-fn handle_events(rpc: &Rpc, peers: &mut HashMap<usize, Peer>, events: Vec<Event>) {
+fn handle_events(
+    rpc: &Rpc,
+    peers: &mut HashMap<usize, Peer>,
+    events: Vec<Event>,
+    counters: &Counters,
+) {
     let mut events_by_peer = HashMap::<usize, Vec<Message>>::new();
     events
         .into_iter()
         .for_each(|e| events_by_peer.entry(e.peer_id).or_default().push(e.msg));
     for (peer_id, messages) in events_by_peer {
-        handle_peer_events(rpc, peers, peer_id, messages);
+        if let Err(msg) =
+            catch_panic(|| handle_peer_events(rpc, peers, peer_id, messages, counters))
+        {
+            match peers.remove(&peer_id) {
+                Some(peer) => disconnect_after_panic(peer, "handle", &msg, counters),
+                None => {
+                    error!("{}: panic during handle: {}", peer_id, msg);
+                    counters.panics.inc("handle");
+                }
+            }
+        }
     }
 }
 
@@ -178,16 +548,24 @@ fn handle_peer_events(
     peers: &mut HashMap<usize, Peer>,
     peer_id: usize,
     messages: Vec<Message>,
+    counters: &Counters,
 ) {
     let mut lines = vec![];
+    let mut pings = vec![];
     let mut done = false;
     for msg in messages {
         match msg {
-            Message::New(stream) => {
-                debug!("{}: connected", peer_id);
-                peers.insert(peer_id, Peer::new(peer_id, stream));
+            Message::New(stream, transport) => {
+                debug!("{}: connected ({:?})", peer_id, transport);
+                match Peer::new(peer_id, stream, transport, rpc.new_client()) {
+                    Ok(peer) => {
+                        peers.insert(peer_id, peer);
+                    }
+                    Err(e) => warn!("{}: failed to add peer: {:#}", peer_id, e),
+                }
             }
             Message::Request(line) => lines.push(line),
+            Message::Ping(payload) => pings.push(payload),
             Message::Done => {
                 done = true;
                 break;
@@ -197,24 +575,91 @@ fn handle_peer_events(
     let result = match peers.get_mut(&peer_id) {
         Some(peer) => {
             let responses = rpc.handle_requests(&mut peer.client, &lines);
-            peer.send(responses)
+            pings
+                .iter()
+                .try_for_each(|payload| peer.pong(payload))
+                .and_then(|()| peer.send(responses))
         }
         None => return, // unknown peer
     };
     if let Err(e) = result {
-        error!("{}: disconnecting due to {}", peer_id, e);
+        let peer = peers.remove(&peer_id).unwrap();
+        disconnect_after_error(peer, "handle", &e, counters);
+    } else if peers[&peer_id].client.should_disconnect() {
+        info!(
+            "{}: disconnecting due to unsupported protocol version",
+            peer_id
+        );
         peers.remove(&peer_id).unwrap().disconnect();
     } else if done {
         peers.remove(&peer_id); // already disconnected, just remove from peers' map
     }
 }
 
-fn accept_loop(listener: TcpListener, server_tx: Sender<Event>) -> Result<()> {
-    for (peer_id, conn) in listener.incoming().enumerate() {
+fn is_allowed(peer_id: usize, stream: &TcpStream, allowlist: &IpAllowlist) -> bool {
+    match stream.peer_addr() {
+        Ok(addr) if allowlist.contains(addr.ip()) => true,
+        Ok(addr) => {
+            warn!("{}: rejected connection from {}", peer_id, addr);
+            false
+        }
+        Err(e) => {
+            warn!("{}: failed to get peer address: {}", peer_id, e);
+            false
+        }
+    }
+}
+
+// peers' IDs are unique across all listeners
+static NEXT_PEER_ID: AtomicUsize = AtomicUsize::new(0);
+
+// `recv_loop` only reads lines from its connection (forwarding them to the serving thread),
+// so idle connections don't need a full-size stack each. Requests are handled by the serving
+// thread, so an idle connection costs only its (mostly untouched) stacks and its read buffer:
+// see `test_idle_connection_memory` (which is why connections are not handled asynchronously).
+const RECV_STACK_SIZE: usize = 256 << 10;
+
+#[derive(Clone, Copy)]
+struct RecvLimits {
+    buffer_size: usize,
+    max_line_bytes: Option<usize>,
+}
+
+impl Default for RecvLimits {
+    fn default() -> Self {
+        Self {
+            buffer_size: 8 << 10,
+            max_line_bytes: None,
+        }
+    }
+}
+
+fn accept_loop(
+    listener: TcpListener,
+    server_tx: Sender<Event>,
+    allowlist: Option<IpAllowlist>,
+    write_timeout: Option<Duration>,
+    limits: RecvLimits,
+    transport: Transport,
+) -> Result<()> {
+    for conn in listener.incoming() {
         let stream = conn.context("failed to accept")?;
+        let peer_id = NEXT_PEER_ID.fetch_add(1, Ordering::Relaxed);
+        if let Some(allowlist) = &allowlist {
+            if !is_allowed(peer_id, &stream, allowlist) {
+                let _ = stream.shutdown(Shutdown::Both);
+                continue;
+            }
+        }
+        // shared with the stream's clone, used for sending responses
+        if let Err(e) = stream.set_write_timeout(write_timeout) {
+            warn!("{}: failed to set write timeout: {}", peer_id, e);
+            let _ = stream.shutdown(Shutdown::Both);
+            continue;
+        }
         let tx = server_tx.clone();
-        spawn("recv_loop", move || {
-            let result = recv_loop(peer_id, &stream, tx);
+        spawn_with_stack_size("recv_loop", RECV_STACK_SIZE, move || {
+            let result = recv_loop(peer_id, &stream, tx, limits, transport);
             if let Err(e) = stream.shutdown(Shutdown::Read) {
                 warn!("{}: failed to shutdown TCP receiving {}", peer_id, e)
             }
@@ -224,12 +669,47 @@ fn accept_loop(listener: TcpListener, server_tx: Sender<Event>) -> Result<()> {
     Ok(())
 }
 
-fn recv_loop(peer_id: usize, stream: &TcpStream, server_tx: Sender<Event>) -> Result<()> {
-    let msg = Message::New(stream.try_clone()?);
+fn recv_loop(
+    peer_id: usize,
+    stream: &TcpStream,
+    server_tx: Sender<Event>,
+    limits: RecvLimits,
+    transport: Transport,
+) -> Result<()> {
+    let mut reader = BufReader::with_capacity(limits.buffer_size, stream);
+    let transport = match transport {
+        Transport::Tcp => Transport::Tcp,
+        Transport::WebSocket(allowed) => {
+            let compression = websocket::accept(&mut reader, stream, allowed)
+                .with_context(|| format!("{}: WebSocket handshake failed", peer_id))?;
+            Transport::WebSocket(compression)
+        }
+    };
+    let msg = Message::New(stream.try_clone()?, transport);
     server_tx.send(Event { peer_id, msg })?;
 
+    let result = match transport {
+        Transport::Tcp => recv_requests(peer_id, reader, &server_tx, limits),
+        Transport::WebSocket(compression) => {
+            recv_messages(peer_id, reader, &server_tx, limits, compression)
+        }
+    };
+
+    // the peer is also dropped after a receiving error (e.g. an over-length line)
+    debug!("{}: disconnected", peer_id);
+    let msg = Message::Done;
+    server_tx.send(Event { peer_id, msg })?;
+    result
+}
+
+fn recv_requests(
+    peer_id: usize,
+    mut reader: impl BufRead,
+    server_tx: &Sender<Event>,
+    limits: RecvLimits,
+) -> Result<()> {
     let mut first_line = true;
-    for line in BufReader::new(stream).lines() {
+    while let Some(line) = read_line(&mut reader, limits.max_line_bytes).transpose() {
         if let Err(e) = &line {
             if first_line && e.kind() == std::io::ErrorKind::InvalidData {
                 warn!("InvalidData on first line may indicate client attempted to connect using SSL when server expects unencrypted communication.")
@@ -241,9 +721,389 @@ fn recv_loop(peer_id: usize, stream: &TcpStream, server_tx: Sender<Event>) -> Re
         server_tx.send(Event { peer_id, msg })?;
         first_line = false;
     }
+    Ok(())
+}
 
-    debug!("{}: disconnected", peer_id);
-    let msg = Message::Done;
-    server_tx.send(Event { peer_id, msg })?;
+fn recv_messages(
+    peer_id: usize,
+    mut reader: impl BufRead,
+    server_tx: &Sender<Event>,
+    limits: RecvLimits,
+    compression: Compression,
+) -> Result<()> {
+    let mut messages = websocket::MessageReader::new(limits.max_line_bytes, compression);
+    while let Some(msg) = messages
+        .read(&mut reader)
+        .with_context(|| format!("{}: recv failed", peer_id))?
+    {
+        let msg = match msg {
+            websocket::Message::Data(data) => {
+                let text = String::from_utf8(data)
+                    .with_context(|| format!("{}: invalid UTF-8 message", peer_id))?;
+                for line in text.lines().filter(|line| !line.is_empty()) {
+                    debug!("{}: recv {}", peer_id, line);
+                    let msg = Message::Request(line.to_owned());
+                    server_tx.send(Event { peer_id, msg })?;
+                }
+                continue;
+            }
+            websocket::Message::Ping(payload) => Message::Ping(payload),
+            websocket::Message::Pong => continue, // unsolicited pongs are ignored
+            websocket::Message::Close => break,   // the connection is dropped after `Message::Done`
+        };
+        server_tx.send(Event { peer_id, msg })?;
+    }
     Ok(())
 }
+
+/// Read a single line (like `BufRead::lines()`), failing as soon as it exceeds `max_len` bytes,
+/// so that at most `max_len` bytes (and the reader's buffer) are held in memory.
+fn read_line(reader: &mut impl BufRead, max_len: Option<usize>) -> io::Result<Option<String>> {
+    let mut line = Vec::new();
+    loop {
+        let available = reader.fill_buf()?;
+        if available.is_empty() {
+            if line.is_empty() {
+                return Ok(None); // EOF
+            }
+            break;
+        }
+        let (chunk, found) = match available.iter().position(|&b| b == b'\n') {
+            Some(pos) => (&available[..pos], true),
+            None => (available, false),
+        };
+        if let Some(max_len) = max_len {
+            if line.len() + chunk.len() > max_len {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("request line too long (>{} bytes)", max_len),
+                ));
+            }
+        }
+        line.extend_from_slice(chunk);
+        let consumed = chunk.len() + usize::from(found);
+        reader.consume(consumed);
+        if found {
+            break;
+        }
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    String::from_utf8(line)
+        .map(Some)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        accept_loop, catch_panic, is_write_timeout, read_line, Message, Peer, Poller, RecvLimits,
+        Transport,
+    };
+    use crate::electrum::{Client, Response};
+    use crate::websocket::{tests::client_frame, Compression};
+    use crossbeam_channel::unbounded;
+    use std::collections::HashSet;
+    use std::io::{self, BufRead, BufReader, Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_catch_panic() {
+        let mut handled = vec![];
+        for peer_id in 0..3 {
+            let result = catch_panic(|| {
+                if peer_id == 1 {
+                    panic!("peer {} failed", peer_id);
+                }
+                handled.push(peer_id);
+            });
+            assert_eq!(result.is_err(), peer_id == 1);
+        }
+        // other peers are handled before and after the panic
+        assert_eq!(handled, vec![0, 2]);
+
+        assert_eq!(
+            catch_panic(|| panic!("static")),
+            Err::<(), _>("static".to_owned())
+        );
+        assert_eq!(catch_panic(|| 42), Ok(42));
+    }
+
+    #[test]
+    fn test_rpc_allowlist() {
+        let accept = |allowlist: &str| {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let addr = listener.local_addr().unwrap();
+            let (server_tx, server_rx) = unbounded();
+            let allowlist = Some(allowlist.parse().unwrap());
+            std::thread::spawn(move || {
+                let limits = RecvLimits::default();
+                accept_loop(listener, server_tx, allowlist, None, limits, Transport::Tcp)
+            });
+            let client = TcpStream::connect(addr).unwrap();
+            (client, server_rx)
+        };
+
+        let (mut client, server_rx) = accept("10.0.0.0/8,::1");
+        let mut buf = [0u8; 1];
+        assert_eq!(client.read(&mut buf).unwrap(), 0); // closed by the server
+        assert!(server_rx.recv_timeout(Duration::from_millis(100)).is_err());
+
+        let (_client, server_rx) = accept("127.0.0.0/8");
+        let event = server_rx.recv_timeout(Duration::from_secs(10)).unwrap();
+        assert!(matches!(event.msg, Message::New(..)));
+    }
+
+    #[test]
+    fn test_many_connections() {
+        const CLIENTS: usize = 200;
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (server_tx, server_rx) = unbounded();
+        std::thread::spawn(move || {
+            let limits = RecvLimits::default();
+            accept_loop(listener, server_tx, None, None, limits, Transport::Tcp)
+        });
+
+        let request = r#"{"jsonrpc": "2.0", "method": "blockchain.headers.subscribe", "id": 1}"#;
+        let _clients: Vec<TcpStream> = (0..CLIENTS)
+            .map(|_| {
+                let mut client = TcpStream::connect(addr).unwrap();
+                writeln!(client, "{}", request).unwrap();
+                client
+            })
+            .collect();
+
+        let mut requests = HashSet::new();
+        while requests.len() < CLIENTS {
+            let event = server_rx.recv_timeout(Duration::from_secs(10)).unwrap();
+            if let Message::Request(line) = event.msg {
+                assert_eq!(line, request);
+                assert!(requests.insert(event.peer_id)); // a single request per client
+            }
+        }
+    }
+
+    /// Measures the receiving side of idle connections (their sending threads use the same stack
+    /// size). Run separately (using `--ignored`), since other tests' threads would be counted too.
+    #[test]
+    #[ignore]
+    #[cfg(target_os = "linux")]
+    fn test_idle_connection_memory() {
+        const CLIENTS: usize = 300;
+        let status = |key: &str| -> u64 {
+            let status = std::fs::read_to_string("/proc/self/status").unwrap();
+            let line = status.lines().find(|line| line.starts_with(key)).unwrap();
+            line.split_whitespace().nth(1).unwrap().parse().unwrap() // in KiB
+        };
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (server_tx, server_rx) = unbounded();
+        std::thread::spawn(move || {
+            let limits = RecvLimits::default();
+            accept_loop(listener, server_tx, None, None, limits, Transport::Tcp)
+        });
+
+        let (rss, size) = (status("VmRSS:"), status("VmSize:"));
+        let request = r#"{"jsonrpc": "2.0", "method": "blockchain.headers.subscribe", "id": 1}"#;
+        let _clients: Vec<TcpStream> = (0..CLIENTS)
+            .map(|_| {
+                let mut client = TcpStream::connect(addr).unwrap();
+                writeln!(client, "{}", request).unwrap();
+                client
+            })
+            .collect();
+        let mut requests = 0;
+        while requests < CLIENTS {
+            let event = server_rx.recv_timeout(Duration::from_secs(10)).unwrap();
+            if let Message::Request(_) = event.msg {
+                requests += 1;
+            }
+        }
+        let rss = (status("VmRSS:") - rss) / CLIENTS as u64;
+        let size = (status("VmSize:") - size) / CLIENTS as u64;
+        println!(
+            "idle connection: {} KiB resident, {} KiB virtual",
+            rss, size
+        );
+        assert!(rss < 64);
+        assert!(size < 1024); // the default (2 MiB) stacks would exceed it
+    }
+
+    #[test]
+    fn test_slow_client() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (server_tx, server_rx) = unbounded();
+        let write_timeout = Some(Duration::from_secs(60));
+        std::thread::spawn(move || {
+            accept_loop(
+                listener,
+                server_tx,
+                None,
+                write_timeout,
+                RecvLimits::default(),
+                Transport::Tcp,
+            )
+        });
+
+        let _client = TcpStream::connect(addr).unwrap(); // never reads its responses
+        let event = server_rx.recv_timeout(Duration::from_secs(10)).unwrap();
+        let mut peer = match event.msg {
+            Message::New(stream, transport) => {
+                Peer::new(event.peer_id, stream, transport, Client::default()).unwrap()
+            }
+            _ => panic!("unexpected message"),
+        };
+        let response = || vec![Response::Serialized("x".repeat(1 << 20))];
+        // sending doesn't wait for the (long) write timeout, once the queue is full
+        let start = Instant::now();
+        let err = (0..1000)
+            .find_map(|_| peer.send(response()).err())
+            .expect("send buffer is never full");
+        assert!(is_write_timeout(&err));
+        assert!(start.elapsed() < Duration::from_secs(30));
+    }
+
+    #[test]
+    fn test_read_line() {
+        let mut reader = BufReader::with_capacity(4, &b"abc\r\ndefghij\n\nklm"[..]);
+        let mut lines = vec![];
+        while let Some(line) = read_line(&mut reader, Some(7)).unwrap() {
+            lines.push(line);
+        }
+        assert_eq!(lines, vec!["abc", "defghij", "", "klm"]);
+
+        let mut reader = BufReader::with_capacity(4, &b"abcdefgh\n"[..]);
+        let err = read_line(&mut reader, Some(7)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(err.to_string(), "request line too long (>7 bytes)");
+
+        // an endless line is rejected, instead of being buffered
+        let mut reader = BufReader::with_capacity(1 << 10, io::repeat(b'x'));
+        assert!(read_line(&mut reader, Some(1 << 20)).is_err());
+    }
+
+    #[test]
+    fn test_long_request_line() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (server_tx, server_rx) = unbounded();
+        let limits = RecvLimits {
+            buffer_size: 1 << 10,
+            max_line_bytes: Some(100),
+        };
+        std::thread::spawn(move || {
+            accept_loop(listener, server_tx, None, None, limits, Transport::Tcp)
+        });
+
+        let mut client = TcpStream::connect(addr).unwrap();
+        writeln!(client, "{}", "x".repeat(100)).unwrap();
+        writeln!(client, "{}", "y".repeat(101)).unwrap();
+        writeln!(client, "{}", "z".repeat(10)).unwrap();
+
+        let recv = || server_rx.recv_timeout(Duration::from_secs(10)).unwrap().msg;
+        assert!(matches!(recv(), Message::New(..)));
+        assert!(matches!(recv(), Message::Request(line) if line == "x".repeat(100)));
+        assert!(matches!(recv(), Message::Done)); // following requests are not received
+    }
+
+    #[test]
+    fn test_websocket() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (server_tx, server_rx) = unbounded();
+        std::thread::spawn(move || {
+            let limits = RecvLimits::default();
+            accept_loop(
+                listener,
+                server_tx,
+                None,
+                None,
+                limits,
+                Transport::WebSocket(Compression::Deflate),
+            )
+        });
+
+        let mut client = TcpStream::connect(addr).unwrap();
+        write!(
+            client,
+            "GET / HTTP/1.1\r\n\
+             Upgrade: websocket\r\n\
+             Connection: Upgrade\r\n\
+             Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+             Sec-WebSocket-Version: 13\r\n\r\n"
+        )
+        .unwrap();
+        let mut reader = BufReader::new(client.try_clone().unwrap());
+        let mut status = String::new();
+        reader.read_line(&mut status).unwrap();
+        assert_eq!(status, "HTTP/1.1 101 Switching Protocols\r\n");
+        loop {
+            let mut header = String::new();
+            reader.read_line(&mut header).unwrap();
+            if header == "\r\n" {
+                break;
+            }
+        }
+
+        let request = r#"{"jsonrpc": "2.0", "method": "server.ping", "id": 1}"#;
+        client
+            .write_all(&client_frame(true, 0x9, b"keepalive"))
+            .unwrap();
+        client
+            .write_all(&client_frame(true, 0x1, request.as_bytes()))
+            .unwrap();
+
+        let recv = || server_rx.recv_timeout(Duration::from_secs(10)).unwrap();
+        let event = recv();
+        let mut peer = match event.msg {
+            // compression is not offered by the client
+            Message::New(stream, transport @ Transport::WebSocket(Compression::Disabled)) => {
+                Peer::new(event.peer_id, stream, transport, Client::default()).unwrap()
+            }
+            _ => panic!("unexpected message"),
+        };
+        let payload = match recv().msg {
+            Message::Ping(payload) => payload,
+            _ => panic!("unexpected message"),
+        };
+        assert_eq!(payload, b"keepalive");
+        assert!(matches!(recv().msg, Message::Request(line) if line == request));
+
+        let response = r#"{"id":1,"jsonrpc":"2.0","result":null}"#;
+        peer.pong(&payload).unwrap();
+        peer.send(vec![Response::Serialized(response.to_owned())])
+            .unwrap();
+
+        let mut frame = vec![0u8; 2 + 9];
+        reader.read_exact(&mut frame).unwrap();
+        assert_eq!(frame, b"\x8a\x09keepalive"); // unmasked pong
+        let mut frame = vec![0u8; 2 + response.len()];
+        reader.read_exact(&mut frame).unwrap();
+        assert_eq!(frame[..2], [0x81, response.len() as u8]); // a single text frame
+        assert_eq!(&frame[2..], response.as_bytes());
+    }
+
+    #[test]
+    fn test_poller() {
+        let secs = Duration::from_secs;
+        let start = Instant::now();
+
+        let mut poller = Poller::new(secs(10), secs(30));
+        assert!(poller.mempool_due(start)); // initial mempool sync
+        poller.mempool_polled(start);
+        assert!(!poller.mempool_due(start));
+        assert_eq!(poller.timeout(start), secs(10)); // tip polling
+        assert_eq!(poller.timeout(start + secs(25)), secs(5)); // mempool polling
+        assert!(poller.mempool_due(start + secs(30)));
+        assert_eq!(poller.timeout(start + secs(40)), secs(0));
+
+        let mut poller = Poller::new(secs(10), secs(3));
+        poller.mempool_polled(start);
+        assert_eq!(poller.timeout(start), secs(3));
+        assert_eq!(poller.timeout(start + secs(1)), secs(2));
+    }
+}
@@ -1,37 +1,52 @@
 use anyhow::Result;
 use bitcoin::{
+    hashes::hex::ToHex,
     hashes::{sha256, Hash, HashEngine},
-    Amount, Block, BlockHash, OutPoint, SignedAmount, Transaction, Txid,
+    Address, Amount, Block, BlockHash, Network, OutPoint, SignedAmount, Transaction, Txid,
 };
+use parking_lot::Mutex;
 use rayon::prelude::*;
 use serde::ser::{Serialize, Serializer};
 
 use std::collections::{BTreeMap, HashMap, HashSet};
 use std::convert::TryFrom;
+use std::io::Write;
+use std::ops::RangeInclusive;
+use std::sync::Arc;
+use std::time::Duration;
 
 use crate::{
     cache::Cache,
     chain::Chain,
+    config::ExportFormat,
     daemon::Daemon,
+    errors::QueryError,
     index::Index,
     mempool::Mempool,
+    metrics::{Counter, Metrics},
     types::{ScriptHash, StatusHash},
 };
 
+/// Coinbase outputs can be spent only after this number of confirmations
+pub(crate) const COINBASE_MATURITY: usize = 100;
+
 /// Given a scripthash, store relevant inputs and outputs of a specific transaction
-struct TxEntry {
+#[derive(Clone)]
+pub(crate) struct TxEntry {
     txid: Txid,
-    outputs: Vec<TxOutput>, // relevant funded outputs and their amounts
-    spent: Vec<OutPoint>,   // relevant spent outpoints
+    pub(crate) outputs: Vec<TxOutput>, // relevant funded outputs and their amounts
+    spent: Vec<OutPoint>,              // relevant spent outpoints
 }
 
-struct TxOutput {
+#[derive(Clone)]
+pub(crate) struct TxOutput {
     index: u32,
     value: Amount,
+    coinbase: bool,
 }
 
 impl TxEntry {
-    fn new(txid: Txid) -> Self {
+    pub(crate) fn new(txid: Txid) -> Self {
         Self {
             txid,
             outputs: Vec::new(),
@@ -48,6 +63,7 @@ impl TxEntry {
 // Confirmation height of a transaction or its mempool state:
 // https://electrumx-spesmilo.readthedocs.io/en/latest/protocol-methods.html#blockchain-scripthash-get-history
 // https://electrumx-spesmilo.readthedocs.io/en/latest/protocol-methods.html#blockchain-scripthash-get-mempool
+#[derive(Clone, Copy)]
 enum Height {
     Confirmed { height: usize },
     Unconfirmed { has_unconfirmed_inputs: bool },
@@ -85,7 +101,7 @@ impl std::fmt::Display for Height {
 // A single history entry:
 // https://electrumx-spesmilo.readthedocs.io/en/latest/protocol-methods.html#blockchain-scripthash-get-history
 // https://electrumx-spesmilo.readthedocs.io/en/latest/protocol-methods.html#blockchain-scripthash-get-mempool
-#[derive(Serialize)]
+#[derive(Clone, Serialize)]
 pub(crate) struct HistoryEntry {
     #[serde(rename = "tx_hash")]
     txid: Txid,
@@ -122,6 +138,67 @@ impl HistoryEntry {
     }
 }
 
+/// A confirmed transaction with its effect on the scripthash balance (used for exporting)
+#[derive(Debug, PartialEq, Serialize)]
+pub(crate) struct HistoryDelta {
+    height: usize,
+    txid: Txid,
+    value_delta: i64, // in satoshis
+}
+
+/// Write the history as CSV (with a header line) or as a JSON array.
+pub(crate) fn write_history_deltas(
+    deltas: &[HistoryDelta],
+    format: ExportFormat,
+    mut out: impl Write,
+) -> Result<()> {
+    match format {
+        ExportFormat::Csv => {
+            writeln!(out, "height,txid,value_delta")?;
+            for delta in deltas {
+                writeln!(out, "{},{},{}", delta.height, delta.txid, delta.value_delta)?;
+            }
+        }
+        ExportFormat::Json => {
+            serde_json::to_writer_pretty(&mut out, deltas)?;
+            writeln!(out)?;
+        }
+    }
+    out.flush()?;
+    Ok(())
+}
+
+/// Fetches the blocks required for syncing the statuses (from `bitcoind`)
+pub(crate) trait BlockSource {
+    fn for_blocks<B, F>(&self, blockhashes: B, func: F) -> Result<()>
+    where
+        B: IntoIterator<Item = BlockHash>,
+        F: FnMut(BlockHash, Block);
+}
+
+/// Fetches the queried blocks from `bitcoind`, finding the pruned ones via the local chain
+/// (see `--on-pruned-block`)
+pub(crate) struct QueryBlocks<'a> {
+    daemon: &'a Daemon,
+    chain: &'a Chain,
+}
+
+impl<'a> QueryBlocks<'a> {
+    pub(crate) fn new(daemon: &'a Daemon, chain: &'a Chain) -> Self {
+        Self { daemon, chain }
+    }
+}
+
+impl BlockSource for QueryBlocks<'_> {
+    fn for_blocks<B, F>(&self, blockhashes: B, func: F) -> Result<()>
+    where
+        B: IntoIterator<Item = BlockHash>,
+        F: FnMut(BlockHash, Block),
+    {
+        self.daemon.for_query_blocks(self.chain, blockhashes, func)
+    }
+}
+
 /// ScriptHash subscription status
 pub struct ScriptHashStatus {
     scripthash: ScriptHash, // specfic scripthash to be queried
@@ -129,11 +206,48 @@ pub struct ScriptHashStatus {
     confirmed: HashMap<BlockHash, Vec<TxEntry>>, // confirmed entries, partitioned per block (may contain stale blocks)
     mempool: Vec<TxEntry>,                       // unconfirmed entries
     history: Vec<HistoryEntry>,                  // computed from confirmed and mempool entries
-    statushash: Option<StatusHash>,              // computed from history
+    confirmed_len: usize,                        // number of confirmed entries in history
+    confirmed_engine: Option<sha256::HashEngine>, // memoized status hash state (after confirmed entries)
+    statushash: Option<StatusHash>,               // computed from history
+    unspent: Mutex<Option<(BlockHash, Arc<Unspent>)>>, // memoized unspent outputs (keyed by chain tip)
+}
+
+/// Logs scripthash status syncs that take longer than the configured threshold
+pub(crate) struct SlowQueryLog {
+    threshold: Option<Duration>,
+    counter: Counter,
+}
+
+impl SlowQueryLog {
+    pub(crate) fn new(threshold: Option<Duration>, metrics: &Metrics) -> Self {
+        let counter = metrics.counter(
+            "slow_queries",
+            "# of queries exceeding the slow query threshold",
+            "type",
+        );
+        Self { threshold, counter }
+    }
+
+    /// Return `true` if the sync (taking `elapsed`) was logged as slow.
+    pub(crate) fn observe(&self, status: &ScriptHashStatus, elapsed: Duration) -> bool {
+        match self.threshold {
+            Some(threshold) if elapsed > threshold => (),
+            _ => return false,
+        }
+        warn!(
+            "slow query: scripthash {} took {:.3}s ({} confirmed blocks, {} mempool transactions)",
+            status.scripthash,
+            elapsed.as_secs_f64(),
+            status.confirmed.len(),
+            status.mempool.len()
+        );
+        self.counter.inc("scripthash");
+        true
+    }
 }
 
 /// Specific scripthash balance
-#[derive(Default, Eq, PartialEq, Serialize)]
+#[derive(Debug, Default, Eq, PartialEq, Serialize)]
 pub(crate) struct Balance {
     #[serde(with = "bitcoin::util::amount::serde::as_sat", rename = "confirmed")]
     confirmed_balance: Amount,
@@ -143,21 +257,33 @@ pub(crate) struct Balance {
 
 // A single unspent transaction output entry:
 // https://electrumx-spesmilo.readthedocs.io/en/latest/protocol-methods.html#blockchain-scripthash-listunspent
-#[derive(Serialize)]
+#[derive(Debug, PartialEq, Serialize)]
 pub(crate) struct UnspentEntry {
     height: usize, // 0 = mempool entry
     tx_hash: Txid,
     tx_pos: u32,
     #[serde(with = "bitcoin::util::amount::serde::as_sat")]
     value: Amount,
+    // verbose fields (non-standard), taken from the funding transaction
+    #[serde(skip_serializing_if = "Option::is_none")]
+    script_pubkey: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    address: Option<String>,
+}
+
+struct UnspentOutput {
+    value: Amount,
+    height: usize, // 0 = mempool entry
+    pos: usize,    // the funding transaction's order (within its block or the mempool)
+    coinbase: bool,
 }
 
 #[derive(Default)]
 struct Unspent {
-    // mapping an outpoint to its value & confirmation height
-    outpoints: HashMap<OutPoint, (Amount, usize)>,
-    confirmed_balance: Amount,
-    mempool_delta: SignedAmount,
+    outpoints: HashMap<OutPoint, UnspentOutput>,
+    confirmed: BTreeMap<usize, Amount>, // confirmed balance (before mempool spends) per height
+    coinbase: BTreeMap<usize, Amount>,  // the part of `confirmed` funded by coinbase outputs
+    total: Amount,                      // including mempool transactions
 }
 
 impl Unspent {
@@ -166,30 +292,65 @@ impl Unspent {
 
         status
             .confirmed_height_entries(chain)
-            .for_each(|(height, entries)| entries.iter().for_each(|e| unspent.insert(e, height)));
+            .for_each(|(height, entries)| unspent.insert_all(entries, height));
         status
             .confirmed_entries(chain)
             .for_each(|e| unspent.remove(e));
 
-        unspent.confirmed_balance = unspent.balance();
+        for output in unspent.outpoints.values() {
+            *unspent.confirmed.entry(output.height).or_default() += output.value;
+            if output.coinbase {
+                *unspent.coinbase.entry(output.height).or_default() += output.value;
+            }
+        }
 
-        status.mempool.iter().for_each(|e| unspent.insert(e, 0)); // mempool height = 0
+        unspent.insert_all(&status.mempool, 0); // mempool height = 0
         status.mempool.iter().for_each(|e| unspent.remove(e));
 
-        unspent.mempool_delta =
-            unspent.balance().to_signed().unwrap() - unspent.confirmed_balance.to_signed().unwrap();
-
+        unspent.total = unspent.balance();
         unspent
     }
 
-    fn into_entries(self) -> Vec<UnspentEntry> {
-        self.outpoints
+    /// Confirmed balance of the outputs confirmed up to (and including) `max_height`,
+    /// and of the coinbase outputs confirmed up to `max_coinbase_height`.
+    /// Shallower outputs are counted as unconfirmed.
+    fn balance_at(&self, max_height: usize, max_coinbase_height: usize) -> Balance {
+        let sum = |amounts: &BTreeMap<usize, Amount>, range: RangeInclusive<usize>| {
+            amounts
+                .range(range)
+                .fold(Amount::default(), |acc, (_height, value)| acc + *value)
+        };
+        let mut confirmed_balance = sum(&self.confirmed, 0..=max_height);
+        if max_coinbase_height < max_height {
+            confirmed_balance =
+                confirmed_balance - sum(&self.coinbase, max_coinbase_height + 1..=max_height);
+        }
+        Balance {
+            confirmed_balance,
+            mempool_delta: self.total.to_signed().unwrap() - confirmed_balance.to_signed().unwrap(),
+        }
+    }
+
+    /// Coinbase outputs confirmed after `max_coinbase_height` are excluded (they can't be spent yet).
+    /// The entries are sorted by (height, position, vout), keeping the mempool entries last.
+    fn entries(&self, max_coinbase_height: usize) -> Vec<UnspentEntry> {
+        let mut outpoints: Vec<(&OutPoint, &UnspentOutput)> = self
+            .outpoints
+            .iter()
+            .filter(|(_outpoint, output)| !output.coinbase || output.height <= max_coinbase_height)
+            .collect();
+        outpoints.sort_by_key(|(outpoint, output)| {
+            (output.height == 0, output.height, output.pos, outpoint.vout)
+        });
+        outpoints
             .into_iter()
-            .map(|(outpoint, (value, height))| UnspentEntry {
-                height,
+            .map(|(outpoint, output)| UnspentEntry {
+                height: output.height,
                 tx_hash: outpoint.txid,
                 tx_pos: outpoint.vout,
-                value,
+                value: output.value,
+                script_pubkey: None,
+                address: None,
             })
             .collect()
     }
@@ -197,16 +358,30 @@ impl Unspent {
     fn balance(&self) -> Amount {
         self.outpoints
             .values()
-            .fold(Amount::default(), |acc, v| acc + v.0)
+            .fold(Amount::default(), |acc, output| acc + output.value)
+    }
+
+    /// Insert the outputs of the given entries (sorted by their position, at the same height).
+    fn insert_all(&mut self, entries: &[TxEntry], height: usize) {
+        entries
+            .iter()
+            .enumerate()
+            .for_each(|(pos, entry)| self.insert(entry, height, pos));
     }
 
-    fn insert(&mut self, entry: &TxEntry, height: usize) {
+    fn insert(&mut self, entry: &TxEntry, height: usize, pos: usize) {
         for output in &entry.outputs {
             let outpoint = OutPoint {
                 txid: entry.txid,
                 vout: output.index,
             };
-            self.outpoints.insert(outpoint, (output.value, height));
+            let output = UnspentOutput {
+                value: output.value,
+                height,
+                pos,
+                coinbase: output.coinbase,
+            };
+            self.outpoints.insert(outpoint, output);
         }
     }
 
@@ -217,6 +392,23 @@ impl Unspent {
     }
 }
 
+// A watched status is cloned for serving its queries (instead of syncing a new one)
+impl Clone for ScriptHashStatus {
+    fn clone(&self) -> Self {
+        Self {
+            scripthash: self.scripthash,
+            tip: self.tip,
+            confirmed: self.confirmed.clone(),
+            mempool: self.mempool.clone(),
+            history: self.history.clone(),
+            confirmed_len: self.confirmed_len,
+            confirmed_engine: self.confirmed_engine.clone(),
+            statushash: self.statushash,
+            unspent: Mutex::new(self.unspent.lock().clone()),
+        }
+    }
+}
+
 impl ScriptHashStatus {
     /// Return non-synced (empty) status for a given script hash.
     pub fn new(scripthash: ScriptHash) -> Self {
@@ -226,7 +418,10 @@ impl ScriptHashStatus {
             confirmed: HashMap::new(),
             mempool: Vec::new(),
             history: Vec::new(),
+            confirmed_len: 0,
+            confirmed_engine: None,
             statushash: None,
+            unspent: Mutex::new(None),
         }
     }
 
@@ -259,22 +454,148 @@ impl ScriptHashStatus {
             .collect()
     }
 
-    pub(crate) fn get_unspent(&self, chain: &Chain) -> Vec<UnspentEntry> {
-        Unspent::build(self, chain).into_entries()
+    /// Unspent outputs are memoized until the next sync (i.e. a new block or mempool change),
+    /// so hot scripthashes don't rebuild them on every `listunspent` or `get_balance` call.
+    fn unspent(&self, chain: &Chain) -> Arc<Unspent> {
+        let tip = chain.tip();
+        let mut cached = self.unspent.lock();
+        if let Some((cached_tip, unspent)) = &*cached {
+            if *cached_tip == tip {
+                return Arc::clone(unspent);
+            }
+        }
+        let unspent = Arc::new(Unspent::build(self, chain));
+        *cached = Some((tip, Arc::clone(&unspent)));
+        unspent
     }
 
-    pub(crate) fn get_balance(&self, chain: &Chain) -> Balance {
-        let unspent = Unspent::build(self, chain);
-        Balance {
-            confirmed_balance: unspent.confirmed_balance,
-            mempool_delta: unspent.mempool_delta,
+    /// If `min_conf` is given, outputs with less confirmations (see `max_confirmed_height`) are
+    /// excluded (including mempool outputs, unless it is 0). Immature coinbase outputs (see
+    /// `max_mature_height`) are excluded.
+    pub(crate) fn get_unspent(
+        &self,
+        chain: &Chain,
+        min_conf: Option<usize>,
+        coinbase_min_conf: usize,
+    ) -> Vec<UnspentEntry> {
+        let mut entries = self
+            .unspent(chain)
+            .entries(max_mature_height(chain, coinbase_min_conf));
+        if let Some(min_conf) = min_conf {
+            let max_height = max_confirmed_height(chain, min_conf);
+            entries.retain(|e| match e.height {
+                0 => min_conf == 0, // mempool
+                height => height <= max_height,
+            });
+        }
+        entries
+    }
+
+    /// The outputs funded between `min_height` and `max_height` (inclusive) which were still
+    /// unspent at `max_height`, reconstructed from the confirmed entries (not cached).
+    /// Mempool transactions are ignored, so this is the UTXO set "as of" `max_height`.
+    pub(crate) fn get_unspent_at(
+        &self,
+        chain: &Chain,
+        min_height: usize,
+        max_height: usize,
+    ) -> Vec<UnspentEntry> {
+        let entries: Vec<(usize, &[TxEntry])> = self
+            .confirmed_height_entries(chain)
+            .filter(|(height, _entries)| *height <= max_height)
+            .collect();
+        let mut unspent = Unspent::default();
+        for (height, entries) in &entries {
+            if *height >= min_height {
+                unspent.insert_all(entries, *height);
+            }
+        }
+        for (_height, entries) in &entries {
+            entries.iter().for_each(|e| unspent.remove(e));
+        }
+        unspent.entries(usize::MAX)
+    }
+
+    /// Include each output's scriptPubKey (and address, if it has one), using the funding
+    /// transactions that were cached during the sync.
+    pub(crate) fn get_unspent_verbose(
+        &self,
+        chain: &Chain,
+        min_conf: Option<usize>,
+        coinbase_min_conf: usize,
+        cache: &Cache,
+        network: Network,
+    ) -> Vec<UnspentEntry> {
+        let mut entries = self.get_unspent(chain, min_conf, coinbase_min_conf);
+        for entry in &mut entries {
+            let vout = entry.tx_pos as usize;
+            let script = cache
+                .get_tx(&entry.tx_hash, |tx| {
+                    tx.output.get(vout).map(|txo| txo.script_pubkey.clone())
+                })
+                .flatten();
+            match script {
+                Some(script) => {
+                    entry.address =
+                        Address::from_script(&script, network).map(|addr| addr.to_string());
+                    entry.script_pubkey = Some(script.to_hex());
+                }
+                None => warn!("missing cached output {}:{}", entry.tx_hash, vout),
+            }
         }
+        entries
+    }
+
+    /// Outputs with less than `min_conf` confirmations (see `max_confirmed_height`), and immature
+    /// coinbase outputs, are counted as unconfirmed.
+    pub(crate) fn get_balance(
+        &self,
+        chain: &Chain,
+        min_conf: usize,
+        coinbase_min_conf: usize,
+    ) -> Balance {
+        let max_height = max_confirmed_height(chain, min_conf);
+        self.unspent(chain).balance_at(
+            max_height,
+            std::cmp::min(max_height, max_mature_height(chain, coinbase_min_conf)),
+        )
+    }
+
+    /// Compute the balances of multiple scripthashes (in parallel).
+    pub(crate) fn get_balances(
+        statuses: &[&Self],
+        chain: &Chain,
+        min_conf: usize,
+        coinbase_min_conf: usize,
+    ) -> Vec<Balance> {
+        statuses
+            .par_iter()
+            .map(|status| status.get_balance(chain, min_conf, coinbase_min_conf))
+            .collect()
     }
 
     pub(crate) fn get_history(&self) -> &[HistoryEntry] {
         &self.history
     }
 
+    /// Fail if the history (both confirmed and mempool entries) is longer than `max_history`,
+    /// so clients would switch to a lighter method (instead of fetching a huge response).
+    pub(crate) fn check_history_len(&self, max_history: Option<usize>) -> Result<()> {
+        match max_history {
+            Some(max_history) if self.history.len() > max_history => {
+                Err(QueryError::HistoryTooLong(max_history).into())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// The earliest confirmed transaction (i.e. the first one funding this scripthash), and its height.
+    pub(crate) fn get_first_use(&self, chain: &Chain) -> Option<(usize, Txid)> {
+        self.confirmed_height_entries(chain)
+            .filter_map(|(height, entries)| entries.first().map(|entry| (height, entry.txid)))
+            .min_by_key(|(height, _txid)| *height)
+    }
+
     /// Collect all confirmed history entries (in block order).
     fn get_confirmed_history(&self, chain: &Chain) -> Vec<HistoryEntry> {
         self.confirmed_height_entries(chain)
@@ -288,22 +609,58 @@ impl ScriptHashStatus {
             .collect()
     }
 
-    /// Collect all mempool history entries (keeping transactions with unconfirmed parents last).
-    fn get_mempool_history(&self, mempool: &Mempool) -> Vec<HistoryEntry> {
-        let mut entries = self
-            .mempool
-            .iter()
-            .filter_map(|e| mempool.get(&e.txid))
-            .collect::<Vec<_>>();
-        entries.sort_by_key(|e| (e.has_unconfirmed_inputs, e.txid));
-        entries
+    /// Collect all confirmed transactions (in block order), with their effect on the balance.
+    pub(crate) fn get_confirmed_deltas(&self, chain: &Chain) -> Vec<HistoryDelta> {
+        let funded: HashMap<OutPoint, Amount> = self
+            .confirmed_entries(chain)
+            .flat_map(|entry| {
+                entry
+                    .funding_outpoints()
+                    .zip(entry.outputs.iter().map(|output| output.value))
+            })
+            .collect();
+        self.confirmed_height_entries(chain)
+            .collect::<BTreeMap<usize, &[TxEntry]>>()
             .into_iter()
-            .map(|e| HistoryEntry::unconfirmed(e.txid, e.has_unconfirmed_inputs, e.fee))
+            .flat_map(|(height, entries)| entries.iter().map(move |entry| (height, entry)))
+            .map(|(height, entry)| {
+                let received: u64 = entry.outputs.iter().map(|o| o.value.as_sat()).sum();
+                let sent: u64 = entry
+                    .spent
+                    .iter()
+                    .filter_map(|outpoint| funded.get(outpoint))
+                    .map(|value| value.as_sat())
+                    .sum();
+                HistoryDelta {
+                    height,
+                    txid: entry.txid,
+                    value_delta: received as i64 - sent as i64,
+                }
+            })
             .collect()
     }
 
+    /// Collect all mempool history entries (keeping transactions with unconfirmed parents last).
+    fn get_mempool_history(&self, mempool: &Mempool) -> Vec<HistoryEntry> {
+        mempool_history(&self.mempool, mempool)
+    }
+
+    /// Get the current mempool history, without syncing the confirmed entries.
+    /// Note that mempool transactions spending confirmed outputs are found only if the confirmed
+    /// entries were already synced (e.g. for subscribed scripthashes).
+    pub(crate) fn get_mempool(
+        &self,
+        chain: &Chain,
+        mempool: &Mempool,
+        cache: &Cache,
+    ) -> Vec<HistoryEntry> {
+        let mut outpoints = self.confirmed_outpoints(chain);
+        let entries = self.sync_mempool(mempool, cache, &mut outpoints);
+        mempool_history(&entries, mempool)
+    }
+
     /// Apply func only on the new blocks (fetched from daemon).
-    fn for_new_blocks<B, F>(&self, blockhashes: B, daemon: &Daemon, func: F) -> Result<()>
+    fn for_new_blocks<B, F>(&self, blockhashes: B, daemon: &impl BlockSource, func: F) -> Result<()>
     where
         B: IntoIterator<Item = BlockHash>,
         F: FnMut(BlockHash, Block),
@@ -321,10 +678,13 @@ impl ScriptHashStatus {
     fn sync_confirmed(
         &self,
         index: &Index,
-        daemon: &Daemon,
+        daemon: &impl BlockSource,
         cache: &Cache,
         outpoints: &mut HashSet<OutPoint>,
     ) -> Result<HashMap<BlockHash, Vec<TxEntry>>> {
+        // both funding and spending rows are needed for a correct history and balance
+        index.require_funding()?;
+        index.require_spending()?;
         let scripthash = self.scripthash;
         let mut result = HashMap::<BlockHash, HashMap<usize, TxEntry>>::new();
 
@@ -392,7 +752,7 @@ impl ScriptHashStatus {
         cache: &Cache,
         outpoints: &mut HashSet<OutPoint>,
     ) -> Vec<TxEntry> {
-        let mut result = HashMap::<Txid, TxEntry>::new();
+        let mut result = BTreeMap::<Txid, TxEntry>::new(); // sorted by txid (for a deterministic order)
         for entry in mempool.filter_by_funding(&self.scripthash) {
             let funding_outputs = filter_outputs(&entry.tx, self.scripthash);
             assert!(!funding_outputs.is_empty());
@@ -424,16 +784,15 @@ impl ScriptHashStatus {
         &mut self,
         index: &Index,
         mempool: &Mempool,
-        daemon: &Daemon,
+        daemon: &impl BlockSource,
         cache: &Cache,
     ) -> Result<()> {
+        *self.unspent.get_mut() = None; // confirmed or mempool entries may change
         let mut outpoints: HashSet<OutPoint> = self.confirmed_outpoints(index.chain());
 
-        let new_tip = index.chain().tip();
-        if self.tip != new_tip {
+        if self.tip != index.chain().tip() {
             let update = self.sync_confirmed(index, daemon, cache, &mut outpoints)?;
-            self.confirmed.extend(update);
-            self.tip = new_tip;
+            self.update_confirmed(index.chain(), update);
         }
         if !self.confirmed.is_empty() {
             debug!(
@@ -442,17 +801,63 @@ impl ScriptHashStatus {
                 self.confirmed.len()
             );
         }
-        self.mempool = self.sync_mempool(mempool, cache, &mut outpoints);
+        self.update_mempool(index.chain(), mempool, cache, &mut outpoints);
+        Ok(())
+    }
+
+    /// Add the entries from the new blocks, and drop the ones from stale blocks (after a reorg).
+    /// The confirmed history (and the status hash) will be recomputed on the next mempool update,
+    /// so subscribers are notified if a transaction was reorged out or moved to another block.
+    fn update_confirmed(&mut self, chain: &Chain, update: HashMap<BlockHash, Vec<TxEntry>>) {
+        self.confirmed.extend(update);
+        let before = self.confirmed.len();
+        self.confirmed
+            .retain(|blockhash, _entries| chain.get_block_height(blockhash).is_some());
+        let stale = before - self.confirmed.len();
+        if stale > 0 {
+            debug!("{} stale blocks dropped from {}", stale, self.scripthash);
+        }
+        self.tip = chain.tip();
+        self.confirmed_engine = None; // confirmed history should be recomputed
+    }
+
+    /// Recompute the mempool entries (dropping transactions which left the mempool),
+    /// and update the history and the status hash accordingly.
+    fn update_mempool(
+        &mut self,
+        chain: &Chain,
+        mempool: &Mempool,
+        cache: &Cache,
+        outpoints: &mut HashSet<OutPoint>,
+    ) {
+        self.mempool = self.sync_mempool(mempool, cache, outpoints);
         if !self.mempool.is_empty() {
             debug!("{} mempool transactions", self.mempool.len());
         }
-        self.history.clear();
-        self.history
-            .extend(self.get_confirmed_history(index.chain()));
+        let confirmed_engine = match self.confirmed_engine.take() {
+            Some(engine) => {
+                self.history.truncate(self.confirmed_len); // only mempool entries are recomputed
+                engine
+            }
+            None => {
+                self.history = self.get_confirmed_history(chain);
+                self.confirmed_len = self.history.len();
+                hash_entries(StatusHash::engine(), &self.history)
+            }
+        };
         self.history.extend(self.get_mempool_history(mempool));
 
-        self.statushash = compute_status_hash(&self.history);
-        Ok(())
+        self.statushash = compute_status_hash(&confirmed_engine, &self.history, self.confirmed_len);
+        self.confirmed_engine = Some(confirmed_engine);
+    }
+
+    pub(crate) fn scripthash(&self) -> ScriptHash {
+        self.scripthash
+    }
+
+    /// The chain tip of the last sync.
+    pub(crate) fn tip(&self) -> BlockHash {
+        self.tip
     }
 
     /// Get current status hash.
@@ -461,13 +866,28 @@ impl ScriptHashStatus {
     }
 }
 
+/// Convert mempool TxEntries into history entries, following ElectrumX ordering: transactions
+/// with confirmed inputs (height 0) before those with unconfirmed parents (height -1), and by
+/// txid within each group.
+fn mempool_history(entries: &[TxEntry], mempool: &Mempool) -> Vec<HistoryEntry> {
+    let mut entries = entries
+        .iter()
+        .filter_map(|e| mempool.get(&e.txid))
+        .collect::<Vec<_>>();
+    entries.sort_by_key(|e| (e.has_unconfirmed_inputs, e.txid));
+    entries
+        .into_iter()
+        .map(|e| HistoryEntry::unconfirmed(e.txid, e.has_unconfirmed_inputs, e.fee))
+        .collect()
+}
+
 fn make_outpoints(txid: Txid, outputs: &[TxOutput]) -> impl Iterator<Item = OutPoint> + '_ {
     outputs
         .iter()
         .map(move |out| OutPoint::new(txid, out.index))
 }
 
-fn filter_outputs(tx: &Transaction, scripthash: ScriptHash) -> Vec<TxOutput> {
+pub(crate) fn filter_outputs(tx: &Transaction, scripthash: ScriptHash) -> Vec<TxOutput> {
     let outputs = tx.output.iter().zip(0u32..);
     outputs
         .filter_map(move |(txo, vout)| {
@@ -475,6 +895,7 @@ fn filter_outputs(tx: &Transaction, scripthash: ScriptHash) -> Vec<TxOutput> {
                 Some(TxOutput {
                     index: vout,
                     value: Amount::from_sat(txo.value),
+                    coinbase: tx.is_coin_base(),
                 })
             } else {
                 None
@@ -496,14 +917,23 @@ fn filter_inputs(tx: &Transaction, outpoints: &HashSet<OutPoint>) -> Vec<OutPoin
         .collect()
 }
 
-fn compute_status_hash(history: &[HistoryEntry]) -> Option<StatusHash> {
+fn hash_entries(mut engine: sha256::HashEngine, entries: &[HistoryEntry]) -> sha256::HashEngine {
+    for entry in entries {
+        entry.hash(&mut engine);
+    }
+    engine
+}
+
+/// Resume status hash computation from the memoized state of the first `confirmed_len` entries.
+fn compute_status_hash(
+    confirmed_engine: &sha256::HashEngine,
+    history: &[HistoryEntry],
+    confirmed_len: usize,
+) -> Option<StatusHash> {
     if history.is_empty() {
         return None;
     }
-    let mut engine = StatusHash::engine();
-    for entry in history {
-        entry.hash(&mut engine);
-    }
+    let engine = hash_entries(confirmed_engine.clone(), &history[confirmed_len..]);
     Some(StatusHash::from_engine(engine))
 }
 
@@ -514,6 +944,18 @@ struct FilteredTx<T> {
     result: Vec<T>,
 }
 
+/// The highest block height having at least `min_conf` confirmations, counted as the tip's height
+/// minus the block's height (so the tip's outputs have none).
+fn max_confirmed_height(chain: &Chain, min_conf: usize) -> usize {
+    chain.height().saturating_sub(min_conf)
+}
+
+/// The highest block height of spendable coinbase outputs (as counted by bitcoind, where the tip
+/// has one confirmation).
+fn max_mature_height(chain: &Chain, coinbase_min_conf: usize) -> usize {
+    (chain.height() + 1).saturating_sub(coinbase_min_conf)
+}
+
 fn filter_block_txs<T: Send>(
     block: Block,
     map_fn: impl Fn(&Transaction) -> Vec<T> + Sync,
@@ -541,10 +983,31 @@ fn filter_block_txs<T: Send>(
 
 #[cfg(test)]
 mod tests {
-    use super::HistoryEntry;
-    use bitcoin::{hashes::hex::FromHex, Amount, Txid};
+    use super::{
+        compute_status_hash, filter_inputs, hash_entries, write_history_deltas, HistoryEntry,
+        ScriptHashStatus, SlowQueryLog, TxEntry, TxOutput, Unspent, UnspentEntry,
+        COINBASE_MATURITY,
+    };
+    use crate::cache::Cache;
+    use crate::chain::{Chain, NewHeader};
+    use crate::config::ExportFormat;
+    use crate::errors::QueryError;
+    use crate::mempool::{Entry as MempoolEntry, Mempool};
+    use crate::tests::{
+        coinbase, make_blocks, make_chain, mempool_entry, metrics, script, spend, test_index,
+        tx_entry, txout, TestBlocks,
+    };
+    use crate::types::{ScriptHash, StatusHash};
+    use bitcoin::{
+        hashes::{hex::FromHex, sha256, Hash, HashEngine},
+        Address, Amount, Block, BlockHash, Network, OutPoint, Transaction, Txid,
+    };
     use serde_json::json;
 
+    use std::collections::HashSet;
+    use std::convert::TryFrom;
+    use std::time::Duration;
+
     #[test]
     fn test_txinfo_json() {
         let txid =
@@ -567,4 +1030,777 @@ mod tests {
             json!({"tx_hash": "5b75086dafeede555fc8f9a810d8b10df57c46f9f176ccc3dd8d2fa20edd685b", "height": 0, "fee": 123})
         );
     }
+
+    #[test]
+    fn test_incremental_status_hash() {
+        let txids: Vec<Txid> = (0..4u8).map(|i| Txid::hash(&[i])).collect();
+        let history = vec![
+            HistoryEntry::confirmed(txids[0], 100),
+            HistoryEntry::confirmed(txids[1], 101),
+            HistoryEntry::unconfirmed(txids[2], false, Amount::from_sat(10)),
+            HistoryEntry::unconfirmed(txids[3], true, Amount::from_sat(20)),
+        ];
+
+        // status hash is computed over "txid:height:" concatenation
+        let mut engine = sha256::Hash::engine();
+        for entry in &history {
+            engine.input(format!("{}:{}:", entry.txid, entry.height).as_bytes());
+        }
+        let expected = StatusHash::from_engine(engine);
+
+        let full = compute_status_hash(&StatusHash::engine(), &history, 0);
+        assert_eq!(full, Some(expected));
+
+        let confirmed_engine = hash_entries(StatusHash::engine(), &history[..2]);
+        let incremental = compute_status_hash(&confirmed_engine, &history, 2);
+        assert_eq!(incremental, full);
+
+        // the memoized state is not modified, so it can be reused after mempool changes
+        let incremental = compute_status_hash(&confirmed_engine, &history[..3], 2);
+        let full = compute_status_hash(&StatusHash::engine(), &history[..3], 0);
+        assert_eq!(incremental, full);
+
+        assert_eq!(compute_status_hash(&StatusHash::engine(), &[], 0), None);
+    }
+
+    #[test]
+    fn test_prefix_collisions() {
+        let tx1 = coinbase(1, vec![txout(100, script(1))]);
+        let funding = OutPoint::new(tx1.txid(), 0);
+        // spends an outpoint whose `txid[:8] as u64 + vout` prefix collides with `funding`
+        let prefix = <[u8; 8]>::try_from(&funding.txid[..8]).unwrap();
+        let mut bytes = funding.txid.into_inner();
+        bytes[..8].copy_from_slice(&u64::from_be_bytes(prefix).wrapping_sub(1).to_be_bytes());
+        let colliding = OutPoint::new(Txid::from_inner(bytes), 1);
+        let tx2 = spend(vec![colliding], vec![txout(90, script(2))]);
+        let blocks = make_blocks(vec![vec![tx1.clone()], vec![tx2]]);
+        let test = test_index(blocks.clone());
+        let chain = test.index.chain();
+        let source = TestBlocks(blocks.clone());
+        let mempool = Mempool::new(&metrics());
+        let cache = Cache::new(&metrics());
+        let sync = |scripthash| {
+            let mut status = ScriptHashStatus::new(scripthash);
+            status.sync(&test.index, &mempool, &source, &cache).unwrap();
+            status
+        };
+
+        // a scripthash sharing its 8-byte prefix with `script(1)`: the index returns the block
+        // funding `script(1)`, but the fetched block's outputs don't match the full scripthash
+        let mut bytes = ScriptHash::new(&script(1)).into_inner();
+        bytes[31] ^= 0xFF;
+        let scripthash = ScriptHash::from_inner(bytes);
+        let candidates: Vec<BlockHash> = test.index.filter_by_funding(scripthash).collect();
+        assert_eq!(candidates, vec![blocks[0].block_hash()]);
+        let status = sync(scripthash);
+        assert!(status.get_history().is_empty());
+        assert!(status.get_unspent(chain, None, 0).is_empty());
+
+        // the index returns the colliding spend, but the fetched block's inputs don't match
+        let candidates: Vec<BlockHash> = test.index.filter_by_spending(funding).collect();
+        assert_eq!(candidates, vec![blocks[1].block_hash()]);
+        let status = sync(ScriptHash::new(&script(1)));
+        assert_eq!(
+            json!(status.get_history()),
+            json!([{"tx_hash": tx1.txid(), "height": 1}])
+        );
+        assert_eq!(status.get_unspent(chain, None, 0).len(), 1); // still unspent
+    }
+
+    #[test]
+    fn test_slow_query_log() {
+        let status = ScriptHashStatus::new(ScriptHash::new(&script(1)));
+
+        let log = SlowQueryLog::new(Some(Duration::from_millis(100)), &metrics());
+        assert!(!log.observe(&status, Duration::from_millis(50)));
+        assert!(!log.observe(&status, Duration::from_millis(100)));
+        assert!(log.observe(&status, Duration::from_millis(150)));
+
+        let log = SlowQueryLog::new(None, &metrics());
+        assert!(!log.observe(&status, Duration::from_secs(1000)));
+    }
+
+    #[test]
+    fn test_cached_unspent() {
+        let tx1 = coinbase(1, vec![txout(100, script(1)), txout(50, script(1))]);
+        let tx2 = spend(
+            vec![OutPoint::new(tx1.txid(), 0)],
+            vec![txout(90, script(2))],
+        );
+        let blocks = make_blocks(vec![vec![tx1.clone()], vec![tx2.clone()]]);
+        let mut chain = make_chain(&blocks);
+
+        let mut status = ScriptHashStatus::new(ScriptHash::new(&script(1)));
+        let mut funding = TxEntry::new(tx1.txid());
+        funding.outputs = vec![
+            TxOutput {
+                index: 0,
+                value: Amount::from_sat(100),
+                coinbase: true,
+            },
+            TxOutput {
+                index: 1,
+                value: Amount::from_sat(50),
+                coinbase: true,
+            },
+        ];
+        let mut spending = TxEntry::new(tx2.txid());
+        spending.spent = vec![OutPoint::new(tx1.txid(), 0)];
+        status
+            .confirmed
+            .insert(blocks[0].block_hash(), vec![funding]);
+        status
+            .confirmed
+            .insert(blocks[1].block_hash(), vec![spending]);
+
+        let sorted = |mut entries: Vec<UnspentEntry>| {
+            entries.sort_by_key(|e| (e.tx_hash, e.tx_pos));
+            entries
+        };
+        let fresh = sorted(Unspent::build(&status, &chain).entries(usize::MAX));
+        assert_eq!(fresh.len(), 1);
+        assert_eq!(sorted(status.get_unspent(&chain, None, 0)), fresh); // computed and cached
+        assert_eq!(sorted(status.get_unspent(&chain, None, 0)), fresh); // cached
+        assert!(status.get_balance(&chain, 1, 0) == status.get_balance(&chain, 1, 0));
+
+        // a reorg (changing the tip) invalidates the cached result
+        chain.drop_last_headers(1);
+        let fresh = sorted(Unspent::build(&status, &chain).entries(usize::MAX));
+        assert_eq!(fresh.len(), 2);
+        assert_eq!(sorted(status.get_unspent(&chain, None, 0)), fresh);
+    }
+
+    #[test]
+    fn test_get_balances() {
+        let tx1 = coinbase(1, vec![txout(100, script(1)), txout(50, script(2))]);
+        let blocks = make_blocks(vec![vec![tx1.clone()]]);
+        let chain = make_chain(&blocks);
+
+        let statuses: Vec<ScriptHashStatus> = (1..=3)
+            .map(|i| {
+                let mut status = ScriptHashStatus::new(ScriptHash::new(&script(i)));
+                status
+                    .confirmed
+                    .insert(blocks[0].block_hash(), vec![tx_entry(&tx1, &script(i))]);
+                status
+            })
+            .collect();
+        let statuses: Vec<&ScriptHashStatus> = statuses.iter().collect();
+
+        let batched = ScriptHashStatus::get_balances(&statuses, &chain, 0, 0);
+        let individual: Vec<_> = statuses
+            .iter()
+            .map(|s| s.get_balance(&chain, 0, 0))
+            .collect();
+        assert_eq!(batched, individual);
+        assert_eq!(
+            serde_json::to_value(&batched).unwrap(),
+            json!([
+                {"confirmed": 100, "unconfirmed": 0},
+                {"confirmed": 50, "unconfirmed": 0},
+                {"confirmed": 0, "unconfirmed": 0},
+            ])
+        );
+    }
+
+    #[test]
+    fn test_reorg() {
+        let tx1 = coinbase(1, vec![txout(100, script(1))]);
+        let tx2 = coinbase(2, vec![txout(50, script(2))]);
+        let tx3 = coinbase(3, vec![txout(20, script(2))]);
+        // `tx1` is moved from the 2nd block into the 3rd one (on a different fork)
+        let old_blocks = make_blocks(vec![vec![tx2.clone()], vec![tx1.clone()]]);
+        let mut new_blocks = make_blocks(vec![vec![tx2], vec![tx3], vec![tx1.clone()]]);
+        new_blocks[1].header.time += 1;
+        new_blocks[2].header.prev_blockhash = new_blocks[1].block_hash();
+        assert_eq!(old_blocks[0].block_hash(), new_blocks[0].block_hash());
+        assert_ne!(old_blocks[1].block_hash(), new_blocks[1].block_hash());
+
+        let headers = |blocks: &[Block]| -> Vec<NewHeader> {
+            blocks
+                .iter()
+                .zip(1..)
+                .map(|(block, height)| NewHeader::from((block.header, height)))
+                .collect()
+        };
+        let entries = |block: &Block| (block.block_hash(), vec![tx_entry(&tx1, &script(1))]);
+        let mempool = Mempool::new(&metrics());
+        let cache = Cache::new(&metrics());
+
+        let mut chain = Chain::new(Network::Regtest);
+        chain.update(headers(&old_blocks));
+        let mut status = ScriptHashStatus::new(ScriptHash::new(&script(1)));
+        status.update_confirmed(&chain, vec![entries(&old_blocks[1])].into_iter().collect());
+        status.update_mempool(&chain, &mempool, &cache, &mut HashSet::new());
+        assert_eq!(
+            json!(status.get_history()),
+            json!([{"tx_hash": tx1.txid(), "height": 2}])
+        );
+        let statushash = status.statushash();
+
+        chain.update(headers(&new_blocks).into_iter().skip(1).collect());
+        status.update_confirmed(&chain, vec![entries(&new_blocks[2])].into_iter().collect());
+        status.update_mempool(&chain, &mempool, &cache, &mut HashSet::new());
+        assert_eq!(
+            json!(status.get_history()),
+            json!([{"tx_hash": tx1.txid(), "height": 3}])
+        );
+        assert_ne!(status.statushash(), statushash);
+        assert_eq!(status.tip, chain.tip());
+        // the stale block's entries are dropped
+        assert_eq!(status.confirmed.len(), 1);
+        assert!(status.confirmed.contains_key(&new_blocks[2].block_hash()));
+    }
+
+    #[test]
+    fn test_first_use() {
+        let tx1 = coinbase(1, vec![txout(100, script(1))]);
+        let tx2 = coinbase(2, vec![txout(50, script(1))]);
+        let blocks = make_blocks(vec![vec![], vec![tx1.clone()], vec![tx2.clone()]]);
+        let chain = make_chain(&blocks);
+        let scripthash = ScriptHash::new(&script(1));
+        let entries = |tx: &Transaction| vec![tx_entry(tx, &script(1))];
+
+        let mut status = ScriptHashStatus::new(scripthash);
+        assert_eq!(status.get_first_use(&chain), None);
+        status.update_confirmed(
+            &chain,
+            vec![
+                (blocks[2].block_hash(), entries(&tx2)),
+                (blocks[1].block_hash(), entries(&tx1)),
+            ]
+            .into_iter()
+            .collect(),
+        );
+        assert_eq!(status.get_first_use(&chain), Some((2, tx1.txid())));
+    }
+
+    #[test]
+    fn test_min_conf() {
+        let tx1 = coinbase(1, vec![txout(100, script(1))]);
+        let tx2 = coinbase(2, vec![txout(50, script(1))]);
+        let tx3 = coinbase(3, vec![txout(20, script(2))]);
+        let blocks = make_blocks(vec![vec![tx1.clone()], vec![tx2.clone()], vec![tx3]]);
+        let chain = make_chain(&blocks);
+        assert_eq!(chain.height(), 3);
+
+        let mut status = ScriptHashStatus::new(ScriptHash::new(&script(1)));
+        for (block, tx) in blocks.iter().zip(&[tx1, tx2]) {
+            status
+                .confirmed
+                .insert(block.block_hash(), vec![tx_entry(tx, &script(1))]);
+        }
+        let heights = |min_conf| {
+            let mut heights: Vec<usize> = status
+                .get_unspent(&chain, Some(min_conf), 0)
+                .iter()
+                .map(|e| e.height)
+                .collect();
+            heights.sort_unstable();
+            heights
+        };
+        let balance = |min_conf| json!(status.get_balance(&chain, min_conf, 0));
+
+        // confirmations are counted from the tip, so the output at tip-1 has one confirmation,
+        // and the one at tip-2 has two
+        assert_eq!(heights(0), vec![1, 2]);
+        assert_eq!(balance(0), json!({"confirmed": 150, "unconfirmed": 0}));
+        assert_eq!(heights(1), vec![1, 2]);
+        assert_eq!(balance(1), json!({"confirmed": 150, "unconfirmed": 0}));
+        // shallower outputs are excluded from `listunspent`, and counted as unconfirmed balance
+        assert_eq!(heights(2), vec![1]);
+        assert_eq!(balance(2), json!({"confirmed": 100, "unconfirmed": 50}));
+        assert!(heights(3).is_empty());
+        assert_eq!(balance(3), json!({"confirmed": 0, "unconfirmed": 150}));
+
+        // without `min_conf`, all outputs are returned
+        let unspent = status.get_unspent(&chain, None, 0);
+        assert_eq!(unspent.len(), 2);
+    }
+
+    #[test]
+    fn test_unspent_at() {
+        let tx1 = coinbase(1, vec![txout(100, script(1)), txout(50, script(1))]);
+        let tx2 = spend(
+            vec![OutPoint::new(tx1.txid(), 0)],
+            vec![txout(90, script(1))],
+        );
+        let tx3 = spend(
+            vec![OutPoint::new(tx2.txid(), 0)],
+            vec![txout(80, script(2))],
+        );
+        let tx4 = spend(
+            vec![OutPoint::new(tx1.txid(), 1)],
+            vec![txout(40, script(1))],
+        );
+        let blocks = make_blocks(vec![
+            vec![tx1.clone()],
+            vec![tx2.clone()],
+            vec![tx3.clone()],
+        ]);
+        let chain = make_chain(&blocks);
+
+        let mut status = ScriptHashStatus::new(ScriptHash::new(&script(1)));
+        for (block, tx) in blocks.iter().zip(&[tx1, tx2, tx3]) {
+            let mut entry = tx_entry(tx, &script(1));
+            entry.spent = filter_inputs(tx, &status.confirmed_outpoints(&chain));
+            status.confirmed.insert(block.block_hash(), vec![entry]);
+        }
+        // mempool transactions are ignored
+        let mut entry = tx_entry(&tx4, &script(1));
+        entry.spent = filter_inputs(&tx4, &status.confirmed_outpoints(&chain));
+        status.mempool.push(entry);
+        assert_eq!(status.get_unspent(&chain, None, 0).len(), 1); // only tx4's output
+
+        let unspent = |min_height, max_height| {
+            let mut entries: Vec<(usize, u64)> = status
+                .get_unspent_at(&chain, min_height, max_height)
+                .iter()
+                .map(|e| (e.height, e.value.as_sat()))
+                .collect();
+            entries.sort_unstable();
+            entries
+        };
+        assert_eq!(unspent(0, 0), vec![]);
+        assert_eq!(unspent(0, 1), vec![(1, 50), (1, 100)]);
+        assert_eq!(unspent(0, 2), vec![(1, 50), (2, 90)]);
+        assert_eq!(unspent(0, 3), vec![(1, 50)]);
+        assert_eq!(unspent(2, 2), vec![(2, 90)]); // funded within the window
+        assert_eq!(unspent(0, 3), unspent(0, 100));
+    }
+
+    #[test]
+    fn test_coinbase_maturity() {
+        let tx0 = coinbase(0, vec![txout(10, script(2))]);
+        let tx1 = coinbase(1, vec![txout(100, script(1))]);
+        let tx2 = spend(
+            vec![OutPoint::new(tx0.txid(), 0)],
+            vec![txout(50, script(1))],
+        );
+        let mut txdata = vec![vec![tx1.clone(), tx2.clone()]];
+        txdata.extend(
+            (2..=COINBASE_MATURITY as u32).map(|i| vec![coinbase(i, vec![txout(1, script(3))])]),
+        );
+        let blocks = make_blocks(txdata);
+        let mut chain = make_chain(&blocks);
+        assert_eq!(chain.height(), COINBASE_MATURITY);
+
+        let mut status = ScriptHashStatus::new(ScriptHash::new(&script(1)));
+        let entries = [&tx1, &tx2]
+            .iter()
+            .map(|tx| tx_entry(tx, &script(1)))
+            .collect();
+        status.confirmed.insert(blocks[0].block_hash(), entries);
+
+        let unspent = |chain: &Chain, coinbase_min_conf| {
+            let mut entries: Vec<(u64, usize)> = status
+                .get_unspent(chain, None, coinbase_min_conf)
+                .iter()
+                .map(|e| (e.value.as_sat(), e.height))
+                .collect();
+            entries.sort_unstable();
+            entries
+        };
+        let balance = |chain: &Chain, coinbase_min_conf| {
+            json!(status.get_balance(chain, 1, coinbase_min_conf))
+        };
+
+        // the coinbase output has 100 confirmations, so it can be spent by the next block
+        assert_eq!(unspent(&chain, COINBASE_MATURITY), vec![(50, 1), (100, 1)]);
+        assert_eq!(
+            balance(&chain, COINBASE_MATURITY),
+            json!({"confirmed": 150, "unconfirmed": 0})
+        );
+
+        // a block earlier, only the non-coinbase output is spendable
+        chain.drop_last_headers(1);
+        assert_eq!(unspent(&chain, COINBASE_MATURITY), vec![(50, 1)]);
+        assert_eq!(
+            balance(&chain, COINBASE_MATURITY),
+            json!({"confirmed": 50, "unconfirmed": 100})
+        );
+        // unless coinbase maturity is ignored
+        assert_eq!(unspent(&chain, 0), vec![(50, 1), (100, 1)]);
+        assert_eq!(
+            balance(&chain, 0),
+            json!({"confirmed": 150, "unconfirmed": 0})
+        );
+    }
+
+    #[test]
+    fn test_get_mempool() {
+        let tx1 = coinbase(1, vec![txout(100, script(1)), txout(50, script(3))]);
+        let blocks = make_blocks(vec![vec![tx1.clone()]]);
+        let chain = make_chain(&blocks);
+
+        // spending a confirmed output of `script(1)`
+        let tx2 = spend(
+            vec![OutPoint::new(tx1.txid(), 0)],
+            vec![txout(90, script(2))],
+        );
+        // funding `script(1)`
+        let tx3 = spend(
+            vec![OutPoint::new(tx1.txid(), 1)],
+            vec![txout(40, script(1))],
+        );
+        let mut mempool = Mempool::new(&metrics());
+        mempool.add_entries(
+            vec![tx2.clone(), tx3.clone()]
+                .into_iter()
+                .map(|tx| mempool_entry(&tx, false)),
+        );
+        let cache = Cache::new(&metrics());
+
+        let mut status = ScriptHashStatus::new(ScriptHash::new(&script(1)));
+        status
+            .confirmed
+            .insert(blocks[0].block_hash(), vec![tx_entry(&tx1, &script(1))]);
+
+        let mut txids = vec![tx2.txid(), tx3.txid()];
+        txids.sort();
+        let expected: Vec<HistoryEntry> = txids
+            .into_iter()
+            .map(|txid| HistoryEntry::unconfirmed(txid, false, Amount::from_sat(10)))
+            .collect();
+        // only unconfirmed entries are returned
+        assert_eq!(
+            json!(status.get_mempool(&chain, &mempool, &cache)),
+            json!(expected)
+        );
+
+        // without synced confirmed entries, only the funding transaction is found
+        let status = ScriptHashStatus::new(ScriptHash::new(&script(1)));
+        assert_eq!(
+            json!(status.get_mempool(&chain, &mempool, &cache)),
+            json!([HistoryEntry::unconfirmed(
+                tx3.txid(),
+                false,
+                Amount::from_sat(10)
+            )])
+        );
+    }
+
+    #[test]
+    fn test_history_order() {
+        let tx1 = coinbase(1, vec![txout(100, script(1))]);
+        let tx2 = coinbase(2, vec![txout(50, script(1))]);
+        let blocks = make_blocks(vec![vec![tx1.clone()], vec![tx2.clone()]]);
+        let chain = make_chain(&blocks);
+
+        // spending confirmed outputs
+        let tx3 = spend(
+            vec![OutPoint::new(tx1.txid(), 0)],
+            vec![txout(90, script(2))],
+        );
+        let tx4 = spend(
+            vec![OutPoint::new(tx2.txid(), 0)],
+            vec![txout(40, script(2))],
+        );
+        // spending an unconfirmed output
+        let tx5 = spend(
+            vec![OutPoint::new(tx3.txid(), 0)],
+            vec![txout(80, script(1))],
+        );
+        let mut mempool = Mempool::new(&metrics());
+        mempool.add_entries(
+            vec![
+                (tx5.clone(), true),
+                (tx4.clone(), false),
+                (tx3.clone(), false),
+            ]
+            .into_iter()
+            .map(|(tx, has_unconfirmed_inputs)| mempool_entry(&tx, has_unconfirmed_inputs)),
+        );
+        let cache = Cache::new(&metrics());
+
+        let mut status = ScriptHashStatus::new(ScriptHash::new(&script(1)));
+        for (block, tx) in blocks.iter().zip(vec![&tx1, &tx2]) {
+            status
+                .confirmed
+                .insert(block.block_hash(), vec![tx_entry(tx, &script(1))]);
+        }
+        let mut outpoints = status.confirmed_outpoints(&chain);
+        status.update_mempool(&chain, &mempool, &cache, &mut outpoints);
+
+        // confirmed entries (by height), then mempool entries with confirmed inputs (by txid),
+        // and the ones with unconfirmed inputs last
+        let mut mempool_txids = vec![tx3.txid(), tx4.txid()];
+        mempool_txids.sort();
+        let expected = vec![
+            (tx1.txid(), 1),
+            (tx2.txid(), 2),
+            (mempool_txids[0], 0),
+            (mempool_txids[1], 0),
+            (tx5.txid(), -1),
+        ];
+        let history: Vec<(Txid, i64)> = status
+            .get_history()
+            .iter()
+            .map(|entry| (entry.txid, entry.height.as_i64()))
+            .collect();
+        assert_eq!(history, expected);
+
+        // the status hash, as computed by Electrum clients
+        let concatenated: String = expected
+            .iter()
+            .map(|(txid, height)| format!("{}:{}:", txid, height))
+            .collect();
+        assert_eq!(
+            status.statushash(),
+            Some(StatusHash::hash(concatenated.as_bytes()))
+        );
+    }
+
+    #[test]
+    fn test_deterministic_order() {
+        let tx1 = coinbase(1, vec![txout(100, script(1)), txout(50, script(1))]);
+        let tx2 = coinbase(2, vec![txout(30, script(1))]);
+        let tx3 = spend(
+            vec![OutPoint::new(Txid::hash(b"other"), 0)],
+            vec![txout(5, script(2)), txout(20, script(1))],
+        );
+        let blocks = make_blocks(vec![vec![tx1.clone()], vec![tx2.clone(), tx3.clone()]]);
+        let chain = make_chain(&blocks);
+        let tx4 = spend(
+            vec![OutPoint::new(tx2.txid(), 0)],
+            vec![txout(10, script(1)), txout(15, script(1))],
+        );
+        let mut mempool = Mempool::new(&metrics());
+        mempool.add_entries(vec![MempoolEntry {
+            fee: Amount::from_sat(5),
+            ..mempool_entry(&tx4, false)
+        }]);
+        let cache = Cache::new(&metrics());
+
+        let entry = |tx: &Transaction| tx_entry(tx, &script(1));
+        let build = |reversed: bool| {
+            let mut status = ScriptHashStatus::new(ScriptHash::new(&script(1)));
+            let mut confirmed = vec![
+                (blocks[0].block_hash(), vec![entry(&tx1)]),
+                (blocks[1].block_hash(), vec![entry(&tx2), entry(&tx3)]),
+            ];
+            if reversed {
+                confirmed.reverse();
+            }
+            status.confirmed.extend(confirmed);
+            let mut outpoints = status.confirmed_outpoints(&chain);
+            status.update_mempool(&chain, &mempool, &cache, &mut outpoints);
+            status
+        };
+
+        // the history is sorted by (height, position), with mempool entries last
+        let mut engine = sha256::Hash::engine();
+        for (txid, height) in &[
+            (tx1.txid(), 1),
+            (tx2.txid(), 2),
+            (tx3.txid(), 2),
+            (tx4.txid(), 0),
+        ] {
+            engine.input(format!("{}:{}:", txid, height).as_bytes());
+        }
+        let expected = StatusHash::from_engine(engine);
+
+        // each map is randomly seeded, so its iteration order differs between the statuses
+        let statuses: Vec<ScriptHashStatus> = (0..10).map(|i| build(i % 2 == 1)).collect();
+        for status in &statuses {
+            assert_eq!(status.statushash(), Some(expected));
+        }
+
+        let unspent = statuses[0].get_unspent(&chain, None, 0);
+        for status in &statuses[1..] {
+            assert_eq!(unspent, status.get_unspent(&chain, None, 0));
+        }
+        // sorted by (height, position, vout), with mempool entries last
+        let unspent: Vec<(Txid, u32, usize)> = unspent
+            .iter()
+            .map(|e| (e.tx_hash, e.tx_pos, e.height))
+            .collect();
+        assert_eq!(
+            unspent,
+            vec![
+                (tx1.txid(), 0, 1),
+                (tx1.txid(), 1, 1),
+                (tx3.txid(), 1, 2),
+                (tx4.txid(), 0, 0),
+                (tx4.txid(), 1, 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_evicted_mempool_tx() {
+        let tx1 = coinbase(1, vec![txout(100, script(1))]);
+        let tx2 = spend(
+            vec![OutPoint::new(tx1.txid(), 0)],
+            vec![txout(90, script(1))],
+        );
+        let chain = Chain::new(Network::Regtest);
+        let mut mempool = Mempool::new(&metrics());
+        mempool.add_entries(vec![mempool_entry(&tx2, false)]);
+        let cache = Cache::new(&metrics());
+
+        let mut status = ScriptHashStatus::new(ScriptHash::new(&script(1)));
+        status.update_mempool(&chain, &mempool, &cache, &mut HashSet::new());
+        assert_eq!(status.get_history().len(), 1);
+        let statushash = status.statushash();
+        assert!(statushash.is_some());
+
+        // the transaction is dropped from mempool
+        mempool.remove_entries(vec![tx2.txid()]);
+        status.update_mempool(&chain, &mempool, &cache, &mut HashSet::new());
+        assert!(status.get_history().is_empty());
+        assert_ne!(status.statushash(), statushash);
+    }
+
+    #[test]
+    fn test_unspent_verbose() {
+        let tx = coinbase(1, vec![txout(100, script(1)), txout(20, script(2))]);
+        let chain = Chain::new(Network::Regtest);
+        let mut mempool = Mempool::new(&metrics());
+        mempool.add_entries(vec![mempool_entry(&tx, false)]);
+        let cache = Cache::new(&metrics());
+
+        let mut status = ScriptHashStatus::new(ScriptHash::new(&script(1)));
+        status.update_mempool(&chain, &mempool, &cache, &mut HashSet::new());
+
+        // not included by default
+        assert_eq!(
+            json!(status.get_unspent(&chain, None, 0)),
+            json!([{"height": 0, "tx_hash": tx.txid(), "tx_pos": 0, "value": 100}])
+        );
+        let address = Address::from_script(&script(1), Network::Regtest).unwrap();
+        assert_eq!(
+            json!(status.get_unspent_verbose(&chain, None, 0, &cache, Network::Regtest)),
+            json!([{
+                "height": 0,
+                "tx_hash": tx.txid(),
+                "tx_pos": 0,
+                "value": 100,
+                "script_pubkey": "0014".to_owned() + &"01".repeat(20),
+                "address": address.to_string(),
+            }])
+        );
+    }
+
+    #[test]
+    fn test_export_history() {
+        let tx1 = coinbase(1, vec![txout(100, script(1)), txout(20, script(1))]);
+        let tx2 = coinbase(2, vec![txout(50, script(2))]);
+        let tx3 = spend(
+            vec![OutPoint::new(tx1.txid(), 0)],
+            vec![txout(90, script(2)), txout(5, script(1))],
+        );
+        let blocks = make_blocks(vec![vec![tx1.clone()], vec![tx2], vec![tx3.clone()]]);
+        let chain = make_chain(&blocks);
+
+        let mut status = ScriptHashStatus::new(ScriptHash::new(&script(1)));
+        let entry1 = tx_entry(&tx1, &script(1));
+        let outpoints: HashSet<OutPoint> = entry1.funding_outpoints().collect();
+        let mut entry3 = tx_entry(&tx3, &script(1));
+        entry3.spent = filter_inputs(&tx3, &outpoints);
+        status
+            .confirmed
+            .insert(blocks[0].block_hash(), vec![entry1]);
+        status
+            .confirmed
+            .insert(blocks[2].block_hash(), vec![entry3]);
+
+        let deltas = status.get_confirmed_deltas(&chain);
+        let mut csv = Vec::new();
+        write_history_deltas(&deltas, ExportFormat::Csv, &mut csv).unwrap();
+        assert_eq!(
+            String::from_utf8(csv).unwrap(),
+            format!(
+                "height,txid,value_delta\n1,{},120\n3,{},-95\n",
+                tx1.txid(),
+                tx3.txid()
+            )
+        );
+
+        let mut json = Vec::new();
+        write_history_deltas(&deltas, ExportFormat::Json, &mut json).unwrap();
+        assert_eq!(
+            serde_json::from_slice::<serde_json::Value>(&json).unwrap(),
+            json!([
+                {"height": 1, "txid": tx1.txid(), "value_delta": 120},
+                {"height": 3, "txid": tx3.txid(), "value_delta": -95},
+            ])
+        );
+    }
+
+    #[test]
+    fn test_max_history() {
+        let tx1 = coinbase(1, vec![txout(100, script(1))]);
+        let blocks = make_blocks(vec![vec![tx1.clone()]]);
+        let chain = make_chain(&blocks);
+        let tx2 = spend(
+            vec![OutPoint::new(tx1.txid(), 0)],
+            vec![txout(90, script(2))],
+        );
+        let mut mempool = Mempool::new(&metrics());
+        mempool.add_entries(vec![mempool_entry(&tx2, false)]);
+        let cache = Cache::new(&metrics());
+
+        let mut status = ScriptHashStatus::new(ScriptHash::new(&script(1)));
+        status
+            .confirmed
+            .insert(blocks[0].block_hash(), vec![tx_entry(&tx1, &script(1))]);
+        let mut outpoints = status.confirmed_outpoints(&chain);
+        status.update_mempool(&chain, &mempool, &cache, &mut outpoints);
+        assert_eq!(status.get_history().len(), 2);
+
+        assert!(status.check_history_len(None).is_ok());
+        assert!(status.check_history_len(Some(2)).is_ok());
+        // both confirmed and mempool entries are counted
+        let err = status.check_history_len(Some(1)).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<QueryError>(),
+            Some(QueryError::HistoryTooLong(1))
+        ));
+        assert_eq!(err.to_string(), "history too long (>1 entries)");
+    }
+
+    #[test]
+    fn test_ignored_mempool() {
+        let tx1 = coinbase(1, vec![txout(100, script(1))]);
+        let blocks = make_blocks(vec![vec![tx1.clone()]]);
+        let chain = make_chain(&blocks);
+        let tx2 = spend(
+            vec![OutPoint::new(tx1.txid(), 0)],
+            vec![txout(90, script(2))],
+        );
+        let mut mempool = Mempool::new(&metrics());
+        mempool.add_entries(vec![mempool_entry(&tx2, false)]);
+        // with `--ignore-mempool`, the mempool is never synced (and stays empty)
+        let empty_mempool = Mempool::new(&metrics());
+        let cache = Cache::new(&metrics());
+
+        let new_status = || {
+            let mut status = ScriptHashStatus::new(ScriptHash::new(&script(1)));
+            status
+                .confirmed
+                .insert(blocks[0].block_hash(), vec![tx_entry(&tx1, &script(1))]);
+            status
+        };
+
+        let mut status = new_status();
+        let mut outpoints = status.confirmed_outpoints(&chain);
+        status.update_mempool(&chain, &mempool, &cache, &mut outpoints);
+        assert_eq!(status.get_history().len(), 2);
+        let statushash = status.statushash();
+
+        let mut ignored = new_status();
+        let mut outpoints = ignored.confirmed_outpoints(&chain);
+        ignored.update_mempool(&chain, &empty_mempool, &cache, &mut outpoints);
+        assert_eq!(
+            json!(ignored.get_history()),
+            json!([HistoryEntry::confirmed(tx1.txid(), 1)])
+        );
+        assert!(ignored
+            .get_mempool(&chain, &empty_mempool, &cache)
+            .is_empty());
+        // the status hash reflects only the confirmed entries
+        assert!(ignored.statushash().is_some());
+        assert_ne!(ignored.statushash(), statushash);
+    }
 }
@@ -0,0 +1,185 @@
+//! Test fixtures: synthetic regtest blocks, indexed into a temporary DB.
+
+use anyhow::Result;
+use bitcoin::{
+    consensus::deserialize, network::constants::Network, Amount, Block, BlockHash, BlockHeader,
+    OutPoint, Script, Transaction, TxIn, TxMerkleNode, TxOut,
+};
+
+use std::path::Path;
+
+use crate::{
+    chain::{Chain, NewHeader},
+    config::MonitoringAddr,
+    db::DBStore,
+    index::{Index, IndexConfig},
+    mempool,
+    metrics::Metrics,
+    status::{filter_outputs, BlockSource, TxEntry},
+    types::ScriptHash,
+};
+
+/// A P2WPKH-like script, distinct for each `i`
+pub(crate) fn script(i: u8) -> Script {
+    let mut bytes = vec![0x00, 0x14];
+    bytes.extend(&[i; 20]);
+    Script::from(bytes)
+}
+
+pub(crate) fn txout(value: u64, script_pubkey: Script) -> TxOut {
+    TxOut {
+        value,
+        script_pubkey,
+    }
+}
+
+pub(crate) fn coinbase(height: u32, output: Vec<TxOut>) -> Transaction {
+    let input = TxIn {
+        previous_output: OutPoint::null(),
+        script_sig: Script::from(height.to_le_bytes().to_vec()), // make txid unique
+        sequence: 0xFFFFFFFF,
+        witness: vec![],
+    };
+    Transaction {
+        version: 2,
+        lock_time: 0,
+        input: vec![input],
+        output,
+    }
+}
+
+pub(crate) fn spend(prevouts: Vec<OutPoint>, output: Vec<TxOut>) -> Transaction {
+    let input = prevouts
+        .into_iter()
+        .map(|previous_output| TxIn {
+            previous_output,
+            script_sig: Script::new(),
+            sequence: 0xFFFFFFFF,
+            witness: vec![],
+        })
+        .collect();
+    Transaction {
+        version: 2,
+        lock_time: 0,
+        input,
+        output,
+    }
+}
+
+/// Build a chain of blocks on top of regtest genesis block (merkle roots and PoW are not valid)
+pub(crate) fn make_blocks(txdata: Vec<Vec<Transaction>>) -> Vec<Block> {
+    let mut prev_blockhash = Chain::new(Network::Regtest).tip();
+    txdata
+        .into_iter()
+        .zip(1u32..)
+        .map(|(txdata, nonce)| {
+            let header = BlockHeader {
+                version: 0x2000_0000,
+                prev_blockhash,
+                merkle_root: TxMerkleNode::default(),
+                time: 1_600_000_000 + nonce,
+                bits: 0x207f_ffff,
+                nonce,
+            };
+            prev_blockhash = header.block_hash();
+            Block { header, txdata }
+        })
+        .collect()
+}
+
+/// A chain of the given blocks' headers (as returned by `make_blocks`)
+pub(crate) fn make_chain(blocks: &[Block]) -> Chain {
+    let mut chain = Chain::new(Network::Regtest);
+    chain.update(
+        blocks
+            .iter()
+            .zip(1..)
+            .map(|(block, height)| NewHeader::from((block.header, height)))
+            .collect(),
+    );
+    chain
+}
+
+/// The outputs of `tx` funding the given script (as found by a scripthash query)
+pub(crate) fn tx_entry(tx: &Transaction, script: &Script) -> TxEntry {
+    let mut entry = TxEntry::new(tx.txid());
+    entry.outputs = filter_outputs(tx, ScriptHash::new(script));
+    entry
+}
+
+/// A mempool entry for `tx` (paying 10 sat for 100 vbytes, without RBF signaling)
+pub(crate) fn mempool_entry(tx: &Transaction, has_unconfirmed_inputs: bool) -> mempool::Entry {
+    mempool::Entry {
+        txid: tx.txid(),
+        tx: tx.clone(),
+        fee: Amount::from_sat(10),
+        vsize: 100,
+        has_unconfirmed_inputs,
+        bip125_replaceable: false,
+        package: Default::default(),
+        first_seen: 0,
+    }
+}
+
+/// Serves the given blocks (instead of fetching them from `bitcoind`)
+pub(crate) struct TestBlocks(pub(crate) Vec<Block>);
+
+impl BlockSource for TestBlocks {
+    fn for_blocks<B, F>(&self, blockhashes: B, mut func: F) -> Result<()>
+    where
+        B: IntoIterator<Item = BlockHash>,
+        F: FnMut(BlockHash, Block),
+    {
+        for blockhash in blockhashes {
+            let block = self
+                .0
+                .iter()
+                .find(|block| block.block_hash() == blockhash)
+                .expect("missing block");
+            func(blockhash, block.clone());
+        }
+        Ok(())
+    }
+}
+
+/// Load a (mainnet) block from `src/tests/blocks/`
+pub(crate) fn load_block(block_hash_hex: &str) -> Block {
+    let path = Path::new("src")
+        .join("tests")
+        .join("blocks")
+        .join(block_hash_hex);
+    let data = std::fs::read(path).unwrap();
+    deserialize(&data).unwrap()
+}
+
+pub(crate) fn metrics() -> Metrics {
+    Metrics::new(MonitoringAddr::Tcp("127.0.0.1:0".parse().unwrap()), None, 0).unwrap()
+}
+
+pub(crate) struct TestIndex {
+    pub(crate) index: Index,
+    _dir: tempfile::TempDir, // should be dropped after the index
+}
+
+impl TestIndex {
+    /// Close the DB, and load the index from it again
+    pub(crate) fn reload(self) -> Self {
+        let TestIndex { index, _dir: dir } = self;
+        drop(index);
+        let index = load_index(dir.path());
+        TestIndex { index, _dir: dir }
+    }
+}
+
+fn load_index(path: &Path) -> Index {
+    let store = DBStore::open(path, true).unwrap();
+    let chain = Chain::new(Network::Regtest);
+    Index::load(store, chain, &metrics(), IndexConfig::default()).unwrap()
+}
+
+pub(crate) fn test_index(blocks: Vec<Block>) -> TestIndex {
+    let dir = tempfile::tempdir().unwrap();
+    let mut index = load_index(dir.path());
+    index.index_blocks(blocks);
+    TestIndex { index, _dir: dir }
+}
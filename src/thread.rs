@@ -1,10 +1,43 @@
-use anyhow::Result;
+use anyhow::{Context, Result};
+
+/// Build a thread pool of the given size (or of the number of available cores).
+pub(crate) fn build_pool(name: &'static str, threads: Option<usize>) -> Result<rayon::ThreadPool> {
+    rayon::ThreadPoolBuilder::new()
+        .num_threads(threads.unwrap_or(0)) // 0 means the number of available cores
+        .thread_name(move |i| format!("{}-{}", name, i))
+        .build()
+        .with_context(|| format!("failed to create {} thread pool", name))
+}
 
 pub(crate) fn spawn<F>(name: &'static str, f: F) -> std::thread::JoinHandle<()>
 where
     F: 'static + Send + FnOnce() -> Result<()>,
 {
-    std::thread::Builder::new()
+    spawn_with(std::thread::Builder::new(), name, f)
+}
+
+/// Spawn a thread with a smaller stack than the default (2 MiB), for cheap threads which
+/// may be spawned in large numbers (e.g. one per connected client).
+pub(crate) fn spawn_with_stack_size<F>(
+    name: &'static str,
+    stack_size: usize,
+    f: F,
+) -> std::thread::JoinHandle<()>
+where
+    F: 'static + Send + FnOnce() -> Result<()>,
+{
+    spawn_with(std::thread::Builder::new().stack_size(stack_size), name, f)
+}
+
+fn spawn_with<F>(
+    builder: std::thread::Builder,
+    name: &'static str,
+    f: F,
+) -> std::thread::JoinHandle<()>
+where
+    F: 'static + Send + FnOnce() -> Result<()>,
+{
+    builder
         .name(name.to_owned())
         .spawn(move || {
             if let Err(e) = f() {
@@ -14,3 +47,35 @@ where
         })
         .expect("failed to spawn a thread")
 }
+
+#[cfg(test)]
+mod tests {
+    use super::build_pool;
+    use parking_lot::Mutex;
+    use rayon::prelude::*;
+
+    use std::collections::HashSet;
+
+    #[test]
+    fn test_build_pool() {
+        let pool = build_pool("query", Some(2)).unwrap();
+        let threads = Mutex::new(HashSet::new());
+        let sum: u64 = pool.install(|| {
+            assert_eq!(rayon::current_num_threads(), 2);
+            (1..=100u64)
+                .into_par_iter()
+                .map(|i| {
+                    let name = std::thread::current().name().map(str::to_owned);
+                    threads.lock().insert(name);
+                    i
+                })
+                .sum()
+        });
+        assert_eq!(sum, 5050);
+        let threads = threads.into_inner();
+        assert!(!threads.is_empty() && threads.len() <= 2);
+        for name in threads {
+            assert!(name.unwrap().starts_with("query-"));
+        }
+    }
+}
@@ -1,17 +1,37 @@
-use anyhow::{Context, Result};
-use bitcoin::{BlockHash, Transaction, Txid};
+use anyhow::{bail, Context, Result};
+use bitcoin::{
+    hashes::hex::FromHex, Block, BlockHash, BlockHeader, Network, OutPoint, Transaction, Txid,
+};
+use crossbeam_channel::Sender;
+use parking_lot::Mutex;
+use rayon::prelude::*;
+use serde_json::{json, Value};
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io;
+use std::ops::RangeInclusive;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use crate::{
     cache::Cache,
     chain::Chain,
     config::Config,
-    daemon::Daemon,
+    daemon::{is_tx_not_found, Daemon, SlowRpc},
     db::DBStore,
-    index::Index,
-    mempool::{FeeHistogram, Mempool},
+    errors::QueryError,
+    index::{Index, IndexConfig},
+    mempool::{FeeHistogram, FeeRateEntry, Mempool, Package},
+    merkle::{MerkleTree, Proof},
     metrics::Metrics,
     signals::ExitFlag,
-    status::{Balance, ScriptHashStatus, UnspentEntry},
+    status::{
+        Balance, BlockSource, HistoryDelta, HistoryEntry, QueryBlocks, ScriptHashStatus,
+        SlowQueryLog, UnspentEntry, COINBASE_MATURITY,
+    },
+    types::{opreturn_data, FilterRow, ScriptHash, HASH_PREFIX_LEN},
 };
 
 /// Electrum protocol subscriptions' tracker
@@ -19,30 +39,269 @@ pub struct Tracker {
     index: Index,
     mempool: Mempool,
     metrics: Metrics,
+    slow_query_log: SlowQueryLog,
     ignore_mempool: bool,
+    network: Network,
+    mempool_tip: Option<BlockHash>, // chain tip during the last mempool sync
+    coinbase_min_conf: usize,       // see `--coinbase-maturity`
+    max_serving_lag: Option<usize>,
+    max_history: Option<usize>,
+    watchlist: Watchlist,
+    primed: Mutex<PrimedStatuses>,
+}
+
+// priming shouldn't delay the next blocks' indexing (the remaining statuses are synced on query)
+const MAX_PRIME_DURATION: Duration = Duration::from_secs(1);
+
+// a single large status may take longer than `MAX_PRIME_DURATION` to sync, so it's synced on query
+const MAX_PRIMED_HISTORY: usize = 1000;
+
+/// Statuses kept up-to-date after every sync, for serving their queries from memory
+/// (see `--watch-scripthashes`)
+struct Watchlist {
+    statuses: HashMap<ScriptHash, Arc<ScriptHashStatus>>,
+}
+
+impl Watchlist {
+    fn new(scripthashes: impl IntoIterator<Item = ScriptHash>) -> Self {
+        let statuses = scripthashes
+            .into_iter()
+            .map(|scripthash| (scripthash, Arc::new(ScriptHashStatus::new(scripthash))))
+            .collect();
+        Self { statuses }
+    }
+
+    fn sync(
+        &mut self,
+        index: &Index,
+        mempool: &Mempool,
+        daemon: &(impl BlockSource + Sync),
+        cache: &Cache,
+    ) {
+        // a failed status is not served (see `Tracker::get_watched`) until it's synced again
+        self.statuses
+            .par_iter_mut()
+            .for_each(|(scripthash, status)| {
+                // served statuses are released after their queries, so they're not copied here
+                if let Err(e) = Arc::make_mut(status).sync(index, mempool, daemon, cache) {
+                    warn!("failed to sync watched {}: {:#}", scripthash, e);
+                }
+            })
+    }
+
+    fn get(&self, scripthash: &ScriptHash) -> Option<&Arc<ScriptHashStatus>> {
+        self.statuses.get(scripthash)
+    }
+}
+
+/// Recently queried statuses, re-synced after each new block, so that their next query only
+/// needs to sync the mempool (see `--prime-on-block`)
+struct PrimedStatuses {
+    statuses: HashMap<ScriptHash, (u64, Arc<ScriptHashStatus>)>, // with their last query's sequence
+    order: BTreeMap<u64, ScriptHash>, // by last query, for evicting the least recently queried
+    next_seq: u64,
+    capacity: usize,
+    tip: Option<BlockHash>, // chain tip during the last priming
+}
+
+impl PrimedStatuses {
+    fn new(capacity: usize) -> Self {
+        Self {
+            statuses: HashMap::new(),
+            order: BTreeMap::new(),
+            next_seq: 0,
+            capacity,
+            tip: None,
+        }
+    }
+
+    fn get(&self, scripthash: &ScriptHash) -> Option<Arc<ScriptHashStatus>> {
+        self.statuses
+            .get(scripthash)
+            .map(|(_seq, status)| Arc::clone(status))
+    }
+
+    /// Remove a queried status (so it can be synced without being copied), until it's inserted back.
+    fn take(&mut self, scripthash: &ScriptHash) -> Option<Arc<ScriptHashStatus>> {
+        let (seq, status) = self.statuses.remove(scripthash)?;
+        self.order.remove(&seq);
+        Some(status)
+    }
+
+    fn insert(&mut self, status: Arc<ScriptHashStatus>) {
+        if self.capacity == 0 || status.get_history().len() > MAX_PRIMED_HISTORY {
+            return;
+        }
+        let scripthash = status.scripthash();
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        if let Some((prev_seq, _)) = self.statuses.insert(scripthash, (seq, status)) {
+            self.order.remove(&prev_seq);
+        }
+        self.order.insert(seq, scripthash);
+        while self.statuses.len() > self.capacity {
+            let oldest = *self.order.keys().next().expect("empty order");
+            let scripthash = self.order.remove(&oldest).expect("missing scripthash");
+            self.statuses.remove(&scripthash);
+        }
+    }
+
+    /// Sync the statuses after a new block (returning how many were synced).
+    fn sync(
+        &mut self,
+        index: &Index,
+        mempool: &Mempool,
+        daemon: &(impl BlockSource + Sync),
+        cache: &Cache,
+        max_duration: Duration,
+    ) -> usize {
+        let tip = index.chain().tip();
+        if self.statuses.is_empty() || self.tip == Some(tip) {
+            return 0; // no new blocks (the mempool is synced on query)
+        }
+        self.tip = Some(tip);
+        let start = Instant::now();
+        let results: Vec<(ScriptHash, bool)> = self
+            .statuses
+            .par_iter_mut()
+            .filter(|_| start.elapsed() < max_duration)
+            .map(|(scripthash, (_seq, status))| {
+                match Arc::make_mut(status).sync(index, mempool, daemon, cache) {
+                    Ok(()) => (*scripthash, true),
+                    Err(e) => {
+                        warn!("failed to prime {}: {}", scripthash, e);
+                        (*scripthash, false)
+                    }
+                }
+            })
+            .collect();
+        for (scripthash, _) in results.iter().filter(|(_, synced)| !synced) {
+            self.take(scripthash);
+        }
+        results.iter().filter(|(_, synced)| *synced).count()
+    }
+}
+
+/// Load the watched scripthashes from `path` (one per line), adding the new ones to it.
+fn load_watch_file(path: &Path, scripthashes: &[ScriptHash]) -> Result<Vec<ScriptHash>> {
+    let mut watched = match fs::read_to_string(path) {
+        Ok(content) => content
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .map(|line| {
+                ScriptHash::from_hex(line)
+                    .with_context(|| format!("invalid scripthash {:?} in {}", line, path.display()))
+            })
+            .collect::<Result<Vec<ScriptHash>>>()?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => vec![],
+        Err(e) => return Err(e).with_context(|| format!("failed to read {}", path.display())),
+    };
+    let loaded = watched.len();
+    for scripthash in scripthashes {
+        if !watched.contains(scripthash) {
+            watched.push(*scripthash);
+        }
+    }
+    if watched.len() > loaded || !path.exists() {
+        let content: String = watched.iter().map(|s| format!("{}\n", s)).collect();
+        fs::write(path, content).with_context(|| format!("failed to write {}", path.display()))?;
+    }
+    Ok(watched)
+}
+
+/// Chain-wide statistics are expensive (requiring a full index scan, and bitcoind's UTXO set scan),
+/// so they are computed in a background thread after new blocks are indexed.
+pub(crate) struct ChainStats {
+    tip: Option<BlockHash>, // the last refreshed tip
+    tx: Sender<Value>,      // the index statistics of a new tip
+    latest: Arc<Mutex<Option<Value>>>,
 }
 
+impl ChainStats {
+    fn start(rpc: SlowRpc, count_transactions: impl Fn() -> u64 + Send + 'static) -> Self {
+        let (tx, rx) = crossbeam_channel::unbounded::<Value>();
+        let latest = Arc::new(Mutex::new(None));
+        let result = Arc::clone(&latest);
+        crate::thread::spawn("chain_stats", move || {
+            while let Ok(stats) = rx.recv() {
+                // skip to the latest tip, if more blocks were indexed meanwhile
+                let mut stats = rx.try_iter().last().unwrap_or(stats);
+                let utxo_set = match rpc.get_utxo_set_info() {
+                    Ok(utxo_set) => utxo_set,
+                    Err(e) => {
+                        warn!("failed to compute chain statistics: {:#}", e);
+                        continue;
+                    }
+                };
+                stats["indexed_transactions"] = json!(count_transactions());
+                stats["utxos"] = json!(utxo_set.txouts);
+                stats["total_amount"] = json!(utxo_set.total_amount);
+                *result.lock() = Some(stats);
+            }
+            Ok(()) // the tracker was dropped
+        });
+        Self {
+            tip: None,
+            tx,
+            latest,
+        }
+    }
+
+    /// The last computed statistics (which may lag behind the current tip).
+    pub(crate) fn get(&self) -> Option<Value> {
+        self.latest.lock().clone()
+    }
+}
+
+#[derive(Debug, PartialEq)]
 pub(crate) enum Error {
     NotReady,
+    Lagging(usize), // number of blocks left to index
 }
 
 impl Tracker {
-    pub fn new(config: &Config, metrics: Metrics) -> Result<Self> {
-        let store = DBStore::open(&config.db_path, config.auto_reindex)?;
-        let chain = Chain::new(config.network);
+    pub fn new(config: &Config, metrics: Metrics, genesis: Option<BlockHeader>) -> Result<Self> {
+        let store = if config.export.is_some() {
+            DBStore::open_read_only(&config.db_path)? // don't interfere with a running instance
+        } else {
+            // an index failing verification should be reported, not silently re-indexed
+            let auto_reindex = config.auto_reindex && !config.verify;
+            let mut store = DBStore::open(&config.db_path, auto_reindex)?;
+            store.set_compaction(config.db_compact_after_blocks, config.db_compact);
+            store
+        };
+        let chain = match genesis {
+            Some(header) => Chain::with_genesis(header),
+            None => Chain::new(config.network),
+        };
+        let mut mempool = Mempool::new(&metrics);
+        mempool.set_min_fee_rate(config.mempool_min_fee_rate);
+        let watched = match &config.watch_file {
+            Some(path) => load_watch_file(path, &config.watch_scripthashes)?,
+            None => config.watch_scripthashes.clone(),
+        };
+        if !watched.is_empty() {
+            info!("watching {} scripthashes", watched.len());
+        }
         Ok(Self {
-            index: Index::load(
-                store,
-                chain,
-                &metrics,
-                config.index_batch_size,
-                config.index_lookup_limit,
-                config.reindex_last_blocks,
-            )
-            .context("failed to open index")?,
-            mempool: Mempool::new(&metrics),
+            index: Index::load(store, chain, &metrics, IndexConfig::new(config))
+                .context("failed to open index")?,
+            mempool,
+            slow_query_log: SlowQueryLog::new(config.slow_query, &metrics),
             metrics,
             ignore_mempool: config.ignore_mempool,
+            network: config.network,
+            mempool_tip: None,
+            coinbase_min_conf: if config.coinbase_maturity {
+                COINBASE_MATURITY
+            } else {
+                0
+            },
+            max_serving_lag: config.max_serving_lag_blocks,
+            max_history: config.max_history,
+            watchlist: Watchlist::new(watched),
+            primed: Mutex::new(PrimedStatuses::new(config.prime_on_block)),
         })
     }
 
@@ -50,32 +309,169 @@ impl Tracker {
         self.index.chain()
     }
 
+    /// Confirmed history below this height is missing from the index (see `--keep-blocks`).
+    pub(crate) fn pruned_height(&self) -> Option<usize> {
+        self.index.pruned_height()
+    }
+
     pub(crate) fn fees_histogram(&self) -> &FeeHistogram {
         self.mempool.fees_histogram()
     }
 
+    pub(crate) fn get_mempool_package(
+        &self,
+        daemon: &Daemon,
+        txid: &Txid,
+    ) -> Result<Option<Package>> {
+        self.mempool.get_package(txid, daemon)
+    }
+
+    pub(crate) fn get_mempool_by_fee_rate(&self, max_vsize: u64) -> Vec<FeeRateEntry> {
+        self.mempool.by_fee_rate(max_vsize)
+    }
+
+    pub(crate) fn get_mempool_first_seen(&self, txid: &Txid) -> Option<u64> {
+        self.mempool.get_first_seen(txid)
+    }
+
+    pub(crate) fn get_mempool_replacement(&self, txid: &Txid) -> Option<Txid> {
+        self.mempool.get_replacement(txid)
+    }
+
     pub(crate) fn metrics(&self) -> &Metrics {
         &self.metrics
     }
 
-    pub(crate) fn get_unspent(&self, status: &ScriptHashStatus) -> Vec<UnspentEntry> {
-        status.get_unspent(self.index.chain())
+    pub(crate) fn get_unspent(
+        &self,
+        status: &ScriptHashStatus,
+        min_conf: Option<usize>,
+    ) -> Vec<UnspentEntry> {
+        status.get_unspent(self.index.chain(), min_conf, self.coinbase_min_conf)
+    }
+
+    pub(crate) fn get_unspent_verbose(
+        &self,
+        status: &ScriptHashStatus,
+        min_conf: Option<usize>,
+        cache: &Cache,
+    ) -> Vec<UnspentEntry> {
+        status.get_unspent_verbose(
+            self.index.chain(),
+            min_conf,
+            self.coinbase_min_conf,
+            cache,
+            self.network,
+        )
+    }
+
+    pub(crate) fn get_unspent_at(
+        &self,
+        status: &ScriptHashStatus,
+        min_height: usize,
+        max_height: usize,
+    ) -> Vec<UnspentEntry> {
+        status.get_unspent_at(self.index.chain(), min_height, max_height)
+    }
+
+    /// Fetch the mempool before serving the first clients, for at most `timeout`
+    /// (the remaining transactions are fetched by the next sync).
+    pub(crate) fn warmup_mempool(&mut self, daemon: &Daemon, timeout: Duration) {
+        if !self.ignore_mempool {
+            let start = Instant::now();
+            self.mempool.sync_until(daemon, Some(start + timeout));
+            info!("mempool warmup took {:?}", start.elapsed());
+        }
     }
 
-    pub(crate) fn sync(&mut self, daemon: &Daemon, exit_flag: &ExitFlag) -> Result<bool> {
+    /// Sync the index, and the mempool (if polled, or after a new block).
+    pub(crate) fn sync(
+        &mut self,
+        daemon: &Daemon,
+        exit_flag: &ExitFlag,
+        poll_mempool: bool,
+    ) -> Result<bool> {
+        let prev_tip = self.chain().tip();
         let done = self.index.sync(daemon, exit_flag)?;
-        if done && !self.ignore_mempool {
+        let tip = self.chain().tip();
+        if tip != prev_tip {
+            daemon.update_prune_height()?;
+        }
+        if done && !self.ignore_mempool && (poll_mempool || self.mempool_tip != Some(tip)) {
             self.mempool.sync(daemon);
+            self.mempool_tip = Some(tip);
             // TODO: double check tip - and retry on diff
         }
         Ok(done)
     }
 
-    pub(crate) fn status(&self) -> Result<(), Error> {
-        if self.index.is_ready() {
-            return Ok(());
+    /// Sync the watched statuses (after the index and the mempool are synced).
+    pub(crate) fn sync_watched(&mut self, daemon: &Daemon, cache: &Cache) {
+        let source = QueryBlocks::new(daemon, self.index.chain());
+        self.watchlist
+            .sync(&self.index, &self.mempool, &source, cache)
+    }
+
+    /// Re-sync the recently queried statuses after a new block (see `--prime-on-block`).
+    pub(crate) fn sync_primed(&self, daemon: &Daemon, cache: &Cache) {
+        let start = Instant::now();
+        let mut primed = self.primed.lock();
+        let count = primed.sync(
+            &self.index,
+            &self.mempool,
+            &QueryBlocks::new(daemon, self.chain()),
+            cache,
+            MAX_PRIME_DURATION,
+        );
+        if count > 0 {
+            debug!("primed {} statuses in {:?}", count, start.elapsed());
         }
-        Err(Error::NotReady)
+    }
+
+    /// A recently queried status (possibly behind the tip), to be synced before being used.
+    pub(crate) fn get_primed(&self, scripthash: &ScriptHash) -> Option<Arc<ScriptHashStatus>> {
+        self.primed.lock().get(scripthash)
+    }
+
+    /// Like `get_primed`, but the status is kept again only after its query (see `add_primed`).
+    pub(crate) fn take_primed(&self, scripthash: &ScriptHash) -> Option<Arc<ScriptHashStatus>> {
+        self.primed.lock().take(scripthash)
+    }
+
+    /// Keep a queried status, for re-syncing it after the next blocks.
+    pub(crate) fn add_primed(&self, status: Arc<ScriptHashStatus>) {
+        self.primed.lock().insert(status)
+    }
+
+    /// The up-to-date status of a watched scripthash (if synced).
+    pub(crate) fn get_watched(&self, scripthash: &ScriptHash) -> Option<Arc<ScriptHashStatus>> {
+        self.watchlist
+            .get(scripthash)
+            .filter(|status| status.tip() == self.chain().tip())
+            .map(Arc::clone)
+    }
+
+    /// Add transactions announced by bitcoind (after the mempool was synced).
+    pub(crate) fn add_mempool_txids(&mut self, daemon: &Daemon, txids: Vec<Txid>) {
+        if !self.ignore_mempool && self.mempool_tip.is_some() {
+            self.mempool.add_announced(daemon, txids);
+        }
+    }
+
+    pub(crate) fn verify_index(&self, daemon: &Daemon, exit_flag: &ExitFlag) -> Result<()> {
+        self.index.verify(daemon, exit_flag)
+    }
+
+    pub(crate) fn compact_if_needed(&self) {
+        self.index.compact_if_needed()
+    }
+
+    pub(crate) fn status(&self) -> Result<(), Error> {
+        serving_status(
+            self.index.is_ready(),
+            self.index.lag(),
+            self.max_serving_lag,
+        )
     }
 
     pub(crate) fn update_scripthash_status(
@@ -85,33 +481,650 @@ impl Tracker {
         cache: &Cache,
     ) -> Result<bool> {
         let prev_statushash = status.statushash();
-        status.sync(&self.index, &self.mempool, daemon, cache)?;
+        let start = Instant::now();
+        let source = QueryBlocks::new(daemon, self.chain());
+        status.sync(&self.index, &self.mempool, &source, cache)?;
+        self.slow_query_log.observe(status, start.elapsed());
         Ok(prev_statushash != status.statushash())
     }
 
-    pub(crate) fn get_balance(&self, status: &ScriptHashStatus) -> Balance {
-        status.get_balance(self.chain())
+    /// Get the history response, cached until the status hash changes.
+    pub(crate) fn get_history(
+        &self,
+        status: &ScriptHashStatus,
+        cache: &Cache,
+    ) -> Result<Arc<Value>> {
+        status.check_history_len(self.max_history)?;
+        Ok(match status.statushash() {
+            Some(statushash) => cache.get_history(status.scripthash(), statushash, || {
+                json!(status.get_history())
+            }),
+            None => Arc::new(json!(status.get_history())), // empty history
+        })
     }
 
-    pub(crate) fn lookup_transaction(
+    pub(crate) fn get_history_deltas(&self, status: &ScriptHashStatus) -> Vec<HistoryDelta> {
+        status.get_confirmed_deltas(self.chain())
+    }
+
+    pub(crate) fn get_balance(&self, status: &ScriptHashStatus, min_conf: usize) -> Balance {
+        status.get_balance(self.chain(), min_conf, self.coinbase_min_conf)
+    }
+
+    pub(crate) fn get_mempool_history(
+        &self,
+        status: &ScriptHashStatus,
+        cache: &Cache,
+    ) -> Vec<HistoryEntry> {
+        status.get_mempool(self.chain(), &self.mempool, cache)
+    }
+
+    pub(crate) fn get_balances(
+        &self,
+        statuses: &[&ScriptHashStatus],
+        min_conf: usize,
+    ) -> Vec<Balance> {
+        ScriptHashStatus::get_balances(statuses, self.chain(), min_conf, self.coinbase_min_conf)
+    }
+
+    pub(crate) fn get_block_filter(&self, blockhash: BlockHash) -> Option<FilterRow> {
+        self.index.get_filter(blockhash)
+    }
+
+    /// Find the confirming block of a transaction using the index (without fetching the block),
+    /// unless there are multiple candidates (due to txid prefix collisions).
+    pub(crate) fn get_confirmed_blockhash(
         &self,
         daemon: &Daemon,
         txid: Txid,
-    ) -> Result<Option<(BlockHash, Transaction)>> {
-        // Note: there are two blocks with coinbase transactions having same txid (see BIP-30)
-        let blockhashes = self.index.filter_by_txid(txid);
+    ) -> Result<Option<(usize, BlockHash)>> {
+        if self.index.is_pruned() && self.index.filter_by_txid(txid).next().is_none() {
+            return self.get_pruned_blockhash(daemon, txid);
+        }
+        find_confirmed_blockhash(&self.index, &QueryBlocks::new(daemon, self.chain()), txid)
+    }
+
+    /// Find the confirmation height and the position of a transaction within its block.
+    pub(crate) fn get_tx_position(
+        &self,
+        daemon: &Daemon,
+        cache: &Cache,
+        txid: Txid,
+    ) -> Result<(usize, usize)> {
+        self.ensure_not_in_mempool(txid)?;
+        let (height, blockhash) = match self.get_confirmed_blockhash(daemon, txid)? {
+            Some(result) => result,
+            None => return Err(QueryError::TxNotFound(txid).into()),
+        };
+        let proof = self.get_merkle_proof(daemon, cache, txid, blockhash)?;
+        Ok((height, proof.position()))
+    }
+
+    /// Mempool transactions have no position within a block (nor a merkle proof).
+    pub(crate) fn ensure_not_in_mempool(&self, txid: Txid) -> Result<()> {
+        if self.mempool.get(&txid).is_some() {
+            bail!("transaction {} is not confirmed", txid);
+        }
+        Ok(())
+    }
+
+    /// Create the merkle proof of a confirmed transaction (cached for later requests).
+    pub(crate) fn get_merkle_proof(
+        &self,
+        daemon: &Daemon,
+        cache: &Cache,
+        txid: Txid,
+        blockhash: BlockHash,
+    ) -> Result<Proof> {
+        if let Some(proof) = cache.get_proof(blockhash, txid, Proof::clone) {
+            return Ok(proof);
+        }
+        // the block's tree is shared by its transactions' proofs
+        let tree = self.get_merkle_tree(daemon, cache, blockhash)?;
+        let proof = match tree.find(&txid) {
+            Some(proof) => proof,
+            None => bail!("missing txid {} in block {}", txid, blockhash),
+        };
+        cache.add_proof(blockhash, txid, || proof.clone());
+        Ok(proof)
+    }
+
+    /// The txid prefixes of the transactions confirmed at the given heights (ordered by height).
+    pub(crate) fn get_txid_prefixes_in_range(
+        &self,
+        heights: RangeInclusive<usize>,
+    ) -> Result<Vec<(usize, [u8; HASH_PREFIX_LEN])>> {
+        self.index.txid_prefixes_in_range(heights)
+    }
+
+    fn get_merkle_tree(
+        &self,
+        daemon: &Daemon,
+        cache: &Cache,
+        blockhash: BlockHash,
+    ) -> Result<Arc<MerkleTree>> {
+        cache.get_merkle_tree(blockhash, || {
+            // pruned transactions' blocks may be missing from the index
+            let header = match self.chain().get_block_height(&blockhash) {
+                Some(height) => *self
+                    .chain()
+                    .get_block_header(height)
+                    .expect("missing header"),
+                None => daemon.get_block_header(blockhash)?,
+            };
+            MerkleTree::fetch_verified(header.merkle_root, || daemon.get_block_txids(blockhash))
+        })
+    }
+
+    /// Older transactions may be pruned from the index, so try to find them via `bitcoind`
+    /// (requires `-txindex`).
+    fn get_pruned_blockhash(
+        &self,
+        daemon: &Daemon,
+        txid: Txid,
+    ) -> Result<Option<(usize, BlockHash)>> {
+        let info = match daemon.get_transaction_info(&txid, None) {
+            Ok(info) => info,
+            Err(e) if is_tx_not_found(&e) => {
+                debug!("failed to lookup pruned transaction {}: {:#}", txid, e);
+                return Ok(None);
+            }
+            Err(e) => return Err(e),
+        };
+        let blockhash: BlockHash = match info.get("blockhash") {
+            Some(value) => serde_json::from_value(value.clone()).context("invalid blockhash")?,
+            None => return Ok(None), // unconfirmed transaction
+        };
+        Ok(self
+            .chain()
+            .get_block_height(&blockhash)
+            .map(|height| (height, blockhash)))
+    }
+
+    /// Find the transaction spending the given outpoint (confirmed or in mempool).
+    pub(crate) fn get_spending_txid(
+        &self,
+        daemon: &Daemon,
+        outpoint: OutPoint,
+    ) -> Result<Option<Txid>> {
+        self.index.require_spending()?;
+        // Note: spending index rows may collide, so candidate blocks are verified
+        let blockhashes = self.index.filter_by_spending(outpoint);
         let mut result = None;
-        daemon.for_blocks(blockhashes, |blockhash, block| {
-            for tx in block.txdata {
-                if result.is_some() {
-                    return;
-                }
-                if tx.txid() == txid {
-                    result = Some((blockhash, tx));
-                    return;
-                }
+        daemon.for_query_blocks(self.chain(), blockhashes, |_blockhash, block| {
+            if result.is_none() {
+                result = find_spending_txid(&block, &outpoint);
             }
         })?;
+        if result.is_none() {
+            result = self
+                .mempool
+                .filter_by_spending(&outpoint)
+                .first()
+                .map(|entry| entry.txid);
+        }
+        Ok(result)
+    }
+
+    pub(crate) fn start_chain_stats(&self, daemon: &Daemon) -> ChainStats {
+        ChainStats::start(daemon.slow_rpc(), self.index.transactions_counter())
+    }
+
+    /// Recompute the chain statistics in the background, if the tip has changed
+    /// (the previous ones are served meanwhile).
+    pub(crate) fn refresh_chain_stats(&self, chain_stats: &mut ChainStats) {
+        let chain = self.chain();
+        if chain_stats.tip == Some(chain.tip()) {
+            return;
+        }
+        chain_stats.tip = Some(chain.tip());
+        let stats = json!({
+            "tip": chain.tip(),
+            "indexed_blocks": chain.height() + 1, // including the genesis block
+            "pruned": self.index.is_pruned(),
+            "pruned_height": self.index.pruned_height(),
+        });
+        let _ = chain_stats.tx.send(stats); // fails only if the thread has exited
+    }
+
+    /// Find the earliest confirmed transaction funding the given scripthash (and its height),
+    /// fetching the candidate blocks in height order and stopping at the first match.
+    pub(crate) fn get_first_use(
+        &self,
+        daemon: &Daemon,
+        scripthash: ScriptHash,
+    ) -> Result<Option<(usize, Txid)>> {
+        self.index.require_funding()?;
+        find_first_use(
+            &self.index,
+            &QueryBlocks::new(daemon, self.chain()),
+            scripthash,
+        )
+    }
+
+    /// Find the confirmed transactions having an OP_RETURN output with the given data.
+    pub(crate) fn get_opreturn_txids(
+        &self,
+        daemon: &Daemon,
+        data: &[u8],
+    ) -> Result<Vec<(usize, Txid)>> {
+        if !self.index.indexes_opreturn() {
+            bail!("OP_RETURN outputs are not indexed (see `--index-opreturn`)");
+        }
+        // Note: data index rows may collide, so candidate blocks are verified
+        let blockhashes = self.index.limit_result(self.index.filter_by_data(data))?;
+        let mut result = vec![];
+        daemon.for_query_blocks(self.chain(), blockhashes, |blockhash, block| {
+            if let Some(height) = self.chain().get_block_height(&blockhash) {
+                let txids = find_opreturn_txids(&block, data);
+                result.extend(txids.into_iter().map(|txid| (height, txid)));
+            }
+        })?;
+        result.sort_unstable();
+        result.dedup();
         Ok(result)
     }
+
+    pub(crate) fn lookup_transaction(
+        &self,
+        daemon: &Daemon,
+        txid: Txid,
+    ) -> Result<Option<(BlockHash, Transaction)>> {
+        find_transaction(&self.index, &QueryBlocks::new(daemon, self.chain()), txid)
+    }
+}
+
+fn find_first_use(
+    index: &Index,
+    source: &impl BlockSource,
+    scripthash: ScriptHash,
+) -> Result<Option<(usize, Txid)>> {
+    let chain = index.chain();
+    let mut heights: Vec<usize> = index
+        .filter_by_funding(scripthash)
+        .filter_map(|blockhash| chain.get_block_height(&blockhash))
+        .collect();
+    heights.sort_unstable();
+    heights.dedup();
+    for height in heights {
+        let blockhash = chain.get_block_hash(height).expect("missing block hash");
+        let mut result = None;
+        // Note: funding index rows may collide, so candidate blocks are verified
+        source.for_blocks(std::iter::once(blockhash), |_blockhash, block| {
+            result = find_funding_txid(&block, scripthash);
+        })?;
+        if let Some(txid) = result {
+            return Ok(Some((height, txid)));
+        }
+    }
+    Ok(None)
+}
+
+fn find_transaction(
+    index: &Index,
+    source: &impl BlockSource,
+    txid: Txid,
+) -> Result<Option<(BlockHash, Transaction)>> {
+    // Note: there are two blocks with coinbase transactions having same txid (see BIP-30)
+    let blockhashes = index.filter_by_txid(txid);
+    let mut result = None;
+    source.for_blocks(blockhashes, |blockhash, block| {
+        for tx in block.txdata {
+            if result.is_some() {
+                return;
+            }
+            if tx.txid() == txid {
+                result = Some((blockhash, tx));
+                return;
+            }
+        }
+    })?;
+    Ok(result)
+}
+
+/// The blocks are fetched only if the txid prefix matches more than one indexed block.
+fn find_confirmed_blockhash(
+    index: &Index,
+    source: &impl BlockSource,
+    txid: Txid,
+) -> Result<Option<(usize, BlockHash)>> {
+    let blockhashes: Vec<BlockHash> = index.filter_by_txid(txid).collect();
+    let blockhash = match blockhashes.as_slice() {
+        [] => return Ok(None),
+        [blockhash] => *blockhash,
+        _ => match find_transaction(index, source, txid)? {
+            Some((blockhash, _tx)) => blockhash,
+            None => return Ok(None),
+        },
+    };
+    Ok(index
+        .chain()
+        .get_block_height(&blockhash)
+        .map(|height| (height, blockhash)))
+}
+
+/// Queries are served after the initial sync, unless the index falls behind by more than
+/// `max_lag` blocks (e.g. after bitcoind has synced many new blocks).
+fn serving_status(is_ready: bool, lag: usize, max_lag: Option<usize>) -> Result<(), Error> {
+    if !is_ready {
+        return Err(Error::NotReady);
+    }
+    match max_lag {
+        Some(max_lag) if lag > max_lag => Err(Error::Lagging(lag)),
+        _ => Ok(()),
+    }
+}
+
+fn find_spending_txid(block: &Block, outpoint: &OutPoint) -> Option<Txid> {
+    block
+        .txdata
+        .iter()
+        .find(|tx| tx.input.iter().any(|txi| txi.previous_output == *outpoint))
+        .map(Transaction::txid)
+}
+
+fn find_funding_txid(block: &Block, scripthash: ScriptHash) -> Option<Txid> {
+    block
+        .txdata
+        .iter()
+        .find(|tx| {
+            tx.output
+                .iter()
+                .any(|txo| ScriptHash::new(&txo.script_pubkey) == scripthash)
+        })
+        .map(Transaction::txid)
+}
+
+fn find_opreturn_txids(block: &Block, data: &[u8]) -> Vec<Txid> {
+    block
+        .txdata
+        .iter()
+        .filter(|tx| {
+            tx.output
+                .iter()
+                .any(|txo| opreturn_data(&txo.script_pubkey).as_deref() == Some(data))
+        })
+        .map(Transaction::txid)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        find_confirmed_blockhash, find_first_use, find_funding_txid, find_spending_txid,
+        load_watch_file, serving_status, Error, PrimedStatuses, Watchlist, MAX_PRIMED_HISTORY,
+    };
+    use crate::cache::Cache;
+    use crate::mempool::Mempool;
+    use crate::status::{BlockSource, ScriptHashStatus};
+    use crate::tests::{
+        coinbase, make_blocks, metrics, script, spend, test_index, txout, TestBlocks,
+    };
+    use crate::types::ScriptHash;
+    use anyhow::Result;
+    use bitcoin::{hashes::Hash, Block, BlockHash, OutPoint, Txid};
+    use serde_json::json;
+
+    use std::cell::Cell;
+    use std::convert::TryFrom;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    /// Counts the fetched blocks
+    struct CountingBlocks {
+        blocks: TestBlocks,
+        fetched: Cell<usize>,
+    }
+
+    impl BlockSource for CountingBlocks {
+        fn for_blocks<B, F>(&self, blockhashes: B, func: F) -> Result<()>
+        where
+            B: IntoIterator<Item = BlockHash>,
+            F: FnMut(BlockHash, Block),
+        {
+            let blockhashes: Vec<BlockHash> = blockhashes.into_iter().collect();
+            self.fetched.set(self.fetched.get() + blockhashes.len());
+            self.blocks.for_blocks(blockhashes, func)
+        }
+    }
+
+    #[test]
+    fn test_watchlist() {
+        let tx1 = coinbase(1, vec![txout(100, script(1))]);
+        let tx2 = coinbase(2, vec![txout(50, script(1))]);
+        let blocks = make_blocks(vec![vec![tx1.clone()], vec![tx2.clone()]]);
+        let mut test = test_index(blocks[..1].to_vec());
+        let source = TestBlocks(blocks.clone());
+        let mempool = Mempool::new(&metrics());
+        let cache = Cache::new(&metrics());
+
+        let scripthash = ScriptHash::new(&script(1));
+        let mut watchlist = Watchlist::new(vec![scripthash]);
+        watchlist.sync(&test.index, &mempool, &source, &cache);
+        let status = watchlist.get(&scripthash).unwrap();
+        assert_eq!(
+            json!(status.get_history()),
+            json!([{"tx_hash": tx1.txid(), "height": 1}])
+        );
+        let statushash = status.statushash();
+
+        // a new block is reflected after the sync (without querying the scripthash)
+        test.index.index_blocks(blocks[1..].to_vec());
+        watchlist.sync(&test.index, &mempool, &source, &cache);
+        let status = watchlist.get(&scripthash).unwrap();
+        assert_eq!(
+            json!(status.get_history()),
+            json!([
+                {"tx_hash": tx1.txid(), "height": 1},
+                {"tx_hash": tx2.txid(), "height": 2},
+            ])
+        );
+        assert_ne!(status.statushash(), statushash);
+        assert_eq!(status.tip(), test.index.chain().tip());
+
+        assert!(watchlist.get(&ScriptHash::new(&script(2))).is_none());
+    }
+
+    #[test]
+    fn test_primed_statuses() {
+        let tx1 = coinbase(1, vec![txout(100, script(1))]);
+        let tx2 = coinbase(2, vec![txout(50, script(1))]);
+        let blocks = make_blocks(vec![vec![tx1.clone()], vec![tx2.clone()]]);
+        let mut test = test_index(blocks[..1].to_vec());
+        let source = TestBlocks(blocks.clone());
+        let mempool = Mempool::new(&metrics());
+        let cache = Cache::new(&metrics());
+        let timeout = Duration::from_secs(10);
+
+        // a queried status is kept for priming
+        let scripthash = ScriptHash::new(&script(1));
+        let mut status = ScriptHashStatus::new(scripthash);
+        status.sync(&test.index, &mempool, &source, &cache).unwrap();
+        let mut primed = PrimedStatuses::new(2);
+        primed.insert(Arc::new(status));
+        let sync = |primed: &mut PrimedStatuses, index| {
+            primed.sync(index, &mempool, &source, &cache, timeout)
+        };
+        assert_eq!(sync(&mut primed, &test.index), 1);
+        assert_eq!(sync(&mut primed, &test.index), 0); // no new blocks
+
+        // after a block, the primed status is already synced to the new tip
+        test.index.index_blocks(blocks[1..].to_vec());
+        assert_eq!(sync(&mut primed, &test.index), 1);
+        // (a queried status is taken without being copied, until it's inserted back)
+        let status = primed.take(&scripthash).unwrap();
+        assert!(primed.get(&scripthash).is_none());
+        assert_eq!(Arc::strong_count(&status), 1);
+        assert_eq!(status.tip(), test.index.chain().tip()); // no need to scan the new block
+        assert_eq!(
+            json!(status.get_history()),
+            json!([
+                {"tx_hash": tx1.txid(), "height": 1},
+                {"tx_hash": tx2.txid(), "height": 2},
+            ])
+        );
+
+        // the least recently queried status is evicted
+        let statuses: Vec<Arc<ScriptHashStatus>> = (2..=3)
+            .map(|i| Arc::new(ScriptHashStatus::new(ScriptHash::new(&script(i)))))
+            .collect();
+        primed.insert(Arc::clone(&statuses[0]));
+        primed.insert(status);
+        primed.insert(Arc::clone(&statuses[1]));
+        assert!(primed.get(&scripthash).is_some());
+        assert!(primed.get(&statuses[0].scripthash()).is_none());
+        assert!(primed.get(&statuses[1].scripthash()).is_some());
+        assert_eq!(primed.order.len(), 2);
+
+        // large statuses are synced on query (instead of being primed)
+        let mut primed = PrimedStatuses::new(2);
+        let txs = (0..=MAX_PRIMED_HISTORY)
+            .map(|i| coinbase(i as u32, vec![txout(1, script(4))]))
+            .collect();
+        let blocks = make_blocks(vec![txs]);
+        let large = test_index(blocks.clone());
+        let mut status = ScriptHashStatus::new(ScriptHash::new(&script(4)));
+        let source = TestBlocks(blocks);
+        status
+            .sync(&large.index, &mempool, &source, &cache)
+            .unwrap();
+        assert_eq!(status.get_history().len(), MAX_PRIMED_HISTORY + 1);
+        primed.insert(Arc::new(status));
+        assert!(primed.statuses.is_empty());
+
+        // priming is disabled by default
+        let mut primed = PrimedStatuses::new(0);
+        primed.insert(Arc::clone(&statuses[0]));
+        assert!(primed.get(&statuses[0].scripthash()).is_none());
+        assert_eq!(sync(&mut primed, &test.index), 0);
+    }
+
+    #[test]
+    fn test_load_watch_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("watched.txt");
+        let scripthashes: Vec<ScriptHash> = (0..3u8).map(|i| ScriptHash::hash(&[i])).collect();
+
+        // created on first use
+        assert_eq!(
+            load_watch_file(&path, &scripthashes[..2]).unwrap(),
+            &scripthashes[..2]
+        );
+        // persisted across restarts (and extended by new scripthashes)
+        assert_eq!(load_watch_file(&path, &[]).unwrap(), &scripthashes[..2]);
+        assert_eq!(
+            load_watch_file(&path, &scripthashes[1..]).unwrap(),
+            scripthashes
+        );
+        assert_eq!(load_watch_file(&path, &[]).unwrap(), scripthashes);
+
+        std::fs::write(&path, "abcd\n").unwrap();
+        assert!(load_watch_file(&path, &[]).is_err());
+    }
+
+    #[test]
+    fn test_serving_status() {
+        assert_eq!(serving_status(false, 0, None), Err(Error::NotReady));
+        assert_eq!(serving_status(true, 1000, None), Ok(()));
+
+        let max_lag = Some(6);
+        assert_eq!(serving_status(false, 0, max_lag), Err(Error::NotReady));
+        assert_eq!(serving_status(true, 0, max_lag), Ok(()));
+        assert_eq!(serving_status(true, 6, max_lag), Ok(()));
+        assert_eq!(serving_status(true, 7, max_lag), Err(Error::Lagging(7)));
+        // served again after catching up
+        assert_eq!(serving_status(true, 2, max_lag), Ok(()));
+    }
+
+    #[test]
+    fn test_find_spending_txid() {
+        let tx1 = coinbase(1, vec![txout(100, script(1)), txout(50, script(1))]);
+        let funding = OutPoint::new(tx1.txid(), 0);
+        let tx2 = spend(vec![funding], vec![txout(90, script(2))]);
+        let spending_txid = tx2.txid();
+        let blocks = make_blocks(vec![vec![tx1], vec![tx2]]);
+        let test = test_index(blocks.clone());
+
+        // spent output
+        let candidates: Vec<BlockHash> = test.index.filter_by_spending(funding).collect();
+        assert_eq!(candidates, vec![blocks[1].block_hash()]);
+        assert_eq!(
+            find_spending_txid(&blocks[1], &funding),
+            Some(spending_txid)
+        );
+
+        // unspent output
+        let unspent = OutPoint::new(funding.txid, 1);
+        assert_eq!(test.index.filter_by_spending(unspent).count(), 0);
+
+        // an outpoint colliding with the spent one (`txid[:8] as u64 + vout` is the same)
+        let prefix = <[u8; 8]>::try_from(&funding.txid[..8]).unwrap();
+        let mut bytes = funding.txid.into_inner();
+        bytes[..8].copy_from_slice(&u64::from_be_bytes(prefix).wrapping_sub(1).to_be_bytes());
+        let colliding = OutPoint::new(Txid::from_inner(bytes), 1);
+        let candidates: Vec<BlockHash> = test.index.filter_by_spending(colliding).collect();
+        assert_eq!(candidates, vec![blocks[1].block_hash()]);
+        assert_eq!(find_spending_txid(&blocks[1], &colliding), None);
+    }
+
+    #[test]
+    fn test_find_first_use() {
+        let tx1 = coinbase(1, vec![txout(100, script(1))]);
+        let tx2 = coinbase(2, vec![txout(50, script(2))]);
+        let tx3 = coinbase(3, vec![txout(20, script(2))]);
+        let blocks = make_blocks(vec![vec![tx1.clone()], vec![tx2.clone()], vec![tx3]]);
+        let test = test_index(blocks.clone());
+        let source = CountingBlocks {
+            blocks: TestBlocks(blocks.clone()),
+            fetched: Cell::new(0),
+        };
+
+        // stops at the earliest candidate block (out of two)
+        let first_use = find_first_use(&test.index, &source, ScriptHash::new(&script(2)));
+        assert_eq!(first_use.unwrap(), Some((2, tx2.txid())));
+        assert_eq!(source.fetched.get(), 1);
+
+        let first_use = find_first_use(&test.index, &source, ScriptHash::new(&script(1)));
+        assert_eq!(first_use.unwrap(), Some((1, tx1.txid())));
+        assert_eq!(source.fetched.get(), 2);
+
+        // no candidate blocks are fetched
+        let first_use = find_first_use(&test.index, &source, ScriptHash::new(&script(3)));
+        assert_eq!(first_use.unwrap(), None);
+        assert_eq!(source.fetched.get(), 2);
+
+        assert_eq!(
+            find_funding_txid(&blocks[0], ScriptHash::new(&script(2))),
+            None
+        );
+    }
+
+    #[test]
+    fn test_find_confirmed_blockhash() {
+        let tx1 = coinbase(1, vec![txout(100, script(1))]);
+        let tx2 = spend(
+            vec![OutPoint::new(tx1.txid(), 0)],
+            vec![txout(90, script(2))],
+        );
+        let unconfirmed = spend(
+            vec![OutPoint::new(tx2.txid(), 0)],
+            vec![txout(80, script(3))],
+        );
+        let blocks = make_blocks(vec![vec![tx1.clone()], vec![tx2.clone()]]);
+        let test = test_index(blocks.clone());
+        let source = TestBlocks(blocks.clone());
+
+        assert_eq!(
+            find_confirmed_blockhash(&test.index, &source, tx1.txid()).unwrap(),
+            Some((1, blocks[0].block_hash()))
+        );
+        assert_eq!(
+            find_confirmed_blockhash(&test.index, &source, tx2.txid()).unwrap(),
+            Some((2, blocks[1].block_hash()))
+        );
+        assert_eq!(
+            find_confirmed_blockhash(&test.index, &source, unconfirmed.txid()).unwrap(),
+            None
+        );
+    }
 }
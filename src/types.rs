@@ -3,8 +3,12 @@ use anyhow::Result;
 use std::convert::TryFrom;
 
 use bitcoin::{
+    blockdata::script::Instruction,
     consensus::encode::{deserialize, serialize, Decodable, Encodable},
-    hashes::{borrow_slice_impl, hash_newtype, hex_fmt_impl, index_impl, serde_impl, sha256, Hash},
+    hashes::{
+        borrow_slice_impl, hash_newtype, hex_fmt_impl, index_impl, serde_impl, sha256, sha256d,
+        Hash,
+    },
     BlockHeader, OutPoint, Script, Txid,
 };
 
@@ -37,7 +41,9 @@ macro_rules! impl_consensus_encoding {
     );
 }
 
-const HASH_PREFIX_LEN: usize = 8;
+/// Longer prefixes reduce false-positive index matches, at the cost of disk space.
+/// Changing it requires re-indexing (verified when the DB is opened).
+pub(crate) const HASH_PREFIX_LEN: usize = 8;
 
 type HashPrefix = [u8; HASH_PREFIX_LEN];
 type Height = u32;
@@ -60,6 +66,10 @@ impl HashPrefixRow {
     pub fn height(&self) -> usize {
         usize::try_from(self.height).expect("invalid height")
     }
+
+    pub(crate) fn prefix(&self) -> [u8; HASH_PREFIX_LEN] {
+        self.prefix
+    }
 }
 
 impl_consensus_encoding!(HashPrefixRow, prefix, height);
@@ -101,6 +111,50 @@ impl ScriptHashRow {
 
 // ***************************************************************************
 
+hash_newtype!(
+    DataHash,
+    sha256::Hash,
+    32,
+    doc = "SHA256 of the data pushed by an OP_RETURN output",
+    false
+);
+
+/// Return the data pushed by an OP_RETURN output (if it contains only data pushes).
+pub(crate) fn opreturn_data(script: &Script) -> Option<Vec<u8>> {
+    if !script.is_op_return() {
+        return None;
+    }
+    let mut data = vec![];
+    for instruction in script.instructions().skip(1) {
+        match instruction {
+            Ok(Instruction::PushBytes(bytes)) => data.extend_from_slice(bytes),
+            _ => return None, // non-push opcode or invalid push
+        }
+    }
+    Some(data)
+}
+
+pub(crate) struct DataRow;
+
+impl DataRow {
+    pub(crate) fn scan_prefix(data: &[u8]) -> Box<[u8]> {
+        DataHash::hash(data)[..HASH_PREFIX_LEN]
+            .to_vec()
+            .into_boxed_slice()
+    }
+
+    pub(crate) fn row(data: &[u8], height: usize) -> HashPrefixRow {
+        let mut prefix = HashPrefix::default();
+        prefix.copy_from_slice(&DataHash::hash(data)[..HASH_PREFIX_LEN]);
+        HashPrefixRow {
+            prefix,
+            height: Height::try_from(height).expect("invalid height"),
+        }
+    }
+}
+
+// ***************************************************************************
+
 hash_newtype!(
     StatusHash,
     sha256::Hash,
@@ -112,10 +166,18 @@ hash_newtype!(
 // ***************************************************************************
 
 fn spending_prefix(prev: OutPoint) -> HashPrefix {
-    let txid_prefix = <[u8; HASH_PREFIX_LEN]>::try_from(&prev.txid[..HASH_PREFIX_LEN]).unwrap();
-    let value = u64::from_be_bytes(txid_prefix);
-    let value = value.wrapping_add(prev.vout.into());
-    value.to_be_bytes()
+    // big-endian `txid_prefix + vout` (wrapping on overflow)
+    let mut prefix = txid_prefix(&prev.txid);
+    let mut carry = u64::from(prev.vout);
+    for byte in prefix.iter_mut().rev() {
+        if carry == 0 {
+            break;
+        }
+        let sum = u64::from(*byte) + (carry & 0xFF);
+        *byte = sum as u8;
+        carry = (carry >> 8) + (sum >> 8);
+    }
+    prefix
 }
 
 pub(crate) struct SpendingPrefixRow;
@@ -179,6 +241,44 @@ impl HeaderRow {
     }
 }
 
+// ***************************************************************************
+
+hash_newtype!(
+    FilterHeader,
+    sha256d::Hash,
+    32,
+    doc = "https://github.com/bitcoin/bips/blob/master/bip-0157.mediawiki#filter-headers",
+    true
+);
+
+/// Block filter, stored together with its header (the filters' chain commitment)
+pub(crate) struct FilterRow {
+    pub(crate) header: FilterHeader,
+    pub(crate) filter: Vec<u8>,
+}
+
+impl FilterRow {
+    pub(crate) fn new(filter: Vec<u8>, prev_header: &FilterHeader) -> Self {
+        let filter_hash = sha256d::Hash::hash(&filter);
+        let header = FilterHeader::hash(&[&filter_hash[..], &prev_header[..]].concat());
+        Self { header, filter }
+    }
+
+    pub(crate) fn to_db_row(&self) -> db::Row {
+        [&self.header[..], &self.filter[..]]
+            .concat()
+            .into_boxed_slice()
+    }
+
+    pub(crate) fn from_db_row(row: &[u8]) -> Self {
+        let (header, filter) = row.split_at(FilterHeader::LEN);
+        Self {
+            header: FilterHeader::from_slice(header).expect("bad FilterRow"),
+            filter: filter.to_vec(),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use crate::types::{spending_prefix, HashPrefixRow, ScriptHash, ScriptHashRow, TxidRow};
@@ -0,0 +1,563 @@
+use anyhow::{Context, Result};
+use bitcoin::hashes::{sha1, Hash};
+use flate2::{read::DeflateDecoder, write::DeflateEncoder};
+
+use std::io::{self, BufRead, Read, Write};
+
+// appended to the client's key, for computing the handshake response (see RFC 6455, section 1.3)
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+// the opening handshake is small, so larger requests are rejected before being fully buffered
+const MAX_HANDSHAKE_BYTES: u64 = 8 << 10;
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xA;
+
+// control frames must not be fragmented (see RFC 6455, section 5.5)
+const MAX_CONTROL_PAYLOAD: usize = 125;
+
+// removed from (and appended to) each compressed message (see RFC 7692, section 7.2.1)
+const SYNC_FLUSH_TRAILER: [u8; 4] = [0x00, 0x00, 0xFF, 0xFF];
+
+// small responses (e.g. `server.ping`) gain little from compression
+const MIN_DEFLATE_BYTES: usize = 1 << 10;
+
+/// Messages' compression, negotiated during the handshake.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Compression {
+    Disabled,
+    Deflate, // "permessage-deflate" extension (RFC 7692), without context takeover
+}
+
+/// A complete message, received from a WebSocket client.
+#[derive(Debug, PartialEq)]
+pub(crate) enum Message {
+    Data(Vec<u8>), // text or binary (possibly fragmented)
+    Ping(Vec<u8>),
+    Pong,
+    Close,
+}
+
+/// Read the client's HTTP upgrade request, and reply with the server's handshake response.
+///
+/// Messages are compressed only if `allowed`, and offered by the client.
+pub(crate) fn accept(
+    reader: &mut impl BufRead,
+    mut out: impl Write,
+    allowed: Compression,
+) -> Result<Compression> {
+    let mut request = String::new();
+    let mut key = None;
+    let mut upgrade = false;
+    let mut extensions = vec![];
+    loop {
+        let mut line = String::new();
+        let limit = MAX_HANDSHAKE_BYTES.saturating_sub(request.len() as u64);
+        if reader.by_ref().take(limit).read_line(&mut line)? == 0 {
+            bail!("incomplete handshake request");
+        }
+        request.push_str(&line);
+        if !line.ends_with('\n') {
+            bail!(
+                "handshake request too long (>{} bytes)",
+                MAX_HANDSHAKE_BYTES
+            );
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break; // end of headers
+        }
+        let mut parts = line.splitn(2, ':');
+        let (name, value) = match (parts.next(), parts.next()) {
+            (Some(name), Some(value)) => (name.trim().to_ascii_lowercase(), value.trim()),
+            _ => continue, // request line
+        };
+        match name.as_str() {
+            "sec-websocket-key" => key = Some(value.to_owned()),
+            "upgrade" => upgrade = value.eq_ignore_ascii_case("websocket"),
+            "sec-websocket-extensions" => extensions.push(value.to_owned()),
+            _ => (),
+        }
+    }
+    let key = match key {
+        Some(key) if upgrade => key,
+        _ => {
+            out.write_all(b"HTTP/1.1 400 Bad Request\r\nConnection: close\r\n\r\n")?;
+            bail!("not a WebSocket upgrade request");
+        }
+    };
+    let compression = match allowed {
+        Compression::Deflate if extensions.iter().any(|value| offers_deflate(value)) => {
+            Compression::Deflate
+        }
+        _ => Compression::Disabled,
+    };
+    let extension = match compression {
+        Compression::Deflate => {
+            "Sec-WebSocket-Extensions: permessage-deflate; \
+             server_no_context_takeover; client_no_context_takeover\r\n"
+        }
+        Compression::Disabled => "",
+    };
+    write!(
+        out,
+        "HTTP/1.1 101 Switching Protocols\r\n\
+         Upgrade: websocket\r\n\
+         Connection: Upgrade\r\n\
+         Sec-WebSocket-Accept: {}\r\n{}\r\n",
+        accept_key(&key),
+        extension,
+    )
+    .context("failed to send handshake response")?;
+    Ok(compression)
+}
+
+/// Whether the client offers "permessage-deflate" with acceptable parameters, e.g.:
+/// `permessage-deflate; client_max_window_bits, x-webkit-deflate-frame`
+fn offers_deflate(extensions: &str) -> bool {
+    extensions.split(',').any(|offer| {
+        let mut params = offer.split(';').map(str::trim);
+        // responses are compressed using the default (maximal) window size
+        params.next() == Some("permessage-deflate")
+            && params.all(|param| !param.starts_with("server_max_window_bits"))
+    })
+}
+
+fn accept_key(key: &str) -> String {
+    let hash = sha1::Hash::hash(format!("{}{}", key, ACCEPT_GUID).as_bytes());
+    base64_encode(&hash.into_inner())
+}
+
+fn base64_encode(data: &[u8]) -> String {
+    const CHARS: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut result = String::with_capacity((data.len() + 2) / 3 * 4);
+    for chunk in data.chunks(3) {
+        let bytes = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let n = (u32::from(bytes[0]) << 16) | (u32::from(bytes[1]) << 8) | u32::from(bytes[2]);
+        for i in 0..4 {
+            if i <= chunk.len() {
+                result.push(CHARS[((n >> (18 - 6 * i)) & 0x3F) as usize] as char);
+            } else {
+                result.push('=');
+            }
+        }
+    }
+    result
+}
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn check_len(len: u64, max_len: Option<usize>) -> io::Result<()> {
+    match max_len {
+        Some(max_len) if len > max_len as u64 => Err(invalid_data(format!(
+            "message too long (>{} bytes)",
+            max_len
+        ))),
+        _ => Ok(()),
+    }
+}
+
+fn deflate(data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut encoder = DeflateEncoder::new(vec![], flate2::Compression::fast());
+    encoder.write_all(data)?;
+    encoder.flush()?; // sync flush, ending with an empty (non-final) block
+    let mut result = std::mem::take(encoder.get_mut());
+    debug_assert!(result.ends_with(&SYNC_FLUSH_TRAILER));
+    result.truncate(result.len() - SYNC_FLUSH_TRAILER.len());
+    Ok(result)
+}
+
+fn inflate(data: &[u8], max_len: Option<usize>) -> io::Result<Vec<u8>> {
+    let decoder = DeflateDecoder::new(data.chain(&SYNC_FLUSH_TRAILER[..]));
+    // stop reading as soon as the limit is exceeded (instead of decompressing all of the message)
+    let limit = max_len.map_or(u64::MAX, |max_len| max_len as u64 + 1);
+    let mut result = vec![];
+    decoder.take(limit).read_to_end(&mut result)?;
+    check_len(result.len() as u64, max_len)?;
+    Ok(result)
+}
+
+/// Read a single frame, returning its FIN and RSV1 bits, opcode and (unmasked) payload
+/// (or `None` on EOF).
+fn read_frame(
+    reader: &mut impl Read,
+    max_len: Option<usize>,
+) -> io::Result<Option<(bool, bool, u8, Vec<u8>)>> {
+    let mut head = [0u8; 2];
+    if reader.read(&mut head[..1])? == 0 {
+        return Ok(None);
+    }
+    reader.read_exact(&mut head[1..])?;
+    let fin = head[0] & 0x80 != 0;
+    let rsv1 = head[0] & 0x40 != 0; // set on compressed messages' first frame
+    if head[0] & 0x30 != 0 {
+        return Err(invalid_data("unexpected RSV2/RSV3 bits".to_owned()));
+    }
+    let opcode = head[0] & 0x0F;
+    if head[1] & 0x80 == 0 {
+        return Err(invalid_data("unmasked client frame".to_owned()));
+    }
+    let len = match head[1] & 0x7F {
+        126 => {
+            let mut buf = [0u8; 2];
+            reader.read_exact(&mut buf)?;
+            u64::from(u16::from_be_bytes(buf))
+        }
+        127 => {
+            let mut buf = [0u8; 8];
+            reader.read_exact(&mut buf)?;
+            u64::from_be_bytes(buf)
+        }
+        len => u64::from(len),
+    };
+    check_len(len, max_len)?;
+    let mut mask = [0u8; 4];
+    reader.read_exact(&mut mask)?;
+    let mut payload = vec![];
+    reader.by_ref().take(len).read_to_end(&mut payload)?;
+    if payload.len() as u64 != len {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    for (i, b) in payload.iter_mut().enumerate() {
+        *b ^= mask[i % 4];
+    }
+    Ok(Some((fin, rsv1, opcode, payload)))
+}
+
+/// Reads a connection's messages, keeping a fragmented message's partial payload
+/// across the control frames that may be interleaved with it.
+pub(crate) struct MessageReader {
+    data: Option<Vec<u8>>,
+    compressed: bool,
+    max_len: Option<usize>,
+    compression: Compression,
+}
+
+impl MessageReader {
+    pub(crate) fn new(max_len: Option<usize>, compression: Compression) -> Self {
+        Self {
+            data: None,
+            compressed: false,
+            max_len,
+            compression,
+        }
+    }
+
+    /// Read the next message (reassembling and decompressing fragmented ones),
+    /// failing if it exceeds `max_len` bytes.
+    pub(crate) fn read(&mut self, reader: &mut impl Read) -> io::Result<Option<Message>> {
+        while let Some((fin, rsv1, opcode, payload)) = read_frame(reader, self.max_len)? {
+            let first = self.data.is_none() && (opcode == OPCODE_TEXT || opcode == OPCODE_BINARY);
+            if rsv1 && !(first && self.compression == Compression::Deflate) {
+                return Err(invalid_data("unexpected compressed frame".to_owned()));
+            }
+            // control frames may be interleaved with a fragmented message, but not fragmented
+            if opcode >= OPCODE_CLOSE && !(fin && payload.len() <= MAX_CONTROL_PAYLOAD) {
+                return Err(invalid_data(format!(
+                    "invalid control frame: {:#x}",
+                    opcode
+                )));
+            }
+            match opcode {
+                OPCODE_PING => return Ok(Some(Message::Ping(payload))),
+                OPCODE_PONG => return Ok(Some(Message::Pong)),
+                OPCODE_CLOSE => return Ok(Some(Message::Close)),
+                OPCODE_TEXT | OPCODE_BINARY if first => {
+                    self.compressed = rsv1;
+                    self.data = Some(payload);
+                }
+                OPCODE_CONTINUATION if self.data.is_some() => {
+                    let buf = self.data.as_mut().unwrap();
+                    buf.extend_from_slice(&payload);
+                    check_len(buf.len() as u64, self.max_len)?;
+                }
+                _ => return Err(invalid_data(format!("unexpected opcode: {:#x}", opcode))),
+            }
+            if fin {
+                let data = self.data.take().unwrap();
+                let data = if self.compressed {
+                    inflate(&data, self.max_len)?
+                } else {
+                    data
+                };
+                return Ok(Some(Message::Data(data)));
+            }
+        }
+        match self.data {
+            None => Ok(None),
+            Some(_) => Err(io::ErrorKind::UnexpectedEof.into()), // inside a fragmented message
+        }
+    }
+}
+
+fn write_frame(
+    out: &mut impl Write,
+    opcode: u8,
+    compressed: bool,
+    payload: &[u8],
+) -> io::Result<()> {
+    // FIN, unmasked (as sent by servers)
+    let mut head = vec![0x80 | if compressed { 0x40 } else { 0 } | opcode];
+    match payload.len() {
+        len if len < 126 => head.push(len as u8),
+        len if len <= 0xFFFF => {
+            head.push(126);
+            head.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            head.push(127);
+            head.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    out.write_all(&head)?;
+    out.write_all(payload)
+}
+
+/// Send a text message (as a single frame), compressing it if it's large enough.
+pub(crate) fn write_text(
+    out: &mut impl Write,
+    text: &[u8],
+    compression: Compression,
+) -> io::Result<()> {
+    if compression == Compression::Deflate && text.len() >= MIN_DEFLATE_BYTES {
+        return write_frame(out, OPCODE_TEXT, true, &deflate(text)?);
+    }
+    write_frame(out, OPCODE_TEXT, false, text)
+}
+
+/// Reply to a client's keepalive ping.
+pub(crate) fn write_pong(out: &mut impl Write, payload: &[u8]) -> io::Result<()> {
+    write_frame(out, OPCODE_PONG, false, payload)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::{
+        accept, accept_key, base64_encode, deflate, inflate, write_text, Compression, Message,
+        MessageReader,
+    };
+    use flate2::read::DeflateDecoder;
+    use serde_json::{json, Value};
+    use std::io::{self, BufReader, Read};
+
+    /// Encode a (masked) client frame.
+    pub(crate) fn client_frame(fin: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mask = [0x12, 0x34, 0x56, 0x78];
+        let mut frame = vec![if fin { 0x80 } else { 0 } | opcode];
+        match payload.len() {
+            len if len < 126 => frame.push(0x80 | len as u8),
+            len => {
+                frame.push(0x80 | 126);
+                frame.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+        }
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        frame
+    }
+
+    #[test]
+    fn test_accept_key() {
+        assert_eq!(base64_encode(b""), "");
+        assert_eq!(base64_encode(b"f"), "Zg==");
+        assert_eq!(base64_encode(b"fo"), "Zm8=");
+        assert_eq!(base64_encode(b"foo"), "Zm9v");
+        assert_eq!(base64_encode(b"foobar"), "Zm9vYmFy");
+        // example from RFC 6455
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[test]
+    fn test_accept() {
+        let request = "GET / HTTP/1.1\r\n\
+                       Host: localhost\r\n\
+                       Upgrade: websocket\r\n\
+                       Connection: Upgrade\r\n\
+                       Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+                       Sec-WebSocket-Version: 13\r\n\r\n";
+        let mut reader = BufReader::new(request.as_bytes());
+        let mut response = vec![];
+        let compression = accept(&mut reader, &mut response, Compression::Deflate).unwrap();
+        assert_eq!(compression, Compression::Disabled); // not offered by the client
+        let response = String::from_utf8(response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 101 Switching Protocols\r\n"));
+        assert!(response.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+
+        let mut reader = BufReader::new(&b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n"[..]);
+        let mut response = vec![];
+        let err = accept(&mut reader, &mut response, Compression::Disabled).unwrap_err();
+        assert_eq!(err.to_string(), "not a WebSocket upgrade request");
+        assert!(response.starts_with(b"HTTP/1.1 400 Bad Request\r\n"));
+    }
+
+    #[test]
+    fn test_read_message() {
+        let mut data = vec![];
+        data.extend(client_frame(true, 0x1, b"hello"));
+        data.extend(client_frame(false, 0x1, b"frag"));
+        data.extend(client_frame(true, 0x9, b"ping")); // interleaved control frame
+        data.extend(client_frame(true, 0x0, b"mented"));
+        data.extend(client_frame(true, 0x1, &[b'x'; 300]));
+        data.extend(client_frame(true, 0x8, b""));
+        let mut reader = &data[..];
+        let mut messages = MessageReader::new(Some(300), Compression::Disabled);
+        let mut read = || messages.read(&mut reader).unwrap();
+        assert_eq!(read(), Some(Message::Data(b"hello".to_vec())));
+        assert_eq!(read(), Some(Message::Ping(b"ping".to_vec())));
+        assert_eq!(read(), Some(Message::Data(b"fragmented".to_vec())));
+        assert_eq!(read(), Some(Message::Data(vec![b'x'; 300])));
+        assert_eq!(read(), Some(Message::Close));
+        assert_eq!(read(), None);
+
+        let data = client_frame(true, 0x1, &[b'x'; 301]);
+        let mut messages = MessageReader::new(Some(300), Compression::Disabled);
+        let err = messages.read(&mut &data[..]).unwrap_err();
+        assert_eq!(err.to_string(), "message too long (>300 bytes)");
+
+        let mut data = client_frame(true, 0x1, b"unmasked");
+        data[1] &= 0x7F;
+        let mut messages = MessageReader::new(None, Compression::Disabled);
+        let err = messages.read(&mut &data[..]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        for data in &[
+            client_frame(false, 0x9, b"ping"),
+            client_frame(true, 0x9, &[0; 126]),
+        ] {
+            let mut messages = MessageReader::new(None, Compression::Disabled);
+            let err = messages.read(&mut &data[..]).unwrap_err();
+            assert_eq!(err.to_string(), "invalid control frame: 0x9");
+        }
+    }
+
+    #[test]
+    fn test_write_text() {
+        let mut out = vec![];
+        write_text(&mut out, b"abc", Compression::Deflate).unwrap();
+        assert_eq!(out, b"\x81\x03abc");
+
+        let mut out = vec![];
+        write_text(&mut out, &[b'x'; 200], Compression::Disabled).unwrap();
+        assert_eq!(out[..4], [0x81, 126, 0, 200]);
+        assert_eq!(out.len(), 4 + 200);
+    }
+
+    #[test]
+    fn test_accept_deflate() {
+        let accept_with = |extensions: &str, allowed| {
+            let request = format!(
+                "GET / HTTP/1.1\r\n\
+                 Upgrade: websocket\r\n\
+                 Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+                 Sec-WebSocket-Extensions: {}\r\n\r\n",
+                extensions
+            );
+            let mut response = vec![];
+            let compression = accept(&mut request.as_bytes(), &mut response, allowed).unwrap();
+            let response = String::from_utf8(response).unwrap();
+            let negotiated = response.contains("Sec-WebSocket-Extensions: permessage-deflate;");
+            assert_eq!(negotiated, compression == Compression::Deflate);
+            compression
+        };
+        let offer = "permessage-deflate; client_max_window_bits, x-webkit-deflate-frame";
+        assert_eq!(
+            accept_with(offer, Compression::Deflate),
+            Compression::Deflate
+        );
+        assert_eq!(
+            accept_with(offer, Compression::Disabled),
+            Compression::Disabled
+        );
+        let offer = "permessage-deflate; server_max_window_bits=10";
+        assert_eq!(
+            accept_with(offer, Compression::Deflate),
+            Compression::Disabled
+        );
+        let offer = "x-webkit-deflate-frame";
+        assert_eq!(
+            accept_with(offer, Compression::Deflate),
+            Compression::Disabled
+        );
+    }
+
+    #[test]
+    fn test_compressed_response() {
+        let history: Vec<Value> = (0..100)
+            .map(|height| json!({"tx_hash": "ab".repeat(32), "height": height}))
+            .collect();
+        let response = json!({"jsonrpc": "2.0", "id": 1, "result": history}).to_string();
+
+        let mut out = vec![];
+        write_text(&mut out, response.as_bytes(), Compression::Deflate).unwrap();
+        assert_eq!(out[0], 0xC1); // FIN, RSV1 (compressed) and text opcode
+        let (len, payload) = match out[1] {
+            126 => (usize::from(u16::from_be_bytes([out[2], out[3]])), &out[4..]),
+            len => (usize::from(len), &out[2..]),
+        };
+        assert_eq!(len, payload.len());
+        assert!(len < response.len() / 4);
+
+        // decompressed as specified by RFC 7692 (with the sync flush trailer)
+        let mut input = payload.to_vec();
+        input.extend_from_slice(&[0x00, 0x00, 0xFF, 0xFF]);
+        let mut decompressed = String::new();
+        DeflateDecoder::new(&input[..])
+            .read_to_string(&mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed, response);
+        let value: Value = serde_json::from_str(&decompressed).unwrap();
+        assert_eq!(value["result"][99]["height"], json!(99));
+    }
+
+    #[test]
+    fn test_compressed_request() {
+        let request = r#"{"jsonrpc": "2.0", "method": "server.ping", "id": 1}"#.repeat(10);
+        let compressed = deflate(request.as_bytes()).unwrap();
+        assert_eq!(inflate(&compressed, None).unwrap(), request.as_bytes());
+
+        let mut data = client_frame(true, 0x1, &compressed);
+        data[0] |= 0x40; // RSV1
+        let mut messages = MessageReader::new(None, Compression::Deflate);
+        let msg = messages.read(&mut &data[..]).unwrap();
+        assert_eq!(msg, Some(Message::Data(request.as_bytes().to_vec())));
+
+        // a fragmented compressed message, interleaved with a ping
+        let (head, tail) = compressed.split_at(compressed.len() / 2);
+        let mut data = client_frame(false, 0x1, head);
+        data[0] |= 0x40; // RSV1 (on the first frame only)
+        data.extend(client_frame(true, 0x9, b"ping"));
+        data.extend(client_frame(true, 0x0, tail));
+        let mut reader = &data[..];
+        let mut messages = MessageReader::new(None, Compression::Deflate);
+        let msg = messages.read(&mut reader).unwrap();
+        assert_eq!(msg, Some(Message::Ping(b"ping".to_vec())));
+        let msg = messages.read(&mut reader).unwrap();
+        assert_eq!(msg, Some(Message::Data(request.as_bytes().to_vec())));
+
+        let mut data = client_frame(true, 0x1, &compressed);
+        data[0] |= 0x40; // RSV1
+
+        // decompressed messages are limited too
+        let mut messages = MessageReader::new(Some(200), Compression::Deflate);
+        let err = messages.read(&mut &data[..]).unwrap_err();
+        assert_eq!(err.to_string(), "message too long (>200 bytes)");
+
+        // compression must be negotiated first
+        let mut messages = MessageReader::new(None, Compression::Disabled);
+        let err = messages.read(&mut &data[..]).unwrap_err();
+        assert_eq!(err.to_string(), "unexpected compressed frame");
+    }
+}